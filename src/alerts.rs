@@ -0,0 +1,196 @@
+use crate::error::{GimmewireError, Result};
+use crate::notify;
+use crate::store::Store;
+use crate::wireguard::{self, Interface};
+use configparser::ini::Ini;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Metrics which rules can refer to.
+const METRICS: &[&str] = &[
+    "peer_count",
+    "pool_utilization",
+    "peer_online_count",
+    "rx_bytes",
+    "tx_bytes",
+];
+
+/// A rule from `[Alerts]`, e.g. `PoolFull = pool_utilization > 0.9 for 10m`.
+#[derive(Debug, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub metric: String,
+    pub op: String,
+    pub threshold: f64,
+    pub duration: Duration,
+}
+
+impl Rule {
+    pub fn parse(name: &str, expr: &str) -> Result<Rule> {
+        let parts: Vec<&str> = expr.split_whitespace().collect();
+        let (metric, op, threshold, duration) = match parts[..] {
+            [metric, op, threshold] => (metric, op, threshold, "0s"),
+            [metric, op, threshold, "for", duration] => (metric, op, threshold, duration),
+            _ => {
+                return Err(GimmewireError::Config(format!(
+                    "Alert {}: expected `<metric> <op> <value> [for <duration>]`",
+                    name
+                )))
+            }
+        };
+        if !METRICS.contains(&metric) {
+            return Err(GimmewireError::Config(format!(
+                "Alert {}: unknown metric {}",
+                name, metric
+            )));
+        }
+        if !["<", "<=", ">", ">=", "==", "!="].contains(&op) {
+            return Err(GimmewireError::Config(format!(
+                "Alert {}: unknown operator {}",
+                name, op
+            )));
+        }
+        let threshold = threshold.parse().map_err(|_| {
+            GimmewireError::Config(format!("Alert {}: {} is not a number", name, threshold))
+        })?;
+        Ok(Rule {
+            name: name.to_string(),
+            metric: metric.to_string(),
+            op: op.to_string(),
+            threshold,
+            duration: parse_duration(duration)
+                .ok_or_else(|| GimmewireError::Config(format!("Alert {}: bad duration", name)))?,
+        })
+    }
+
+    pub fn holds(&self, value: f64) -> bool {
+        match self.op.as_str() {
+            "<" => value < self.threshold,
+            "<=" => value <= self.threshold,
+            ">" => value > self.threshold,
+            ">=" => value >= self.threshold,
+            "==" => value == self.threshold,
+            _ => value != self.threshold,
+        }
+    }
+}
+
+/// Parses `90s`, `10m` or `2h`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit())?);
+    let value: u64 = value.parse().ok()?;
+    match unit {
+        "s" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_secs(value * 60)),
+        "h" => Some(Duration::from_secs(value * 60 * 60)),
+        _ => None,
+    }
+}
+
+pub fn rules(config: &Ini) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    let map = config.get_map_ref();
+    if let Some(section) = map.get("alerts") {
+        for (name, expr) in section {
+            match expr.as_deref().map(|expr| Rule::parse(name, expr)) {
+                Some(Ok(rule)) => rules.push(rule),
+                Some(Err(why)) => tracing::error!("{}", why),
+                None => tracing::error!("Alert {} has no rule", name),
+            }
+        }
+    }
+    rules
+}
+
+pub async fn metrics(
+    store: &Store,
+    interfaces: &[Interface],
+) -> crate::error::Result<HashMap<&'static str, f64>> {
+    let peers = store.get_peers().await?;
+    let mut metrics = HashMap::new();
+    metrics.insert("peer_count", peers.len() as f64);
+    let pool_size: usize = interfaces
+        .iter()
+        .map(|interface| interface.addresses().count())
+        .sum();
+    metrics.insert(
+        "pool_utilization",
+        peers.iter().filter(|peer| peer.ip.is_some()).count() as f64 / pool_size as f64,
+    );
+    if let Ok(stats) = wireguard::show_all(interfaces).await {
+        metrics.insert(
+            "peer_online_count",
+            stats.iter().filter(|stat| stat.online()).count() as f64,
+        );
+        metrics.insert("rx_bytes", stats.iter().map(|stat| stat.rx as f64).sum());
+        metrics.insert("tx_bytes", stats.iter().map(|stat| stat.tx as f64).sum());
+    }
+    Ok(metrics)
+}
+
+/// Evaluates `[Alerts]` rules every minute and tells the admin when they fire or resolve.
+pub async fn watch(store: Store, config: Arc<Mutex<Ini>>) {
+    let (rules, interfaces) = {
+        let config = config.lock().await;
+        (rules(&config), wireguard::interfaces(&config))
+    };
+    if rules.is_empty() {
+        return;
+    }
+    // Since when each rule holds, and whether it was reported
+    let mut pending: HashMap<String, (Instant, bool)> = HashMap::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        ticker.tick().await;
+        // No peers is what an unreachable db would look like, rules wait for it instead
+        let metrics = match metrics(&store, &interfaces).await {
+            Err(why) => {
+                tracing::warn!("Cannot evaluate alerts: {}", why);
+                continue;
+            }
+            Ok(metrics) => metrics,
+        };
+        for rule in &rules {
+            let value = match metrics.get(rule.metric.as_str()) {
+                None => continue,
+                Some(value) => *value,
+            };
+            let msg = if rule.holds(value) {
+                let (since, fired) = pending
+                    .entry(rule.name.clone())
+                    .or_insert((Instant::now(), false));
+                if *fired || since.elapsed() < rule.duration {
+                    continue;
+                }
+                *fired = true;
+                format!(
+                    "🔥 Alert {}: {} is {} ({} {})",
+                    rule.name, rule.metric, value, rule.op, rule.threshold
+                )
+            } else {
+                match pending.remove(&rule.name) {
+                    Some((_, true)) => format!(
+                        "✅ Alert {} resolved: {} is {}",
+                        rule.name, rule.metric, value
+                    ),
+                    _ => continue,
+                }
+            };
+            tracing::warn!("{}", msg);
+            notify::send(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn rule_parsing() {
+    let rule = Rule::parse("pool", "pool_utilization > 0.9 for 10m").unwrap();
+    assert!(rule.duration == Duration::from_secs(600) && rule.holds(0.95) && !rule.holds(0.9));
+    let rule = Rule::parse("offline", "peer_online_count == 0").unwrap();
+    assert!(rule.duration == Duration::ZERO && rule.holds(0.0));
+    assert!(Rule::parse("bad", "peer_count ~ 1").is_err());
+    assert!(Rule::parse("bad", "peer_count > 1 for 10y").is_err());
+}
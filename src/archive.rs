@@ -0,0 +1,280 @@
+//! ZIP archives of several client configs, so users with many devices or regions and admins
+//! provisioning a team get one file instead of loose ones. Entries are stored without
+//! compression, configs are tiny. With a password they are encrypted with AES-256 the WinZip way
+//! (AE-2), which 7-Zip, WinRAR, Keka and most mobile unzippers open. The old ZipCrypto would give
+//! the keys away to anyone who knows that configs start with `[Interface]`.
+use crate::error::{GimmewireError, Result};
+use bson::DateTime;
+#[cfg(feature = "zip")]
+use configparser::ini::Ini;
+#[cfg(feature = "zip")]
+use openssl::hash::MessageDigest;
+#[cfg(feature = "zip")]
+use openssl::pkey::PKey;
+#[cfg(feature = "zip")]
+use openssl::sign::Signer;
+#[cfg(feature = "zip")]
+use openssl::symm::{Cipher, Crypter, Mode};
+
+#[cfg(feature = "zip")]
+const SALT: usize = 16;
+#[cfg(feature = "zip")]
+const ITERATIONS: usize = 1000;
+/// Bytes of the HMAC-SHA1 of the encrypted data which are stored.
+#[cfg(feature = "zip")]
+const AUTH: usize = 10;
+/// Letters and digits of passwords, without those which look alike.
+#[cfg(feature = "zip")]
+const ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyzACDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// What `[Zip]` says about sending several configs at once.
+#[cfg(feature = "zip")]
+#[derive(Debug, PartialEq)]
+pub struct Settings {
+    pub enabled: bool,
+    /// Minutes after which the message with the password is deleted, 0 keeps it.
+    pub delete_password: u64,
+}
+
+#[cfg(feature = "zip")]
+pub fn settings(config: &Ini) -> Settings {
+    Settings {
+        enabled: config
+            .getbool("Zip", "Enabled")
+            .unwrap_or(None)
+            .unwrap_or(true),
+        delete_password: config
+            .getuint("Zip", "DeletePassword")
+            .unwrap_or(None)
+            .unwrap_or(60),
+    }
+}
+
+/// A random password for an archive.
+#[cfg(feature = "zip")]
+pub fn password() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes).map_err(ssl)?;
+    Ok(bytes
+        .iter()
+        .map(|byte| ALPHABET[*byte as usize % ALPHABET.len()] as char)
+        .collect())
+}
+
+/// A ZIP of the (name, contents) files, each encrypted with the password when there is one.
+pub fn zip(files: &[(String, Vec<u8>)], password: Option<&str>) -> Result<Vec<u8>> {
+    let (time, date) = dos_date(DateTime::now());
+    // Header 0x9901, 7 bytes: AE-2, vendor AE, AES-256, stored without compression
+    let extra: &[u8] = match password {
+        None => &[],
+        Some(_) => &[0x01, 0x99, 7, 0, 2, 0, b'A', b'E', 3, 0, 0],
+    };
+    let mut archive = Vec::new();
+    let mut central = Vec::new();
+    for (name, contents) in files {
+        // Version 5.1, encrypted, method 99 which is AES, no CRC in AE-2, or 2.0 and stored
+        let (version, flags, method, crc, data) = match password {
+            None => (
+                20u16,
+                0u16,
+                0u16,
+                crc32fast::hash(contents),
+                contents.clone(),
+            ),
+            Some(password) => (51, 1, 99, 0, encrypt(contents, password)?),
+        };
+        let offset = archive.len() as u32;
+        let common = |buffer: &mut Vec<u8>| {
+            buffer.extend_from_slice(&version.to_le_bytes());
+            buffer.extend_from_slice(&flags.to_le_bytes());
+            buffer.extend_from_slice(&method.to_le_bytes());
+            buffer.extend_from_slice(&time.to_le_bytes());
+            buffer.extend_from_slice(&date.to_le_bytes());
+            buffer.extend_from_slice(&crc.to_le_bytes());
+            buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        };
+        archive.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        common(&mut archive);
+        archive.extend_from_slice(name.as_bytes());
+        archive.extend_from_slice(extra);
+        archive.extend_from_slice(&data);
+        central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central.extend_from_slice(&version.to_le_bytes());
+        common(&mut central);
+        // No comment, disk 0, no attributes
+        central.extend_from_slice(&[0; 10]);
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+        central.extend_from_slice(extra);
+    }
+    let (central_offset, central_size) = (archive.len() as u32, central.len() as u32);
+    archive.extend_from_slice(&central);
+    archive.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    archive.extend_from_slice(&[0; 4]);
+    archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&central_size.to_le_bytes());
+    archive.extend_from_slice(&central_offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes());
+    Ok(archive)
+}
+
+/// Salt, password verifier, the data encrypted with AES-256 in CTR mode and its HMAC-SHA1.
+#[cfg(feature = "zip")]
+fn encrypt(data: &[u8], password: &str) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT];
+    openssl::rand::rand_bytes(&mut salt).map_err(ssl)?;
+    let (key, mac_key, verifier) = keys(password, &salt)?;
+    let encrypted = ctr(&key, data)?;
+    let mac_key = PKey::hmac(&mac_key).map_err(ssl)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &mac_key).map_err(ssl)?;
+    signer.update(&encrypted).map_err(ssl)?;
+    let auth = signer.sign_to_vec().map_err(ssl)?;
+    Ok([&salt[..], &verifier, &encrypted, &auth[..AUTH]].concat())
+}
+
+/// AES key, HMAC key and password verifier derived from the password.
+#[cfg(feature = "zip")]
+fn keys(password: &str, salt: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let mut derived = [0u8; 66];
+    openssl::pkcs5::pbkdf2_hmac(
+        password.as_bytes(),
+        salt,
+        ITERATIONS,
+        MessageDigest::sha1(),
+        &mut derived,
+    )
+    .map_err(ssl)?;
+    Ok((
+        derived[..32].to_vec(),
+        derived[32..64].to_vec(),
+        derived[64..].to_vec(),
+    ))
+}
+
+/// CTR the WinZip way, a little-endian counter from 1. Encrypts and decrypts alike.
+#[cfg(feature = "zip")]
+fn ctr(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let counters: Vec<u8> = (1..=data.len().div_ceil(16) as u128)
+        .flat_map(|counter| counter.to_le_bytes())
+        .collect();
+    let cipher = Cipher::aes_256_ecb();
+    let mut crypter = Crypter::new(cipher, Mode::Encrypt, key, None).map_err(ssl)?;
+    crypter.pad(false);
+    let mut stream = vec![0u8; counters.len() + cipher.block_size()];
+    let written = crypter.update(&counters, &mut stream).map_err(ssl)?;
+    stream.truncate(written);
+    Ok(data
+        .iter()
+        .zip(stream)
+        .map(|(byte, key)| byte ^ key)
+        .collect())
+}
+
+/// MS-DOS time and date fields of the UTC date.
+fn dos_date(now: DateTime) -> (u16, u16) {
+    let millis = now.timestamp_millis();
+    let seconds = millis.div_euclid(1000).rem_euclid(86400);
+    // Year, month and day from days since 1970, see http://howardhinnant.github.io/date_algorithms.html
+    let z = millis.div_euclid(86_400_000) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let time = ((seconds / 3600) << 11) | ((seconds % 3600 / 60) << 5) | (seconds % 60 / 2);
+    let date = ((year.clamp(1980, 2107) - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+#[cfg(feature = "zip")]
+fn ssl(why: openssl::error::ErrorStack) -> GimmewireError {
+    GimmewireError::Invalid(format!("Cannot encrypt the archive: {}", why))
+}
+
+#[cfg(not(feature = "zip"))]
+fn encrypt(_: &[u8], _: &str) -> Result<Vec<u8>> {
+    Err(GimmewireError::Config(
+        "gimmewire was built without the `zip` feature".to_string(),
+    ))
+}
+
+/// An AE-1 archive of `alice.conf` with the password `secret`, made by libarchive 3.8.2 with
+/// `bsdtar --format zip --options zip:encryption=aes256,zip:compression=store`.
+#[cfg(all(test, feature = "zip"))]
+const BSDTAR: &[&str] = &[
+    "504b0304140009006300c4536f580000000000000000000000000a002b00616c6963652e636f6e6675780b0001040000",
+    "00000400000000019907000100414503000055540d00073023f4653023f4653b50cf6a18060b184ff687c6939aa39b0f",
+    "b9acdb1e20e1d0b3c4261948dfd3fb6e5d16e59fbfd7ae013ca653a3513a8dc5123324e9520e49c9b2c2504b0708216d",
+    "3e40370000001b000000504b01021403140009006300c4536f58216d3e40370000001b0000000a002300000000000000",
+    "0000a48100000000616c6963652e636f6e6675780b000104000000000400000000019907000100414503000055540500",
+    "013023f465504b050600000000010001005b0000009a0000000000",
+];
+
+#[cfg(all(test, feature = "zip"))]
+#[test]
+fn encrypted_zip() {
+    let u16_at = |bytes: &[u8], at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+    let u32_at =
+        |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    // (name, method, crc, data) of the entries in the central directory
+    let entries = |archive: &[u8]| {
+        let end = archive.len() - 22;
+        let mut at = u32_at(archive, end + 16) as usize;
+        let mut entries = Vec::new();
+        while at < end {
+            let (size, name) = (u32_at(archive, at + 20) as usize, u16_at(archive, at + 28));
+            let local = u32_at(archive, at + 42) as usize;
+            let start = local + 30 + u16_at(archive, local + 26) + u16_at(archive, local + 28);
+            entries.push((
+                String::from_utf8(archive[at + 46..at + 46 + name].to_vec()).unwrap(),
+                u16_at(archive, at + 10),
+                u32_at(archive, at + 16),
+                archive[start..start + size].to_vec(),
+            ));
+            at += 46 + name + u16_at(archive, at + 30) + u16_at(archive, at + 32);
+        }
+        entries
+    };
+    // Checks the verifier and the HMAC before decrypting
+    let open = |data: &[u8], password: &str| {
+        let (salt, rest) = data.split_at(SALT);
+        let (key, mac_key, verifier) = keys(password, salt).unwrap();
+        let (encrypted, auth) = rest[2..].split_at(rest.len() - 2 - AUTH);
+        let mac_key = PKey::hmac(&mac_key).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha1(), &mac_key).unwrap();
+        signer.update(encrypted).unwrap();
+        assert!(rest[..2] == verifier[..] && signer.sign_to_vec().unwrap()[..AUTH] == *auth);
+        ctr(&key, encrypted).unwrap()
+    };
+    let contents = b"[Interface]\nPrivateKey = x\n".to_vec();
+    let vector: Vec<u8> = BSDTAR
+        .concat()
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect();
+    let entry = &entries(&vector)[0];
+    assert!(entry.0 == "alice.conf" && entry.1 == 99 && open(&entry.3, "secret") == contents);
+
+    let files = vec![("alice.conf".to_string(), contents.clone())];
+    let archive = zip(&files, Some("secret")).unwrap();
+    assert!(!archive.windows(11).any(|window| window == b"[Interface]"));
+    let entry = &entries(&archive)[0];
+    assert!(entry.0 == "alice.conf" && entry.1 == 99 && open(&entry.3, "secret") == contents);
+    let archive = zip(&files, None).unwrap();
+    let entry = &entries(&archive)[0];
+    assert!(entry.1 == 0 && entry.2 == crc32fast::hash(&contents) && entry.3 == contents);
+    assert!(archive.len() == 30 + 10 + 27 + 46 + 10 + 22);
+
+    // 2024-03-15 10:30:08
+    let (time, date) = dos_date(DateTime::from_millis(1_710_498_608_000));
+    assert!(time == ((10 << 11) | (30 << 5) | 4) && date == ((44 << 9) | (3 << 5) | 15));
+    assert!(password().unwrap().len() == 16);
+}
@@ -0,0 +1,184 @@
+use crate::notify;
+use crate::store::Store;
+use bson::DateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Events shown per `/audit` page.
+pub const PAGE_SIZE: u64 = 20;
+/// Events read at once while looking for those of a user.
+const SCAN: u64 = 500;
+
+/// A peer lifecycle change, who made it and how it went.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    pub date: DateTime,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    /// None if it succeeded, the error otherwise.
+    pub error: Option<String>,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}: {}",
+            self.date.try_to_rfc3339_string().unwrap_or_default(),
+            self.actor,
+            self.action,
+            self.target,
+            self.error.as_deref().unwrap_or("ok")
+        )
+    }
+}
+
+/// Stores the outcome of an action, a failure to store it is only logged.
+pub async fn record<T, E: fmt::Display>(
+    store: &Store,
+    actor: &str,
+    action: &str,
+    target: &str,
+    result: &Result<T, E>,
+) {
+    let event = Event {
+        date: DateTime::now(),
+        actor: actor.to_string(),
+        action: action.to_string(),
+        target: target.to_string(),
+        error: result.as_ref().err().map(|why| why.to_string()),
+    };
+    if let Err(why) = store.log_event(&event).await {
+        tracing::error!("Cannot record audit event {}: {}", event, why);
+    }
+    if let Some(msg) = notification(&event) {
+        notify::send(msg);
+    }
+    #[cfg(feature = "hooks")]
+    if let Some(hook) = crate::hooks::of(&event) {
+        let payload = crate::hooks::Payload::new(hook, &event.target, actor, action);
+        crate::hooks::send(payload);
+    }
+}
+
+/// What operators are told about an event: failures, new peers and removed ones.
+fn notification(event: &Event) -> Option<String> {
+    if let Some(why) = &event.error {
+        return Some(format!(
+            "⚠️ {} by {} failed for {}: {}",
+            event.action, event.actor, event.target, why
+        ));
+    }
+    match (event.action.as_str(), event.actor.as_str()) {
+        ("remove", "expiry") => Some(format!("⌛ {} expired", event.target)),
+        ("remove", actor) => Some(format!("➖ {} was removed by {}", event.target, actor)),
+        ("approve" | "add" | "add temporary" | "add trial" | "import" | "unarchive", actor) => {
+            Some(format!(
+                "➕ {} was added by {} ({})",
+                event.target, actor, event.action
+            ))
+        }
+        ("suspend", actor) => Some(format!("⏸ {} was suspended by {}", event.target, actor)),
+        (action, _) if action.starts_with("pay ") => Some(format!(
+            "💳 {} paid for {}",
+            event.target,
+            &action["pay ".len()..]
+        )),
+        _ => None,
+    }
+}
+
+pub async fn record_ok(store: &Store, actor: &str, action: &str, target: &str) {
+    record(store, actor, action, target, &Ok::<(), String>(())).await
+}
+
+/// Newest events first, `page` starts at 0.
+pub async fn page(store: &Store, page: u64) -> crate::error::Result<Vec<Event>> {
+    store.get_events(page * PAGE_SIZE, PAGE_SIZE).await
+}
+
+/// Events of a user newest first, `page` starts at 0: what happened to their peers, under the
+/// names they had before a rename too, and what they did themselves. `names` are their peers,
+/// archived ones included.
+pub async fn history(
+    store: &Store,
+    user_id: u64,
+    names: &[String],
+    page: u64,
+) -> crate::error::Result<Vec<Event>> {
+    let actor = format!("user {}", user_id);
+    let mut names: HashSet<String> = names.iter().cloned().collect();
+    let wanted = ((page + 1) * PAGE_SIZE) as usize;
+    let mut found = Vec::new();
+    let mut skip = 0;
+    while found.len() < wanted {
+        let events = store.get_events(skip, SCAN).await?;
+        for event in &events {
+            // Older events name the peer as it was called then
+            if let Some(renamed) = event.action.strip_prefix("rename ") {
+                if names.contains(renamed) {
+                    names.insert(event.target.clone());
+                }
+            }
+            if event.actor == actor || names.contains(&event.target) {
+                found.push(event.clone());
+            }
+        }
+        if (events.len() as u64) < SCAN {
+            break;
+        }
+        skip += SCAN;
+    }
+    Ok(found
+        .into_iter()
+        .skip((page * PAGE_SIZE) as usize)
+        .take(PAGE_SIZE as usize)
+        .collect())
+}
+
+#[cfg(all(test, feature = "file"))]
+#[tokio::test]
+async fn pages_and_history() {
+    let scratch = crate::store::Scratch::new("audit.json");
+    let store = scratch.file();
+    record_ok(&store, "admin", "approve", "alice").await;
+    record(
+        &store,
+        "admin",
+        "remove",
+        "alice",
+        &Err::<(), _>("wg failed"),
+    )
+    .await;
+    let events = page(&store, 0).await.unwrap();
+    assert!(events[0].action == "remove" && events[0].error.as_deref() == Some("wg failed"));
+    assert!(page(&store, 1).await.unwrap().is_empty());
+    record_ok(&store, "admin", "rename alice-laptop", "alice").await;
+    record_ok(&store, "admin", "approve", "bob").await;
+    record_ok(&store, "user 7", "regen", "alice-laptop").await;
+    let names = vec!["alice-laptop".to_string()];
+    let events = history(&store, 7, &names, 0).await.unwrap();
+    assert!(events.len() == 4 && events.iter().all(|event| event.target != "bob"));
+    assert!(history(&store, 7, &names, 1).await.unwrap().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn notifications() {
+    let mut event = Event {
+        date: DateTime::now(),
+        actor: "expiry".to_string(),
+        action: "remove".to_string(),
+        target: "alice".to_string(),
+        error: None,
+    };
+    assert!(notification(&event).unwrap() == "⌛ alice expired");
+    event.action = "rotate".to_string();
+    assert!(notification(&event).is_none());
+    event.error = Some("wg set finished with exit status: 1".to_string());
+    assert!(notification(&event)
+        .unwrap()
+        .starts_with("⚠️ rotate by expiry failed"));
+}
@@ -0,0 +1,155 @@
+use crate::error::{GimmewireError, Result};
+use crate::store::Store;
+use crate::wireguard::{self, Interface, Peer};
+use bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the layout of `Backup` changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Backup {
+    pub schema_version: u32,
+    pub created: DateTime,
+    pub peers: Vec<Peer>,
+}
+
+/// Active and archived peers.
+pub async fn export(store: &Store) -> Result<Backup> {
+    let mut peers = store.get_peers().await?;
+    peers.extend(store.get_archived().await?);
+    Ok(Backup {
+        schema_version: SCHEMA_VERSION,
+        created: DateTime::now(),
+        peers,
+    })
+}
+
+pub fn save(backup: &Backup, path: &str) -> Result<()> {
+    let json = serde_json::to_string_pretty(backup)?;
+    Ok(std::fs::write(path, json)?)
+}
+
+pub fn load(path: &str) -> Result<Backup> {
+    let json = std::fs::read_to_string(path)?;
+    let backup: Backup = serde_json::from_str(&json)?;
+    if backup.schema_version > SCHEMA_VERSION {
+        return Err(GimmewireError::Invalid(format!(
+            "Backup schema {} is newer than supported {}",
+            backup.schema_version, SCHEMA_VERSION
+        )));
+    }
+    Ok(backup)
+}
+
+#[derive(Debug, Default)]
+pub struct Restored {
+    pub added: Vec<String>,
+    pub skipped: Vec<String>,
+    pub conflicts: Vec<String>,
+}
+
+/// Adds backed up peers to the db and their interfaces, peers clashing with existing ones are reported, not written.
+pub async fn restore(backup: Backup, interfaces: &[Interface], store: &Store) -> Result<Restored> {
+    let mut existing = store.get_peers().await?;
+    existing.extend(store.get_archived().await?);
+    let mut restored = Restored::default();
+    let mut touched: Vec<String> = Vec::new();
+    for peer in backup.peers {
+        if let Some(conflict) = conflict(&peer, &existing) {
+            match conflict {
+                None => restored.skipped.push(peer.username),
+                Some(why) => restored
+                    .conflicts
+                    .push(format!("{}: {}", peer.username, why)),
+            }
+            continue;
+        }
+        store.add(&peer).await?;
+        if peer.archived.is_none() && peer.public_key.is_some() && peer.ip.is_some() {
+            match wireguard::interface_of(interfaces, &peer) {
+                Err(why) => restored
+                    .conflicts
+                    .push(format!("{}: {}", peer.username, why)),
+                Ok(_) if !touched.contains(&peer.interface) => touched.push(peer.interface.clone()),
+                Ok(_) => (),
+            }
+        }
+        restored.added.push(peer.username.clone());
+        existing.push(peer);
+    }
+    // Restored peers go on their interfaces in one batch per interface
+    let active = store.get_peers().await?;
+    for interface in interfaces
+        .iter()
+        .filter(|interface| touched.contains(&interface.name))
+    {
+        if let Err(why) = wireguard::sync_peers(interface, &active).await {
+            restored
+                .conflicts
+                .push(format!("cannot apply peers to {}: {}", interface.name, why));
+        }
+    }
+    Ok(restored)
+}
+
+/// `Some(None)` if the same peer is already stored, `Some(Some(reason))` if it clashes with another one.
+fn conflict(peer: &Peer, existing: &[Peer]) -> Option<Option<String>> {
+    for other in existing {
+        if peer.id.is_some() && peer.id == other.id {
+            if peer.public_key == other.public_key && peer.ip == other.ip {
+                return Some(None);
+            }
+            return Some(Some("a different peer with the same id exists".to_string()));
+        }
+        // Archived peers only keep their records, they don't hold names or addresses
+        if peer.archived.is_some() || other.archived.is_some() {
+            continue;
+        }
+        if peer.public_key.is_some() && peer.public_key == other.public_key {
+            return Some(Some(format!("public key is used by {}", other.username)));
+        }
+        if let (Some(ip), true) = (
+            peer.ip,
+            peer.ip == other.ip && peer.interface == other.interface,
+        ) {
+            return Some(Some(format!("{} is assigned to {}", ip, other.username)));
+        }
+        if peer.username == other.username {
+            return Some(Some("the name is taken".to_string()));
+        }
+        if peer.user_id != 0 && peer.user_id == other.user_id {
+            return Some(Some(format!("user {} already has a peer", peer.user_id)));
+        }
+    }
+    None
+}
+
+/// Default location for a backup made now.
+pub fn default_path() -> Result<String> {
+    Ok(format!(
+        "{}/gimmewire-backup-{}.json",
+        wireguard::home()?,
+        DateTime::now().timestamp_millis() / 1000
+    ))
+}
+
+#[cfg(test)]
+#[test]
+fn restore_conflicts() {
+    use std::net::Ipv4Addr;
+    let mut stored = Peer::new(1, "alice".to_string());
+    stored.id = Some(bson::oid::ObjectId::new());
+    stored.ip = Some(Ipv4Addr::new(10, 0, 0, 2));
+    let mut same = Peer::new(1, "alice".to_string());
+    same.id = stored.id;
+    same.ip = stored.ip;
+    let mut clash = Peer::new(2, "bob".to_string());
+    clash.ip = stored.ip;
+    let existing = vec![stored];
+    assert!(conflict(&same, &existing) == Some(None));
+    assert!(matches!(conflict(&clash, &existing), Some(Some(_))));
+    assert!(conflict(&Peer::new(3, "carol".to_string()), &existing).is_none());
+    clash.archived = Some(DateTime::now());
+    assert!(conflict(&clash, &existing).is_none());
+}
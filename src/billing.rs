@@ -0,0 +1,202 @@
+use crate::error::{GimmewireError, Result};
+#[cfg(feature = "telegram")]
+use crate::i18n;
+use crate::scheduler::Context;
+use crate::wireguard::{Peer, Subscription};
+use crate::{audit, peers, queue, trial};
+use bson::DateTime;
+use configparser::ini::Ini;
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// What users can buy, a `[Plan <name>]` section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub name: String,
+    pub title: String,
+    /// In the smallest units of `[Payments] Currency`, e.g. cents.
+    pub price: i32,
+    pub days: u64,
+}
+
+/// What happens to a subscription which isn't paid anymore.
+#[derive(Debug, PartialEq)]
+pub enum Lapse {
+    /// The user is told once, the peer keeps working for `[Payments] GraceDays`.
+    Reminder,
+    Suspend,
+}
+
+/// Plans from the config, none and billing is off without `[Payments] ProviderToken`.
+pub fn plans(config: &Ini) -> Vec<Plan> {
+    if config.get("Payments", "ProviderToken").is_none() {
+        return Vec::new();
+    }
+    let mut sections: Vec<&String> = config.get_map_ref().keys().collect();
+    sections.sort();
+    let mut plans = Vec::new();
+    for section in sections {
+        let name = match section.strip_prefix("plan ") {
+            None => continue,
+            Some(name) => name.trim(),
+        };
+        let price = config.getint(section, "Price").unwrap_or(None);
+        let days = config.getuint(section, "Days").unwrap_or(None);
+        match (price.and_then(|price| i32::try_from(price).ok()), days) {
+            (Some(price), Some(days)) if price > 0 && days > 0 => plans.push(Plan {
+                name: name.to_string(),
+                title: config
+                    .get(section, "Title")
+                    .unwrap_or_else(|| name.to_string()),
+                price,
+                days,
+            }),
+            _ => tracing::error!("[Plan {}] needs a positive Price and Days", name),
+        }
+    }
+    plans
+}
+
+pub fn grace_days(config: &Ini) -> u64 {
+    config
+        .getuint("Payments", "GraceDays")
+        .unwrap_or(None)
+        .unwrap_or(3)
+}
+
+/// Whether the peer may get configs: not suspended and paid for, on a trial or billing is off.
+pub fn active(peer: &Peer, config: &Ini, now: DateTime) -> bool {
+    if peer.suspended.is_some() {
+        return false;
+    }
+    if plans(config).is_empty() || trial::running(peer, now) {
+        return true;
+    }
+    match &peer.subscription {
+        None => false,
+        Some(_) => lapse(peer, grace_days(config), now) != Some(Lapse::Suspend),
+    }
+}
+
+/// Adds the plan to what is left of the subscription, a suspended peer still needs `peers::resume`.
+pub fn extend(peer: &mut Peer, plan: &Plan, now: DateTime) {
+    let from = match &peer.subscription {
+        Some(subscription) if subscription.paid_until > now => subscription.paid_until,
+        _ => now,
+    };
+    peer.subscription = Some(Subscription {
+        plan: plan.name.clone(),
+        paid_until: DateTime::from_millis(from.timestamp_millis() + plan.days as i64 * DAY),
+        reminded: false,
+    });
+}
+
+/// What is due for the peer's subscription at `now`, None while it is paid or was already handled.
+pub fn lapse(peer: &Peer, grace: u64, now: DateTime) -> Option<Lapse> {
+    let subscription = peer.subscription.as_ref()?;
+    let paid_until = subscription.paid_until.timestamp_millis();
+    if peer.suspended.is_some() || now.timestamp_millis() < paid_until {
+        None
+    } else if now.timestamp_millis() >= paid_until + grace as i64 * DAY {
+        Some(Lapse::Suspend)
+    } else if !subscription.reminded {
+        Some(Lapse::Reminder)
+    } else {
+        None
+    }
+}
+
+/// Reminds users of lapsed subscriptions and suspends peers after the grace period, the
+/// `billing` job.
+pub async fn run(ctx: &Context) -> Result<()> {
+    let (store, config) = (&ctx.store, &ctx.config);
+    let grace = {
+        let config = config.lock().await;
+        if plans(&config).is_empty() {
+            return Ok(());
+        }
+        grace_days(&config)
+    };
+    let now = DateTime::now();
+    for listed in store.get_peers().await? {
+        if lapse(&listed, grace, now).is_none() {
+            continue;
+        }
+        // Read again in its turn, so the write doesn't undo a payment made since the list was read
+        let handled = queue::exclusive(async {
+            let mut peer = match peers::current(&listed, store).await? {
+                None => return Ok(None),
+                Some(peer) => peer,
+            };
+            let lapsed = match lapse(&peer, grace, now) {
+                None => return Ok(None),
+                Some(lapsed) => lapsed,
+            };
+            match lapsed {
+                Lapse::Reminder => {
+                    if let Some(subscription) = &mut peer.subscription {
+                        subscription.reminded = true;
+                    }
+                    store.update(&peer).await?;
+                }
+                Lapse::Suspend => {
+                    let suspended = peers::suspend(&mut peer, store, config.clone()).await;
+                    audit::record(store, "billing", "suspend", &peer.username, &suspended).await;
+                    suspended?;
+                }
+            }
+            Ok::<_, GimmewireError>(Some((peer, lapsed)))
+        })
+        .await;
+        match handled {
+            Err(why) => tracing::error!("Cannot handle lapse of {}: {}", listed.username, why),
+            Ok(None) => (),
+            Ok(Some((peer, Lapse::Reminder))) => {
+                tracing::info!("Reminded {} of the unpaid subscription", peer.username);
+                #[cfg(feature = "telegram")]
+                i18n::tell(
+                    &ctx.bot,
+                    &ctx.locales,
+                    &peer,
+                    "subscription-lapsed",
+                    &[("days", &grace.to_string())],
+                )
+                .await;
+            }
+            Ok(Some((peer, Lapse::Suspend))) => {
+                tracing::info!("Suspended unpaid peer {}", peer.username);
+                #[cfg(feature = "telegram")]
+                i18n::tell(&ctx.bot, &ctx.locales, &peer, "subscription-suspended", &[]).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn subscriptions() {
+    let mut config = Ini::new();
+    config
+        .read(
+            "[Payments]\nProviderToken = test\n[Plan month]\nTitle = 1 month\nPrice = 500\nDays = 30\n[Plan broken]\nDays = 1"
+                .to_string(),
+        )
+        .unwrap();
+    let plans = plans(&config);
+    assert!(plans.len() == 1 && plans[0].name == "month" && plans[0].price == 500);
+    let mut peer = Peer::new(1, "alice".to_string());
+    let now = DateTime::from_millis(0);
+    assert!(!active(&peer, &config, now));
+    extend(&mut peer, &plans[0], now);
+    extend(&mut peer, &plans[0], now);
+    let paid_until = peer.subscription.as_ref().unwrap().paid_until;
+    assert!(paid_until.timestamp_millis() == 60 * DAY);
+    assert!(active(&peer, &config, now));
+    let at = |days: i64| DateTime::from_millis(days * DAY);
+    assert!(lapse(&peer, 3, at(59)).is_none());
+    assert!(lapse(&peer, 3, at(61)) == Some(Lapse::Reminder));
+    assert!(active(&peer, &config, at(61)));
+    assert!(lapse(&peer, 3, at(63)) == Some(Lapse::Suspend));
+    assert!(!active(&peer, &config, at(63)));
+}
@@ -0,0 +1,45 @@
+//! Request bodies of the webhook and the dashboard read up to a limit, so a client can't make
+//! the bot buffer as much as it sends, and the secrets they are sent with.
+use hyper::body::HttpBody;
+use hyper::{Body, StatusCode};
+
+/// The whole body, `PAYLOAD_TOO_LARGE` past `limit` bytes and `BAD_REQUEST` when it breaks off.
+pub async fn read(mut body: Body, limit: usize) -> Result<Vec<u8>, StatusCode> {
+    if body.size_hint().lower() > limit as u64 {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if read.len() + chunk.len() > limit {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(read)
+}
+
+/// Compares all of what was sent with the secret, so timing doesn't tell how much of it
+/// matched. Nothing sent is no match.
+pub fn same_secret(sent: Option<&[u8]>, secret: &str) -> bool {
+    match sent {
+        None => false,
+        Some(sent) => {
+            sent.len() == secret.len()
+                && sent
+                    .iter()
+                    .zip(secret.as_bytes())
+                    .fold(0, |differ, (a, b)| differ | (a ^ b))
+                    == 0
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn limited_bodies() {
+    assert!(read(Body::from("hello"), 5).await.unwrap() == b"hello");
+    assert!(read(Body::from("hello!"), 5).await == Err(StatusCode::PAYLOAD_TOO_LARGE));
+    assert!(same_secret(Some(b"s3cret_-"), "s3cret_-"));
+    assert!(!same_secret(Some(b"s3cret_+"), "s3cret_-") && !same_secret(None, "x"));
+}
@@ -1,27 +1,76 @@
+#[cfg(feature = "zip")]
+use crate::archive;
+use crate::error::GimmewireError;
+use crate::export::{self, Format};
+use crate::i18n::{self, Locales, Tr};
+use crate::probe::{self, Probes};
+use crate::roles::Role;
+use crate::scheduler::{Context, Scheduler};
 use crate::wireguard::Peer;
-use crate::{mongo::Mongo, wireguard};
+use crate::{
+    audit, backup, billing, bulk, endpoint, firewall, keys, peers, referral,
+    store::{Filter, Store},
+    throttle, trial, usage, verify, wireguard,
+};
+use bson::DateTime;
+use clap::ValueEnum;
 use configparser::ini::Ini;
-use mongodb::bson::DateTime;
-use simple_error::SimpleError;
 use std::collections::HashMap;
 use std::sync::Arc;
-use teloxide::{prelude::*, types::InputFile, utils::command::BotCommands};
+use teloxide::{
+    prelude::*,
+    types::{
+        InlineKeyboardButton, InlineKeyboardMarkup, InputFile, LabeledPrice, PreCheckoutQuery,
+        Recipient, SuccessfulPayment,
+    },
+    utils::command::BotCommands,
+};
 use tokio::sync::Mutex;
 
-#[derive(BotCommands, Clone)]
+#[derive(BotCommands, Clone, Debug)]
 #[command(
     rename_rule = "lowercase",
     description = "These commands are supported:"
 )]
 pub enum UserCommands {
+    #[command(description = "👋 Start, with a free trial if there is one.")]
+    Start,
     #[command(description = "📝 Register, if you are new user.")]
     Register,
     #[command(description = "🚀 Get WireGuard config.")]
     GetConfig,
+    #[command(description = "📡 Connection status.")]
+    Status,
+    #[command(description = "🔑 Replace your keys, if the config leaked.")]
+    Rotate,
+    #[command(description = "🌍 Move to another region.")]
+    Switch,
+    #[command(description = "📱 Manage your devices, /devices zip sends all configs at once.")]
+    Devices,
+    #[command(description = "💳 Subscribe or renew.")]
+    Subscribe,
+    #[command(description = "🗣 Change language.")]
+    Language,
+    #[command(description = "🎁 Invite friends and get rewards.")]
+    Referrals,
+    #[command(description = "🧭 Use your own DNS servers: /dns 1.1.1.1, 9.9.9.9")]
+    Dns,
+    #[command(description = "➕ Add a device: /add laptop")]
+    Add,
+    #[command(description = "🗑 Revoke a lost device: /revoke laptop")]
+    Revoke,
+    #[command(description = "📨 Resend a config, same keys: /regen laptop")]
+    Regen,
+    #[command(
+        description = "🗺 Configs for every region to switch between: /bundle, or some: /bundle eu us"
+    )]
+    Bundle,
+    #[command(description = "📊 Traffic of the month and last 30 days, /usage chart draws them.")]
+    Usage,
     #[command(description = "📕 Help")]
     Help,
 }
-#[derive(BotCommands, Clone)]
+#[derive(BotCommands, Clone, Debug)]
 #[command(
     rename_rule = "lowercase",
     description = "These commands are supported:"
@@ -33,260 +82,2678 @@ pub enum AdminCommands {
     Reject,
     #[command(description = "Remove peer")]
     Remove,
+    #[command(description = "Grant temporary access: /temporary <name> <hours>")]
+    Temporary,
+    #[command(description = "Link imported peer to user: /claim <name> @username <user id>")]
+    Claim,
+    #[command(description = "Export all peers as a JSON backup")]
+    Backup,
+    #[command(description = "List removed peers")]
+    Archived,
+    #[command(description = "Bring back a removed peer: /unarchive <name>")]
+    Unarchive,
+    // Not /rotate, that is the users' own and would match first, whatever follows it
+    #[command(description = "Replace keys of a peer: /rotatepeer <name>")]
+    RotatePeer,
+    #[command(description = "Recent peer events: /audit [page]")]
+    Audit,
+    #[command(description = "Events of a user and their peers: /history <name|user id> [page]")]
+    History,
+    #[command(description = "Message every user with a peer: /broadcast <message>")]
+    Broadcast,
+    #[command(description = "Extend a trial: /trial <name> <days>")]
+    Trial,
+    #[command(
+        description = "Set config options of a peer: /tune <name> mtu=1280 table=off keepalive=15 dns=1.1.1.1 download=20 upload=5 (Mbit/s), default resets"
+    )]
+    Tune,
+    #[command(
+        description = "Forward a public port to a peer: /forward <name> tcp 8080[:80], /forward <name> lists them"
+    )]
+    Forward,
+    #[command(description = "Stop forwarding a port: /unforward <name> tcp 8080")]
+    Unforward,
+    #[command(
+        description = "Add peers for many users at once and get their configs: /bulk <names or CSV lines>"
+    )]
+    Bulk,
+    #[command(description = "Label a peer: /tag <name> <tag>...")]
+    Tag,
+    #[command(description = "Take labels off a peer: /untag <name> <tag>...")]
+    Untag,
+    #[command(
+        description = "Give a peer another name, same keys and address: /rename <name> <new name>"
+    )]
+    Rename,
+    #[command(description = "Set notes of a peer: /note <name> <text>, /note <name> clears them")]
+    Note,
+    #[command(
+        description = "Search peers: /find tag=vip name=ali ip=10.0.0.5 expires=7 (days left)"
+    )]
+    Find,
+    #[command(
+        description = "Set how many devices the user of a peer may have: /limit <name> <n>|default"
+    )]
+    Limit,
+    #[command(description = "Background jobs, their intervals and last runs")]
+    Jobs,
+    #[command(description = "Run a background job now: /run <job>")]
+    Run,
+    #[command(
+        description = "Move the server and send users new configs: /endpoint [interface] <host:port>, /endpoint lists them"
+    )]
+    Endpoint,
+    #[command(description = "Give a peer a fixed address of its pool: /ip <name> <address>")]
+    Ip,
 }
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(command = ?cmd))]
 pub async fn admin_handle(
     bot: Bot,
     message: Message,
     cmd: AdminCommands,
     chats: Arc<Mutex<HashMap<UserId, ChatId>>>,
-    mongo: Mongo,
+    store: Store,
     config: Arc<Mutex<Ini>>,
+    locales: Locales,
+    scheduler: Scheduler,
 ) -> Result<(), teloxide::RequestError> {
-    let admin_chat_id = config
+    let sender = match message.from() {
+        None => return Ok(()),
+        Some(user) => user.id.0,
+    };
+    let role = {
+        let config = config.lock().await;
+        match Role::of(&config, sender) {
+            Some(role) => role,
+            // The role is the sender's, members of an admin group need one of their own
+            None if message.chat.is_private() && admin_id(&config) == Some(message.chat.id.0) => {
+                Role::Owner
+            }
+            None => return Ok(()),
+        }
+    };
+    // Replies go to the chat the command came from
+    let admin_chat_id = message.chat.id.0;
+    let command = format!("{:?}", cmd).to_lowercase();
+    if !role.allows(&*config.lock().await, &command) {
+        let msg = format!("As {} you cannot run /{}", role, command);
+        bot.send_message(ChatId(admin_chat_id), msg).await?;
+        return Ok(());
+    }
+    let actor = role.actor(sender);
+    let tr = locales.tr(None, None);
+    if unavailable(&bot, message.chat.id, &store, &tr).await? {
+        return Ok(());
+    }
+    let args: Vec<&str> = message.text().unwrap_or_default().split(' ').collect();
+    match cmd {
+        AdminCommands::Temporary => {
+            return temporary(&bot, &args, &store, config, &actor, admin_chat_id).await
+        }
+        AdminCommands::Claim => return claim(&bot, &args, &store, &actor, admin_chat_id).await,
+        AdminCommands::Backup => return backup(&bot, &store, admin_chat_id).await,
+        AdminCommands::Archived => return archived(&bot, &store, admin_chat_id).await,
+        AdminCommands::Ip => {
+            return fixed_ip(&bot, &args, &store, config, &locales, &actor, admin_chat_id).await
+        }
+        AdminCommands::RotatePeer => {
+            return rotate(&bot, &args, &store, config, &locales, &actor, admin_chat_id).await
+        }
+        AdminCommands::Audit => return audit(&bot, &args, &store, admin_chat_id).await,
+        AdminCommands::History => return history(&bot, &args, &store, admin_chat_id).await,
+        AdminCommands::Broadcast => {
+            let text = message.text().unwrap_or_default();
+            let text = text.split_once(' ').map(|(_, text)| text.trim());
+            return match text {
+                Some(text) if !text.is_empty() => {
+                    broadcast(&bot, text, &store, config, &actor, admin_chat_id).await
+                }
+                _ => {
+                    bot.send_message(ChatId(admin_chat_id), "Wrong format")
+                        .await?;
+                    Ok(())
+                }
+            };
+        }
+        AdminCommands::Trial => {
+            let msg = match (args.get(1), args.get(2).and_then(|days| days.parse().ok())) {
+                (Some(name), Some(days)) if args.len() == 3 => {
+                    extend_trial(&bot, name, days, &store, config, &locales, &actor).await
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Tune => {
+            let msg = match &args[..] {
+                [_, name, options @ ..] if !options.is_empty() => {
+                    tune(name, options, &store, config, &actor).await
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Forward => {
+            let msg = match args[..] {
+                [_, name] => match store.find_by_username(name).await? {
+                    None => "Cannot find peer".to_string(),
+                    Some(peer) if peer.forwards.is_empty() => format!("{} has no forwards", name),
+                    Some(peer) => peer
+                        .forwards
+                        .iter()
+                        .map(|forward| {
+                            format!("{} {} → {}", forward.protocol, forward.port, forward.target)
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                },
+                [_, name, protocol, ports] => {
+                    let action = format!("forward {} {}", protocol, ports);
+                    let forwarded = match firewall::parse_forward(protocol, ports) {
+                        Err(why) => Err(why),
+                        Ok(forward) => firewall::forward(name, forward, &store, config).await,
+                    };
+                    audit::record(&store, &actor, &action, name, &forwarded).await;
+                    match forwarded {
+                        Err(why) => why.to_string(),
+                        Ok(_) => format!("{} port {} is forwarded to {}", protocol, ports, name),
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Unforward => {
+            let msg = match (&args[..], args.get(3).and_then(|port| port.parse().ok())) {
+                ([_, name, protocol, _], Some(port)) => {
+                    let action = format!("unforward {} {}", protocol, port);
+                    let removed = firewall::unforward(name, protocol, port, &store, config).await;
+                    audit::record(&store, &actor, &action, name, &removed).await;
+                    match removed {
+                        Err(why) => why.to_string(),
+                        Ok(_) => format!("{} port {} is not forwarded anymore", protocol, port),
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Bulk => {
+            let text = message.text().unwrap_or_default();
+            let names = text
+                .split_once(char::is_whitespace)
+                .map(|(_, names)| bulk::names(names))
+                .unwrap_or_default();
+            return provision(&bot, &names, &store, config, &actor, admin_chat_id).await;
+        }
+        AdminCommands::Tag | AdminCommands::Untag => {
+            let remove = matches!(cmd, AdminCommands::Untag);
+            let msg = match &args[..] {
+                [_, name, tags @ ..] if !tags.is_empty() => {
+                    let tagged = peers::tag(name, tags, remove, &store).await;
+                    let action = match remove {
+                        true => format!("untag {}", tags.join(" ")),
+                        false => format!("tag {}", tags.join(" ")),
+                    };
+                    audit::record(&store, &actor, &action, name, &tagged).await;
+                    match tagged {
+                        Err(why) => why.to_string(),
+                        Ok(peer) if peer.tags.is_empty() => format!("{} has no tags", name),
+                        Ok(peer) => format!("{} is tagged {}", name, peer.tags.join(", ")),
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Rename => {
+            let msg = match args[..] {
+                [_, name, new_name] => {
+                    let renamed = peers::rename(name, new_name, &store).await;
+                    let action = format!("rename {}", new_name);
+                    audit::record(&store, &actor, &action, name, &renamed).await;
+                    match renamed {
+                        Err(why) => why.to_string(),
+                        Ok(_) => format!("{} is now {}", name, new_name),
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Note => {
+            let text = message.text().unwrap_or_default();
+            let mut words = text.splitn(3, char::is_whitespace).skip(1);
+            let msg = match (words.next(), words.next()) {
+                (Some(name), notes) => {
+                    let noted = peers::note(name, notes, &store).await;
+                    audit::record(&store, &actor, "note", name, &noted).await;
+                    match noted {
+                        Err(why) => why.to_string(),
+                        Ok(peer) => match peer.notes {
+                            None => format!("{} has no notes", name),
+                            Some(notes) => format!("Notes of {}: {}", name, notes),
+                        },
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Limit => {
+            let msg = match args[..] {
+                [_, name, limit] => {
+                    let limit = match limit {
+                        "default" => Ok(None),
+                        limit => limit.parse().map(Some),
+                    };
+                    match limit {
+                        Err(_) => "Wrong format".to_string(),
+                        Ok(limit) => {
+                            let set = peers::set_limit(name, limit, &store).await;
+                            let action = format!("limit {}", args[2]);
+                            audit::record(&store, &actor, &action, name, &set).await;
+                            match set {
+                                Err(why) => why.to_string(),
+                                Ok(_) => match limit {
+                                    None => format!("The user of {} has the default limit", name),
+                                    Some(limit) => {
+                                        format!("The user of {} may have {} peers", name, limit)
+                                    }
+                                },
+                            }
+                        }
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Jobs => {
+            bot.send_message(ChatId(admin_chat_id), scheduler.status())
+                .await?;
+            return Ok(());
+        }
+        AdminCommands::Run => {
+            let msg = match args[..] {
+                [_, job] => {
+                    let ran = scheduler.trigger(job).await;
+                    audit::record(&store, &actor, "run", job, &ran).await;
+                    match ran {
+                        Err(why) => why.to_string(),
+                        Ok(_) => format!("Job {} is done", job),
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Endpoint => {
+            let main = wireguard::main_interface(&*config.lock().await);
+            let (interface, moved) = match args[..] {
+                [_] => (None, None),
+                [_, moved] => (Some(main.as_str()), Some(moved)),
+                [_, interface, moved] => (Some(interface), Some(moved)),
+                _ => (None, Some("")),
+            };
+            let msg = match (interface, moved) {
+                (None, None) => {
+                    let config = config.lock().await;
+                    wireguard::interfaces(&config)
+                        .iter()
+                        .map(|interface| {
+                            let endpoint = interface.get(&config, "Endpoint").unwrap_or_default();
+                            format!("{}: {}", interface.name, endpoint)
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                }
+                (Some(interface), Some(moved)) => {
+                    let ctx = Context {
+                        store: store.clone(),
+                        config: config.clone(),
+                        bot: bot.clone(),
+                        locales: locales.clone(),
+                    };
+                    match endpoint::change(&ctx, interface, moved, &actor).await {
+                        Err(why) => why.to_string(),
+                        Ok(sent) => format!(
+                            "{} is now at {}, {} users got a new config",
+                            interface, moved, sent
+                        ),
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Find => {
+            let msg = match peers::Search::parse(&args[1..]) {
+                Err(why) => why.to_string(),
+                Ok(search) if search == peers::Search::default() => "Wrong format".to_string(),
+                Ok(search) => found(&search.run(&store).await?),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        AdminCommands::Unarchive => {
+            let msg = match args[..] {
+                [_, name] => {
+                    let unarchived = peers::unarchive(name, &store, config.clone()).await;
+                    audit::record(&store, &actor, "unarchive", name, &unarchived).await;
+                    match unarchived {
+                        Err(why) => why.to_string(),
+                        Ok(peer) => format!(
+                            "{} is back on {}",
+                            name,
+                            peer.ip.map(|ip| ip.to_string()).unwrap_or_default()
+                        ),
+                    }
+                }
+                _ => "Wrong format".to_string(),
+            };
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        _ => (),
+    }
+    if args.len() != 3 {
+        bot.send_message(ChatId(admin_chat_id), "Wrong format")
+            .await?;
+        return Ok(());
+    }
+    let (username, user_id) = match (args[1].strip_prefix('@'), args[2].parse()) {
+        (Some(username), Ok(user_id)) => (username.to_string(), UserId(user_id)),
+        _ => {
+            bot.send_message(ChatId(admin_chat_id), "Wrong format, expected @username id")
+                .await?;
+            return Ok(());
+        }
+    };
+    match cmd {
+        AdminCommands::Approve => {
+            let mut peer = Peer::new(user_id.0, username);
+            peer.interface = peers::placement(&store, config.clone()).await?;
+            let added = store.add(&peer).await;
+            audit::record(&store, &actor, "approve", &peer.username, &added).await;
+            if added.is_ok() {
+                bot.send_message(chat_of(&chats, user_id).await, tr.get("approved"))
+                    .await?;
+            }
+        }
+        AdminCommands::Reject => {
+            audit::record_ok(&store, &actor, "reject", &username).await;
+            bot.send_message(chat_of(&chats, user_id).await, tr.get("rejected"))
+                .await?;
+        }
+        AdminCommands::Temporary
+        | AdminCommands::Claim
+        | AdminCommands::Backup
+        | AdminCommands::Archived
+        | AdminCommands::Unarchive
+        | AdminCommands::RotatePeer
+        | AdminCommands::Audit
+        | AdminCommands::History
+        | AdminCommands::Broadcast
+        | AdminCommands::Trial
+        | AdminCommands::Tune
+        | AdminCommands::Forward
+        | AdminCommands::Unforward
+        | AdminCommands::Bulk
+        | AdminCommands::Tag
+        | AdminCommands::Untag
+        | AdminCommands::Rename
+        | AdminCommands::Note
+        | AdminCommands::Find
+        | AdminCommands::Limit
+        | AdminCommands::Jobs
+        | AdminCommands::Run
+        | AdminCommands::Endpoint
+        | AdminCommands::Ip => (),
+        AdminCommands::Remove => {
+            if let Some(mut peer) = store.find_by_id(user_id.0).await? {
+                let revoked =
+                    peers::revoke(&mut peer, "removed by admin", &store, config.clone()).await;
+                audit::record(&store, &actor, "remove", &peer.username, &revoked).await;
+                if revoked.is_ok() {
+                    let tr = locales.tr(peer.language.as_deref(), None);
+                    bot.send_message(chat_of(&chats, user_id).await, tr.get("removed"))
+                        .await?;
+                }
+            } else {
+                bot.send_message(ChatId(admin_chat_id), "Cannot find peer")
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn claim(
+    bot: &Bot,
+    args: &[&str],
+    store: &Store,
+    actor: &str,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let msg = match args[..] {
+        [_, name, username, user_id] => match (username.strip_prefix('@'), user_id.parse()) {
+            (Some(username), Ok(user_id)) => {
+                let claimed = peers::claim(name, user_id, username.to_string(), store).await;
+                audit::record(store, actor, "claim", name, &claimed).await;
+                match claimed {
+                    Err(why) => why.to_string(),
+                    Ok(peer) => format!("{} is linked to @{}", name, peer.username),
+                }
+            }
+            _ => "Wrong format".to_string(),
+        },
+        _ => "Wrong format".to_string(),
+    };
+    bot.send_message(ChatId(admin_chat_id), msg).await?;
+    Ok(())
+}
+
+async fn backup(
+    bot: &Bot,
+    store: &Store,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let saved = match backup::default_path() {
+        Err(why) => Err(why),
+        Ok(path) => match backup::export(store).await {
+            Err(why) => Err(why),
+            Ok(exported) => backup::save(&exported, &path).map(|_| path),
+        },
+    };
+    let path = match saved {
+        Ok(path) => path,
+        Err(why) => {
+            bot.send_message(ChatId(admin_chat_id), why.to_string())
+                .await?;
+            return Ok(());
+        }
+    };
+    bot.send_document(ChatId(admin_chat_id), InputFile::file(&path))
+        .await?;
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+/// Sends the configs of the new peers as one ZIP and names those which failed.
+async fn provision(
+    bot: &Bot,
+    names: &[String],
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    actor: &str,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    if names.is_empty() {
+        bot.send_message(ChatId(admin_chat_id), "Wrong format")
+            .await?;
+        return Ok(());
+    }
+    let provisioned = match bulk::provision(names, actor, store, config).await {
+        Err(why) => {
+            bot.send_message(ChatId(admin_chat_id), why.to_string())
+                .await?;
+            return Ok(());
+        }
+        Ok(provisioned) => provisioned,
+    };
+    let mut caption = format!("{} peers are added", provisioned.created.len());
+    for (name, why) in &provisioned.failed {
+        caption.push_str(&format!("\n{}: {}", name, why));
+    }
+    // Captions are cut at 1024 characters, failed.txt in the archive has everything
+    let caption: String = caption.chars().take(1024).collect();
+    bot.send_document(
+        ChatId(admin_chat_id),
+        InputFile::memory(provisioned.archive).file_name("gimmewire-peers.zip"),
+    )
+    .caption(caption)
+    .await?;
+    Ok(())
+}
+
+async fn rotate(
+    bot: &Bot,
+    args: &[&str],
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    locales: &Locales,
+    actor: &str,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let peer = match args[..] {
+        [_, name] => store.find_by_username(name).await?,
+        _ => None,
+    };
+    let mut peer = match peer {
+        None => {
+            bot.send_message(ChatId(admin_chat_id), "Cannot find peer")
+                .await?;
+            return Ok(());
+        }
+        Some(peer) => peer,
+    };
+    let rotated = peers::rotate(
+        &mut peer,
+        &format!("rotated by {}", actor),
+        store,
+        config.clone(),
+    )
+    .await;
+    audit::record(store, actor, "rotate", &peer.username, &rotated).await;
+    if let Err(why) = rotated {
+        bot.send_message(ChatId(admin_chat_id), why.to_string())
+            .await?;
+        return Ok(());
+    }
+    // Linked users get the new config themselves, private chat ids match user ids
+    let chat_id = match peer.user_id {
+        0 => ChatId(admin_chat_id),
+        user_id => ChatId(user_id as i64),
+    };
+    let tr = locales.tr(peer.language.as_deref(), None);
+    let caption = tr.get("keys-replaced-by-admin");
+    send_conf(bot, chat_id, &peer, config, &tr, &caption).await?;
+    if chat_id != ChatId(admin_chat_id) {
+        bot.send_message(
+            ChatId(admin_chat_id),
+            format!("Keys of {} are replaced", peer.username),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Handles /ip, linked users get a config with the new address.
+async fn fixed_ip(
+    bot: &Bot,
+    args: &[&str],
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    locales: &Locales,
+    actor: &str,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let (name, ip) = match args[..] {
+        [_, name, ip] => (name, ip),
+        _ => {
+            bot.send_message(ChatId(admin_chat_id), "Wrong format")
+                .await?;
+            return Ok(());
+        }
+    };
+    let (mut peer, ip) = match (store.find_by_username(name).await?, ip.parse()) {
+        (None, _) => {
+            bot.send_message(ChatId(admin_chat_id), "Cannot find peer")
+                .await?;
+            return Ok(());
+        }
+        (_, Err(_)) => {
+            let msg = format!("{} is not an IPv4 address", ip);
+            bot.send_message(ChatId(admin_chat_id), msg).await?;
+            return Ok(());
+        }
+        (Some(peer), Ok(ip)) => (peer, ip),
+    };
+    let assigned = peers::assign_ip(&mut peer, ip, store, config.clone()).await;
+    let action = format!("ip {}", ip);
+    audit::record(store, actor, &action, &peer.username, &assigned).await;
+    if let Err(why) = assigned {
+        bot.send_message(ChatId(admin_chat_id), why.to_string())
+            .await?;
+        return Ok(());
+    }
+    let mut msg = format!("{} is the address of {} now", ip, peer.username);
+    if peer.public_key.is_some() && peer.user_id != 0 {
+        let tr = locales.tr(peer.language.as_deref(), None);
+        let chat_id = ChatId(peer.user_id as i64);
+        let ip = ip.to_string();
+        let args = [("name", peer.username.as_str()), ("ip", ip.as_str())];
+        match peer.private_key {
+            Some(_) => {
+                let caption = tr.format("ip-changed", &args);
+                send_conf(bot, chat_id, &peer, config, &tr, &caption).await?;
+            }
+            None => {
+                bot.send_message(chat_id, tr.format("ip-moved", &args))
+                    .await?;
+            }
+        }
+        msg.push_str(", its user got a new config");
+    }
+    bot.send_message(ChatId(admin_chat_id), msg).await?;
+    Ok(())
+}
+
+async fn audit(
+    bot: &Bot,
+    args: &[&str],
+    store: &Store,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let page = match args {
+        [_] => Some(0),
+        [_, page] => page.parse().ok(),
+        _ => None,
+    };
+    let msg = match page {
+        None => "Wrong format".to_string(),
+        Some(page) => match audit::page(store, page).await {
+            Err(why) => why.to_string(),
+            Ok(events) if events.is_empty() => "No events".to_string(),
+            Ok(events) => {
+                let mut msg: String = events.iter().map(|event| format!("{}\n", event)).collect();
+                if events.len() as u64 == audit::PAGE_SIZE {
+                    msg.push_str(&format!("Older: /audit {}", page + 1));
+                }
+                msg
+            }
+        },
+    };
+    bot.send_message(ChatId(admin_chat_id), msg).await?;
+    Ok(())
+}
+
+/// The timeline of a user for abuse reports and support, found by a peer name, archived ones
+/// too, or by the Telegram user id.
+async fn history(
+    bot: &Bot,
+    args: &[&str],
+    store: &Store,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let (user, page) = match args {
+        [_, user] => (*user, Some(0)),
+        [_, user, page] => (*user, page.parse().ok()),
+        _ => ("", None),
+    };
+    let peers: Vec<Peer> = [store.get_peers().await?, store.get_archived().await?].concat();
+    let user_id = peers
+        .iter()
+        .find(|peer| peer.username == user)
+        .map(|peer| peer.user_id)
+        .or_else(|| user.parse().ok());
+    let msg = match (user_id, page) {
+        (_, None) => "Wrong format".to_string(),
+        (None, _) => "Cannot find user".to_string(),
+        (Some(user_id), Some(page)) => {
+            let names: Vec<String> = peers
+                .into_iter()
+                .filter(|peer| peer.user_id == user_id)
+                .map(|peer| peer.username)
+                .collect();
+            match audit::history(store, user_id, &names, page).await {
+                Err(why) => why.to_string(),
+                Ok(events) if events.is_empty() => format!("No events of user {}", user_id),
+                Ok(events) => {
+                    let mut msg = format!("User {}: {}\n", user_id, names.join(", "));
+                    msg.extend(events.iter().map(|event| format!("{}\n", event)));
+                    if events.len() as u64 == audit::PAGE_SIZE {
+                        msg.push_str(&format!("Older: /history {} {}", user, page + 1));
+                    }
+                    msg
+                }
+            }
+        }
+    };
+    bot.send_message(ChatId(admin_chat_id), msg).await?;
+    Ok(())
+}
+
+/// Extends the trial of a peer and tells its user, returns the reply for the admin.
+async fn extend_trial(
+    bot: &Bot,
+    name: &str,
+    days: u64,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    locales: &Locales,
+    actor: &str,
+) -> String {
+    let mut peer = match store.find_by_username(name).await {
+        Err(why) => return why.to_string(),
+        Ok(None) => return "Cannot find peer".to_string(),
+        Ok(Some(peer)) => peer,
+    };
+    let extended = trial::extend(&mut peer, days, store, config).await;
+    audit::record(store, actor, "extend trial", name, &extended).await;
+    if let Err(why) = extended {
+        return why.to_string();
+    }
+    let until = peer
+        .trial
+        .as_ref()
+        .and_then(|trial| trial.until)
+        .and_then(|until| until.try_to_rfc3339_string().ok())
+        .unwrap_or_default();
+    i18n::tell(bot, locales, &peer, "trial-extended", &[("date", &until)]).await;
+    format!("Trial of {} is extended until {}", name, until)
+}
+
+/// Applies `option=value` pairs to the peer, its next config has them.
+async fn tune(
+    name: &str,
+    options: &[&str],
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    actor: &str,
+) -> String {
+    let mut peer = match store.find_by_username(name).await {
+        Err(why) => return why.to_string(),
+        Ok(None) => return "Cannot find peer".to_string(),
+        Ok(Some(peer)) => peer,
+    };
+    for option in options {
+        let tuned = match option.split_once('=') {
+            None => Err(GimmewireError::Invalid(format!(
+                "Expected option=value, got {}",
+                option
+            ))),
+            Some((option, value)) => peers::tune(&mut peer, option, value),
+        };
+        if let Err(why) = tuned {
+            return why.to_string();
+        }
+    }
+    let updated = store.update(&peer).await;
+    audit::record(
+        store,
+        actor,
+        &format!("tune {}", options.join(" ")),
+        name,
+        &updated,
+    )
+    .await;
+    match updated {
+        Err(why) => why.to_string(),
+        Ok(_) => {
+            // Rate limits take effect right away
+            peers::refresh_rules(store, config).await;
+            format!("{} gets the options with its next config", name)
+        }
+    }
+}
+
+/// Sends the message to every linked user with an active peer, `[Bot] BroadcastDelay` ms apart
+/// to stay under Telegram limits, and reports who didn't get it.
+async fn broadcast(
+    bot: &Bot,
+    text: &str,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    actor: &str,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let delay = config
         .lock()
         .await
-        .getint("Bot", "AdminId")
-        .expect("Cannot find admin chat id")
-        .unwrap();
-    if message.chat.id != ChatId(admin_chat_id) {
+        .getuint("Bot", "BroadcastDelay")
+        .unwrap_or(None)
+        .unwrap_or(50);
+    let mut users: Vec<(u64, String)> = store
+        .get_peers()
+        .await?
+        .into_iter()
+        .filter(|peer| peer.user_id != 0)
+        .map(|peer| (peer.user_id, peer.username))
+        .collect();
+    users.sort();
+    users.dedup_by_key(|(user_id, _)| *user_id);
+    let mut failed = Vec::new();
+    for (i, (user_id, username)) in users.iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+        }
+        // Private chat ids match user ids
+        if let Err(why) = bot.send_message(ChatId(*user_id as i64), text).await {
+            tracing::warn!("Cannot deliver broadcast to {}: {}", username, why);
+            failed.push(format!("@{}", username));
+        }
+    }
+    let report = format!(
+        "Delivered to {} of {}",
+        users.len() - failed.len(),
+        users.len()
+    );
+    audit::record_ok(store, actor, "broadcast", &report).await;
+    let report = match failed.is_empty() {
+        true => report,
+        false => format!("{}, failed: {}", report, failed.join(", ")),
+    };
+    bot.send_message(ChatId(admin_chat_id), report).await?;
+    Ok(())
+}
+
+async fn send_conf(
+    bot: &Bot,
+    chat_id: ChatId,
+    peer: &Peer,
+    config: Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
+    caption: &str,
+) -> Result<(), teloxide::RequestError> {
+    #[cfg(feature = "http")]
+    if let Some(sent) = send_link(bot, chat_id, peer, config.clone(), tr, caption).await {
+        if let Err(why) = sent {
+            tracing::error!("Cannot send a config link to {}: {}", peer.username, why);
+            bot.send_message(chat_id, tr.get("config-failed")).await?;
+        }
+        return Ok(());
+    }
+    match wireguard::gen_conf(peer, config.clone()).await {
+        Err(why) => {
+            tracing::error!("Cannot generate config for {}: {}", peer.username, why);
+            bot.send_message(chat_id, tr.get("config-failed")).await?;
+        }
+        Ok(config_path) => {
+            let sent = bot
+                .send_document(chat_id, InputFile::file(&config_path))
+                .caption(caption)
+                .await;
+            keys::forget(&config_path, &*config.lock().await);
+            sent?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the configs of the devices as one encrypted ZIP and its password in a message of its
+/// own, deleted after `[Zip] DeletePassword` minutes. Nothing is written to disk. Devices
+/// without keys are provisioned first.
+#[cfg(feature = "zip")]
+async fn send_zip(
+    bot: &Bot,
+    chat_id: ChatId,
+    devices: Vec<Peer>,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let mut files = Vec::new();
+    for mut device in devices {
+        if device.public_key.is_none() || device.private_key.is_none() {
+            let provisioned = peers::provision(&mut device, store, config.clone()).await;
+            let actor = format!("user {}", device.user_id);
+            audit::record(store, &actor, "regenerate", &device.username, &provisioned).await;
+            if let Err(why) = provisioned {
+                send_and_log_msg(
+                    bot,
+                    chat_id,
+                    Some(format!("Cannot provision peer {}", device.username)),
+                    Some(tr.error(&why).unwrap_or_else(|| tr.get("config-failed"))),
+                    Some(why.to_string()),
+                    admin_chat_id,
+                )
+                .await;
+                continue;
+            }
+        }
+        match wireguard::client_conf(&device, config.clone()).await {
+            Err(why) => tracing::error!("Cannot generate config for {}: {}", device.username, why),
+            Ok(conf) => files.push((
+                format!("{}.conf", device.username),
+                conf.render().into_bytes(),
+            )),
+        }
+    }
+    if files.is_empty() {
+        bot.send_message(chat_id, tr.get("config-failed")).await?;
+        return Ok(());
+    }
+    let packed = archive::password()
+        .and_then(|password| Ok((archive::zip(&files, Some(&password))?, password)));
+    let (zip, password) = match packed {
+        Err(why) => {
+            tracing::error!("Cannot pack configs: {}", why);
+            bot.send_message(chat_id, tr.get("config-failed")).await?;
+            return Ok(());
+        }
+        Ok(packed) => packed,
+    };
+    let count = files.len().to_string();
+    bot.send_document(chat_id, InputFile::memory(zip).file_name("gimmewire.zip"))
+        .caption(tr.format("zip", &[("count", &count)]))
+        .await?;
+    let minutes = archive::settings(&*config.lock().await).delete_password;
+    let mut msg = tr.format("zip-password", &[("password", &password)]);
+    if minutes > 0 {
+        msg.push('\n');
+        msg.push_str(&tr.format("zip-password-deleted", &[("minutes", &minutes.to_string())]));
+    }
+    let sent = bot.send_message(chat_id, msg).await?;
+    if minutes > 0 {
+        let bot = bot.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_secs(minutes * 60)).await;
+            if let Err(why) = bot.delete_message(chat_id, sent.id).await {
+                tracing::warn!("Cannot delete an archive password: {}", why);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Lines of the peers /find found, the first ones only in larger fleets.
+fn found(peers: &[Peer]) -> String {
+    const SHOWN: usize = 50;
+    if peers.is_empty() {
+        return "No peers found".to_string();
+    }
+    let mut msg: String = peers
+        .iter()
+        .take(SHOWN)
+        .map(|peer| {
+            let ends = peers::access_ends(peer);
+            format!(
+                "{} {} {}{}{}{}\n",
+                peer.username,
+                peer.interface,
+                peer.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                ends.and_then(|date| date.try_to_rfc3339_string().ok())
+                    .map(|date| format!(" until {}", date))
+                    .unwrap_or_default(),
+                match peer.tags.is_empty() {
+                    true => String::new(),
+                    false => format!(" #{}", peer.tags.join(" #")),
+                },
+                peer.notes
+                    .as_ref()
+                    .map(|notes| format!(" ({})", notes))
+                    .unwrap_or_default()
+            )
+        })
+        .collect();
+    if peers.len() > SHOWN {
+        msg.push_str(&format!("… and {} more", peers.len() - SHOWN));
+    }
+    msg
+}
+
+async fn archived(
+    bot: &Bot,
+    store: &Store,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let mut msg = String::new();
+    for peer in store.get_archived().await? {
+        msg.push_str(&format!(
+            "{} {}: {}\n",
+            peer.username,
+            peer.archived
+                .and_then(|date| date.try_to_rfc3339_string().ok())
+                .unwrap_or_default(),
+            peer.archive_reason.unwrap_or_default()
+        ));
+    }
+    if msg.is_empty() {
+        msg.push_str("No archived peers");
+    }
+    bot.send_message(ChatId(admin_chat_id), msg).await?;
+    Ok(())
+}
+
+async fn temporary(
+    bot: &Bot,
+    args: &[&str],
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    actor: &str,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let hours = match args {
+        [_, _, hours] => hours.parse().ok(),
+        _ => None,
+    };
+    let hours = match hours {
+        None => {
+            bot.send_message(ChatId(admin_chat_id), "Wrong format")
+                .await?;
+            return Ok(());
+        }
+        Some(hours) => hours,
+    };
+    let granted = peers::grant_temporary(args[1].to_string(), hours, store, config.clone()).await;
+    audit::record(store, actor, "add temporary", args[1], &granted).await;
+    let peer = match granted {
+        Err(why) => {
+            bot.send_message(ChatId(admin_chat_id), why.to_string())
+                .await?;
+            return Ok(());
+        }
+        Ok(peer) => peer,
+    };
+    match wireguard::gen_conf(&peer, config.clone()).await {
+        Err(why) => {
+            bot.send_message(ChatId(admin_chat_id), why.to_string())
+                .await?;
+        }
+        Ok(config_path) => {
+            let sent = bot
+                .send_document(ChatId(admin_chat_id), InputFile::file(&config_path))
+                .caption(format!("Valid for {} hours", hours))
+                .await;
+            keys::forget(&config_path, &*config.lock().await);
+            sent?;
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(
+    user_id = message.from().map(|user| user.id.0),
+    username = message.chat.username(),
+    command = ?cmd,
+))]
+// Every argument is a dispatcher dependency
+#[allow(clippy::too_many_arguments)]
+pub async fn user_handle(
+    bot: Bot,
+    message: Message,
+    store: Store,
+    cmd: UserCommands,
+    chats: Arc<Mutex<HashMap<UserId, ChatId>>>,
+    config: Arc<Mutex<Ini>>,
+    probes: Probes,
+    locales: Locales,
+) -> Result<(), teloxide::RequestError> {
+    let username = message.chat.username().unwrap_or("None").to_string();
+    // Commands always come from a user in private chats, channel posts have nobody to answer
+    let (user_id, telegram_lang) = match message.from() {
+        None => return Ok(()),
+        Some(user) => (user.id, user.language_code.as_deref()),
+    };
+    let admin_chat_id = match admin_id(&*config.lock().await) {
+        None => return Ok(()),
+        Some(admin_chat_id) => admin_chat_id,
+    };
+    if unavailable(
+        &bot,
+        message.chat.id,
+        &store,
+        &locales.tr(None, telegram_lang),
+    )
+    .await?
+    {
+        return Ok(());
+    }
+    let peer = store.find_by_id(user_id.0).await?;
+    let language = peer.as_ref().and_then(|peer| peer.language.as_deref());
+    let tr = locales.tr(language, telegram_lang);
+    match cmd {
+        UserCommands::Start => {
+            // Referral links open the bot with `/start ref<user id>`
+            let payload = message.text().and_then(|text| text.split_once(' '));
+            let referrer = payload.and_then(|(_, payload)| referral::referrer(payload));
+            let referrals = referral::reward(&*config.lock().await).is_some();
+            if let (Some(referrer), None, true) = (referrer, &peer, referrals) {
+                if let Err(why) = referral::attribute(referrer, user_id.0, &store).await {
+                    tracing::warn!("Referral of {} is not counted: {}", user_id, why);
+                }
+            }
+            let trials = trial::settings(&*config.lock().await).is_some();
+            if peer.is_some() || !trials {
+                bot.send_message(message.chat.id, tr.get("help")).await?;
+                return Ok(());
+            }
+            if unverified(&bot, message.chat.id, user_id, &config, &tr).await? {
+                return Ok(());
+            }
+            let name = message
+                .chat
+                .username()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("user{}", user_id));
+            let started = trial::start(user_id.0, name.clone(), &store, config.clone()).await;
+            let actor = format!("user {}", user_id);
+            audit::record(&store, &actor, "add trial", &name, &started).await;
+            match started {
+                Err(why) => {
+                    tracing::warn!("No trial for {}: {}", name, why);
+                    bot.send_message(message.chat.id, tr.get("help")).await?;
+                }
+                Ok(peer) => {
+                    chats.lock().await.insert(user_id, message.chat.id);
+                    let mut msg = tr.get("trial-started");
+                    if let Some(trial) = &peer.trial {
+                        msg.push('\n');
+                        msg.push_str(&trial_limits(trial, &tr));
+                    }
+                    bot.send_message(message.chat.id, msg).await?;
+                }
+            }
+        }
+        UserCommands::Register => {
+            if peer.is_some() {
+                bot.send_message(message.chat.id, tr.get("already-registered"))
+                    .await?;
+            } else if !unverified(&bot, message.chat.id, user_id, &config, &tr).await? {
+                let chat_id = message.chat.id;
+                let msg = format!("@{} {}", username, user_id);
+                chats.lock().await.insert(user_id, chat_id);
+                bot.send_message(ChatId(admin_chat_id), msg).await?;
+                bot.send_message(message.chat.id, tr.get("request-sent"))
+                    .await?;
+            }
+        }
+        UserCommands::GetConfig => {
+            if let Some(peer) = peer {
+                if unpaid(&bot, message.chat.id, &peer, &config, &tr).await? {
+                    return Ok(());
+                }
+                // New users pick a region when there is more than one
+                let regions = regions(&*config.lock().await);
+                if peer.public_key.is_none() && regions.len() > 1 {
+                    bot.send_message(message.chat.id, tr.get("choose-region"))
+                        .reply_markup(keyboard("region", &regions))
+                        .await?;
+                    return Ok(());
+                }
+                deliver(
+                    &bot,
+                    message.chat.id,
+                    peer,
+                    &store,
+                    config,
+                    &tr,
+                    admin_chat_id,
+                )
+                .await?;
+            } else {
+                bot.send_message(message.chat.id, tr.get("register-first"))
+                    .await?;
+            }
+        }
+        UserCommands::Status => {
+            let msg = match peer {
+                None => tr.get("register-first"),
+                Some(peer) => status(&peer, &probes, config, &tr).await,
+            };
+            bot.send_message(message.chat.id, msg).await?;
+        }
+        UserCommands::Rotate => match peer {
+            None => {
+                bot.send_message(message.chat.id, tr.get("register-first"))
+                    .await?;
+            }
+            Some(peer) => {
+                if unpaid(&bot, message.chat.id, &peer, &config, &tr).await? {
+                    return Ok(());
+                }
+                rotate_own(
+                    &bot,
+                    message.chat.id,
+                    peer,
+                    &store,
+                    config,
+                    &tr,
+                    admin_chat_id,
+                )
+                .await?
+            }
+        },
+        UserCommands::Subscribe => {
+            let plans = billing::plans(&*config.lock().await);
+            let peer = match peer {
+                _ if plans.is_empty() => {
+                    bot.send_message(message.chat.id, tr.get("payments-disabled"))
+                        .await?;
+                    return Ok(());
+                }
+                None => {
+                    bot.send_message(message.chat.id, tr.get("register-first"))
+                        .await?;
+                    return Ok(());
+                }
+                Some(peer) => peer,
+            };
+            let mut msg = String::new();
+            if let Some(subscription) = &peer.subscription {
+                msg.push_str(&paid_until(subscription, &tr));
+                msg.push('\n');
+            }
+            msg.push_str(&tr.get("choose-plan"));
+            let plans: Vec<(String, String)> = plans
+                .into_iter()
+                .map(|plan| (plan.name, plan.title))
+                .collect();
+            bot.send_message(message.chat.id, msg)
+                .reply_markup(keyboard("plan", &plans))
+                .await?;
+        }
+        UserCommands::Devices => {
+            let filter = Filter {
+                user_id: Some(user_id.0),
+                ..Filter::default()
+            };
+            let devices: Vec<Peer> = store.find_peers(&filter, 0, None).await?;
+            if devices.is_empty() {
+                let msg = match peer {
+                    None => tr.get("register-first"),
+                    Some(_) => tr.get("no-devices"),
+                };
+                bot.send_message(message.chat.id, msg).await?;
+                return Ok(());
+            }
+            // Every config in one archive with /devices zip
+            #[cfg(feature = "zip")]
+            if message
+                .text()
+                .is_some_and(|text| text.split_whitespace().nth(1) == Some("zip"))
+            {
+                if unpaid(&bot, message.chat.id, &devices[0], &config, &tr).await? {
+                    return Ok(());
+                }
+                let chat_id = message.chat.id;
+                return send_zip(&bot, chat_id, devices, &store, config, &tr, admin_chat_id).await;
+            }
+            let regions = regions(&*config.lock().await);
+            for device in devices {
+                let region = regions
+                    .iter()
+                    .find(|(name, _)| name == &device.interface)
+                    .map(|(_, label)| label.as_str())
+                    .unwrap_or(&device.interface);
+                let msg = tr.format("device", &[("name", &device.username), ("region", region)]);
+                bot.send_message(message.chat.id, msg)
+                    .reply_markup(device_keyboard(&device.username, &tr))
+                    .await?;
+            }
+        }
+        UserCommands::Switch => match peer {
+            None => {
+                bot.send_message(message.chat.id, tr.get("register-first"))
+                    .await?;
+            }
+            Some(peer) => {
+                if unpaid(&bot, message.chat.id, &peer, &config, &tr).await? {
+                    return Ok(());
+                }
+                let regions: Vec<(String, String)> = regions(&*config.lock().await)
+                    .into_iter()
+                    .filter(|(name, _)| name != &peer.interface)
+                    .collect();
+                if regions.is_empty() {
+                    bot.send_message(message.chat.id, tr.get("no-other-regions"))
+                        .await?;
+                } else {
+                    bot.send_message(message.chat.id, tr.get("choose-new-region"))
+                        .reply_markup(keyboard("switch", &regions))
+                        .await?;
+                }
+            }
+        },
+        UserCommands::Language => {
+            if peer.is_none() {
+                bot.send_message(message.chat.id, tr.get("register-first"))
+                    .await?;
+                return Ok(());
+            }
+            let languages: Vec<(String, String)> = locales
+                .languages()
+                .into_iter()
+                .map(|lang| {
+                    let name = locales.tr(Some(&lang), None).get("language-name");
+                    (lang, name)
+                })
+                .collect();
+            bot.send_message(message.chat.id, tr.get("choose-language"))
+                .reply_markup(keyboard("language", &languages))
+                .await?;
+        }
+        UserCommands::Referrals => {
+            let msg = match peer {
+                _ if referral::reward(&*config.lock().await).is_none() => {
+                    tr.get("referrals-disabled")
+                }
+                None => tr.get("register-first"),
+                Some(_) => {
+                    let me = bot.get_me().await?;
+                    let link = format!(
+                        "https://t.me/{}?start={}",
+                        me.username(),
+                        referral::payload(user_id.0)
+                    );
+                    let referrals: Vec<_> = store
+                        .get_referrals()
+                        .await?
+                        .into_iter()
+                        .filter(|referral| referral.referrer == user_id.0)
+                        .collect();
+                    let credited = referrals
+                        .iter()
+                        .filter(|referral| referral.credited.is_some())
+                        .count();
+                    tr.format(
+                        "referrals",
+                        &[
+                            ("link", &link),
+                            ("invited", &referrals.len().to_string()),
+                            ("credited", &credited.to_string()),
+                        ],
+                    )
+                }
+            };
+            bot.send_message(message.chat.id, msg).await?;
+        }
+        UserCommands::Dns => {
+            let mut peer = match peer {
+                None => {
+                    bot.send_message(message.chat.id, tr.get("register-first"))
+                        .await?;
+                    return Ok(());
+                }
+                Some(peer) => peer,
+            };
+            let servers = message
+                .text()
+                .and_then(|text| text.split_once(' '))
+                .map(|(_, servers)| servers.trim())
+                .filter(|servers| !servers.is_empty());
+            let servers = match servers {
+                None => {
+                    let current = match &peer.dns {
+                        None => tr.get("dns-default"),
+                        Some(dns) => dns.clone(),
+                    };
+                    bot.send_message(message.chat.id, tr.format("dns", &[("dns", &current)]))
+                        .await?;
+                    return Ok(());
+                }
+                Some(servers) => servers,
+            };
+            if let Err(why) = peers::tune(&mut peer, "dns", servers) {
+                tracing::info!("{} gave wrong DNS servers: {}", peer.username, why);
+                bot.send_message(message.chat.id, tr.get("dns-invalid"))
+                    .await?;
+                return Ok(());
+            }
+            let updated = store.update(&peer).await;
+            let actor = format!("user {}", user_id);
+            audit::record(&store, &actor, "set dns", &peer.username, &updated).await;
+            let msg = match updated {
+                Err(why) => tr.error(&why).unwrap_or_else(|| tr.get("config-failed")),
+                Ok(_) => tr.get("dns-changed"),
+            };
+            bot.send_message(message.chat.id, msg).await?;
+        }
+        UserCommands::Add => {
+            let owner = match peer {
+                None => {
+                    bot.send_message(message.chat.id, tr.get("register-first"))
+                        .await?;
+                    return Ok(());
+                }
+                Some(owner) => owner,
+            };
+            if unpaid(&bot, message.chat.id, &owner, &config, &tr).await? {
+                return Ok(());
+            }
+            let device = match message.text().and_then(|text| text.split_once(' ')) {
+                Some((_, device)) if !device.trim().is_empty() => device.trim().to_lowercase(),
+                _ => {
+                    bot.send_message(message.chat.id, tr.get("add-device"))
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let added = peers::add_device(&owner, &device, &store, config.clone()).await;
+            let actor = format!("user {}", user_id);
+            audit::record(&store, &actor, "add device", &owner.username, &added).await;
+            match added {
+                Err(GimmewireError::Invalid(_)) => {
+                    bot.send_message(message.chat.id, tr.get("add-device"))
+                        .await?;
+                }
+                Err(why) => {
+                    let msg = tr.error(&why).unwrap_or_else(|| tr.get("config-failed"));
+                    bot.send_message(message.chat.id, msg).await?;
+                }
+                Ok(peer) => {
+                    deliver(
+                        &bot,
+                        message.chat.id,
+                        peer,
+                        &store,
+                        config,
+                        &tr,
+                        admin_chat_id,
+                    )
+                    .await?
+                }
+            }
+        }
+        UserCommands::Bundle => {
+            let owner = match peer {
+                None => {
+                    bot.send_message(message.chat.id, tr.get("register-first"))
+                        .await?;
+                    return Ok(());
+                }
+                Some(owner) => owner,
+            };
+            if unpaid(&bot, message.chat.id, &owner, &config, &tr).await? {
+                return Ok(());
+            }
+            let regions = regions(&*config.lock().await);
+            if regions.len() < 2 {
+                bot.send_message(message.chat.id, tr.get("no-other-regions"))
+                    .await?;
+                return Ok(());
+            }
+            // Regions go by interface or label, all of them without any
+            let wanted: Vec<String> = message
+                .text()
+                .unwrap_or_default()
+                .split_whitespace()
+                .skip(1)
+                .map(str::to_lowercase)
+                .collect();
+            let chosen: Vec<&(String, String)> = regions
+                .iter()
+                .filter(|(name, label)| {
+                    wanted.is_empty()
+                        || wanted.contains(&name.to_lowercase())
+                        || wanted.contains(&label.to_lowercase())
+                })
+                .collect();
+            if chosen.is_empty() {
+                bot.send_message(message.chat.id, tr.get("region-unavailable"))
+                    .await?;
+                return Ok(());
+            }
+            let interfaces: Vec<String> = chosen.iter().map(|(name, _)| name.clone()).collect();
+            let bundled = peers::bundle(&owner, &interfaces, &store, config.clone()).await;
+            let actor = format!("user {}", user_id);
+            let action = format!("bundle {}", interfaces.join(" "));
+            audit::record(&store, &actor, &action, &owner.username, &bundled).await;
+            let bundle = match bundled {
+                Err(why) => {
+                    let msg = tr.error(&why).unwrap_or_else(|| tr.get("config-failed"));
+                    bot.send_message(message.chat.id, msg).await?;
+                    return Ok(());
+                }
+                Ok(bundle) => bundle,
+            };
+            let count = bundle.len().to_string();
+            bot.send_message(message.chat.id, tr.format("bundle", &[("count", &count)]))
+                .await?;
+            #[cfg(feature = "zip")]
+            if bundle.len() > 1 && archive::settings(&*config.lock().await).enabled {
+                let chat_id = message.chat.id;
+                return send_zip(&bot, chat_id, bundle, &store, config, &tr, admin_chat_id).await;
+            }
+            for (device, (_, label)) in bundle.into_iter().zip(chosen) {
+                match (&device.public_key, &device.private_key) {
+                    (Some(_), Some(_)) => {
+                        let caption = tr.format("bundle-region", &[("region", label)]);
+                        send_conf(
+                            &bot,
+                            message.chat.id,
+                            &device,
+                            config.clone(),
+                            &tr,
+                            &caption,
+                        )
+                        .await?
+                    }
+                    _ => {
+                        let chat_id = message.chat.id;
+                        issue(
+                            &bot,
+                            chat_id,
+                            device,
+                            &store,
+                            config.clone(),
+                            &tr,
+                            admin_chat_id,
+                        )
+                        .await
+                    }
+                }
+            }
+        }
+        UserCommands::Revoke | UserCommands::Regen => {
+            let action = match cmd {
+                UserCommands::Revoke => "delete",
+                _ => "regen",
+            };
+            let filter = Filter {
+                user_id: Some(user_id.0),
+                ..Filter::default()
+            };
+            let devices = store.find_peers(&filter, 0, None).await?;
+            let named = message
+                .text()
+                .and_then(|text| text.split_once(' '))
+                .map(|(_, name)| name.trim())
+                .filter(|name| !name.is_empty());
+            // A device goes by its full name or the one given to /add
+            let chosen: Vec<&Peer> = devices
+                .iter()
+                .filter(|device| {
+                    named.is_none_or(|name| {
+                        device.username == name
+                            || device
+                                .username
+                                .strip_suffix(name)
+                                .is_some_and(|owner| owner.ends_with('-'))
+                    })
+                })
+                .collect();
+            match chosen[..] {
+                [] if devices.is_empty() => {
+                    bot.send_message(message.chat.id, tr.get("register-first"))
+                        .await?;
+                }
+                [] => {
+                    bot.send_message(message.chat.id, tr.get("device-not-found"))
+                        .await?;
+                }
+                // Revoking takes the confirmation of /devices
+                [only] => {
+                    let data = format!("{}:{}", action, only.username);
+                    device(&bot, message.chat.id, user_id.0, &data, &store, config, &tr).await?;
+                }
+                _ => {
+                    let choices: Vec<(String, String)> = chosen
+                        .iter()
+                        .map(|device| {
+                            let data = format!("{}:{}", action, device.username);
+                            (data, device.username.clone())
+                        })
+                        .collect();
+                    bot.send_message(message.chat.id, tr.get(&format!("choose-{}", action)))
+                        .reply_markup(keyboard("device", &choices))
+                        .await?;
+                }
+            }
+        }
+        UserCommands::Usage => {
+            let filter = Filter {
+                user_id: Some(user_id.0),
+                ..Filter::default()
+            };
+            let devices: Vec<Peer> = store.find_peers(&filter, 0, None).await?;
+            if devices.is_empty() {
+                let msg = match peer {
+                    None => tr.get("register-first"),
+                    Some(_) => tr.get("no-devices"),
+                };
+                bot.send_message(message.chat.id, msg).await?;
+                return Ok(());
+            }
+            let days = usage::daily(&devices, DateTime::now());
+            let (down, up) = days
+                .iter()
+                .fold((0, 0), |(down, up), day| (down + day.0, up + day.1));
+            let (down, up) = (usage::bytes(down), usage::bytes(up));
+            let args = [("down", down.as_str()), ("up", up.as_str())];
+            let chart = message
+                .text()
+                .is_some_and(|text| text.split_whitespace().nth(1) == Some("chart"));
+            if !chart {
+                let mut msg = tr.format("usage", &args);
+                msg.push('\n');
+                msg.push_str(&usage_report(&devices, config, &tr).await);
+                bot.send_message(message.chat.id, msg).await?;
+                return Ok(());
+            }
+            let path = std::env::temp_dir()
+                .join(format!("gimmewire-usage-{}.png", user_id))
+                .to_string_lossy()
+                .to_string();
+            match usage::chart(&days, &path) {
+                Err(why) => {
+                    tracing::error!("Cannot draw usage of {}: {}", user_id, why);
+                    bot.send_message(message.chat.id, tr.format("usage", &args))
+                        .await?;
+                }
+                Ok(_) => {
+                    let sent = bot
+                        .send_photo(message.chat.id, InputFile::file(&path))
+                        .caption(tr.format("usage-chart", &args))
+                        .await;
+                    let _ = std::fs::remove_file(&path);
+                    sent?;
+                }
+            }
+        }
+        UserCommands::Help => {
+            bot.send_message(message.chat.id, tr.get("help")).await?;
+        }
+    };
+
+    Ok(())
+}
+
+/// Traffic of the month and a line for every device of /usage: its latest handshake, when its
+/// access ends and the trial traffic it has left. Handshakes come from the interfaces, those
+/// last recorded when they can't be read.
+async fn usage_report(devices: &[Peer], config: Arc<Mutex<Ini>>, tr: &Tr<'_>) -> String {
+    let now = DateTime::now();
+    let interfaces = wireguard::interfaces(&*config.lock().await);
+    let handshakes: HashMap<String, DateTime> = match wireguard::show_all(&interfaces).await {
+        Err(why) => {
+            tracing::error!("Cannot read handshakes: {}", why);
+            HashMap::new()
+        }
+        Ok(stats) => stats
+            .into_iter()
+            .filter_map(|stat| Some((stat.public_key, stat.latest_handshake?)))
+            .collect(),
+    };
+    let (down, up) = usage::month(devices, now);
+    let mut lines = vec![tr.format(
+        "usage-month",
+        &[("down", &usage::bytes(down)), ("up", &usage::bytes(up))],
+    )];
+    for device in devices {
+        let handshake = device
+            .public_key
+            .as_ref()
+            .and_then(|key| handshakes.get(key).copied())
+            .or(device.last_handshake)
+            .and_then(|date| date.try_to_rfc3339_string().ok())
+            .unwrap_or_else(|| tr.get("never"));
+        let mut line = tr.format(
+            "usage-device",
+            &[("name", &device.username), ("handshake", &handshake)],
+        );
+        let ends = peers::access_ends(device)
+            .or(device.trial.as_ref().and_then(|trial| trial.until))
+            .and_then(|date| date.try_to_rfc3339_string().ok());
+        if let Some(ends) = ends {
+            line.push_str(&tr.format("usage-until", &[("date", &ends)]));
+        }
+        if let Some(left) = usage::quota_left(device, now) {
+            line.push_str(&tr.format("usage-quota", &[("left", &usage::bytes(left))]));
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+async fn status(peer: &Peer, probes: &Probes, config: Arc<Mutex<Ini>>, tr: &Tr<'_>) -> String {
+    let key = match &peer.public_key {
+        None => return tr.get("no-config"),
+        Some(key) => key,
+    };
+    let interface = match wireguard::find_interface(&*config.lock().await, &peer.interface) {
+        Err(why) => {
+            tracing::error!("{}", why);
+            return tr.get("status-failed");
+        }
+        Ok(interface) => interface,
+    };
+    let stat = match wireguard::show(&interface).await {
+        Err(why) => {
+            tracing::error!("Cannot read {} state: {}", peer.interface, why);
+            return tr.get("status-failed");
+        }
+        Ok(stats) => stats.into_iter().find(|stat| &stat.public_key == key),
+    };
+    let stat = match stat {
+        None => return tr.get("peer-missing"),
+        Some(stat) => stat,
+    };
+    let handshake = stat
+        .latest_handshake
+        .and_then(|date| date.try_to_rfc3339_string().ok())
+        .unwrap_or_else(|| tr.get("never"));
+    let mut msg = tr.format(
+        "status",
+        &[
+            ("ip", &peer.ip.map(|ip| ip.to_string()).unwrap_or_default()),
+            ("handshake", &handshake),
+            ("rx", &stat.rx.to_string()),
+            ("tx", &stat.tx.to_string()),
+        ],
+    );
+    if let Some(latency) = probes.lock().await.get(key).and_then(|s| probe::summary(s)) {
+        msg.push('\n');
+        msg.push_str(&tr.format("latency", &[("latency", &latency)]));
+    }
+    if let Some(subscription) = &peer.subscription {
+        msg.push('\n');
+        msg.push_str(&paid_until(subscription, tr));
+    }
+    if let Some(trial) = peer.trial.as_ref().filter(|trial| trial.ended.is_none()) {
+        msg.push('\n');
+        msg.push_str(&trial_limits(trial, tr));
+    }
+    msg
+}
+
+/// When the trial ends and how much traffic it allows.
+fn trial_limits(trial: &wireguard::Trial, tr: &Tr<'_>) -> String {
+    let mut limits = Vec::new();
+    if let Some(until) = trial
+        .until
+        .and_then(|until| until.try_to_rfc3339_string().ok())
+    {
+        limits.push(tr.format("trial-until", &[("date", &until)]));
+    }
+    if let Some(quota) = trial.quota {
+        let gb = (quota / (1024 * 1024 * 1024)).to_string();
+        limits.push(tr.format("trial-quota", &[("gb", &gb)]));
+    }
+    limits.join("\n")
+}
+
+/// Handles region buttons, `region:<interface>` for new peers and `switch:<interface>` for moves,
+/// `language:<code>` language buttons, `device:<action>:<name>` buttons of /devices and
+/// `plan:<name>` buttons of /subscribe.
+#[tracing::instrument(skip_all, fields(user_id = query.from.id.0, data = query.data.as_deref()))]
+pub async fn callback_handle(
+    bot: Bot,
+    query: CallbackQuery,
+    store: Store,
+    config: Arc<Mutex<Ini>>,
+    locales: Locales,
+) -> Result<(), teloxide::RequestError> {
+    bot.answer_callback_query(query.id).await?;
+    let admin_chat_id = match admin_id(&*config.lock().await) {
+        None => return Ok(()),
+        Some(admin_chat_id) => admin_chat_id,
+    };
+    let chat_id = match &query.message {
+        Some(message) => message.chat.id,
+        None => ChatId(query.from.id.0 as i64),
+    };
+    let telegram_lang = query.from.language_code.as_deref();
+    if unavailable(&bot, chat_id, &store, &locales.tr(None, telegram_lang)).await? {
         return Ok(());
     }
-    let args: Vec<&str> = message.text().unwrap().split(" ").collect();
-    if args.len() != 3 {
-        bot.send_message(ChatId(admin_chat_id), "Wrong format")
-            .await?;
+    let (action, value) = match query.data.as_deref().and_then(|data| data.split_once(':')) {
+        None => return Ok(()),
+        Some(data) => data,
+    };
+    if action == "verify" {
+        let tr = locales.tr(None, telegram_lang);
+        let msg = match verify::answer(query.from.id.0, value) {
+            Some(true) => tr.get("verify-passed"),
+            Some(false) => tr.get("verify-wrong"),
+            None => return ask(&bot, chat_id, query.from.id, &tr).await,
+        };
+        bot.send_message(chat_id, msg).await?;
+        if !verify::solved(query.from.id.0) {
+            ask(&bot, chat_id, query.from.id, &tr).await?;
+        }
         return Ok(());
     }
-    let (username, user_id) = (
-        args[1].to_string().strip_prefix("@").unwrap().to_string(),
-        UserId(args[2].parse().unwrap()),
-    );
-    match cmd {
-        AdminCommands::Approve => {
-            if mongo
-                .add(&Peer {
-                    user_id: user_id.0,
-                    username: username,
-                    private_key: None,
-                    public_key: None,
-                    ip: None,
-                    date: DateTime::now(),
-                })
-                .await
-                .is_ok()
-            {
-                bot.send_message(
-                    chats.lock().await[&user_id],
-                    "Congrats! Admin's approved your request, now you can get a config",
-                )
-                .await?;
-            }
+    let mut peer = match store.find_by_id(query.from.id.0).await? {
+        None => {
+            let tr = locales.tr(None, telegram_lang);
+            bot.send_message(chat_id, tr.get("register-first")).await?;
+            return Ok(());
         }
-        AdminCommands::Reject => {
-            bot.send_message(
-                chats.lock().await[&user_id],
-                "Sorry, admin's rejected your request",
-            )
+        Some(peer) => peer,
+    };
+    if action == "language" {
+        if !locales.languages().iter().any(|lang| lang == value) {
+            return Ok(());
+        }
+        peer.language = Some(value.to_string());
+        let tr = locales.tr(peer.language.as_deref(), telegram_lang);
+        let msg = match store.update(&peer).await {
+            Err(why) => {
+                tracing::error!("Cannot save language of {}: {}", peer.username, why);
+                tr.error(&why).unwrap_or_else(|| why.to_string())
+            }
+            Ok(_) => tr.get("language-changed"),
+        };
+        bot.send_message(chat_id, msg).await?;
+        return Ok(());
+    }
+    let tr = locales.tr(peer.language.as_deref(), telegram_lang);
+    if action == "device" {
+        return device(&bot, chat_id, peer.user_id, value, &store, config, &tr).await;
+    }
+    if action == "plan" {
+        return invoice(&bot, chat_id, value, config, &tr).await;
+    }
+    let interface = value;
+    if !regions(&*config.lock().await)
+        .iter()
+        .any(|(name, _)| name == interface)
+    {
+        bot.send_message(chat_id, tr.get("region-unavailable"))
             .await?;
+        return Ok(());
+    }
+    match action {
+        "region" if peer.public_key.is_none() => {
+            peer.interface = interface.to_string();
+            issue(&bot, chat_id, peer, &store, config, &tr, admin_chat_id).await;
         }
-        AdminCommands::Remove => {
-            if let Some(peer) = mongo.find_by_id(user_id.0).await {
-                let _ = wireguard::remove_peer(&peer).await;
-                if mongo.delete(&peer).await.is_ok() {
-                    bot.send_message(
-                        chats.lock().await[&user_id],
-                        "You've been removed from gimmewire",
+        "region" => {
+            bot.send_message(chat_id, tr.get("already-have-config"))
+                .await?;
+        }
+        "switch" => {
+            let switched = peers::switch(&mut peer, interface, &store, config.clone()).await;
+            let actor = format!("user {}", peer.user_id);
+            let action = format!("switch to {}", interface);
+            audit::record(&store, &actor, &action, &peer.username, &switched).await;
+            match switched {
+                Err(why) => {
+                    send_and_log_msg(
+                        &bot,
+                        chat_id,
+                        Some(format!("Cannot move {} to {}", peer.username, interface)),
+                        Some(tr.error(&why).unwrap_or_else(|| tr.get("switch-failed"))),
+                        Some(why.to_string()),
+                        admin_chat_id,
                     )
-                    .await?;
+                    .await
+                }
+                Ok(_) => {
+                    let caption = tr.get("region-changed");
+                    send_conf(&bot, chat_id, &peer, config, &tr, &caption).await?
                 }
-            } else {
-                bot.send_message(ChatId(admin_chat_id), "Cannot find peer")
-                    .await?;
             }
         }
+        _ => (),
     }
     Ok(())
 }
 
-pub async fn user_handle(
-    bot: Bot,
-    message: Message,
-    mongo: Mongo,
-    cmd: UserCommands,
-    chats: Arc<Mutex<HashMap<UserId, ChatId>>>,
+/// (interface, label) pairs users can choose from, labelled by the `Region` setting.
+fn regions(config: &Ini) -> Vec<(String, String)> {
+    wireguard::interfaces(config)
+        .into_iter()
+        .map(|interface| {
+            let label = interface
+                .get(config, "Region")
+                .unwrap_or_else(|| interface.name.clone());
+            (interface.name, label)
+        })
+        .collect()
+}
+
+/// One `<action>:<value>` button per (value, label) pair.
+fn keyboard(action: &str, choices: &[(String, String)]) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(choices.iter().map(|(name, label)| {
+        vec![InlineKeyboardButton::callback(
+            label.clone(),
+            format!("{}:{}", action, name),
+        )]
+    }))
+}
+
+fn device_keyboard(name: &str, tr: &Tr<'_>) -> InlineKeyboardMarkup {
+    let button = |action: &str| device_button(name, action, tr);
+    let mut files = vec![button("config"), button("qr"), button("mobileconfig")];
+    if !cfg!(feature = "qr") {
+        files.remove(1);
+    }
+    InlineKeyboardMarkup::new([
+        files,
+        vec![button("router"), button("networkmanager")],
+        vec![button("killswitch"), button("rotate"), button("delete")],
+    ])
+}
+
+fn device_button(name: &str, action: &str, tr: &Tr<'_>) -> InlineKeyboardButton {
+    InlineKeyboardButton::callback(
+        tr.get(&format!("button-{}", action)),
+        format!("device:{}:{}", action, name),
+    )
+}
+
+/// Handles a /devices button of one of the user's own peers.
+async fn device(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: u64,
+    data: &str,
+    store: &Store,
     config: Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
 ) -> Result<(), teloxide::RequestError> {
-    let username = message.chat.username().unwrap_or("None").to_string();
-    let user_id = message.from().unwrap().id;
-    let admin_chat_id = config
-        .lock()
+    let admin_chat_id = match admin_id(&*config.lock().await) {
+        None => return Ok(()),
+        Some(admin_chat_id) => admin_chat_id,
+    };
+    let (action, name) = data.split_once(':').unwrap_or((data, ""));
+    let mut peer = match store.find_by_username(name).await? {
+        Some(peer) if peer.user_id == user_id => peer,
+        _ => {
+            bot.send_message(chat_id, tr.get("device-not-found"))
+                .await?;
+            return Ok(());
+        }
+    };
+    if action != "delete" && action != "remove" && unpaid(bot, chat_id, &peer, &config, tr).await? {
+        return Ok(());
+    }
+    let resent = matches!(
+        action,
+        "config" | "qr" | "mobileconfig" | "mikrotik" | "openwrt" | "networkmanager" | "regen"
+    ) && peer.public_key.is_some()
+        && peer.private_key.is_some();
+    if resent && !resend(bot, chat_id, user_id, &config, tr).await? {
+        return Ok(());
+    }
+    match action {
+        "config" | "qr" | "mobileconfig" | "mikrotik" | "openwrt" | "networkmanager" | "regen"
+            if peer.public_key.is_some() && peer.private_key.is_none() =>
+        {
+            bot.send_message(chat_id, tr.get("key-not-kept")).await?;
+        }
+        "config" if peer.public_key.is_none() => {
+            issue(bot, chat_id, peer, store, config, tr, admin_chat_id).await
+        }
+        "config" => {
+            let caption = tr.get("open-with-wireguard");
+            send_conf(bot, chat_id, &peer, config, tr, &caption).await?
+        }
+        "regen" if peer.public_key.is_none() => {
+            bot.send_message(chat_id, tr.get("no-config")).await?;
+        }
+        // The same keys and address again, as a file and a QR code
+        "regen" => {
+            audit::record_ok(store, &format!("user {}", user_id), "regen", name).await;
+            let caption = tr.get("open-with-wireguard");
+            send_conf(bot, chat_id, &peer, config.clone(), tr, &caption).await?;
+            match qr(&peer, config).await {
+                Err(why) => tracing::error!("Cannot make a QR code for {}: {}", name, why),
+                Ok(png) => {
+                    bot.send_photo(chat_id, InputFile::memory(png).file_name("qr.png"))
+                        .caption(tr.get("qr-caption"))
+                        .await?;
+                }
+            }
+        }
+        "router" => {
+            let formats = InlineKeyboardMarkup::new([vec![
+                device_button(name, "mikrotik", tr),
+                device_button(name, "openwrt", tr),
+            ]]);
+            bot.send_message(chat_id, tr.get("choose-router"))
+                .reply_markup(formats)
+                .await?;
+        }
+        "mobileconfig" | "mikrotik" | "openwrt" | "networkmanager" => match export::export(
+            &peer,
+            config.clone(),
+            Format::from_str(action, false).unwrap_or(Format::Conf),
+        )
         .await
-        .getint("Bot", "AdminId")
-        .expect("Cannot find admin chat id")
-        .unwrap();
-    match cmd {
-        UserCommands::Register => {
-            if mongo
-                .find_by_id(message.from().unwrap().id.0)
-                .await
-                .is_some()
-            {
-                bot.send_message(message.chat.id, "This account is already registered")
-                    .await?;
-            } else {
-                let chat_id = message.chat.id;
-                let msg = format!("@{} {}", username, user_id);
-                chats.lock().await.insert(user_id, chat_id);
-                bot.send_message(ChatId(admin_chat_id), msg).await?;
-                bot.send_message(message.chat.id, "Request is sent to admin")
+        {
+            Err(why) => {
+                tracing::error!("Cannot export {} of {}: {}", action, peer.username, why);
+                bot.send_message(chat_id, tr.get("config-failed")).await?;
+            }
+            Ok(path) => {
+                let sent = bot
+                    .send_document(chat_id, InputFile::file(&path))
+                    .caption(tr.get(&format!("export-{}", action)))
+                    .await;
+                keys::forget(&path, &*config.lock().await);
+                sent?;
+            }
+        },
+        "qr" => match qr(&peer, config).await {
+            Err(why) => {
+                tracing::error!("Cannot make a QR code for {}: {}", peer.username, why);
+                bot.send_message(chat_id, tr.get("config-failed")).await?;
+            }
+            Ok(png) => {
+                bot.send_photo(chat_id, InputFile::memory(png).file_name("qr.png"))
+                    .caption(tr.get("qr-caption"))
                     .await?;
             }
-        }
-        UserCommands::GetConfig => {
-            if let Some(mut peer) = mongo.find_by_id(user_id.0).await {
-                // remove old peer, if err => send message to user and to admin
-                if peer.public_key.is_some() {
-                    match wireguard::remove_peer(&peer).await {
-                        Err(why) => {
-                            send_and_log_msg(
-                                &bot,
-                                &message,
-                                Some(format!("Cannot remove existing peer {}", peer.username)),
-                                Some("Sorry cannot generate config".to_string()),
-                                Some(why),
-                                admin_chat_id,
-                            )
-                            .await;
-                            return Ok(());
-                        }
-                        Ok(_) => (),
-                    }
+        },
+        "rotate" => rotate_own(bot, chat_id, peer, store, config, tr, admin_chat_id).await?,
+        "killswitch" => {
+            let enabled = !wireguard::kill_switch(&peer, &*config.lock().await);
+            peer.kill_switch = Some(enabled);
+            let updated = store.update(&peer).await;
+            let actor = format!("user {}", user_id);
+            let action = match enabled {
+                true => "kill switch on",
+                false => "kill switch off",
+            };
+            audit::record(store, &actor, action, &peer.username, &updated).await;
+            match updated {
+                Err(why) => {
+                    tracing::error!("Cannot save {}: {}", peer.username, why);
+                    bot.send_message(chat_id, tr.get("config-failed")).await?;
                 }
-                // Add peer to wireguard, if err => send message to user and to admin
-                match wireguard::add_peer(&mut peer, &mongo).await {
-                    Err(why) => {
-                        send_and_log_msg(
-                            &bot,
-                            &message,
-                            Some(format!("Cannot add peer {}", peer.username)),
-                            Some("Sorry cannot generate config".to_string()),
-                            Some(why),
-                            admin_chat_id,
-                        )
-                        .await;
-                        return Ok(());
-                    }
-                    Ok(_) => (),
-                };
-                // Update peer in db, if err => send message to user and to admin
-                match mongo.update(&peer).await {
-                    Err(why) => {
-                        let _ = wireguard::remove_peer(&peer).await; // Something like dummy rollback
-                        send_and_log_msg(
-                            &bot,
-                            &message,
-                            Some(format!("Cannot update peer {}", peer.username)),
-                            Some("Sorry cannot generate config".to_string()),
-                            Some(why),
-                            admin_chat_id,
-                        )
-                        .await;
-                        return Ok(());
-                    }
-                    Ok(_) => (),
+                Ok(_) => {
+                    let key = match enabled {
+                        true => "kill-switch-on",
+                        false => "kill-switch-off",
+                    };
+                    bot.send_message(chat_id, tr.get(key)).await?;
                 }
-                // If everything is ok => generate and send config
-                if let Ok(config_path) = wireguard::gen_conf(&peer, config).await {
-                    match bot
-                        .send_document(message.chat.id, InputFile::file(config_path))
-                        .await
-                    {
-                        Err(why) => {
-                            send_and_log_msg(
-                                &bot,
-                                &message,
-                                Some(format!("Cannot send config to {}", peer.username)),
-                                Some("Sorry cannot send config".to_string()),
-                                Some(SimpleError::from(why)),
-                                admin_chat_id,
-                            )
-                            .await;
-                            let _ = wireguard::remove_peer(&peer).await; // Something like dummy rollback
-                            return Ok(());
-                        }
-                        Ok(_) => (),
-                    }
-                    // If everything is ok => send message to user
-                    match bot
-                        .send_message(message.chat.id, "Open it with WireGuard")
-                        .await
-                    {
-                        Err(why) => {
-                            send_and_log_msg(
-                                &bot,
-                                &message,
-                                Some(format!("Cannot send success message to {}", peer.username)),
-                                None,
-                                Some(SimpleError::from(why)),
-                                admin_chat_id,
-                            )
-                            .await
-                        }
-                        Ok(_) => (),
-                    }
-                } else {
+            }
+        }
+        // Deleting drops the config, so it takes a second tap
+        "delete" => {
+            let confirm = InlineKeyboardButton::callback(
+                tr.get("button-confirm-delete"),
+                format!("device:remove:{}", name),
+            );
+            bot.send_message(chat_id, tr.format("confirm-delete", &[("name", name)]))
+                .reply_markup(InlineKeyboardMarkup::new([[confirm]]))
+                .await?;
+        }
+        "remove" => {
+            let revoked = peers::revoke(&mut peer, "removed by user", store, config).await;
+            let actor = format!("user {}", user_id);
+            audit::record(store, &actor, "remove", &peer.username, &revoked).await;
+            match revoked {
+                Err(why) => {
                     send_and_log_msg(
-                        &bot,
-                        &message,
-                        Some(format!("Cannot create config for {}", peer.username)),
-                        Some("Sorry cannot generate config".to_string()),
-                        None,
+                        bot,
+                        chat_id,
+                        Some(format!("Cannot remove {}", peer.username)),
+                        Some(tr.error(&why).unwrap_or_else(|| tr.get("delete-failed"))),
+                        Some(why.to_string()),
                         admin_chat_id,
                     )
-                    .await;
+                    .await
+                }
+                Ok(_) => {
+                    bot.send_message(chat_id, tr.format("device-removed", &[("name", name)]))
+                        .await?;
                 }
-            } else {
-                bot.send_message(message.chat.id, "Register first").await?;
             }
         }
-        UserCommands::Help => {
-            bot.send_message(
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Sends the Telegram invoice of a plan, paid ones come back to `payment_handle`.
+async fn invoice(
+    bot: &Bot,
+    chat_id: ChatId,
+    name: &str,
+    config: Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
+) -> Result<(), teloxide::RequestError> {
+    let (plans, token, currency) = {
+        let config = config.lock().await;
+        (
+            billing::plans(&config),
+            config.get("Payments", "ProviderToken").unwrap_or_default(),
+            config
+                .get("Payments", "Currency")
+                .unwrap_or_else(|| "USD".to_string()),
+        )
+    };
+    let plan = match plans.into_iter().find(|plan| plan.name == name) {
+        None => {
+            bot.send_message(chat_id, tr.get("plan-unavailable"))
+                .await?;
+            return Ok(());
+        }
+        Some(plan) => plan,
+    };
+    let description = tr.format("invoice-description", &[("days", &plan.days.to_string())]);
+    bot.send_invoice(
+        chat_id,
+        plan.title.clone(),
+        description,
+        plan.name,
+        token,
+        currency,
+        vec![LabeledPrice::new(plan.title, plan.price)],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Lets a checkout through when its plan still exists at the invoiced price.
+#[tracing::instrument(skip_all, fields(user_id = query.from.id.0, plan = %query.invoice_payload))]
+pub async fn pre_checkout_handle(
+    bot: Bot,
+    query: PreCheckoutQuery,
+    store: Store,
+    config: Arc<Mutex<Ini>>,
+    locales: Locales,
+) -> Result<(), teloxide::RequestError> {
+    let valid = billing::plans(&*config.lock().await)
+        .iter()
+        .any(|plan| plan.name == query.invoice_payload && plan.price == query.total_amount);
+    let peer = store.find_by_id(query.from.id.0).await?;
+    let answer = bot.answer_pre_checkout_query(query.id, valid && peer.is_some());
+    match (valid, peer) {
+        (true, Some(_)) => answer.await?,
+        (_, peer) => {
+            let language = peer.as_ref().and_then(|peer| peer.language.as_deref());
+            let tr = locales.tr(language, query.from.language_code.as_deref());
+            answer.error_message(tr.get("plan-unavailable")).await?
+        }
+    };
+    Ok(())
+}
+
+/// Extends the subscription by the paid plan, brings a suspended peer back and issues a first config.
+#[tracing::instrument(skip_all, fields(
+    user_id = message.from().map(|user| user.id.0),
+    plan = %payment.invoice_payload,
+))]
+pub async fn payment_handle(
+    bot: Bot,
+    message: Message,
+    payment: SuccessfulPayment,
+    store: Store,
+    config: Arc<Mutex<Ini>>,
+    locales: Locales,
+) -> Result<(), teloxide::RequestError> {
+    let (user_id, telegram_lang) = match message.from() {
+        None => return Ok(()),
+        Some(user) => (user.id, user.language_code.as_deref()),
+    };
+    let admin_chat_id = match admin_id(&*config.lock().await) {
+        None => return Ok(()),
+        Some(admin_chat_id) => admin_chat_id,
+    };
+    let charge = &payment.telegram_payment_charge_id;
+    let plan = billing::plans(&*config.lock().await)
+        .into_iter()
+        .find(|plan| plan.name == payment.invoice_payload);
+    let (mut peer, plan) = match (store.find_by_id(user_id.0).await?, plan) {
+        (Some(peer), Some(plan)) => (peer, plan),
+        (peer, _) => {
+            // Money is taken, the admin has to sort it out
+            let language = peer.as_ref().and_then(|peer| peer.language.as_deref());
+            let tr = locales.tr(language, telegram_lang);
+            send_and_log_msg(
+                &bot,
                 message.chat.id,
-                "Hello!😉 Quick start:
-0. 📱 Install WireGuard client from App Store.
-1. 📝 Register
-2. 🚀 Get config
-3. 🔥 Open config with WireGuard client
-             ",
+                Some(format!(
+                    "Payment {} of user {} for plan {} has no peer or plan to extend",
+                    charge, user_id, payment.invoice_payload
+                )),
+                Some(tr.get("payment-failed")),
+                None,
+                admin_chat_id,
             )
+            .await;
+            return Ok(());
+        }
+    };
+    let tr = locales.tr(peer.language.as_deref(), telegram_lang);
+    billing::extend(&mut peer, &plan, DateTime::now());
+    let saved = match peer.suspended.is_some() {
+        true => peers::resume(&mut peer, &store, config.clone()).await,
+        false => {
+            let updated = store.update(&peer).await;
+            // The plan may come with other rate limits
+            if updated.is_ok() {
+                peers::refresh_rules(&store, config.clone()).await;
+            }
+            updated
+        }
+    };
+    let actor = format!("user {}", user_id);
+    let action = format!("pay {}", plan.name);
+    audit::record(&store, &actor, &action, &peer.username, &saved).await;
+    if let Err(why) = saved {
+        send_and_log_msg(
+            &bot,
+            message.chat.id,
+            Some(format!(
+                "Cannot extend subscription of {} after payment {}",
+                peer.username, charge
+            )),
+            Some(tr.get("payment-failed")),
+            Some(why.to_string()),
+            admin_chat_id,
+        )
+        .await;
+        return Ok(());
+    }
+    if let Some(subscription) = &peer.subscription {
+        bot.send_message(message.chat.id, paid_until(subscription, &tr))
             .await?;
+    }
+    if peer.public_key.is_none() {
+        issue(
+            &bot,
+            message.chat.id,
+            peer,
+            &store,
+            config,
+            &tr,
+            admin_chat_id,
+        )
+        .await;
+    }
+    Ok(())
+}
+
+fn paid_until(subscription: &wireguard::Subscription, tr: &Tr<'_>) -> String {
+    let date = subscription
+        .paid_until
+        .try_to_rfc3339_string()
+        .unwrap_or_default();
+    tr.format("paid-until", &[("date", &date)])
+}
+
+/// Renders the client config as a PNG QR code for the mobile apps to scan. It holds the private
+/// key, so it is only ever kept in memory.
+async fn qr(peer: &Peer, config: Arc<Mutex<Ini>>) -> crate::error::Result<Vec<u8>> {
+    let conf_path = wireguard::gen_conf(peer, config.clone()).await?;
+    let conf = std::fs::read_to_string(&conf_path);
+    keys::forget(&conf_path, &*config.lock().await);
+    qr_png(&conf?)
+}
+
+#[cfg(feature = "qr")]
+fn qr_png(text: &str) -> crate::error::Result<Vec<u8>> {
+    let code = qrcode::QrCode::new(text.as_bytes())
+        .map_err(|why| GimmewireError::Invalid(why.to_string()))?;
+    let mut png = std::io::Cursor::new(Vec::new());
+    code.render::<image::Luma<u8>>()
+        .min_dimensions(512, 512)
+        .build()
+        .write_to(&mut png, image::ImageFormat::Png)
+        .map_err(|why| GimmewireError::Io(std::io::Error::other(why)))?;
+    Ok(png.into_inner())
+}
+
+#[cfg(not(feature = "qr"))]
+fn qr_png(_: &str) -> crate::error::Result<Vec<u8>> {
+    Err(GimmewireError::Config(
+        "gimmewire was built without the `qr` feature".to_string(),
+    ))
+}
+
+/// Sends a one-time download link and its QR code instead of the config file, so the key
+/// doesn't stay in the chat history. None when `[Links] URL` is unset.
+#[cfg(feature = "http")]
+async fn send_link(
+    bot: &Bot,
+    chat_id: ChatId,
+    peer: &Peer,
+    config: Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
+    caption: &str,
+) -> Option<Result<(), String>> {
+    let url = match crate::links::share(peer, config).await {
+        Ok(None) => return None,
+        Ok(Some(url)) => url,
+        Err(why) => return Some(Err(why.to_string())),
+    };
+    let caption = format!("{}\n{}\n{}", caption, tr.get("link-once"), url);
+    let sent = match qr_png(&url) {
+        // The link alone still works
+        Err(why) => {
+            tracing::error!("Cannot make a QR code of the link: {}", why);
+            bot.send_message(chat_id, caption)
+                .disable_web_page_preview(true)
+                .await
+                .map(|_| ())
+        }
+        Ok(png) => bot
+            .send_photo(chat_id, InputFile::memory(png).file_name("link.png"))
+            .caption(caption)
+            .await
+            .map(|_| ()),
+    };
+    Some(sent.map_err(|why| why.to_string()))
+}
+
+/// Replaces keys of the user's own peer and sends them the new config.
+async fn rotate_own(
+    bot: &Bot,
+    chat_id: ChatId,
+    mut peer: Peer,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    let rotated = peers::rotate(&mut peer, "rotated by user", store, config.clone()).await;
+    let actor = format!("user {}", peer.user_id);
+    audit::record(store, &actor, "rotate", &peer.username, &rotated).await;
+    match rotated {
+        Err(why) => {
+            send_and_log_msg(
+                bot,
+                chat_id,
+                Some(format!("Cannot rotate keys of {}", peer.username)),
+                Some(tr.error(&why).unwrap_or_else(|| tr.get("rotate-failed"))),
+                Some(why.to_string()),
+                admin_chat_id,
+            )
+            .await
+        }
+        Ok(_) => {
+            let caption = tr.get("keys-replaced");
+            send_conf(bot, chat_id, &peer, config, tr, &caption).await?
+        }
+    }
+    Ok(())
+}
+
+/// Sends the config of a peer which is on its interface with its keys kept, so asking twice
+/// doesn't replace them. Other peers are issued.
+async fn deliver(
+    bot: &Bot,
+    chat_id: ChatId,
+    peer: Peer,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
+    admin_chat_id: i64,
+) -> Result<(), teloxide::RequestError> {
+    match (&peer.public_key, &peer.private_key) {
+        (Some(_), Some(_)) => {
+            if !resend(bot, chat_id, peer.user_id, &config, tr).await? {
+                return Ok(());
+            }
+            let caption = tr.get("open-with-wireguard");
+            send_conf(bot, chat_id, &peer, config, tr, &caption).await
+        }
+        _ => {
+            issue(bot, chat_id, peer, store, config, tr, admin_chat_id).await;
+            Ok(())
+        }
+    }
+}
+
+/// Undoes issuing a config which could not be sent, see `peers::withdraw`.
+async fn withdraw(peer: &mut Peer, store: &Store, config: Arc<Mutex<Ini>>) {
+    if let Err(why) = peers::withdraw(peer, store, config).await {
+        tracing::error!(
+            "Cannot withdraw the unsent config of {}: {}",
+            peer.username,
+            why
+        );
+    }
+}
+
+/// Counts sending a kept config again against `RegenPerDay`, in any format, and tells the user
+/// when they had enough for today.
+async fn resend(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: u64,
+    config: &Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
+) -> Result<bool, teloxide::RequestError> {
+    let per_day = config
+        .lock()
+        .await
+        .getuint("Bot", "RegenPerDay")
+        .unwrap_or(None)
+        .unwrap_or(5);
+    let day = std::time::Duration::from_secs(24 * 60 * 60);
+    if throttle::allow(user_id, "regen", per_day, day) {
+        return Ok(true);
+    }
+    bot.send_message(chat_id, tr.get("regen-limit")).await?;
+    Ok(false)
+}
+
+/// Issues fresh keys for a user's peer and sends them the config.
+async fn issue(
+    bot: &Bot,
+    chat_id: ChatId,
+    mut peer: Peer,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    tr: &Tr<'_>,
+    admin_chat_id: i64,
+) {
+    // Re-issue peer on its interface and in db, if err => send message to user and to admin
+    let provisioned = peers::provision(&mut peer, store, config.clone()).await;
+    let actor = format!("user {}", peer.user_id);
+    audit::record(store, &actor, "regenerate", &peer.username, &provisioned).await;
+    if let Err(why) = provisioned {
+        send_and_log_msg(
+            bot,
+            chat_id,
+            Some(format!("Cannot provision peer {}", peer.username)),
+            Some(tr.error(&why).unwrap_or_else(|| tr.get("config-failed"))),
+            Some(why.to_string()),
+            admin_chat_id,
+        )
+        .await;
+        return;
+    }
+    #[cfg(feature = "http")]
+    {
+        let caption = tr.get("open-with-wireguard");
+        match send_link(bot, chat_id, &peer, config.clone(), tr, &caption).await {
+            None => (),
+            Some(Ok(_)) => return,
+            Some(Err(why)) => {
+                send_and_log_msg(
+                    bot,
+                    chat_id,
+                    Some(format!("Cannot send config link to {}", peer.username)),
+                    Some(tr.get("send-failed")),
+                    Some(why),
+                    admin_chat_id,
+                )
+                .await;
+                withdraw(&mut peer, store, config).await;
+                return;
+            }
+        }
+    }
+    // If everything is ok => generate and send config
+    if let Ok(config_path) = wireguard::gen_conf(&peer, config.clone()).await {
+        let sent = bot
+            .send_document(chat_id, InputFile::file(&config_path))
+            .await;
+        keys::forget(&config_path, &*config.lock().await);
+        if let Err(why) = sent {
+            send_and_log_msg(
+                bot,
+                chat_id,
+                Some(format!("Cannot send config to {}", peer.username)),
+                Some(tr.get("send-failed")),
+                Some(why.to_string()),
+                admin_chat_id,
+            )
+            .await;
+            withdraw(&mut peer, store, config).await;
+            return;
         }
+        // If everything is ok => send message to user
+        if let Err(why) = bot
+            .send_message(chat_id, tr.get("open-with-wireguard"))
+            .await
+        {
+            send_and_log_msg(
+                bot,
+                chat_id,
+                Some(format!("Cannot send success message to {}", peer.username)),
+                None,
+                Some(why.to_string()),
+                admin_chat_id,
+            )
+            .await
+        }
+    } else {
+        send_and_log_msg(
+            bot,
+            chat_id,
+            Some(format!("Cannot create config for {}", peer.username)),
+            Some(tr.get("config-failed")),
+            None,
+            admin_chat_id,
+        )
+        .await;
+    }
+}
+
+/// `[Bot] AdminId`, handlers do nothing without it.
+fn admin_id(config: &Ini) -> Option<i64> {
+    let admin_id = config.getint("Bot", "AdminId").unwrap_or(None);
+    if admin_id.is_none() {
+        tracing::error!("Cannot find admin chat id, set [Bot] AdminId");
+    }
+    admin_id
+}
+
+/// Chat the user registered from, their private chat has the same id otherwise.
+async fn chat_of(chats: &Mutex<HashMap<UserId, ChatId>>, user_id: UserId) -> ChatId {
+    chats
+        .lock()
+        .await
+        .get(&user_id)
+        .copied()
+        .unwrap_or(ChatId(user_id.0 as i64))
+}
+
+/// Asks to /subscribe instead of handing out configs to suspended peers and, when billing is on,
+/// to peers which aren't paid for.
+async fn unpaid(
+    bot: &Bot,
+    chat_id: ChatId,
+    peer: &Peer,
+    config: &Mutex<Ini>,
+    tr: &Tr<'_>,
+) -> Result<bool, teloxide::RequestError> {
+    let config = config.lock().await;
+    if billing::active(peer, &config, DateTime::now()) {
+        return Ok(false);
+    }
+    let msg = match billing::plans(&config).is_empty() {
+        true => tr.get("suspended"),
+        false => tr.get("subscribe-first"),
+    };
+    bot.send_message(chat_id, msg).await?;
+    Ok(true)
+}
+
+/// Runs the `[Verify]` checks on a new user, telling them what is missing or asking the question
+/// when one fails.
+async fn unverified(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: UserId,
+    config: &Mutex<Ini>,
+    tr: &Tr<'_>,
+) -> Result<bool, teloxide::RequestError> {
+    let settings = match verify::settings(&*config.lock().await) {
+        None => return Ok(false),
+        Some(settings) => settings,
     };
+    if settings.max_user_id.is_some_and(|max| user_id.0 > max) {
+        tracing::info!("{} is refused as too new an account", user_id);
+        bot.send_message(chat_id, tr.get("verify-too-new")).await?;
+        return Ok(true);
+    }
+    if let Some(channel) = &settings.channel {
+        let recipient = match channel.parse() {
+            Ok(id) => Recipient::Id(ChatId(id)),
+            Err(_) => Recipient::ChannelUsername(channel.clone()),
+        };
+        let joined = match bot.get_chat_member(recipient, user_id).await {
+            Ok(member) => member.kind.is_present(),
+            Err(why) => {
+                tracing::error!("Cannot tell if {} is in {}: {}", user_id, channel, why);
+                false
+            }
+        };
+        if !joined {
+            let msg = tr.format("verify-join", &[("channel", channel)]);
+            bot.send_message(chat_id, msg).await?;
+            return Ok(true);
+        }
+    }
+    if settings.captcha && !verify::solved(user_id.0) {
+        ask(bot, chat_id, user_id, tr).await?;
+        return Ok(true);
+    }
+    Ok(false)
+}
 
+/// Asks a new `[Verify] Captcha` question, answered with `verify:<choice>` buttons.
+async fn ask(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: UserId,
+    tr: &Tr<'_>,
+) -> Result<(), teloxide::RequestError> {
+    let (question, choices) = verify::ask(user_id.0);
+    let buttons = choices.iter().map(|choice| {
+        InlineKeyboardButton::callback(choice.to_string(), format!("verify:{}", choice))
+    });
+    let msg = tr.format("verify-question", &[("question", &question)]);
+    bot.send_message(chat_id, msg)
+        .reply_markup(InlineKeyboardMarkup::new([buttons]))
+        .await?;
     Ok(())
 }
 
+/// Asks to come back later instead of failing on every lookup while the db is down.
+async fn unavailable(
+    bot: &Bot,
+    chat_id: ChatId,
+    store: &Store,
+    tr: &Tr<'_>,
+) -> Result<bool, teloxide::RequestError> {
+    if store.available().await {
+        return Ok(false);
+    }
+    bot.send_message(chat_id, tr.get("unavailable")).await?;
+    Ok(true)
+}
+
 async fn send_and_log_msg(
     bot: &Bot,
-    message: &Message,
+    chat_id: ChatId,
     admin_msg: Option<String>,
     user_msg: Option<String>,
-    err: Option<SimpleError>,
+    err: Option<String>,
     admin_chat_id: i64,
 ) {
     if let Some(msg) = user_msg {
-        match bot.send_message(message.chat.id, msg).await {
-            Err(why) => log::error!("{}", why),
-            Ok(_) => (),
+        if let Err(why) = bot.send_message(chat_id, msg).await {
+            tracing::error!("{}", why);
         }
     }
     if let Some(msg) = admin_msg {
-        match bot.send_message(ChatId(admin_chat_id), msg).await {
-            Err(why) => log::error!("{}", why),
-            Ok(_) => (),
+        if let Err(why) = bot.send_message(ChatId(admin_chat_id), msg).await {
+            tracing::error!("{}", why);
         }
     }
     if let Some(error) = err {
-        log::error!("{}", error);
+        tracing::error!("{}", error);
     }
 }
+
+#[cfg(test)]
+#[test]
+fn user_and_admin_commands() {
+    // Users are dispatched first, so no admin command may share a name with theirs
+    assert!(matches!(
+        UserCommands::parse("/rotate alice", "bot"),
+        Ok(UserCommands::Rotate)
+    ));
+    assert!(AdminCommands::parse("/rotate alice", "bot").is_err());
+    assert!(matches!(
+        AdminCommands::parse("/rotatepeer alice", "bot"),
+        Ok(AdminCommands::RotatePeer)
+    ));
+    assert!(UserCommands::parse("/rotatepeer alice", "bot").is_err());
+}
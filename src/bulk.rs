@@ -0,0 +1,106 @@
+//! Provisioning many peers at once, e.g. a team from a CSV, with their configs in one ZIP.
+use crate::error::Result;
+use crate::store::Store;
+use crate::{archive, audit, keys, peers, wireguard};
+use configparser::ini::Ini;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Debug, Default)]
+pub struct Provisioned {
+    /// Configs of the created peers, with `failed.txt` when some failed.
+    pub archive: Vec<u8>,
+    pub created: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+/// Names from a list or a CSV: the first field of every line, or every name of a single line.
+/// A `username` header, blanks and repeats are skipped.
+pub fn names(text: &str) -> Vec<String> {
+    let lines: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let fields: Vec<&str> = match lines[..] {
+        [line] => line
+            .split([',', ';', ' '])
+            .filter(|name| !name.is_empty())
+            .collect(),
+        _ => lines
+            .iter()
+            .filter_map(|line| line.split([',', ';']).next())
+            .collect(),
+    };
+    let mut names: Vec<String> = Vec::new();
+    for name in fields {
+        let name = name.trim().trim_matches('"').trim_start_matches('@');
+        if name.is_empty() || name.eq_ignore_ascii_case("username") {
+            continue;
+        }
+        if !names.iter().any(|other| other == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+/// Creates an unlinked peer for every name and collects their configs, a name which fails is
+/// reported and doesn't stop the others.
+pub async fn provision(
+    names: &[String],
+    actor: &str,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<Provisioned> {
+    let mut provisioned = Provisioned::default();
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    for name in names {
+        // Names become file names in the archive
+        if let Err(why) = peers::valid_name(name) {
+            provisioned.failed.push((name.clone(), why.to_string()));
+            continue;
+        }
+        let created = peers::create(name.clone(), None, None, None, store, config.clone()).await;
+        audit::record(store, actor, "bulk add", name, &created).await;
+        let conf = match created {
+            Err(why) => Err(why),
+            Ok(peer) => conf(&peer, config.clone()).await,
+        };
+        match conf {
+            Err(why) => provisioned.failed.push((name.clone(), why.to_string())),
+            Ok(conf) => {
+                files.push((format!("{}.conf", name), conf));
+                provisioned.created.push(name.clone());
+            }
+        }
+    }
+    if !provisioned.failed.is_empty() {
+        let report: String = provisioned
+            .failed
+            .iter()
+            .map(|(name, why)| format!("{}: {}\n", name, why))
+            .collect();
+        files.push(("failed.txt".to_string(), report.into_bytes()));
+    }
+    provisioned.archive = archive::zip(&files, None)?;
+    Ok(provisioned)
+}
+
+async fn conf(peer: &wireguard::Peer, config: Arc<Mutex<Ini>>) -> Result<Vec<u8>> {
+    let path = wireguard::gen_conf(peer, config.clone()).await?;
+    let content = std::fs::read(&path);
+    keys::forget(&path, &*config.lock().await);
+    Ok(content?)
+}
+
+#[cfg(test)]
+#[test]
+fn bulk_names() {
+    assert!(names("alice bob, carol,alice") == ["alice", "bob", "carol"]);
+    assert!(
+        names("username,email\nalice,a@example.com\n\n\"@bob\",b@example.com\n")
+            == ["alice", "bob"]
+    );
+    assert!(names("alice\nbob\n") == ["alice", "bob"] && names(" \n").is_empty());
+    assert!(peers::valid_name("../alice").is_err() && peers::valid_name("bob_2-a").is_ok());
+}
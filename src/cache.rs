@@ -0,0 +1,218 @@
+//! Peers kept in memory in front of the backend, so allocation and lookups on every bot message
+//! don't query the db. Writes through this store drop the copy, which is also reloaded every
+//! `[Storage] Cache` seconds for changes made elsewhere, e.g. by the cli. Until then those are
+//! missed, so it is meant for a bot which is the only writer.
+use crate::audit::Event;
+use crate::error::Result;
+use crate::referral::Referral;
+use crate::repair::Repair;
+use crate::rotation::Rotation;
+use crate::store::{Filter, PeerStore, Status, Store};
+use crate::wireguard::Peer;
+use async_trait::async_trait;
+use configparser::ini::Ini;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// `[Storage] Cache`, seconds peers are kept in memory, off by default since it misses peers
+/// other processes add.
+pub fn ttl(config: &Ini) -> Duration {
+    let seconds = config
+        .getuint("Storage", "Cache")
+        .unwrap_or(None)
+        .unwrap_or(0);
+    Duration::from_secs(seconds)
+}
+
+struct Snapshot {
+    active: Vec<Peer>,
+    archived: Vec<Peer>,
+    loaded: Instant,
+}
+
+pub struct Cached {
+    inner: Store,
+    ttl: Duration,
+    snapshot: Mutex<Option<Arc<Snapshot>>>,
+    /// Bumped by every write, a load which began before one is not kept.
+    generation: AtomicU64,
+}
+
+impl Cached {
+    pub fn new(inner: Store, ttl: Duration) -> Self {
+        Cached {
+            inner,
+            ttl,
+            snapshot: Mutex::new(None),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn cached(&self) -> Option<Arc<Snapshot>> {
+        let snapshot = self
+            .snapshot
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        snapshot
+            .as_ref()
+            .filter(|snapshot| snapshot.loaded.elapsed() < self.ttl)
+            .cloned()
+    }
+
+    /// The peers in memory, loaded from the backend when missing or stale.
+    async fn snapshot(&self) -> Result<Arc<Snapshot>> {
+        if let Some(snapshot) = self.cached() {
+            return Ok(snapshot);
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        let snapshot = Arc::new(Snapshot {
+            active: self.inner.get_peers().await?,
+            archived: self.inner.get_archived().await?,
+            loaded: Instant::now(),
+        });
+        if self.generation.load(Ordering::SeqCst) == generation {
+            *self
+                .snapshot
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(snapshot.clone());
+        }
+        Ok(snapshot)
+    }
+
+    fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        *self
+            .snapshot
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+    }
+}
+
+#[async_trait]
+impl PeerStore for Cached {
+    async fn add(&self, peer: &Peer) -> Result<()> {
+        let added = self.inner.add(peer).await;
+        self.invalidate();
+        added
+    }
+
+    async fn update(&self, peer: &Peer) -> Result<()> {
+        let updated = self.inner.update(peer).await;
+        self.invalidate();
+        updated
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<Option<Peer>> {
+        let snapshot = self.snapshot().await?;
+        Ok(snapshot
+            .active
+            .iter()
+            .find(|peer| peer.user_id == id)
+            .cloned())
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<Peer>> {
+        let snapshot = self.snapshot().await?;
+        Ok(snapshot
+            .active
+            .iter()
+            .find(|peer| peer.username == username)
+            .cloned())
+    }
+
+    async fn delete(&self, peer: &Peer) -> Result<()> {
+        let deleted = self.inner.delete(peer).await;
+        self.invalidate();
+        deleted
+    }
+
+    async fn get_peers(&self) -> Result<Vec<Peer>> {
+        Ok(self.snapshot().await?.active.clone())
+    }
+
+    async fn get_archived(&self) -> Result<Vec<Peer>> {
+        Ok(self.snapshot().await?.archived.clone())
+    }
+
+    async fn find_peers(
+        &self,
+        filter: &Filter,
+        skip: u64,
+        limit: Option<u64>,
+    ) -> Result<Vec<Peer>> {
+        let snapshot = self.snapshot().await?;
+        let peers = match filter.status {
+            Some(Status::Archived) => &snapshot.archived,
+            _ => &snapshot.active,
+        };
+        Ok(peers
+            .iter()
+            .filter(|peer| filter.matches(peer))
+            .skip(skip as usize)
+            .take(limit.map_or(usize::MAX, |limit| limit as usize))
+            .cloned()
+            .collect())
+    }
+
+    async fn log_rotation(&self, rotation: &Rotation) -> Result<()> {
+        self.inner.log_rotation(rotation).await
+    }
+
+    async fn log_event(&self, event: &Event) -> Result<()> {
+        self.inner.log_event(event).await
+    }
+
+    async fn get_events(&self, skip: u64, limit: u64) -> Result<Vec<Event>> {
+        self.inner.get_events(skip, limit).await
+    }
+
+    async fn save_referral(&self, referral: &Referral) -> Result<()> {
+        self.inner.save_referral(referral).await
+    }
+
+    async fn get_referrals(&self) -> Result<Vec<Referral>> {
+        self.inner.get_referrals().await
+    }
+
+    async fn save_repair(&self, repair: &Repair) -> Result<()> {
+        self.inner.save_repair(repair).await
+    }
+
+    async fn get_repairs(&self) -> Result<Vec<Repair>> {
+        self.inner.get_repairs().await
+    }
+
+    async fn delete_repair(&self, repair: &Repair) -> Result<()> {
+        self.inner.delete_repair(repair).await
+    }
+
+    async fn available(&self) -> bool {
+        self.inner.available().await
+    }
+}
+
+#[cfg(all(test, feature = "file"))]
+#[tokio::test]
+async fn cached_peers() {
+    let scratch = crate::store::Scratch::new("cache.json");
+    let file = scratch.file();
+    let store = Cached::new(file.clone(), Duration::from_secs(60));
+    store.add(&Peer::new(1, "alice".to_string())).await.unwrap();
+    assert!(store.find_by_id(1).await.unwrap().is_some());
+    // Changes behind its back wait for the copy to go stale, its own writes don't
+    file.add(&Peer::new(2, "bob".to_string())).await.unwrap();
+    assert!(store.find_by_username("bob").await.unwrap().is_none());
+    let mut alice = store.find_by_id(1).await.unwrap().unwrap();
+    alice.archived = Some(bson::DateTime::now());
+    store.update(&alice).await.unwrap();
+    assert!(
+        store.find_by_id(1).await.unwrap().is_none()
+            && store.find_by_id(2).await.unwrap().is_some()
+    );
+    let filter = Filter {
+        status: Some(Status::Archived),
+        ..Filter::default()
+    };
+    assert!(store.find_peers(&filter, 0, None).await.unwrap().len() == 1);
+}
@@ -0,0 +1,399 @@
+use crate::error::{GimmewireError, Result};
+use crate::store::{Filter, Store};
+use crate::wireguard::{self, Peer};
+use crate::{audit, backup, doctor, export, keys, peers, server};
+use clap::Subcommand;
+use configparser::ini::Ini;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Peers fetched at once by `peer list`.
+const LIST_PAGE: u64 = 500;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage peers
+    #[command(subcommand)]
+    Peer(PeerCommand),
+    /// Manage client configs
+    #[command(subcommand)]
+    Conf(ConfCommand),
+    /// Import interface peers missing from the db, from `wg showconf` or a saved copy of it
+    Import {
+        #[arg(short, long)]
+        file: Option<String>,
+        /// Interface the peers belong to, the `[Peer]` one by default
+        #[arg(short, long)]
+        interface: Option<String>,
+    },
+    /// Dump all peers into a JSON backup
+    Backup {
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Restore peers from a JSON backup and apply them to their interfaces
+    Restore { file: String },
+    /// Manage WireGuard interfaces
+    #[command(subcommand)]
+    Server(ServerCommand),
+    /// Look for inconsistencies between the db, the interfaces and the config
+    Doctor {
+        /// Repair everything without asking, a backup is taken first
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Bring stored peers to the current schema version, the bot does it when it starts
+    #[cfg(feature = "mongo")]
+    Migrate {
+        /// Print what would change and how to roll it back without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PeerCommand {
+    /// Create a peer which is not linked to a Telegram user
+    Add {
+        name: String,
+        /// Grant temporary access which is revoked after this many hours
+        #[arg(long)]
+        hours: Option<u64>,
+        /// Interface to put the peer on, the `[Peer]` one by default
+        #[arg(short, long)]
+        interface: Option<String>,
+    },
+    /// Remove a peer from its interface and archive it
+    Rm { name: String },
+    /// List all peers
+    List,
+    /// Replace keys of a peer keeping its address, prints the new config
+    Rotate { name: String },
+    /// List archived peers
+    Archived,
+    /// Bring an archived peer back
+    Restore { name: String },
+    /// Link an unclaimed peer to a Telegram user
+    Link {
+        name: String,
+        user_id: u64,
+        username: String,
+    },
+    /// Give a peer another name, keeping its keys and address
+    Rename { name: String, new_name: String },
+    /// Set the notes of a peer, clears them without text
+    Note { name: String, text: Option<String> },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ServerCommand {
+    /// List configured interfaces and how many peers each has
+    List,
+    /// Replace the private key of an interface and send its peers a new config
+    RotateKey {
+        /// The `[Peer]` interface by default
+        #[arg(short, long)]
+        interface: Option<String>,
+    },
+    /// Write the wg-quick config of an interface with the peers of the db, e.g. /etc/wireguard/wg0.conf
+    Export {
+        /// The `[Peer]` interface by default
+        #[arg(short, long)]
+        interface: Option<String>,
+        /// File with the server private key, read from the running interface otherwise
+        #[arg(long)]
+        private_key: Option<String>,
+        /// Printed when unset
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfCommand {
+    /// Print client config of a peer, or save it to a file
+    Export {
+        name: String,
+        #[arg(short, long)]
+        output: Option<String>,
+        #[arg(short, long, value_enum, default_value = "conf")]
+        format: export::Format,
+    },
+}
+
+pub async fn run(
+    command: Command,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    config_path: &str,
+) -> Result<()> {
+    match command {
+        Command::Peer(PeerCommand::Add {
+            name,
+            hours,
+            interface,
+        }) => {
+            let created = match (hours, interface) {
+                (Some(_), Some(_)) => {
+                    return Err(GimmewireError::Invalid(
+                        "Temporary peers always go to the [Peer] interface".to_string(),
+                    ))
+                }
+                (Some(hours), None) => {
+                    peers::grant_temporary(name.clone(), hours, store, config.clone()).await
+                }
+                (None, interface) => {
+                    peers::create(name.clone(), None, None, interface, store, config.clone()).await
+                }
+            };
+            audit::record(store, "cli", "add", &name, &created).await;
+            let peer = created?;
+            print_conf(&peer, config).await?;
+        }
+        Command::Peer(PeerCommand::Rm { name }) => {
+            let revoked = peers::revoke(
+                &mut find(store, &name).await?,
+                "removed from cli",
+                store,
+                config,
+            )
+            .await;
+            audit::record(store, "cli", "remove", &name, &revoked).await;
+            revoked?;
+            println!("Removed {}", name);
+        }
+        Command::Peer(PeerCommand::List) => {
+            println!(
+                "{:<24} {:<12} {:<10} {:<15} {:<44} DATE",
+                "NAME", "USER", "INTERFACE", "IP", "PUBLIC KEY"
+            );
+            // A page at a time, not the whole table in memory
+            let mut skip = 0;
+            loop {
+                let page = store
+                    .find_peers(&Filter::default(), skip, Some(LIST_PAGE))
+                    .await?;
+                let last = (page.len() as u64) < LIST_PAGE;
+                skip += page.len() as u64;
+                for peer in page {
+                    println!(
+                        "{:<24} {:<12} {:<10} {:<15} {:<44} {}",
+                        peer.username,
+                        peer.user_id,
+                        peer.interface,
+                        peer.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                        peer.public_key.unwrap_or_default(),
+                        peer.date.try_to_rfc3339_string().unwrap_or_default()
+                    );
+                }
+                if last {
+                    break;
+                }
+            }
+        }
+        Command::Peer(PeerCommand::Rotate { name }) => {
+            let mut peer = find(store, &name).await?;
+            let rotated = peers::rotate(&mut peer, "rotated from cli", store, config.clone()).await;
+            audit::record(store, "cli", "rotate", &name, &rotated).await;
+            rotated?;
+            print_conf(&peer, config).await?;
+        }
+        Command::Peer(PeerCommand::Archived) => {
+            println!("{:<24} {:<15} {:<25} REASON", "NAME", "IP", "ARCHIVED");
+            for peer in store.get_archived().await? {
+                println!(
+                    "{:<24} {:<15} {:<25} {}",
+                    peer.username,
+                    peer.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                    peer.archived
+                        .and_then(|date| date.try_to_rfc3339_string().ok())
+                        .unwrap_or_default(),
+                    peer.archive_reason.unwrap_or_default()
+                );
+            }
+        }
+        Command::Peer(PeerCommand::Restore { name }) => {
+            let unarchived = peers::unarchive(&name, store, config).await;
+            audit::record(store, "cli", "unarchive", &name, &unarchived).await;
+            let peer = unarchived?;
+            println!(
+                "Restored {} on {}",
+                name,
+                peer.ip.map(|ip| ip.to_string()).unwrap_or_default()
+            );
+        }
+        Command::Peer(PeerCommand::Link {
+            name,
+            user_id,
+            username,
+        }) => {
+            let claimed = peers::claim(&name, user_id, username, store).await;
+            audit::record(store, "cli", "claim", &name, &claimed).await;
+            let peer = claimed?;
+            println!("Linked {} to {}", name, peer.username);
+        }
+        Command::Peer(PeerCommand::Rename { name, new_name }) => {
+            let renamed = peers::rename(&name, &new_name, store).await;
+            audit::record(
+                store,
+                "cli",
+                &format!("rename {}", new_name),
+                &name,
+                &renamed,
+            )
+            .await;
+            renamed?;
+            println!("{} is now {}", name, new_name);
+        }
+        Command::Peer(PeerCommand::Note { name, text }) => {
+            let noted = peers::note(&name, text.as_deref(), store).await;
+            audit::record(store, "cli", "note", &name, &noted).await;
+            noted?;
+        }
+        Command::Import { file, interface } => {
+            let interface = {
+                let config = config.lock().await;
+                let name = interface.unwrap_or_else(|| wireguard::main_interface(&config));
+                wireguard::find_interface(&config, &name)?
+            };
+            let showconf = match file {
+                Some(file) => std::fs::read_to_string(file)?,
+                None => wireguard::showconf(&interface).await?,
+            };
+            for peer in peers::import(&showconf, &interface.name, store).await? {
+                audit::record_ok(store, "cli", "import", &peer.username).await;
+                println!(
+                    "Imported {} {}",
+                    peer.username,
+                    peer.ip.map(|ip| ip.to_string()).unwrap_or_default()
+                );
+            }
+        }
+        Command::Conf(ConfCommand::Export {
+            name,
+            output,
+            format,
+        }) => {
+            let peer = find(store, &name).await?;
+            if peer.private_key.is_none() {
+                return Err(GimmewireError::Invalid(format!(
+                    "Peer {} has no private key, rotate it for a new config",
+                    name
+                )));
+            }
+            let path = export::export(&peer, config, format).await?;
+            match output {
+                Some(output) => {
+                    std::fs::copy(&path, &output)?;
+                    println!("{}", output);
+                }
+                None => std::io::stdout().write_all(&std::fs::read(&path)?)?,
+            }
+        }
+        Command::Backup { output } => {
+            let path = match output {
+                Some(output) => output,
+                None => backup::default_path()?,
+            };
+            backup::save(&backup::export(store).await?, &path)?;
+            println!("{}", path);
+        }
+        Command::Restore { file } => {
+            let restored = backup::restore(
+                backup::load(&file)?,
+                &wireguard::interfaces(&*config.lock().await),
+                store,
+            )
+            .await?;
+            for name in &restored.added {
+                println!("Restored {}", name);
+            }
+            for name in &restored.skipped {
+                println!("Skipped {}, it's already in the db", name);
+            }
+            for conflict in &restored.conflicts {
+                println!("Conflict {}", conflict);
+            }
+            if !restored.conflicts.is_empty() {
+                return Err(GimmewireError::Invalid(format!(
+                    "{} peers were not restored",
+                    restored.conflicts.len()
+                )));
+            }
+        }
+        Command::Server(ServerCommand::List) => {
+            let peers = store.get_peers().await?;
+            println!(
+                "{:<10} {:<10} {:<24} {:<18} PEERS",
+                "NAME", "DEVICE", "HOST", "POOL"
+            );
+            for interface in wireguard::interfaces(&*config.lock().await) {
+                println!(
+                    "{:<10} {:<10} {:<24} {:<18} {}",
+                    interface.name,
+                    interface.device,
+                    interface.host.as_deref().unwrap_or("local"),
+                    format!("{}/{}", interface.network, interface.prefix),
+                    peers
+                        .iter()
+                        .filter(|peer| peer.interface == interface.name)
+                        .count()
+                );
+            }
+        }
+        Command::Server(ServerCommand::RotateKey { interface }) => {
+            let interface = match interface {
+                Some(interface) => interface,
+                None => wireguard::main_interface(&*config.lock().await),
+            };
+            let rotated = server::rotate_key(&interface, store, config, config_path).await;
+            audit::record(store, "cli", "rotate server key", &interface, &rotated).await;
+            rotated?
+        }
+        Command::Server(ServerCommand::Export {
+            interface,
+            private_key,
+            output,
+        }) => {
+            let interface = match interface {
+                Some(interface) => interface,
+                None => wireguard::main_interface(&*config.lock().await),
+            };
+            server::export(&interface, private_key, output, store, config).await?
+        }
+        Command::Doctor { fix } => doctor::run(store, config, config_path, fix).await?,
+        #[cfg(feature = "mongo")]
+        Command::Migrate { dry_run } => {
+            let report = crate::migrations::run(&*config.lock().await, dry_run).await?;
+            if report.is_empty() {
+                println!("Peers are at schema {}", crate::wireguard::SCHEMA_VERSION);
+            }
+            for line in report {
+                println!("{}", line);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn find(store: &Store, name: &str) -> Result<Peer> {
+    match store.find_by_username(name).await? {
+        None => Err(GimmewireError::PeerNotFound(name.to_string())),
+        Some(peer) => Ok(peer),
+    }
+}
+
+/// Prints where the new client config is, or the config itself when private keys aren't kept.
+async fn print_conf(peer: &Peer, config: Arc<Mutex<Ini>>) -> Result<()> {
+    let path = wireguard::gen_conf(peer, config.clone()).await?;
+    let config = config.lock().await;
+    if keys::stored(&config) {
+        println!("{}", path);
+        return Ok(());
+    }
+    let content = std::fs::read_to_string(&path);
+    keys::forget(&path, &config);
+    print!("{}", content?);
+    Ok(())
+}
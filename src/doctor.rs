@@ -0,0 +1,371 @@
+use crate::error::Result;
+use crate::store::Store;
+use crate::wireguard::{self, Interface, Peer, PeerStats};
+use crate::{backup, reload};
+use configparser::ini::Ini;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Debug, PartialEq)]
+pub enum Finding {
+    DuplicateIp {
+        ip: Ipv4Addr,
+        peers: Vec<String>,
+    },
+    UnknownPeer {
+        interface: String,
+        public_key: String,
+    },
+    MissingPeer {
+        username: String,
+    },
+    AllowedIpsDrift {
+        username: String,
+        actual: String,
+    },
+    MissingServerKey {
+        interface: String,
+        section: String,
+        interface_key: Option<String>,
+    },
+    ReservedIp {
+        username: String,
+        ip: Ipv4Addr,
+    },
+}
+
+/// The `Key` an interface has in the config next to the one it reports.
+pub struct ServerKey {
+    pub interface: String,
+    pub section: String,
+    pub config: Option<String>,
+    pub actual: Option<String>,
+}
+
+pub async fn server_keys(interfaces: &[Interface], config: &Ini) -> Vec<ServerKey> {
+    let mut keys = Vec::new();
+    for interface in interfaces {
+        keys.push(ServerKey {
+            interface: interface.name.clone(),
+            section: interface.section.clone(),
+            config: interface.get(config, "Key"),
+            actual: wireguard::public_key(interface).await.ok(),
+        });
+    }
+    keys
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Finding::DuplicateIp { ip, peers } => write!(
+                f,
+                "{} is assigned to {}; only one of them can use it, the others will get new addresses",
+                ip,
+                peers.join(", ")
+            ),
+            Finding::UnknownPeer {
+                interface,
+                public_key,
+            } => write!(
+                f,
+                "peer {} is on {} but has no db record; it will be removed from {}",
+                public_key, interface, interface
+            ),
+            Finding::MissingPeer { username } => write!(
+                f,
+                "peer {} is in the db but not on its interface; it will be re-applied",
+                username
+            ),
+            Finding::AllowedIpsDrift { username, actual } => write!(
+                f,
+                "peer {} has allowed-ips {} on its interface which differ from the db; the db address will be re-applied",
+                username, actual
+            ),
+            Finding::MissingServerKey {
+                interface,
+                section,
+                interface_key: Some(key),
+            } => write!(
+                f,
+                "[{}] Key is missing or doesn't match the {} public key; it will be set to {}",
+                section, interface, key
+            ),
+            Finding::MissingServerKey {
+                interface,
+                section,
+                interface_key: None,
+            } => write!(
+                f,
+                "[{}] Key is missing and {} public key cannot be read; set it by hand to `wg show {} public-key`",
+                section, interface, interface
+            ),
+            Finding::ReservedIp { username, ip } => write!(
+                f,
+                "peer {} has {} which is reserved; give it another address with /ip or take it out of Reserved",
+                username, ip
+            ),
+        }
+    }
+}
+
+impl Finding {
+    fn fixable(&self) -> bool {
+        !matches!(
+            self,
+            Finding::MissingServerKey {
+                interface_key: None,
+                ..
+            } | Finding::ReservedIp { .. }
+        )
+    }
+}
+
+pub fn diagnose(
+    peers: &[Peer],
+    stats: &[PeerStats],
+    server_keys: &[ServerKey],
+    interfaces: &[Interface],
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut by_ip: HashMap<(&str, Ipv4Addr), Vec<String>> = HashMap::new();
+    for peer in peers {
+        if let Some(ip) = peer.ip {
+            by_ip
+                .entry((&peer.interface, ip))
+                .or_default()
+                .push(peer.username.clone());
+        }
+    }
+    let mut duplicates: Vec<((&str, Ipv4Addr), Vec<String>)> = by_ip
+        .into_iter()
+        .filter(|(_, peers)| peers.len() > 1)
+        .collect();
+    duplicates.sort();
+    for ((_, ip), peers) in duplicates {
+        findings.push(Finding::DuplicateIp { ip, peers });
+    }
+    let known: HashSet<(&str, &str)> = peers
+        .iter()
+        .flat_map(|peer| Some((peer.interface.as_str(), peer.public_key.as_deref()?)))
+        .collect();
+    for stat in stats {
+        if !known.contains(&(stat.interface.as_str(), stat.public_key.as_str())) {
+            findings.push(Finding::UnknownPeer {
+                interface: stat.interface.clone(),
+                public_key: stat.public_key.clone(),
+            });
+        }
+    }
+    let applied: HashMap<(&str, &str), &str> = stats
+        .iter()
+        .map(|stat| {
+            (
+                (stat.interface.as_str(), stat.public_key.as_str()),
+                stat.allowed_ips.as_str(),
+            )
+        })
+        .collect();
+    for peer in peers.iter().filter(|peer| peer.suspended.is_none()) {
+        let (key, ip) = match (&peer.public_key, peer.ip) {
+            (Some(key), Some(ip)) => (key, ip),
+            _ => continue,
+        };
+        match applied.get(&(peer.interface.as_str(), key.as_str())) {
+            None => findings.push(Finding::MissingPeer {
+                username: peer.username.clone(),
+            }),
+            Some(actual) if *actual != format!("{}/32", ip) => {
+                findings.push(Finding::AllowedIpsDrift {
+                    username: peer.username.clone(),
+                    actual: actual.to_string(),
+                })
+            }
+            Some(_) => (),
+        }
+    }
+    let valid = |key: &str| key.len() == 44 && key.ends_with('=');
+    for key in server_keys {
+        let finding = |interface_key: Option<&String>| Finding::MissingServerKey {
+            interface: key.interface.clone(),
+            section: key.section.clone(),
+            interface_key: interface_key.cloned(),
+        };
+        match (&key.config, &key.actual) {
+            (Some(config), Some(actual)) if config != actual => {
+                findings.push(finding(Some(actual)))
+            }
+            (Some(config), _) if valid(config) => (),
+            (_, actual) => findings.push(finding(actual.as_ref())),
+        }
+    }
+    // Addresses admins gave out themselves may be reserved for that
+    for peer in peers.iter().filter(|peer| !peer.fixed_ip) {
+        let reserved = match (peer.ip, wireguard::interface_of(interfaces, peer)) {
+            (Some(ip), Ok(interface)) if interface.reserves(ip) => ip,
+            _ => continue,
+        };
+        findings.push(Finding::ReservedIp {
+            username: peer.username.clone(),
+            ip: reserved,
+        });
+    }
+    findings
+}
+
+pub async fn run(
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    config_path: &str,
+    fix: bool,
+) -> Result<()> {
+    let mut peers = store.get_peers().await?;
+    let interfaces = wireguard::interfaces(&*config.lock().await);
+    let stats = wireguard::show_all(&interfaces).await?;
+    let server_keys = server_keys(&interfaces, &*config.lock().await).await;
+    let findings = diagnose(&peers, &stats, &server_keys, &interfaces);
+    if findings.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    let mut backed_up = false;
+    for finding in findings {
+        println!("- {}", finding);
+        if !finding.fixable() || !(fix || confirm()?) {
+            continue;
+        }
+        if !backed_up {
+            println!("Backup saved to {}", backup(store, config_path).await?);
+            backed_up = true;
+        }
+        if let Finding::MissingServerKey {
+            section,
+            interface_key: Some(key),
+            ..
+        } = &finding
+        {
+            let mut config = config.lock().await;
+            config.set(section, "Key", Some(key.clone()));
+            reload::persist(config_path, section, "Key", key)?;
+        } else {
+            repair(&finding, &mut peers, &interfaces, store).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Repairs a peer level finding, returns false for findings it doesn't handle.
+pub async fn repair(
+    finding: &Finding,
+    peers: &mut [Peer],
+    interfaces: &[Interface],
+    store: &Store,
+) -> Result<bool> {
+    match finding {
+        Finding::DuplicateIp { peers: names, .. } => {
+            for name in &names[1..] {
+                let interface = match peers.iter().find(|peer| &peer.username == name) {
+                    None => continue,
+                    Some(peer) => wireguard::interface_of(interfaces, peer)?,
+                };
+                let ip = wireguard::get_ip(peers, interface)?;
+                let peer = match peers.iter_mut().find(|peer| &peer.username == name) {
+                    None => continue,
+                    Some(peer) => peer,
+                };
+                peer.ip = Some(ip);
+                if peer.public_key.is_some() {
+                    wireguard::apply_peer(peer, interface).await?;
+                }
+                store.update(peer).await?;
+            }
+            // The address might have been taken away from the first owner on the interface
+            if let Some(peer) = peers.iter().find(|peer| peer.username == names[0]) {
+                if peer.public_key.is_some() {
+                    wireguard::apply_peer(peer, wireguard::interface_of(interfaces, peer)?).await?;
+                }
+            }
+        }
+        Finding::UnknownPeer {
+            interface,
+            public_key,
+        } => match interfaces.iter().find(|other| &other.name == interface) {
+            None => return Ok(false),
+            Some(interface) => wireguard::remove_key(interface, public_key).await?,
+        },
+        Finding::MissingPeer { username } | Finding::AllowedIpsDrift { username, .. } => {
+            if let Some(peer) = peers.iter().find(|peer| &peer.username == username) {
+                wireguard::apply_peer(peer, wireguard::interface_of(interfaces, peer)?).await?;
+            }
+        }
+        Finding::MissingServerKey { .. } | Finding::ReservedIp { .. } => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn confirm() -> Result<bool> {
+    print!("  Fix it? [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Saves peers and a copy of the config next to each other in $HOME.
+async fn backup(store: &Store, config_path: &str) -> Result<String> {
+    let path = backup::default_path()?;
+    backup::save(&backup::export(store).await?, &path)?;
+    std::fs::copy(config_path, format!("{}.conf", path))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+#[test]
+fn find_problems() {
+    let key = "kFpzem87OujfORpD9WkVD7vjjESONndZRcT32Dw0xWg=";
+    let mut alice = Peer::new(1, "alice".to_string());
+    alice.public_key = Some("A".to_string());
+    alice.ip = Some(Ipv4Addr::new(10, 0, 0, 2));
+    let mut bob = Peer::new(2, "bob".to_string());
+    bob.public_key = Some("B".to_string());
+    bob.ip = Some(Ipv4Addr::new(10, 0, 0, 2));
+    let stats = vec![PeerStats {
+        interface: "wg0".to_string(),
+        public_key: "C".to_string(),
+        endpoint: None,
+        allowed_ips: "10.0.0.3/32".to_string(),
+        latest_handshake: None,
+        rx: 0,
+        tx: 0,
+    }];
+    let server_keys = vec![ServerKey {
+        interface: "wg0".to_string(),
+        section: "Peer".to_string(),
+        config: Some(key.to_string()),
+        actual: Some(key.to_string()),
+    }];
+    let findings = diagnose(&[alice, bob], &stats, &server_keys, &[]);
+    assert!(
+        findings
+            == vec![
+                Finding::DuplicateIp {
+                    ip: Ipv4Addr::new(10, 0, 0, 2),
+                    peers: vec!["alice".to_string(), "bob".to_string()]
+                },
+                Finding::UnknownPeer {
+                    interface: "wg0".to_string(),
+                    public_key: "C".to_string()
+                },
+                Finding::MissingPeer {
+                    username: "alice".to_string()
+                },
+                Finding::MissingPeer {
+                    username: "bob".to_string()
+                },
+            ]
+    );
+}
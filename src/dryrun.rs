@@ -0,0 +1,48 @@
+//! Dry runs, with `--dry-run` or `[DryRun] Enabled`: changes to interfaces, firewall and shaping
+//! are logged instead of made, and peers are kept in a staging copy of the store, so bot flows
+//! and reconciliation can be tried on a production host.
+use configparser::ini::Ini;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ON: AtomicBool = AtomicBool::new(false);
+
+/// Suffix of the staging copy, of the Mongo collection or the JSON file.
+pub const STAGING: &str = "_staging";
+
+pub fn enable() {
+    ON.store(true, Ordering::SeqCst);
+    tracing::warn!("Dry run: interfaces are not changed and the store is a staging copy");
+}
+
+pub fn on() -> bool {
+    ON.load(Ordering::SeqCst)
+}
+
+pub fn configured(config: &Ini) -> bool {
+    config
+        .getbool("DryRun", "Enabled")
+        .unwrap_or(None)
+        .unwrap_or(false)
+}
+
+/// Logs the command instead of it being run when dry-running, true when it is to be skipped.
+/// `input` is only shown when it holds no secrets, like firewall scripts.
+pub fn skip(command: &str, input: Option<&str>) -> bool {
+    if !on() {
+        return false;
+    }
+    match input {
+        None => tracing::info!("Dry run, not running {}", command),
+        Some(input) => tracing::info!("Dry run, not running {} with:\n{}", command, input),
+    }
+    true
+}
+
+#[cfg(test)]
+#[test]
+fn dry_run() {
+    let mut config = Ini::new();
+    assert!(!configured(&config) && !skip("wg set wg0", None));
+    config.read("[DryRun]\nEnabled = true".to_string()).unwrap();
+    assert!(configured(&config));
+}
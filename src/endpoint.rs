@@ -0,0 +1,225 @@
+//! Server endpoints which move: the `endpoint` job re-resolves hostname endpoints and tells users
+//! to reconnect when the address changes, and with `[Endpoint] Detect` replaces an address
+//! endpoint by the public address of this host. /endpoint changes one by hand, either way users
+//! are sent configs with the new one.
+use crate::error::{GimmewireError, Result};
+use crate::scheduler::Context;
+#[cfg(feature = "telegram")]
+use crate::store::{Filter, Status};
+use crate::wireguard::{self, Interface};
+use crate::{audit, notify, reload};
+#[cfg(feature = "telegram")]
+use crate::{i18n, keys};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+#[cfg(feature = "telegram")]
+use teloxide::{prelude::*, types::InputFile};
+
+/// Addresses hostname endpoints resolved to last time, by interface.
+static RESOLVED: LazyLock<Mutex<HashMap<String, Vec<IpAddr>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Host and port of an endpoint like `vpn.example.com:51820`.
+pub fn split(endpoint: &str) -> Option<(&str, u16)> {
+    let (host, port) = endpoint.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Some((host, port.parse().ok()?)).filter(|(host, _)| !host.is_empty())
+}
+
+/// Checks the endpoints, the `endpoint` job.
+pub async fn run(ctx: &Context) -> Result<()> {
+    let (interfaces, detect) = {
+        let config = ctx.config.lock().await;
+        let detect = config
+            .getbool("Endpoint", "Detect")
+            .unwrap_or(None)
+            .unwrap_or(false);
+        (wireguard::interfaces(&config), detect)
+    };
+    for interface in interfaces {
+        let endpoint = match interface.get(&*ctx.config.lock().await, "Endpoint") {
+            None => continue,
+            Some(endpoint) => endpoint,
+        };
+        let (host, port) = match split(&endpoint) {
+            None => continue,
+            Some(split) => split,
+        };
+        match host.parse::<IpAddr>() {
+            Ok(current) if detect && interface.host.is_none() => {
+                let detected = match public_ip() {
+                    Some(detected) if detected != current => detected,
+                    _ => continue,
+                };
+                let moved = format!("{}:{}", detected, port);
+                tracing::warn!("Public address of {} is now {}", interface.name, detected);
+                change(ctx, &interface.name, &moved, "endpoint").await?;
+            }
+            Ok(_) => (),
+            Err(_) => resolved(ctx, &interface, &endpoint).await,
+        }
+    }
+    Ok(())
+}
+
+/// Re-resolves a hostname endpoint, users of the interface are told to reconnect when it
+/// points elsewhere, their clients only resolve it when connecting.
+async fn resolved(ctx: &Context, interface: &Interface, endpoint: &str) {
+    let mut addresses: Vec<IpAddr> = match tokio::net::lookup_host(endpoint).await {
+        Err(why) => {
+            tracing::warn!("Cannot resolve {}: {}", endpoint, why);
+            return;
+        }
+        Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+    };
+    addresses.sort();
+    addresses.dedup();
+    let before = RESOLVED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(interface.name.clone(), addresses.clone());
+    match before {
+        Some(before) if before != addresses => (),
+        _ => return,
+    }
+    let shown: Vec<String> = addresses.iter().map(IpAddr::to_string).collect();
+    tracing::warn!("{} now resolves to {}", endpoint, shown.join(", "));
+    notify::send(format!(
+        "🌐 {} now resolves to {}, users of {} are told to reconnect",
+        endpoint,
+        shown.join(", "),
+        interface.name
+    ));
+    #[cfg(feature = "telegram")]
+    match peers_of(ctx, &interface.name).await {
+        Err(why) => tracing::error!(
+            "Cannot tell users of {} to reconnect: {}",
+            interface.name,
+            why
+        ),
+        Ok(peers) => {
+            for peer in peers {
+                let args = [("name", peer.username.as_str()), ("endpoint", endpoint)];
+                i18n::tell(&ctx.bot, &ctx.locales, &peer, "endpoint-moved", &args).await;
+            }
+        }
+    }
+    #[cfg(not(feature = "telegram"))]
+    let _ = ctx;
+}
+
+/// Sets the endpoint of the interface, in the config file as well, and sends every user of it
+/// a config with the new one. Returns how many were sent.
+pub async fn change(ctx: &Context, interface: &str, endpoint: &str, actor: &str) -> Result<usize> {
+    if split(endpoint).is_none() {
+        return Err(GimmewireError::Invalid(
+            "Endpoint must look like host:port".to_string(),
+        ));
+    }
+    let section = {
+        let mut config = ctx.config.lock().await;
+        let section = wireguard::find_interface(&config, interface)?.section;
+        config.set(&section, "Endpoint", Some(endpoint.to_string()));
+        section
+    };
+    let persisted = match reload::path() {
+        None => Ok(()),
+        Some(path) => reload::persist(path, &section, "Endpoint", endpoint),
+    };
+    audit::record(
+        &ctx.store,
+        actor,
+        &format!("endpoint {}", endpoint),
+        interface,
+        &persisted,
+    )
+    .await;
+    if let Err(why) = persisted {
+        tracing::error!("Cannot save the endpoint in the config file: {}", why);
+    }
+    notify::send(format!("🌐 Endpoint of {} is now {}", interface, endpoint));
+    reissue(ctx, interface, endpoint).await
+}
+
+#[cfg(feature = "telegram")]
+async fn reissue(ctx: &Context, interface: &str, endpoint: &str) -> Result<usize> {
+    let mut sent = 0;
+    for peer in peers_of(ctx, interface).await? {
+        // Without a stored key there is no config to send, users rotate for a new one
+        if peer.private_key.is_none() {
+            let args = [("name", peer.username.as_str()), ("endpoint", endpoint)];
+            i18n::tell(&ctx.bot, &ctx.locales, &peer, "endpoint-moved", &args).await;
+            continue;
+        }
+        let path = match wireguard::gen_conf(&peer, ctx.config.clone()).await {
+            Err(why) => {
+                tracing::error!("Cannot generate config for {}: {}", peer.username, why);
+                continue;
+            }
+            Ok(path) => path,
+        };
+        let caption = ctx.locales.tr(peer.language.as_deref(), None).format(
+            "endpoint-changed",
+            &[("name", &peer.username), ("endpoint", endpoint)],
+        );
+        let delivered = ctx
+            .bot
+            .send_document(ChatId(peer.user_id as i64), InputFile::file(&path))
+            .caption(caption)
+            .await;
+        keys::forget(&path, &*ctx.config.lock().await);
+        match delivered {
+            Err(why) => tracing::error!("Cannot send config to {}: {}", peer.username, why),
+            Ok(_) => sent += 1,
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(not(feature = "telegram"))]
+async fn reissue(_ctx: &Context, _interface: &str, _endpoint: &str) -> Result<usize> {
+    Ok(0)
+}
+
+/// Linked peers of the interface which are not suspended.
+#[cfg(feature = "telegram")]
+async fn peers_of(ctx: &Context, interface: &str) -> Result<Vec<wireguard::Peer>> {
+    let filter = Filter {
+        interface: Some(interface.to_string()),
+        status: Some(Status::Active),
+        ..Filter::default()
+    };
+    Ok(ctx
+        .store
+        .find_peers(&filter, 0, None)
+        .await?
+        .into_iter()
+        .filter(|peer| peer.user_id != 0)
+        .collect())
+}
+
+/// The source address of this host towards the internet, None behind NAT where it is private.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn public_ip() -> Option<IpAddr> {
+    let route = wireguard::run("/sbin/ip", &["-4", "route", "get", "1.1.1.1"], None).ok()?;
+    let mut words = route.split_whitespace();
+    words.find(|word| *word == "src")?;
+    match words.next()?.parse().ok()? {
+        IpAddr::V4(ip) if ip.is_private() || ip.is_loopback() => None,
+        ip => Some(ip),
+    }
+}
+
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+fn public_ip() -> Option<IpAddr> {
+    None
+}
+
+#[cfg(test)]
+#[test]
+fn endpoint_parts() {
+    assert!(split("vpn.example.com:51820") == Some(("vpn.example.com", 51820)));
+    assert!(split("[2001:db8::1]:51820") == Some(("2001:db8::1", 51820)));
+    assert!(split("vpn.example.com").is_none() && split(":51820").is_none());
+}
@@ -0,0 +1,93 @@
+use thiserror::Error;
+
+/// Errors of wg, storage and peer handling which callers tell apart.
+#[derive(Debug, Error)]
+pub enum GimmewireError {
+    #[error("{command} finished with {message}")]
+    WgCommandFailed { command: String, message: String },
+    #[error("Cannot generate keys: {0}")]
+    KeyGeneration(String),
+    #[error("No free addresses left on {0}")]
+    PoolExhausted(String),
+    #[error("Interface {0} is not configured")]
+    UnknownInterface(String),
+    #[error("Peer {0} already exists")]
+    PeerExists(String),
+    #[error("Cannot find peer {0}")]
+    PeerNotFound(String),
+    /// The user has as many active peers as they may.
+    #[error("Only {0} peers are allowed per user")]
+    PeerLimit(u64),
+    /// The request itself is wrong, e.g. a peer in the wrong state for it.
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("Config error: {0}")]
+    Config(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, GimmewireError>;
+
+impl GimmewireError {
+    /// What a user may be told, None when only the admin should see the details.
+    pub fn user_message(&self) -> Option<String> {
+        match self {
+            GimmewireError::PoolExhausted(_) => {
+                Some("There are no free addresses left, the admin has been told".to_string())
+            }
+            GimmewireError::Storage(_) => {
+                Some("Service is temporarily unavailable, please try again later".to_string())
+            }
+            GimmewireError::PeerExists(_)
+            | GimmewireError::PeerNotFound(_)
+            | GimmewireError::PeerLimit(_)
+            | GimmewireError::Invalid(_) => Some(self.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for GimmewireError {
+    fn from(why: serde_json::Error) -> Self {
+        GimmewireError::Storage(why.to_string())
+    }
+}
+
+#[cfg(feature = "mongo")]
+impl From<mongodb::error::Error> for GimmewireError {
+    fn from(why: mongodb::error::Error) -> Self {
+        GimmewireError::Storage(why.to_string())
+    }
+}
+
+/// Bot handlers stop where the store can't be read, instead of going on as if it had no peers.
+/// The dispatcher logs it and the next message gets the unavailable answer.
+#[cfg(feature = "telegram")]
+impl From<GimmewireError> for teloxide::RequestError {
+    fn from(why: GimmewireError) -> Self {
+        teloxide::RequestError::Io(std::io::Error::other(why.to_string()))
+    }
+}
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+impl From<sqlx::Error> for GimmewireError {
+    fn from(why: sqlx::Error) -> Self {
+        GimmewireError::Storage(why.to_string())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn user_messages() {
+    let full = GimmewireError::PoolExhausted("wg0".to_string());
+    assert!(full.to_string() == "No free addresses left on wg0" && full.user_message().is_some());
+    let wg = GimmewireError::WgCommandFailed {
+        command: "wg set".to_string(),
+        message: "exit status: 1".to_string(),
+    };
+    assert!(wg.user_message().is_none());
+    assert!(wg.to_string() == "wg set finished with exit status: 1");
+}
@@ -0,0 +1,366 @@
+//! Client configs in formats other than wg-quick, built from the same `ClientConf`.
+use crate::error::{GimmewireError, Result};
+use crate::wireguard::{self, ClientConf, Peer};
+use configparser::ini::Ini;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// wg-quick config for the WireGuard apps
+    Conf,
+    /// Apple configuration profile for iOS and macOS
+    Mobileconfig,
+    /// RouterOS 7 commands
+    Mikrotik,
+    /// UCI sections for /etc/config/network
+    Openwrt,
+    /// NetworkManager keyfile for Linux desktops
+    Networkmanager,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Conf => "conf",
+            Format::Mobileconfig => "mobileconfig",
+            Format::Mikrotik => "rsc",
+            Format::Openwrt => "uci",
+            Format::Networkmanager => "nmconnection",
+        }
+    }
+}
+
+/// Writes the peer's config in the format next to its .conf, returns the path.
+pub async fn export(peer: &Peer, config: Arc<Mutex<Ini>>, format: Format) -> Result<String> {
+    let client = wireguard::client_conf(peer, config.clone()).await?;
+    if !client.obfuscation.is_empty() && !matches!(format, Format::Conf) {
+        return Err(GimmewireError::Invalid(
+            "AmneziaWG configs are only imported by the AmneziaWG apps, as conf".to_string(),
+        ));
+    }
+    let content = match format {
+        Format::Conf => client.render().into_bytes(),
+        Format::Mobileconfig => {
+            let config = config.lock().await;
+            // The Apple apps refuse configs with wg-quick hooks or a Table
+            let client = ClientConf {
+                table: None,
+                scripts: vec![],
+                ..client
+            };
+            let profile = mobileconfig(&client, &peer.username, &config);
+            sign(profile.into_bytes(), &config)?
+        }
+        Format::Mikrotik => mikrotik(&client).into_bytes(),
+        Format::Openwrt => openwrt(&client).into_bytes(),
+        Format::Networkmanager => networkmanager(&client, &peer.username).into_bytes(),
+    };
+    let path = format!(
+        "{}/{}.{}",
+        wireguard::home()?,
+        peer.username,
+        format.extension()
+    );
+    std::fs::write(&path, content)?;
+    Ok(path)
+}
+
+/// A profile with one WireGuard VPN payload, `[Export] ApplePlatform` picks the ios or macos app.
+/// Its ids only depend on the peer, so installing a new profile replaces the old one.
+pub fn mobileconfig(client: &ClientConf, name: &str, config: &Ini) -> String {
+    let identifier = config
+        .get("Export", "AppleIdentifier")
+        .unwrap_or_else(|| "com.gimmewire".to_string());
+    let platform = config
+        .get("Export", "ApplePlatform")
+        .unwrap_or_else(|| "ios".to_string());
+    let identifier = format!("{}.{}", identifier, name);
+    let endpoint = client.endpoint.clone().unwrap_or_default();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>PayloadDisplayName</key>
+    <string>{name}</string>
+    <key>PayloadType</key>
+    <string>Configuration</string>
+    <key>PayloadVersion</key>
+    <integer>1</integer>
+    <key>PayloadIdentifier</key>
+    <string>{identifier}</string>
+    <key>PayloadUUID</key>
+    <string>{profile}</string>
+    <key>PayloadContent</key>
+    <array>
+        <dict>
+            <key>PayloadDisplayName</key>
+            <string>VPN</string>
+            <key>PayloadType</key>
+            <string>com.apple.vpn.managed</string>
+            <key>PayloadVersion</key>
+            <integer>1</integer>
+            <key>PayloadIdentifier</key>
+            <string>{identifier}.vpn</string>
+            <key>PayloadUUID</key>
+            <string>{vpn}</string>
+            <key>UserDefinedName</key>
+            <string>{name}</string>
+            <key>VPNType</key>
+            <string>VPN</string>
+            <key>VPNSubType</key>
+            <string>com.wireguard.{platform}</string>
+            <key>VendorConfig</key>
+            <dict>
+                <key>WgQuickConfig</key>
+                <string>{conf}</string>
+            </dict>
+            <key>VPN</key>
+            <dict>
+                <key>RemoteAddress</key>
+                <string>{endpoint}</string>
+                <key>AuthenticationMethod</key>
+                <string>Password</string>
+            </dict>
+        </dict>
+    </array>
+</dict>
+</plist>
+"#,
+        name = escape(name),
+        identifier = escape(&identifier),
+        profile = uuid(&identifier),
+        vpn = uuid(&format!("{}.vpn", identifier)),
+        platform = escape(&platform),
+        conf = escape(client.render().trim_end()),
+        endpoint = escape(&endpoint),
+    )
+}
+
+/// Name of the tunnel interface on routers and desktops.
+const ROUTER_INTERFACE: &str = "gimmewire";
+
+/// Host and port of the endpoint, routers take them separately.
+fn endpoint(client: &ClientConf) -> (String, String) {
+    let endpoint = client.endpoint.clone().unwrap_or_default();
+    match endpoint.rsplit_once(':') {
+        Some((host, port)) => (host.to_string(), port.to_string()),
+        None => (endpoint, "51820".to_string()),
+    }
+}
+
+/// The option with its value, nothing when it is unset.
+fn option(name: &str, value: &Option<String>) -> String {
+    value
+        .as_ref()
+        .map(|value| format!("{}{}", name, value))
+        .unwrap_or_default()
+}
+
+fn list(values: &str) -> impl Iterator<Item = &str> {
+    values
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// RouterOS commands to paste into the terminal. Traffic isn't routed into the tunnel, a default
+/// route through it would cut the router off from the endpoint.
+pub fn mikrotik(client: &ClientConf) -> String {
+    let (host, port) = endpoint(client);
+    let allowed: Vec<&str> = list(&client.allowed_ips).collect();
+    let dns: Vec<&str> = list(&client.dns).collect();
+    format!(
+        "/interface wireguard add name={iface} private-key=\"{private_key}\"{mtu}
+/interface wireguard peers add interface={iface} public-key=\"{public_key}\" endpoint-address={host} endpoint-port={port} allowed-address={allowed} persistent-keepalive={keepalive}s
+/ip address add address={address} interface={iface}
+/ip dns set servers={dns}
+# Route traffic into the tunnel with /ip route, keeping a route to {host} outside of it
+",
+        iface = ROUTER_INTERFACE,
+        private_key = client.private_key,
+        mtu = option(" mtu=", &client.mtu),
+        public_key = client.public_key.clone().unwrap_or_default(),
+        allowed = allowed.join(","),
+        keepalive = client.keepalive,
+        address = client.address,
+        dns = dns.join(","),
+    )
+}
+
+/// Sections for /etc/config/network, applied with `/etc/init.d/network reload`.
+pub fn openwrt(client: &ClientConf) -> String {
+    let (host, port) = endpoint(client);
+    let mut uci = format!(
+        "config interface '{}'\n\toption proto 'wireguard'\n\toption private_key '{}'\n\tlist addresses '{}'\n",
+        ROUTER_INTERFACE, client.private_key, client.address
+    );
+    for dns in list(&client.dns) {
+        uci.push_str(&format!("\tlist dns '{}'\n", dns));
+    }
+    if let Some(mtu) = &client.mtu {
+        uci.push_str(&format!("\toption mtu '{}'\n", mtu));
+    }
+    uci.push_str(&format!(
+        "\nconfig wireguard_{}\n\toption public_key '{}'\n\toption endpoint_host '{}'\n\toption endpoint_port '{}'\n\toption persistent_keepalive '{}'\n\toption route_allowed_ips '1'\n",
+        ROUTER_INTERFACE,
+        client.public_key.clone().unwrap_or_default(),
+        host,
+        port,
+        client.keepalive
+    ));
+    for allowed in list(&client.allowed_ips) {
+        uci.push_str(&format!("\tlist allowed_ips '{}'\n", allowed));
+    }
+    uci
+}
+
+/// A keyfile for /etc/NetworkManager/system-connections, NetworkManager wants it owned by root
+/// with mode 600. The connection is named after the peer.
+pub fn networkmanager(client: &ClientConf, name: &str) -> String {
+    let (host, port) = endpoint(client);
+    let semicolons = |values: &str| {
+        list(values)
+            .map(|value| format!("{};", value))
+            .collect::<String>()
+    };
+    format!(
+        "[connection]
+id={name}
+uuid={uuid}
+type=wireguard
+interface-name={iface}
+
+[wireguard]
+private-key={private_key}
+{mtu}
+[wireguard-peer.{public_key}]
+endpoint={host}:{port}
+allowed-ips={allowed}
+persistent-keepalive={keepalive}
+
+[ipv4]
+method=manual
+address1={address}
+dns={dns}
+ignore-auto-dns=true
+
+[ipv6]
+method=disabled
+",
+        uuid = uuid(&format!("networkmanager.{}", name)),
+        iface = ROUTER_INTERFACE,
+        private_key = client.private_key,
+        mtu = option("mtu=", &client.mtu.as_ref().map(|mtu| format!("{}\n", mtu))),
+        public_key = client.public_key.clone().unwrap_or_default(),
+        allowed = semicolons(&client.allowed_ips),
+        keepalive = client.keepalive,
+        address = client.address,
+        dns = semicolons(&client.dns),
+    )
+}
+
+/// Signs the profile with `[Export] SigningCert` and `SigningKey` when both are set, so the
+/// devices show it as verified. Unsigned profiles install too.
+#[cfg(feature = "signing")]
+fn sign(profile: Vec<u8>, config: &Ini) -> Result<Vec<u8>> {
+    use crate::error::GimmewireError;
+    use openssl::{pkcs7, pkey::PKey, stack::Stack, x509::X509};
+    let (cert, key) = match (
+        config.get("Export", "SigningCert"),
+        config.get("Export", "SigningKey"),
+    ) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(profile),
+    };
+    let invalid = |why: openssl::error::ErrorStack| {
+        GimmewireError::Config(format!("Cannot sign the profile: {}", why))
+    };
+    let cert = X509::from_pem(&std::fs::read(cert)?).map_err(invalid)?;
+    let key = PKey::private_key_from_pem(&std::fs::read(key)?).map_err(invalid)?;
+    let chain = Stack::new().map_err(invalid)?;
+    pkcs7::Pkcs7::sign(&cert, &key, &chain, &profile, pkcs7::Pkcs7Flags::BINARY)
+        .and_then(|signed| signed.to_der())
+        .map_err(invalid)
+}
+
+#[cfg(not(feature = "signing"))]
+fn sign(profile: Vec<u8>, config: &Ini) -> Result<Vec<u8>> {
+    if config.get("Export", "SigningCert").is_some() {
+        tracing::warn!(
+            "Profiles are not signed, gimmewire was built without the `signing` feature"
+        );
+    }
+    Ok(profile)
+}
+
+/// The URL namespace of RFC 4122, under which profile UUIDs are made.
+const NAMESPACE: [u8; 16] = [
+    0x6b, 0xa7, 0xb8, 0x11, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+];
+
+/// A stable UUID for the name, version 5 so it is the same for every build and platform.
+fn uuid(name: &str) -> String {
+    use sha1::{Digest, Sha1};
+    let digest = Sha1::new()
+        .chain_update(NAMESPACE)
+        .chain_update(name)
+        .finalize();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+#[test]
+fn formats() {
+    let client = ClientConf {
+        private_key: "private".to_string(),
+        address: "10.0.0.2/16".to_string(),
+        dns: "8.8.8.8".to_string(),
+        mtu: Some("1280".to_string()),
+        table: None,
+        public_key: Some("server".to_string()),
+        endpoint: Some("vpn.example.com:51820".to_string()),
+        allowed_ips: "0.0.0.0/0".to_string(),
+        keepalive: "25".to_string(),
+        scripts: vec![],
+        obfuscation: vec![],
+    };
+    let profile = mobileconfig(&client, "alice", &Ini::new());
+    assert!(profile.contains("<string>com.wireguard.ios</string>"));
+    assert!(profile.contains("PrivateKey=private") && profile.contains("vpn.example.com:51820"));
+    let commands = mikrotik(&client);
+    assert!(commands.contains("endpoint-address=vpn.example.com endpoint-port=51820"));
+    assert!(commands.contains("persistent-keepalive=25s"));
+    assert!(commands
+        .starts_with("/interface wireguard add name=gimmewire private-key=\"private\" mtu=1280\n"));
+    let uci = openwrt(&client);
+    assert!(uci.contains("\tlist addresses '10.0.0.2/16'\n\tlist dns '8.8.8.8'\n"));
+    assert!(uci.contains("option endpoint_port '51820'") && uci.ends_with("'0.0.0.0/0'\n"));
+    let keyfile = networkmanager(&client, "alice");
+    assert!(keyfile.contains("private-key=private\nmtu=1280\n\n"));
+    assert!(keyfile.contains("[wireguard-peer.server]\nendpoint=vpn.example.com:51820\n"));
+    assert!(keyfile.contains("allowed-ips=0.0.0.0/0;\n") && keyfile.contains("dns=8.8.8.8;\n"));
+    assert!(uuid("a") != uuid("b"));
+    // uuid.uuid5(uuid.NAMESPACE_URL, "networkmanager.alice") of Python
+    assert!(uuid("networkmanager.alice") == "0F450C8A-9467-5EC5-99E5-A0209445774C");
+}
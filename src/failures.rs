@@ -0,0 +1,98 @@
+//! Failures which keep happening, like a wg binary gone missing or a db refusing writes: once
+//! `[Failures] Threshold` operations of a kind fail in a row the admin is told the last command
+//! and its error, again at twice as many and so on, and once the kind works again.
+use crate::notify;
+use configparser::ini::Ini;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+static THRESHOLD: AtomicU64 = AtomicU64::new(3);
+/// Failures in a row by kind, like `wg` or `mongo`.
+static STREAKS: LazyLock<Mutex<HashMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Reads `[Failures] Threshold`, 0 turns the alerts off.
+pub fn configure(config: &Ini) {
+    let threshold = config
+        .getuint("Failures", "Threshold")
+        .unwrap_or(None)
+        .unwrap_or(3);
+    THRESHOLD.store(threshold, Ordering::SeqCst);
+}
+
+/// Counts a failed operation, `what` is the exact command and `why` what it printed.
+pub fn failed(kind: &str, what: &str, why: &str) {
+    let failures = {
+        let mut streaks = STREAKS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let failures = streaks.entry(kind.to_string()).or_insert(0);
+        *failures += 1;
+        *failures
+    };
+    if escalates(failures, THRESHOLD.load(Ordering::SeqCst)) {
+        tracing::error!(
+            "{} {} operations failed in a row: {}: {}",
+            failures,
+            kind,
+            what,
+            why
+        );
+        notify::send(format!(
+            "🚨 {} {} operations failed in a row, the last one:\n{}\n{}",
+            failures, kind, what, why
+        ));
+    }
+}
+
+/// Ends the streak of failures of the kind.
+pub fn succeeded(kind: &str) {
+    let failures = STREAKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .remove(kind)
+        .unwrap_or(0);
+    let threshold = THRESHOLD.load(Ordering::SeqCst);
+    if threshold > 0 && failures >= threshold {
+        notify::send(format!(
+            "✅ {} operations work again after {} failures",
+            kind, failures
+        ));
+    }
+}
+
+/// Whether the admin is told at this many failures: at the threshold, twice it, four times...
+fn escalates(failures: u64, threshold: u64) -> bool {
+    threshold > 0
+        && failures >= threshold
+        && failures.is_multiple_of(threshold)
+        && (failures / threshold).is_power_of_two()
+}
+
+/// Runs the result through the tracker under `kind`.
+pub fn track<T, E: std::fmt::Display>(
+    kind: &str,
+    what: impl FnOnce() -> String,
+    result: Result<T, E>,
+) -> Result<T, E> {
+    match &result {
+        Ok(_) => succeeded(kind),
+        Err(why) => failed(kind, &what(), &why.to_string()),
+    }
+    result
+}
+
+#[cfg(test)]
+#[test]
+fn escalation() {
+    let alerted: Vec<u64> = (1..=25)
+        .filter(|failures| escalates(*failures, 3))
+        .collect();
+    assert!(alerted == vec![3, 6, 12, 24]);
+    assert!(!(1..=25).any(|failures| escalates(failures, 0)));
+    failed("test", "wg show", "Unable to access interface");
+    assert!(STREAKS.lock().unwrap()["test"] == 1);
+    succeeded("test");
+    assert!(!STREAKS.lock().unwrap().contains_key("test"));
+}
@@ -0,0 +1,44 @@
+use configparser::ini::Ini;
+
+/// Config section, cargo feature that handles it, and whether it is compiled in.
+const SUBSYSTEMS: &[(&str, &str, bool)] = &[
+    ("Storage", "store", cfg!(feature = "store")),
+    ("Mongo", "mongo", cfg!(feature = "mongo")),
+    ("Bot", "telegram", cfg!(feature = "telegram")),
+    ("Http", "http", cfg!(feature = "http")),
+    ("Webhook", "webhook", cfg!(feature = "webhook")),
+    ("Hooks", "hooks", cfg!(feature = "hooks")),
+    ("Firewall", "nftables", cfg!(feature = "nftables")),
+];
+
+/// Returns config sections which reference subsystems missing from this build.
+pub fn disabled(config: &Ini) -> Vec<(&'static str, &'static str)> {
+    let sections = config.sections();
+    SUBSYSTEMS
+        .iter()
+        .filter(|(section, _, enabled)| !enabled && sections.contains(&section.to_lowercase()))
+        .map(|(section, feature, _)| (*section, *feature))
+        .collect()
+}
+
+pub fn check(config: &Ini) {
+    for (section, feature) in disabled(config) {
+        tracing::warn!(
+            "Config section [{}] is ignored: gimmewire was built without the `{}` feature",
+            section,
+            feature
+        );
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn detect_disabled() {
+    let mut config = Ini::new();
+    config
+        .read("[Mongo]\nURL = x\n[Bot]\nAdminId = 1".to_string())
+        .unwrap();
+    let disabled = disabled(&config);
+    assert!(disabled.iter().any(|(s, _)| *s == "Mongo") != cfg!(feature = "mongo"));
+    assert!(disabled.iter().any(|(s, _)| *s == "Bot") != cfg!(feature = "telegram"));
+}
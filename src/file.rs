@@ -0,0 +1,300 @@
+use crate::audit::Event;
+use crate::error::{GimmewireError, Result};
+use crate::referral::Referral;
+use crate::repair::Repair;
+use crate::rotation::Rotation;
+use crate::store::PeerStore;
+use crate::wireguard::Peer;
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Default)]
+struct Data {
+    peers: Vec<Peer>,
+    rotations: Vec<Rotation>,
+    #[serde(default)]
+    events: Vec<Event>,
+    #[serde(default)]
+    referrals: Vec<Referral>,
+    #[serde(default)]
+    repairs: Vec<Repair>,
+}
+
+/// The data as last read, with the modification time and size of the file it was read at.
+#[derive(Default)]
+struct Cache {
+    stamp: Option<(SystemTime, u64)>,
+    data: Data,
+}
+
+/// Keeps everything in one JSON file for installs without a database. The file is rewritten
+/// through a temporary file and a rename, so a crash never leaves it half written. The bot and
+/// the cli are separate processes, so every change takes a lock on `<path>.lock` and starts
+/// from the file as it is on disk, and reads pick up changes the other one made.
+pub struct File {
+    path: PathBuf,
+    cache: Mutex<Cache>,
+}
+
+impl File {
+    pub fn open(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let mut cache = Cache::default();
+        load(&path, &mut cache, false)?;
+        Ok(File {
+            path,
+            cache: Mutex::new(cache),
+        })
+    }
+
+    async fn read<T>(&self, read: impl FnOnce(&Data) -> T) -> Result<T> {
+        let mut cache = self.cache.lock().await;
+        let _lock = load(&self.path, &mut cache, false)?;
+        Ok(read(&cache.data))
+    }
+
+    /// Applies the change to the data on disk and saves it, all under the lock so no other
+    /// process writes in between. A failed save leaves the cache to be read again.
+    async fn write<T>(&self, change: impl FnOnce(&mut Data) -> T) -> Result<T> {
+        let mut cache = self.cache.lock().await;
+        let _lock = load(&self.path, &mut cache, true)?;
+        let changed = change(&mut cache.data);
+        cache.stamp = None;
+        self.save(&cache.data)?;
+        cache.stamp = stamp(&self.path)?;
+        Ok(changed)
+    }
+
+    /// The file holds private keys, so it is written like a saved interface config.
+    fn save(&self, data: &Data) -> Result<()> {
+        let json = serde_json::to_string_pretty(data).map_err(GimmewireError::from)?;
+        crate::wireguard::write_private(&self.path.to_string_lossy(), &json).map_err(|why| {
+            tracing::error!("Cannot write {}: {}", self.path.display(), why);
+            GimmewireError::from(why)
+        })
+    }
+}
+
+fn stamp(path: &Path) -> Result<Option<(SystemTime, u64)>> {
+    match std::fs::metadata(path) {
+        Err(why) if why.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(why) => Err(GimmewireError::from(why)),
+        Ok(metadata) => Ok(Some((metadata.modified()?, metadata.len()))),
+    }
+}
+
+/// Locks `<path>.lock`, exclusively to write, and reads the file again if it changed since the
+/// cache was filled. The lock is held until the returned file is dropped.
+fn load(path: &Path, cache: &mut Cache, exclusive: bool) -> Result<std::fs::File> {
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(format!("{}.lock", path.display()))?;
+    match exclusive {
+        true => lock.lock()?,
+        false => lock.lock_shared()?,
+    }
+    let stamp = stamp(path)?;
+    if stamp != cache.stamp || stamp.is_none() {
+        cache.data = match std::fs::read_to_string(path) {
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => Data::default(),
+            Err(why) => return Err(GimmewireError::from(why)),
+            Ok(json) => serde_json::from_str(&json).map_err(GimmewireError::from)?,
+        };
+        cache.stamp = stamp;
+    }
+    Ok(lock)
+}
+
+/// Whether `stored` is the peer, by id or for peers without one by the user's active peer.
+fn same(stored: &Peer, peer: &Peer) -> bool {
+    match peer.id {
+        Some(id) => stored.id == Some(id),
+        None => stored.user_id == peer.user_id && stored.archived.is_none(),
+    }
+}
+
+#[async_trait]
+impl PeerStore for File {
+    async fn add(&self, peer: &Peer) -> Result<()> {
+        let mut peer = peer.clone();
+        peer.id.get_or_insert_with(ObjectId::new);
+        self.write(|data| data.peers.push(peer)).await
+    }
+
+    async fn update(&self, peer: &Peer) -> Result<()> {
+        self.write(|data| {
+            let index = data.peers.iter().position(|other| same(other, peer));
+            let mut peer = peer.clone();
+            peer.id.get_or_insert_with(ObjectId::new);
+            match index {
+                None => data.peers.push(peer),
+                Some(index) => data.peers[index] = peer,
+            }
+        })
+        .await
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<Option<Peer>> {
+        self.read(|data| {
+            data.peers
+                .iter()
+                .find(|peer| peer.user_id == id && peer.archived.is_none())
+                .cloned()
+        })
+        .await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<Peer>> {
+        self.read(|data| {
+            data.peers
+                .iter()
+                .find(|peer| peer.username == username && peer.archived.is_none())
+                .cloned()
+        })
+        .await
+    }
+
+    async fn delete(&self, peer: &Peer) -> Result<()> {
+        self.write(|data| data.peers.retain(|other| !same(other, peer)))
+            .await
+    }
+
+    async fn get_peers(&self) -> Result<Vec<Peer>> {
+        self.read(|data| {
+            data.peers
+                .iter()
+                .filter(|peer| peer.archived.is_none())
+                .cloned()
+                .collect()
+        })
+        .await
+    }
+
+    async fn get_archived(&self) -> Result<Vec<Peer>> {
+        self.read(|data| {
+            data.peers
+                .iter()
+                .filter(|peer| peer.archived.is_some())
+                .cloned()
+                .collect()
+        })
+        .await
+    }
+
+    async fn log_rotation(&self, rotation: &Rotation) -> Result<()> {
+        self.write(|data| data.rotations.push(rotation.clone()))
+            .await
+    }
+
+    async fn log_event(&self, event: &Event) -> Result<()> {
+        self.write(|data| data.events.push(event.clone())).await
+    }
+
+    async fn get_events(&self, skip: u64, limit: u64) -> Result<Vec<Event>> {
+        self.read(|data| {
+            data.events
+                .iter()
+                .rev()
+                .skip(skip as usize)
+                .take(limit as usize)
+                .cloned()
+                .collect()
+        })
+        .await
+    }
+
+    async fn save_referral(&self, referral: &Referral) -> Result<()> {
+        self.write(|data| {
+            data.referrals
+                .retain(|other| other.referred != referral.referred);
+            data.referrals.push(referral.clone());
+        })
+        .await
+    }
+
+    async fn get_referrals(&self) -> Result<Vec<Referral>> {
+        self.read(|data| data.referrals.clone()).await
+    }
+
+    async fn save_repair(&self, repair: &Repair) -> Result<()> {
+        self.write(|data| {
+            data.repairs.retain(|other| {
+                other.interface != repair.interface || other.public_key != repair.public_key
+            });
+            data.repairs.push(repair.clone());
+        })
+        .await
+    }
+
+    async fn get_repairs(&self) -> Result<Vec<Repair>> {
+        self.read(|data| data.repairs.clone()).await
+    }
+
+    async fn delete_repair(&self, repair: &Repair) -> Result<()> {
+        self.write(|data| {
+            data.repairs.retain(|other| {
+                other.interface != repair.interface || other.public_key != repair.public_key
+            });
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn file_store() {
+    let scratch = crate::store::Scratch::new("file.json");
+    let store = File::open(scratch.path()).unwrap();
+    let mut peer = Peer::new(7, "alice".to_string());
+    store.add(&peer).await.unwrap();
+    peer = store.find_by_username("alice").await.unwrap().unwrap();
+    peer.archived = Some(bson::DateTime::now());
+    store.update(&peer).await.unwrap();
+    // Everything survives a restart
+    let store = File::open(scratch.path()).unwrap();
+    assert!(
+        store.find_by_id(7).await.unwrap().is_none()
+            && store.get_archived().await.unwrap().len() == 1
+    );
+    store
+        .log_event(&crate::audit::Event {
+            date: bson::DateTime::now(),
+            actor: "admin".to_string(),
+            action: "approve".to_string(),
+            target: "alice".to_string(),
+            error: None,
+        })
+        .await
+        .unwrap();
+    assert!(
+        File::open(scratch.path())
+            .unwrap()
+            .get_events(0, 10)
+            .await
+            .unwrap()
+            .len()
+            == 1
+    );
+    let mut referral = Referral {
+        referrer: 7,
+        referred: 8,
+        date: bson::DateTime::now(),
+        credited: None,
+    };
+    store.save_referral(&referral).await.unwrap();
+    referral.credited = Some(bson::DateTime::now());
+    store.save_referral(&referral).await.unwrap();
+    assert!(store.get_referrals().await.unwrap() == vec![referral]);
+    // Another process, like the cli, writing in between is neither missed nor overwritten
+    let other = File::open(scratch.path()).unwrap();
+    other.add(&Peer::new(8, "bob".to_string())).await.unwrap();
+    store.add(&Peer::new(9, "carol".to_string())).await.unwrap();
+    assert!(other.get_peers().await.unwrap().len() == 2);
+    assert!(store.find_by_username("bob").await.unwrap().is_some());
+}
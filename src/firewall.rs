@@ -0,0 +1,320 @@
+//! nftables rules of the interfaces, kept in one `inet gimmewire` table per node which is
+//! rebuilt from the db whenever peers come and go: client isolation and port forwards.
+use crate::error::{GimmewireError, Result};
+use crate::store::Store;
+use crate::wireguard::{self, Forward, Interface, Peer};
+use configparser::ini::Ini;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const TABLE: &str = "inet gimmewire";
+
+/// Whether the table may be on the nodes, a previous run could have left it.
+static INSTALLED: AtomicBool = AtomicBool::new(true);
+
+/// `[Firewall] Isolation`, whether peers of an interface cannot reach each other.
+pub fn isolated(config: &Ini) -> bool {
+    config
+        .getbool("Firewall", "Isolation")
+        .unwrap_or(None)
+        .unwrap_or(false)
+}
+
+/// Peers which are on their interface right now.
+fn applied(peer: &Peer) -> bool {
+    peer.public_key.is_some() && peer.ip.is_some() && peer.suspended.is_none()
+}
+
+/// Set names only take letters, digits and `_`.
+fn set_name(interface: &Interface) -> String {
+    let device: String = interface
+        .device
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("peers_{}", device)
+}
+
+/// An nft script replacing the table with the rules of the node's interfaces. With isolation
+/// traffic between peers of the same interface is dropped, traffic to the internet and the node
+/// passes. Forwarded ports of the node are sent on to their peers.
+pub fn ruleset(interfaces: &[(&Interface, Vec<&Peer>)], isolation: bool) -> String {
+    let mut script = format!(
+        "table {table}\ndelete table {table}\ntable {table} {{\n",
+        table = TABLE
+    );
+    for (interface, peers) in interfaces {
+        script.push_str(&format!(
+            "\tset {} {{\n\t\ttype ipv4_addr\n",
+            set_name(interface)
+        ));
+        let ips: Vec<String> = peers
+            .iter()
+            .filter_map(|peer| peer.ip)
+            .map(|ip| ip.to_string())
+            .collect();
+        if !ips.is_empty() {
+            script.push_str(&format!("\t\telements = {{ {} }}\n", ips.join(", ")));
+        }
+        script.push_str("\t}\n");
+    }
+    script
+        .push_str("\tchain forward {\n\t\ttype filter hook forward priority -10; policy accept;\n");
+    for (interface, _) in interfaces.iter().filter(|_| isolation) {
+        script.push_str(&format!(
+            "\t\tiifname \"{device}\" oifname \"{device}\" ip saddr @{set} ip daddr @{set} drop\n",
+            device = interface.device,
+            set = set_name(interface)
+        ));
+    }
+    script.push_str("\t}\n");
+    script.push_str(
+        "\tchain prerouting {\n\t\ttype nat hook prerouting priority dstnat; policy accept;\n",
+    );
+    for peer in interfaces.iter().flat_map(|(_, peers)| peers) {
+        let ip = match peer.ip {
+            None => continue,
+            Some(ip) => ip,
+        };
+        for forward in &peer.forwards {
+            script.push_str(&format!(
+                "\t\tfib daddr type local {} dport {} dnat ip to {}:{}\n",
+                forward.protocol, forward.port, ip, forward.target
+            ));
+        }
+    }
+    script.push_str("\t}\n}\n");
+    script
+}
+
+/// Parses `8080` or `8080:80`, a public port and the peer's port it goes to.
+pub fn parse_forward(protocol: &str, ports: &str) -> Result<Forward> {
+    let invalid = || {
+        Err(GimmewireError::Invalid(
+            "Expected tcp or udp and a port like 8080 or 8080:80".to_string(),
+        ))
+    };
+    if protocol != "tcp" && protocol != "udp" {
+        return invalid();
+    }
+    let (port, target) = ports.split_once(':').unwrap_or((ports, ports));
+    match (port.parse::<u16>(), target.parse::<u16>()) {
+        (Ok(port), Ok(target)) if port > 0 && target > 0 => Ok(Forward {
+            protocol: protocol.to_string(),
+            port,
+            target,
+        }),
+        _ => invalid(),
+    }
+}
+
+/// What already uses the public port on the node of the interface: another forward or the
+/// WireGuard port itself.
+pub fn conflict(
+    forward: &Forward,
+    interface: &Interface,
+    interfaces: &[Interface],
+    peers: &[Peer],
+    config: &Ini,
+) -> Option<String> {
+    let node: Vec<&Interface> = interfaces
+        .iter()
+        .filter(|other| other.host == interface.host)
+        .collect();
+    for other in &node {
+        let listen_port = other
+            .get(config, "Endpoint")
+            .and_then(|endpoint| endpoint.rsplit_once(':')?.1.parse::<u16>().ok());
+        if forward.protocol == "udp" && listen_port == Some(forward.port) {
+            return Some(format!("the WireGuard port of {}", other.name));
+        }
+    }
+    peers
+        .iter()
+        .filter(|peer| node.iter().any(|other| other.name == peer.interface))
+        .find(|peer| {
+            peer.forwards
+                .iter()
+                .any(|taken| taken.protocol == forward.protocol && taken.port == forward.port)
+        })
+        .map(|peer| format!("a forward to {}", peer.username))
+}
+
+/// Forwards the public port of the peer's node to the peer.
+pub async fn forward(
+    name: &str,
+    forward: Forward,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<()> {
+    if !cfg!(feature = "nftables") {
+        return Err(GimmewireError::Config(
+            "gimmewire was built without the `nftables` feature".to_string(),
+        ));
+    }
+    let mut peer = match store.find_by_username(name).await? {
+        None => return Err(GimmewireError::PeerNotFound(name.to_string())),
+        Some(peer) => peer,
+    };
+    {
+        let config = config.lock().await;
+        let interfaces = wireguard::interfaces(&config);
+        let interface = wireguard::interface_of(&interfaces, &peer)?;
+        let peers = store.get_peers().await?;
+        if let Some(taken) = conflict(&forward, interface, &interfaces, &peers, &config) {
+            return Err(GimmewireError::Invalid(format!(
+                "{} port {} is taken by {}",
+                forward.protocol, forward.port, taken
+            )));
+        }
+    }
+    peer.forwards.push(forward);
+    store.update(&peer).await?;
+    sync(store, config).await
+}
+
+/// Stops forwarding the public port to the peer.
+pub async fn unforward(
+    name: &str,
+    protocol: &str,
+    port: u16,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<()> {
+    let mut peer = match store.find_by_username(name).await? {
+        None => return Err(GimmewireError::PeerNotFound(name.to_string())),
+        Some(peer) => peer,
+    };
+    let before = peer.forwards.len();
+    peer.forwards
+        .retain(|forward| forward.protocol != protocol || forward.port != port);
+    if peer.forwards.len() == before {
+        return Err(GimmewireError::Invalid(format!(
+            "{} has no forward of {} port {}",
+            name, protocol, port
+        )));
+    }
+    store.update(&peer).await?;
+    sync(store, config).await
+}
+
+/// Brings the rules of every node in line with the db, or removes them when there is neither
+/// isolation nor a forward.
+pub async fn sync(store: &Store, config: Arc<Mutex<Ini>>) -> Result<()> {
+    // `features::check` warns about a [Firewall] section this build ignores
+    if !cfg!(feature = "nftables") {
+        return Ok(());
+    }
+    let (isolation, interfaces) = {
+        let config = config.lock().await;
+        (isolated(&config), wireguard::interfaces(&config))
+    };
+    let peers: Vec<Peer> = store
+        .get_peers()
+        .await?
+        .into_iter()
+        .filter(applied)
+        .collect();
+    let enabled = isolation || peers.iter().any(|peer| !peer.forwards.is_empty());
+    if !enabled && !INSTALLED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let mut nodes: BTreeMap<Option<String>, Vec<(&Interface, Vec<&Peer>)>> = BTreeMap::new();
+    for interface in &interfaces {
+        let members = peers
+            .iter()
+            .filter(|peer| peer.interface == interface.name)
+            .collect();
+        nodes
+            .entry(interface.host.clone())
+            .or_default()
+            .push((interface, members));
+    }
+    for (host, interfaces) in nodes {
+        match enabled {
+            true => nft_on(host.as_deref(), &ruleset(&interfaces, isolation))?,
+            false => {
+                let script = format!("table {table}\ndelete table {table}\n", table = TABLE);
+                if let Err(why) = nft_on(host.as_deref(), &script) {
+                    tracing::debug!("Cannot remove the firewall rules: {}", why);
+                }
+            }
+        }
+    }
+    INSTALLED.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Loads the script with `nft -f -` on the node, over ssh if it has a Host, or only logs it in a
+/// dry run.
+fn nft_on(host: Option<&str>, script: &str) -> Result<()> {
+    if crate::dryrun::skip("nft -f -", Some(script)) {
+        return Ok(());
+    }
+    nft(host, script)
+}
+
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn nft(host: Option<&str>, script: &str) -> Result<()> {
+    match host {
+        Some(host) => wireguard::run(
+            "/usr/bin/ssh",
+            &["-o", "BatchMode=yes", host, "nft", "-f", "-"],
+            Some(script),
+        ),
+        None => wireguard::run("/usr/sbin/nft", &["-f", "-"], Some(script)),
+    }
+    .map(|_| ())
+}
+
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+fn nft(host: Option<&str>, script: &str) -> Result<()> {
+    crate::mock::nft(host, script)
+}
+
+#[cfg(test)]
+#[test]
+fn isolation_rules() {
+    let interface = Interface {
+        name: "wg0".to_string(),
+        section: "peer".to_string(),
+        device: "wg-0".to_string(),
+        host: None,
+        network: std::net::Ipv4Addr::new(10, 0, 0, 0),
+        prefix: 16,
+        amnezia: false,
+        reserved: vec![],
+        save: None,
+    };
+    let mut alice = Peer::new(1, "alice".to_string());
+    alice.ip = Some(std::net::Ipv4Addr::new(10, 0, 0, 2));
+    let mut bob = Peer::new(2, "bob".to_string());
+    bob.ip = Some(std::net::Ipv4Addr::new(10, 0, 0, 3));
+    bob.forwards.push(parse_forward("tcp", "8080:80").unwrap());
+    let script = ruleset(&[(&interface, vec![&alice, &bob])], true);
+    assert!(script.starts_with("table inet gimmewire\ndelete table inet gimmewire\n"));
+    assert!(script.contains(
+        "\tset peers_wg_0 {\n\t\ttype ipv4_addr\n\t\telements = { 10.0.0.2, 10.0.0.3 }\n"
+    ));
+    assert!(script.contains(
+        "iifname \"wg-0\" oifname \"wg-0\" ip saddr @peers_wg_0 ip daddr @peers_wg_0 drop"
+    ));
+    assert!(script.contains("\t\tfib daddr type local tcp dport 8080 dnat ip to 10.0.0.3:80\n"));
+    assert!(!ruleset(&[(&interface, vec![])], false).contains("elements"));
+    assert!(parse_forward("tcp", "0").is_err() && parse_forward("icmp", "22").is_err());
+    let mut config = Ini::new();
+    config.set(
+        "Peer",
+        "Endpoint",
+        Some("vpn.example.com:51820".to_string()),
+    );
+    let interfaces = [interface.clone()];
+    let taken = |protocol, ports| {
+        let forward = parse_forward(protocol, ports).unwrap();
+        conflict(&forward, &interface, &interfaces, &[bob.clone()], &config)
+    };
+    assert!(taken("tcp", "8080").as_deref() == Some("a forward to bob"));
+    assert!(taken("udp", "51820").is_some() && taken("udp", "8080").is_none());
+}
@@ -0,0 +1,220 @@
+//! Outgoing webhooks, with `[Hooks] URL`: peer lifecycle events are posted as JSON so billing,
+//! CRM or SIEM systems hear about them without reading the store. With `[Hooks] Secret` every
+//! body is signed, `X-Gimmewire-Signature: sha256=<hex HMAC-SHA256 of the body>`, and carries
+//! its date so receivers can refuse replays.
+use crate::audit::Event;
+use bson::DateTime;
+use configparser::ini::Ini;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Events hooks can receive, `[Hooks] Events` picks some of them.
+pub const EVENTS: [&str; 4] = ["created", "removed", "expired", "quota-exceeded"];
+
+static SENDER: OnceLock<UnboundedSender<Payload>> = OnceLock::new();
+/// Events queued and not delivered to every URL yet.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// What a hook receives.
+#[derive(Serialize, Debug, Clone)]
+pub struct Payload {
+    pub event: String,
+    pub peer: String,
+    pub actor: String,
+    /// The action which caused it, like `approve` or `add trial`.
+    pub action: String,
+    /// RFC 3339.
+    pub date: String,
+}
+
+impl Payload {
+    pub fn new(event: &str, peer: &str, actor: &str, action: &str) -> Payload {
+        Payload {
+            event: event.to_string(),
+            peer: peer.to_string(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            date: DateTime::now().try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+/// The hook event of an audit event which succeeded, like the notifications in `audit`.
+pub fn of(event: &Event) -> Option<&'static str> {
+    if event.error.is_some() {
+        return None;
+    }
+    match (event.action.as_str(), event.actor.as_str()) {
+        ("remove", "expiry") => Some("expired"),
+        ("remove", _) => Some("removed"),
+        ("approve" | "add" | "add temporary" | "add trial" | "import" | "unarchive", _) => {
+            Some("created")
+        }
+        _ => None,
+    }
+}
+
+/// Queues an event for the hooks, it is dropped when there are none or they don't take it.
+pub fn send(payload: Payload) {
+    if let Some(sender) = SENDER.get() {
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        if sender.send(payload).is_err() {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Waits up to `timeout` for queued events to be delivered, before shutting down.
+pub async fn flush(timeout: Duration) {
+    let started = std::time::Instant::now();
+    while PENDING.load(Ordering::SeqCst) > 0 && started.elapsed() < timeout {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// What is wrong with `[Hooks]`, for `Settings::parse`.
+pub fn problems(config: &Ini) -> Vec<String> {
+    let mut problems = Vec::new();
+    let urls = config.get("Hooks", "URL").unwrap_or_default();
+    if urls
+        .split_whitespace()
+        .any(|url| !url.starts_with("https://") && !url.starts_with("http://"))
+    {
+        problems.push("[Hooks] URL must be http:// or https:// URLs".to_string());
+    }
+    let events = config.get("Hooks", "Events").unwrap_or_default();
+    if let Some(event) = events
+        .split_whitespace()
+        .find(|event| !EVENTS.contains(event))
+    {
+        problems.push(format!(
+            "[Hooks] Events has {}, not one of {}",
+            event,
+            EVENTS.join(" ")
+        ));
+    }
+    problems
+}
+
+/// Starts delivering events queued by `send` to `[Hooks] URL`, space separated URLs. Each is
+/// tried 3 times, 10 seconds apart, before the event is dropped for it.
+pub fn start(config: &Ini) {
+    let urls: Vec<String> = match config.get("Hooks", "URL") {
+        None => return,
+        Some(urls) => urls.split_whitespace().map(str::to_string).collect(),
+    };
+    let events: Vec<String> = match config.get("Hooks", "Events") {
+        None => EVENTS.iter().map(|event| event.to_string()).collect(),
+        Some(events) => events.split_whitespace().map(str::to_string).collect(),
+    };
+    let secret = config.get("Hooks", "Secret");
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+    {
+        Err(why) => {
+            tracing::error!("Cannot start webhooks: {}", why);
+            return;
+        }
+        Ok(client) => client,
+    };
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<Payload>();
+    if SENDER.set(sender).is_err() {
+        return;
+    }
+    tracing::info!(
+        "Posting {} events to {}",
+        events.join(", "),
+        urls.join(", ")
+    );
+    tokio::spawn(async move {
+        while let Some(payload) = receiver.recv().await {
+            if events.contains(&payload.event) {
+                let body = serde_json::to_string(&payload).unwrap_or_default();
+                for url in &urls {
+                    deliver(&client, url, &payload.event, &body, secret.as_deref()).await;
+                }
+            }
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    });
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    event: &str,
+    body: &str,
+    secret: Option<&str>,
+) {
+    for attempt in 1..=3 {
+        let mut request = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Gimmewire-Event", event)
+            .body(body.to_string());
+        if let Some(secret) = secret {
+            request = request.header("X-Gimmewire-Signature", signature(secret, body));
+        }
+        match request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(_) => return,
+            Err(why) if attempt == 3 => {
+                tracing::error!("Cannot post {} event to {}: {}", event, url, why)
+            }
+            Err(why) => {
+                tracing::warn!("Cannot post {} event to {}, retrying: {}", event, url, why);
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        }
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of the body with the secret.
+pub fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+#[test]
+fn hook_events() {
+    // RFC 4231, test case 2
+    assert!(
+        signature("Jefe", "what do ya want for nothing?")
+            == "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    let mut event = Event {
+        date: DateTime::now(),
+        actor: "expiry".to_string(),
+        action: "remove".to_string(),
+        target: "alice".to_string(),
+        error: None,
+    };
+    assert!(of(&event) == Some("expired"));
+    event.actor = "admin".to_string();
+    assert!(of(&event) == Some("removed"));
+    event.error = Some("Cannot find peer alice".to_string());
+    assert!(of(&event).is_none());
+    let mut config = Ini::new();
+    config
+        .read("[Hooks]\nURL = ftp://crm\nEvents = created paid".to_string())
+        .unwrap();
+    assert!(problems(&config).len() == 2);
+}
@@ -0,0 +1,492 @@
+use crate::error::GimmewireError;
+use crate::probe::{self, Probes};
+use crate::store::Store;
+use crate::usage::bytes;
+use crate::wireguard::{self, Peer, PeerStats};
+use crate::{audit, bulk, keys, links, peers, shutdown};
+use configparser::ini::Ini;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, HeaderMap, Method, Request, Response, Server, StatusCode};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Largest form the dashboard reads, bulk names included.
+const MAX_BODY: usize = 1024 * 1024;
+/// The cookie a browser keeps the admin token in after signing in.
+const COOKIE: &str = "gimmewire";
+
+pub async fn serve(store: Store, config: Arc<Mutex<Ini>>, probes: Probes) {
+    let addr: SocketAddr = match config.lock().await.get("Http", "Listen") {
+        None => return,
+        Some(listen) => match listen.parse() {
+            Err(why) => {
+                tracing::error!("Cannot parse http listen address {}: {}", listen, why);
+                return;
+            }
+            Ok(addr) => addr,
+        },
+    };
+    if config.lock().await.get("Http", "Token").is_none() {
+        tracing::error!("Http admin token is not set, dashboard is disabled");
+        return;
+    }
+    let make_svc = make_service_fn(move |_| {
+        let (store, config, probes) = (store.clone(), config.clone(), probes.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, store.clone(), config.clone(), probes.clone())
+            }))
+        }
+    });
+    tracing::info!("Serving dashboard on {}", addr);
+    let server = Server::bind(&addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown::stopped());
+    if let Err(why) = server.await {
+        tracing::error!("Http server failed: {}", why);
+    }
+}
+
+#[tracing::instrument(skip_all, fields(method = %req.method(), path = req.uri().path()))]
+async fn handle(
+    req: Request<Body>,
+    store: Store,
+    config: Arc<Mutex<Ini>>,
+    probes: Probes,
+) -> Result<Response<Body>, Infallible> {
+    let mut query: HashMap<String, String> =
+        form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+    // Links are for users, their token is the only authorization
+    if let Some(token) = req.uri().path().strip_prefix("/config/") {
+        return Ok(link(req.method(), token));
+    }
+    let token = config.lock().await.get("Http", "Token");
+    if req.method() == Method::POST && req.uri().path() == "/login" {
+        return Ok(login(req, token.as_deref()).await);
+    }
+    if !authorized(req.headers(), token.as_deref()) {
+        return Ok(match (req.method(), req.uri().path()) {
+            (&Method::GET, "/") => sign_in(StatusCode::UNAUTHORIZED),
+            _ => text(StatusCode::UNAUTHORIZED, "Unauthorized"),
+        });
+    }
+    if !store.available().await {
+        return Ok(text(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Database is unavailable, try again later",
+        ));
+    }
+    let _busy = match shutdown::begin() {
+        None => return Ok(text(StatusCode::SERVICE_UNAVAILABLE, "Shutting down")),
+        Some(busy) => busy,
+    };
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    // Html forms send their fields in the body
+    match crate::body::read(req.into_body(), MAX_BODY).await {
+        Err(status) => return Ok(text(status, "Cannot read the request")),
+        Ok(body) => query.extend(form_urlencoded::parse(&body).into_owned()),
+    }
+    let peer = match query.get("name") {
+        Some(name) => match store.find_by_username(name).await {
+            Err(why) => return Ok(error(&why)),
+            Ok(peer) => peer,
+        },
+        None => None,
+    };
+    let response = match (&method, path.as_str(), peer) {
+        (&Method::GET, "/", _) => dashboard(&store, &query, &probes, config).await,
+        (&Method::POST, "/revoke", Some(mut peer)) => {
+            let revoked =
+                peers::revoke(&mut peer, "revoked from dashboard", &store, config.clone()).await;
+            audit::record(&store, "dashboard", "remove", &peer.username, &revoked).await;
+            match revoked {
+                Err(why) => error(&why),
+                Ok(_) => redirect(),
+            }
+        }
+        (&Method::POST, "/regenerate", Some(mut peer)) => {
+            let provisioned = peers::provision(&mut peer, &store, config.clone()).await;
+            audit::record(
+                &store,
+                "dashboard",
+                "regenerate",
+                &peer.username,
+                &provisioned,
+            )
+            .await;
+            match provisioned {
+                Err(why) => error(&why),
+                Ok(_) => download(&peer, config).await,
+            }
+        }
+        (&Method::POST, "/ip", Some(mut peer)) => {
+            let ip = match query.get("ip").and_then(|ip| ip.parse().ok()) {
+                None => return Ok(text(StatusCode::BAD_REQUEST, "ip must be an IPv4 address")),
+                Some(ip) => ip,
+            };
+            let assigned = peers::assign_ip(&mut peer, ip, &store, config.clone()).await;
+            let action = format!("ip {}", ip);
+            audit::record(&store, "dashboard", &action, &peer.username, &assigned).await;
+            match assigned {
+                Err(why) => error(&why),
+                Ok(_) => redirect(),
+            }
+        }
+        (&Method::POST, "/bulk", _) => provision(&store, &query, config).await,
+        (&Method::POST, "/temporary", None) => temporary(&store, &query, config).await,
+        (&Method::POST, "/temporary", Some(_)) => text(StatusCode::CONFLICT, "Peer already exists"),
+        (&Method::POST, _, None) => text(StatusCode::NOT_FOUND, "Cannot find peer"),
+        _ => text(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(response)
+}
+
+/// A page with a download button, so link previews don't use up the link, and the config itself
+/// on the button's POST.
+fn link(method: &Method, token: &str) -> Response<Body> {
+    let gone = "This link has expired or was already used, ask the bot for a new config";
+    if method == Method::GET {
+        if !links::exists(token) {
+            return text(StatusCode::NOT_FOUND, gone);
+        }
+        let page = "<!DOCTYPE html>
+<html><head><meta charset=\"utf-8\"><title>gimmewire</title></head><body>
+<p>The config can be downloaded once.</p>
+<form method=\"post\"><button>Download</button></form>
+</body></html>";
+        return built(
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(page)),
+        );
+    }
+    match links::take(token) {
+        None => text(StatusCode::NOT_FOUND, gone),
+        Some(link) => built(
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", link.filename),
+                )
+                .body(Body::from(link.content)),
+        ),
+    }
+}
+
+/// Requests carry the admin token as `Authorization: Bearer <token>`, or browsers in the cookie
+/// `login` sets. Urls are logged and shared, so the token is never taken from one.
+fn authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let token = match token {
+        None => return false,
+        Some(token) => token,
+    };
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "));
+    let cookie = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|byte| *byte == b';'))
+        .find_map(|pair| {
+            pair.trim_ascii()
+                .strip_prefix(format!("{}=", COOKIE).as_bytes())
+        });
+    let cookie_token = form_urlencoded::byte_serialize(token.as_bytes()).collect::<String>();
+    crate::body::same_secret(bearer, token) || crate::body::same_secret(cookie, &cookie_token)
+}
+
+/// Checks the token of the sign in form and keeps it in a cookie which other sites' forms
+/// don't send along.
+async fn login(req: Request<Body>, token: Option<&str>) -> Response<Body> {
+    let body = match crate::body::read(req.into_body(), MAX_BODY).await {
+        Err(status) => return text(status, "Cannot read the request"),
+        Ok(body) => body,
+    };
+    let sent = form_urlencoded::parse(&body)
+        .find(|(key, _)| key == "token")
+        .map(|(_, value)| value.into_owned());
+    let token = match token {
+        Some(token) if crate::body::same_secret(sent.as_deref().map(str::as_bytes), token) => token,
+        _ => return sign_in(StatusCode::UNAUTHORIZED),
+    };
+    built(
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(header::LOCATION, "/")
+            .header(
+                header::SET_COOKIE,
+                format!(
+                    "{}={}; Path=/; HttpOnly; SameSite=Strict",
+                    COOKIE,
+                    form_urlencoded::byte_serialize(token.as_bytes()).collect::<String>()
+                ),
+            )
+            .body(Body::empty()),
+    )
+}
+
+fn sign_in(status: StatusCode) -> Response<Body> {
+    let page = "<!DOCTYPE html>
+<html><head><meta charset=\"utf-8\"><title>gimmewire</title></head><body>
+<form method=\"post\" action=\"/login\"><input type=\"password\" name=\"token\" placeholder=\"Token\">
+<button>Sign in</button></form>
+</body></html>";
+    built(
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(page)),
+    )
+}
+
+async fn dashboard(
+    store: &Store,
+    query: &HashMap<String, String>,
+    probes: &Probes,
+    config: Arc<Mutex<Ini>>,
+) -> Response<Body> {
+    let search = query.get("q").cloned().unwrap_or_default();
+    let interfaces = wireguard::interfaces(&*config.lock().await);
+    let stats: HashMap<String, PeerStats> = match wireguard::show_all(&interfaces).await {
+        Err(why) => {
+            tracing::error!("Cannot read interface state: {}", why);
+            HashMap::new()
+        }
+        Ok(stats) => stats
+            .into_iter()
+            .map(|stat| (stat.public_key.clone(), stat))
+            .collect(),
+    };
+    let peers = match store.get_peers().await {
+        Err(why) => return error(&why),
+        Ok(peers) => peers,
+    };
+    let probes = probes.lock().await;
+    let mut rows = String::new();
+    for peer in peers.iter().filter(|peer| matches(peer, &search)) {
+        let stat = peer.public_key.as_ref().and_then(|key| stats.get(key));
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape(&peer.username),
+            escape(&peer.interface),
+            peer.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            stat.and_then(|stat| stat.endpoint.clone())
+                .unwrap_or_default(),
+            stat.and_then(|stat| stat.latest_handshake)
+                .and_then(|date| date.try_to_rfc3339_string().ok())
+                .unwrap_or_else(|| "never".to_string()),
+            stat.map(|stat| format!("{} / {}", bytes(stat.rx), bytes(stat.tx)))
+                .unwrap_or_default(),
+            peer.public_key
+                .as_ref()
+                .and_then(|key| probes.get(key))
+                .and_then(|samples| probe::summary(samples))
+                .unwrap_or_default(),
+            peer.expires
+                .and_then(|date| date.try_to_rfc3339_string().ok())
+                .unwrap_or_else(|| "never".to_string()),
+            form("revoke", &peer.username),
+            form("regenerate", &peer.username),
+        ));
+    }
+    let page = format!(
+        "<!DOCTYPE html>
+<html><head><meta charset=\"utf-8\"><title>gimmewire</title></head><body>
+<form method=\"get\" action=\"/\"><input name=\"q\" value=\"{}\" placeholder=\"Search\"><button>Search</button></form>
+<form method=\"post\" action=\"/temporary\"><input name=\"name\" placeholder=\"Name\">
+<input name=\"hours\" placeholder=\"Hours\"><button>Temporary access</button></form>
+<form method=\"post\" action=\"/bulk\"><textarea name=\"names\" placeholder=\"Usernames or CSV\"></textarea>
+<button>Add all</button></form>
+<table border=\"1\" cellpadding=\"4\">
+<tr><th>User</th><th>Interface</th><th>IP</th><th>Endpoint</th><th>Last handshake</th><th>Rx / Tx</th><th>Latency</th><th>Expires</th><th></th><th></th></tr>
+{}</table></body></html>",
+        escape(&search),
+        rows
+    );
+    built(
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(page)),
+    )
+}
+
+async fn download(peer: &Peer, config: Arc<Mutex<Ini>>) -> Response<Body> {
+    let content = match wireguard::gen_conf(peer, config.clone()).await {
+        Err(why) => return error(&why),
+        Ok(path) => {
+            let content = std::fs::read(&path);
+            keys::forget(&path, &*config.lock().await);
+            content
+        }
+    };
+    match content {
+        Err(why) => text(StatusCode::INTERNAL_SERVER_ERROR, &why.to_string()),
+        Ok(content) => built(
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.conf\"", peer.username),
+                )
+                .body(Body::from(content)),
+        ),
+    }
+}
+
+/// Peers for every name of the `names` field, a list or a CSV with usernames first, e.g.
+/// `curl --data-urlencode names@team.csv`. Answers with a ZIP of their configs.
+async fn provision(
+    store: &Store,
+    query: &HashMap<String, String>,
+    config: Arc<Mutex<Ini>>,
+) -> Response<Body> {
+    let names = bulk::names(query.get("names").map(String::as_str).unwrap_or_default());
+    if names.is_empty() {
+        return text(StatusCode::BAD_REQUEST, "Expected names");
+    }
+    match bulk::provision(&names, "dashboard", store, config).await {
+        Err(why) => error(&why),
+        Ok(provisioned) => built(
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/zip")
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"gimmewire-peers.zip\"",
+                )
+                .body(Body::from(provisioned.archive)),
+        ),
+    }
+}
+
+async fn temporary(
+    store: &Store,
+    query: &HashMap<String, String>,
+    config: Arc<Mutex<Ini>>,
+) -> Response<Body> {
+    let (name, hours) = match (
+        query.get("name"),
+        query.get("hours").and_then(|hours| hours.parse().ok()),
+    ) {
+        (Some(name), Some(hours)) => (name.clone(), hours),
+        _ => return text(StatusCode::BAD_REQUEST, "name and hours are required"),
+    };
+    let granted = peers::grant_temporary(name.clone(), hours, store, config.clone()).await;
+    audit::record(store, "dashboard", "add temporary", &name, &granted).await;
+    match granted {
+        Err(why) => error(&why),
+        Ok(peer) => download(&peer, config).await,
+    }
+}
+
+fn matches(peer: &Peer, search: &str) -> bool {
+    let search = search.to_lowercase();
+    peer.username.to_lowercase().contains(&search)
+        || peer.user_id.to_string().contains(&search)
+        || peer.ip.map(|ip| ip.to_string().contains(&search)) == Some(true)
+}
+
+fn form(action: &str, name: &str) -> String {
+    format!(
+        "<form method=\"post\" action=\"/{}\"><input type=\"hidden\" name=\"name\" value=\"{}\"><button>{}</button></form>",
+        action,
+        escape(name),
+        action
+    )
+}
+
+fn redirect() -> Response<Body> {
+    built(
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(header::LOCATION, "/")
+            .body(Body::empty()),
+    )
+}
+
+fn text(status: StatusCode, msg: &str) -> Response<Body> {
+    built(
+        Response::builder()
+            .status(status)
+            .body(Body::from(msg.to_string())),
+    )
+}
+
+/// The response, or a 500 if a header can't be sent, e.g. a file name with a newline.
+fn built(response: hyper::http::Result<Response<Body>>) -> Response<Body> {
+    response.unwrap_or_else(|why| {
+        tracing::error!("Cannot build a response: {}", why);
+        let mut response = Response::new(Body::from("Cannot build the response"));
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    })
+}
+
+fn error(why: &GimmewireError) -> Response<Body> {
+    let status = match why {
+        GimmewireError::PeerNotFound(_) => StatusCode::NOT_FOUND,
+        GimmewireError::PeerExists(_) | GimmewireError::PoolExhausted(_) => StatusCode::CONFLICT,
+        GimmewireError::Invalid(_) => StatusCode::BAD_REQUEST,
+        GimmewireError::Storage(_) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    text(status, &why.to_string())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn admin_authorization() {
+    let headers = |name, value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    };
+    assert!(authorized(
+        &headers(header::AUTHORIZATION, "Bearer t&ken"),
+        Some("t&ken")
+    ));
+    assert!(!authorized(
+        &headers(header::AUTHORIZATION, "Bearer t&kem"),
+        Some("t&ken")
+    ));
+    assert!(!authorized(
+        &headers(header::AUTHORIZATION, "Bearer t&ken"),
+        None
+    ));
+    assert!(authorized(
+        &headers(header::COOKIE, "theme=dark; gimmewire=t%26ken"),
+        Some("t&ken")
+    ));
+    assert!(!authorized(&HeaderMap::new(), Some("t&ken")));
+    let signed_in = |body: &'static str| {
+        let req = Request::post("/login").body(Body::from(body)).unwrap();
+        async move { login(req, Some("t&ken")).await }
+    };
+    let response = signed_in("token=t%26ken").await;
+    let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+    assert!(response.status() == StatusCode::SEE_OTHER);
+    assert!(authorized(
+        &headers(header::COOKIE, cookie.split(';').next().unwrap()),
+        Some("t&ken")
+    ));
+    assert!(signed_in("token=wrong").await.status() == StatusCode::UNAUTHORIZED);
+    let newline = Response::builder()
+        .header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"a\nb.conf\"",
+        )
+        .body(Body::empty());
+    assert!(built(newline).status() == StatusCode::INTERNAL_SERVER_ERROR);
+}
@@ -0,0 +1,174 @@
+use crate::error::{GimmewireError, Result};
+use crate::wireguard::Peer;
+use configparser::ini::Ini;
+use std::collections::HashMap;
+use std::sync::Arc;
+use teloxide::{prelude::*, types::BotCommand};
+
+/// Bundles built into the binary, `en` has every message and is the last fallback.
+const BUNDLES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.toml")),
+    ("ru", include_str!("../locales/ru.toml")),
+];
+const FALLBACK: &str = "en";
+
+pub type Locales = Arc<Catalog>;
+
+/// Bot messages by language and key.
+pub struct Catalog {
+    default: String,
+    bundles: HashMap<String, HashMap<String, String>>,
+}
+
+impl Catalog {
+    /// Built-in bundles, overridden or extended by `<language>.toml` files in `[Bot] Locales`.
+    pub fn load(config: &Ini) -> Result<Self> {
+        let mut bundles = HashMap::new();
+        for (lang, content) in BUNDLES {
+            bundles.insert(lang.to_string(), parse(lang, content)?);
+        }
+        if let Some(dir) = config.get("Bot", "Locales") {
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                let lang = match path.file_stem().and_then(|stem| stem.to_str()) {
+                    Some(lang) if path.extension().is_some_and(|ext| ext == "toml") => {
+                        lang.to_lowercase()
+                    }
+                    _ => continue,
+                };
+                let messages = parse(&lang, &std::fs::read_to_string(&path)?)?;
+                bundles.entry(lang).or_default().extend(messages);
+            }
+        }
+        let default = config
+            .get("Bot", "Language")
+            .map(|lang| lang.to_lowercase())
+            .unwrap_or_else(|| FALLBACK.to_string());
+        if !bundles.contains_key(&default) {
+            return Err(GimmewireError::Config(format!(
+                "[Bot] Language {} has no messages",
+                default
+            )));
+        }
+        Ok(Catalog { default, bundles })
+    }
+
+    /// Codes of the available languages, sorted.
+    pub fn languages(&self) -> Vec<String> {
+        let mut languages: Vec<String> = self.bundles.keys().cloned().collect();
+        languages.sort();
+        languages
+    }
+
+    /// Messages in the user's chosen language, else their Telegram one, else `[Bot] Language`.
+    pub fn tr(&self, chosen: Option<&str>, telegram: Option<&str>) -> Tr<'_> {
+        let lang = [chosen, telegram]
+            .into_iter()
+            .flatten()
+            // Telegram sends IETF tags like `en-US`, bundles are per language
+            .map(|code| code.split('-').next().unwrap_or(code).to_lowercase())
+            .find(|lang| self.bundles.contains_key(lang))
+            .unwrap_or_else(|| self.default.clone());
+        Tr {
+            catalog: self,
+            lang,
+        }
+    }
+
+    fn get(&self, lang: &str, key: &str) -> String {
+        [lang, &self.default, FALLBACK]
+            .iter()
+            .find_map(|lang| self.bundles.get(*lang)?.get(key))
+            .cloned()
+            .unwrap_or_else(|| key.to_string())
+    }
+}
+
+/// The catalog in one language.
+pub struct Tr<'a> {
+    catalog: &'a Catalog,
+    pub lang: String,
+}
+
+impl Tr<'_> {
+    pub fn get(&self, key: &str) -> String {
+        self.catalog.get(&self.lang, key)
+    }
+
+    /// A message with its `{name}` placeholders filled in.
+    pub fn format(&self, key: &str, args: &[(&str, &str)]) -> String {
+        let mut msg = self.get(key);
+        for (name, value) in args {
+            msg = msg.replace(&format!("{{{}}}", name), value);
+        }
+        msg
+    }
+
+    /// What a user may be told about the error, in their language.
+    pub fn error(&self, why: &GimmewireError) -> Option<String> {
+        let msg = why.user_message()?;
+        Some(match why {
+            GimmewireError::PoolExhausted(_) => self.get("error-pool-exhausted"),
+            GimmewireError::Storage(_) => self.get("unavailable"),
+            GimmewireError::PeerExists(name) => self.format("error-peer-exists", &[("name", name)]),
+            GimmewireError::PeerNotFound(name) => {
+                self.format("error-peer-not-found", &[("name", name)])
+            }
+            GimmewireError::PeerLimit(limit) => {
+                self.format("error-peer-limit", &[("limit", &limit.to_string())])
+            }
+            _ => msg,
+        })
+    }
+
+    /// Bot commands with descriptions from `command-<name>`.
+    pub fn commands(&self, commands: Vec<BotCommand>) -> Vec<BotCommand> {
+        commands
+            .into_iter()
+            .map(|command| {
+                let key = format!("command-{}", command.command.trim_start_matches('/'));
+                match self.get(&key) {
+                    description if description == key => command,
+                    description => BotCommand::new(command.command, description),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Sends a message to the user of a peer in their language, unlinked peers have nobody to tell.
+pub async fn tell(bot: &Bot, locales: &Locales, peer: &Peer, key: &str, args: &[(&str, &str)]) {
+    if peer.user_id == 0 {
+        return;
+    }
+    let msg = locales.tr(peer.language.as_deref(), None).format(key, args);
+    // Private chat ids match user ids
+    if let Err(why) = bot.send_message(ChatId(peer.user_id as i64), msg).await {
+        tracing::error!("Cannot send a message to {}: {}", peer.username, why);
+    }
+}
+
+fn parse(lang: &str, content: &str) -> Result<HashMap<String, String>> {
+    toml::from_str(content)
+        .map_err(|why| GimmewireError::Config(format!("Cannot parse {} messages: {}", lang, why)))
+}
+
+#[cfg(test)]
+#[test]
+fn catalog() {
+    let catalog = Catalog::load(&Ini::new()).unwrap();
+    let en = &catalog.bundles["en"];
+    for lang in catalog.languages() {
+        let bundle = &catalog.bundles[&lang];
+        let missing: Vec<&String> = en.keys().filter(|key| !bundle.contains_key(*key)).collect();
+        assert!(missing.is_empty(), "{} lacks {:?}", lang, missing);
+    }
+    assert!(catalog.tr(None, Some("ru-RU")).lang == "ru");
+    assert!(catalog.tr(Some("en"), Some("ru")).lang == "en");
+    assert!(catalog.tr(None, Some("xx")).lang == "en");
+    let tr = catalog.tr(None, None);
+    assert!(tr.format("latency", &[("latency", "12 ms")]) == "Latency: 12 ms");
+    assert!(tr.get("no-such-key") == "no-such-key");
+    let why = GimmewireError::PeerNotFound("alice".to_string());
+    assert!(catalog.tr(Some("ru"), None).error(&why).unwrap() == "Пир alice не найден");
+}
@@ -0,0 +1,431 @@
+use crate::audit::Event;
+use crate::error::{GimmewireError, Result};
+use crate::referral::Referral;
+use crate::repair::Repair;
+use crate::rotation::Rotation;
+use crate::store::{Filter, PeerStore, Store};
+use crate::wireguard::Peer;
+use async_trait::async_trait;
+use configparser::ini::Ini;
+#[cfg(feature = "encryption")]
+use openssl::{base64, rand::rand_bytes, symm};
+
+/// `[Keys] Store`, whether client private keys are kept once their config is handed out.
+pub fn stored(config: &Ini) -> bool {
+    config
+        .getbool("Keys", "Store")
+        .unwrap_or(None)
+        .unwrap_or(true)
+}
+
+/// Deletes a generated client config once it is sent, when private keys mustn't stay on disk.
+pub fn forget(path: &str, config: &Ini) {
+    if stored(config) {
+        return;
+    }
+    if let Err(why) = std::fs::remove_file(path) {
+        tracing::error!("Cannot delete {}: {}", path, why);
+    }
+}
+
+/// A store which never writes or returns private keys. Keys live only in the peer a config is
+/// generated from, so configs cannot be sent again and users rotate keys to get a new one.
+pub struct Transient {
+    inner: Store,
+}
+
+impl Transient {
+    pub fn new(inner: Store) -> Self {
+        Transient { inner }
+    }
+}
+
+/// `[Keys] MasterKey` or the content of `[Keys] MasterKeyFile`, 32 bytes in base64.
+#[cfg(feature = "encryption")]
+pub fn master_key(config: &Ini) -> Result<Option<Vec<u8>>> {
+    let encoded = match (
+        config.get("Keys", "MasterKey"),
+        config.get("Keys", "MasterKeyFile"),
+    ) {
+        (Some(key), _) => key,
+        (None, Some(path)) => std::fs::read_to_string(path)?,
+        (None, None) => return Ok(None),
+    };
+    match base64::decode_block(encoded.trim()) {
+        Ok(key) if key.len() == 32 => Ok(Some(key)),
+        _ => Err(GimmewireError::Config(
+            "[Keys] MasterKey must be 32 bytes in base64, e.g. from openssl rand -base64 32"
+                .to_string(),
+        )),
+    }
+}
+
+/// Refuses to start with a master key which this build cannot use, keys would be kept in plain text.
+#[cfg(not(feature = "encryption"))]
+pub fn master_key(config: &Ini) -> Result<()> {
+    match config
+        .get("Keys", "MasterKey")
+        .or(config.get("Keys", "MasterKeyFile"))
+    {
+        None => Ok(()),
+        Some(_) => Err(GimmewireError::Config(
+            "[Keys] MasterKey is set but gimmewire was built without the `encryption` feature"
+                .to_string(),
+        )),
+    }
+}
+
+fn strip(mut peer: Peer) -> Peer {
+    peer.private_key = None;
+    peer
+}
+
+#[async_trait]
+impl PeerStore for Transient {
+    async fn add(&self, peer: &Peer) -> Result<()> {
+        self.inner.add(&strip(peer.clone())).await
+    }
+
+    async fn update(&self, peer: &Peer) -> Result<()> {
+        self.inner.update(&strip(peer.clone())).await
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<Option<Peer>> {
+        Ok(self.inner.find_by_id(id).await?.map(strip))
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<Peer>> {
+        Ok(self.inner.find_by_username(username).await?.map(strip))
+    }
+
+    async fn delete(&self, peer: &Peer) -> Result<()> {
+        self.inner.delete(peer).await
+    }
+
+    async fn get_peers(&self) -> Result<Vec<Peer>> {
+        Ok(self
+            .inner
+            .get_peers()
+            .await?
+            .into_iter()
+            .map(strip)
+            .collect())
+    }
+
+    async fn get_archived(&self) -> Result<Vec<Peer>> {
+        Ok(self
+            .inner
+            .get_archived()
+            .await?
+            .into_iter()
+            .map(strip)
+            .collect())
+    }
+
+    async fn find_peers(
+        &self,
+        filter: &Filter,
+        skip: u64,
+        limit: Option<u64>,
+    ) -> Result<Vec<Peer>> {
+        Ok(self
+            .inner
+            .find_peers(filter, skip, limit)
+            .await?
+            .into_iter()
+            .map(strip)
+            .collect())
+    }
+
+    async fn log_rotation(&self, rotation: &Rotation) -> Result<()> {
+        self.inner.log_rotation(rotation).await
+    }
+
+    async fn log_event(&self, event: &Event) -> Result<()> {
+        self.inner.log_event(event).await
+    }
+
+    async fn get_events(&self, skip: u64, limit: u64) -> Result<Vec<Event>> {
+        self.inner.get_events(skip, limit).await
+    }
+
+    async fn save_referral(&self, referral: &Referral) -> Result<()> {
+        self.inner.save_referral(referral).await
+    }
+
+    async fn get_referrals(&self) -> Result<Vec<Referral>> {
+        self.inner.get_referrals().await
+    }
+
+    async fn save_repair(&self, repair: &Repair) -> Result<()> {
+        self.inner.save_repair(repair).await
+    }
+
+    async fn get_repairs(&self) -> Result<Vec<Repair>> {
+        self.inner.get_repairs().await
+    }
+
+    async fn delete_repair(&self, repair: &Repair) -> Result<()> {
+        self.inner.delete_repair(repair).await
+    }
+
+    async fn available(&self) -> bool {
+        self.inner.available().await
+    }
+}
+
+/// Sealed private keys look like `sealed:<data key>:<key>`, both parts are a nonce, ciphertext
+/// and tag in base64. The data key is random per key and sealed with the master key.
+#[cfg(feature = "encryption")]
+const SEALED: &str = "sealed:";
+#[cfg(feature = "encryption")]
+const NONCE: usize = 12;
+#[cfg(feature = "encryption")]
+const TAG: usize = 16;
+
+#[cfg(feature = "encryption")]
+fn encrypt(key: &[u8], data: &[u8]) -> std::result::Result<String, openssl::error::ErrorStack> {
+    let mut nonce = [0; NONCE];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0; TAG];
+    let ciphertext = symm::encrypt_aead(
+        symm::Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        &[],
+        data,
+        &mut tag,
+    )?;
+    Ok(base64::encode_block(
+        &[&nonce[..], &ciphertext, &tag].concat(),
+    ))
+}
+
+#[cfg(feature = "encryption")]
+fn decrypt(key: &[u8], sealed: &str) -> Option<Vec<u8>> {
+    let sealed = base64::decode_block(sealed).ok()?;
+    if sealed.len() < NONCE + TAG {
+        return None;
+    }
+    let (nonce, rest) = sealed.split_at(NONCE);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG);
+    symm::decrypt_aead(
+        symm::Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        &[],
+        ciphertext,
+        tag,
+    )
+    .ok()
+}
+
+#[cfg(feature = "encryption")]
+fn seal(master: &[u8], private_key: &str) -> Result<String> {
+    let sealed = || -> std::result::Result<String, openssl::error::ErrorStack> {
+        let mut data_key = [0; 32];
+        rand_bytes(&mut data_key)?;
+        Ok(format!(
+            "{}{}:{}",
+            SEALED,
+            encrypt(master, &data_key)?,
+            encrypt(&data_key, private_key.as_bytes())?
+        ))
+    };
+    sealed().map_err(|why| GimmewireError::Storage(format!("Cannot seal a private key: {}", why)))
+}
+
+/// The private key of a sealed one, keys stored before encryption was enabled come back as they are.
+#[cfg(feature = "encryption")]
+fn unseal(master: &[u8], stored: &str) -> Option<String> {
+    let (data_key, private_key) = match stored.strip_prefix(SEALED) {
+        None => return Some(stored.to_string()),
+        Some(sealed) => sealed.split_once(':')?,
+    };
+    let data_key = decrypt(master, data_key)?;
+    String::from_utf8(decrypt(&data_key, private_key)?).ok()
+}
+
+/// A store which seals private keys with the master key before they are written and unseals
+/// them when peers are read, so a db dump has no usable client keys.
+#[cfg(feature = "encryption")]
+pub struct Sealed {
+    inner: Store,
+    master: Vec<u8>,
+}
+
+#[cfg(feature = "encryption")]
+impl Sealed {
+    pub fn new(inner: Store, master: Vec<u8>) -> Self {
+        Sealed { inner, master }
+    }
+
+    /// Refuses a master key which doesn't unseal every stored key. Peers whose key cannot be
+    /// unsealed come back without one, and the next update of them would drop it for good.
+    pub async fn open(inner: Store, master: Vec<u8>) -> Result<Self> {
+        let mut peers = inner.get_peers().await?;
+        peers.extend(inner.get_archived().await?);
+        let sealed = Sealed::new(inner, master);
+        let unreadable: Vec<_> = peers
+            .iter()
+            .filter(|peer| {
+                peer.private_key
+                    .as_deref()
+                    .is_some_and(|stored| unseal(&sealed.master, stored).is_none())
+            })
+            .map(|peer| peer.username.as_str())
+            .collect();
+        match unreadable.is_empty() {
+            true => Ok(sealed),
+            false => Err(GimmewireError::Config(format!(
+                "[Keys] MasterKey cannot unseal the private keys of {}, is it the key they were sealed with?",
+                unreadable.join(", ")
+            ))),
+        }
+    }
+
+    fn seal(&self, peer: &Peer) -> Result<Peer> {
+        let mut peer = peer.clone();
+        if let Some(private_key) = &peer.private_key {
+            peer.private_key = Some(seal(&self.master, private_key)?);
+        }
+        Ok(peer)
+    }
+
+    fn unseal(&self, mut peer: Peer) -> Peer {
+        if let Some(stored) = &peer.private_key {
+            peer.private_key = unseal(&self.master, stored);
+            if peer.private_key.is_none() {
+                tracing::error!("Cannot unseal the private key of {}", peer.username);
+            }
+        }
+        peer
+    }
+}
+
+#[cfg(feature = "encryption")]
+#[async_trait]
+impl PeerStore for Sealed {
+    async fn add(&self, peer: &Peer) -> Result<()> {
+        self.inner.add(&self.seal(peer)?).await
+    }
+
+    async fn update(&self, peer: &Peer) -> Result<()> {
+        self.inner.update(&self.seal(peer)?).await
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<Option<Peer>> {
+        Ok(self
+            .inner
+            .find_by_id(id)
+            .await?
+            .map(|peer| self.unseal(peer)))
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<Peer>> {
+        let peer = self.inner.find_by_username(username).await?;
+        Ok(peer.map(|peer| self.unseal(peer)))
+    }
+
+    async fn delete(&self, peer: &Peer) -> Result<()> {
+        self.inner.delete(peer).await
+    }
+
+    async fn get_peers(&self) -> Result<Vec<Peer>> {
+        let peers = self.inner.get_peers().await?;
+        Ok(peers.into_iter().map(|peer| self.unseal(peer)).collect())
+    }
+
+    async fn get_archived(&self) -> Result<Vec<Peer>> {
+        let peers = self.inner.get_archived().await?;
+        Ok(peers.into_iter().map(|peer| self.unseal(peer)).collect())
+    }
+
+    async fn find_peers(
+        &self,
+        filter: &Filter,
+        skip: u64,
+        limit: Option<u64>,
+    ) -> Result<Vec<Peer>> {
+        let peers = self.inner.find_peers(filter, skip, limit).await?;
+        Ok(peers.into_iter().map(|peer| self.unseal(peer)).collect())
+    }
+
+    async fn log_rotation(&self, rotation: &Rotation) -> Result<()> {
+        self.inner.log_rotation(rotation).await
+    }
+
+    async fn log_event(&self, event: &Event) -> Result<()> {
+        self.inner.log_event(event).await
+    }
+
+    async fn get_events(&self, skip: u64, limit: u64) -> Result<Vec<Event>> {
+        self.inner.get_events(skip, limit).await
+    }
+
+    async fn save_referral(&self, referral: &Referral) -> Result<()> {
+        self.inner.save_referral(referral).await
+    }
+
+    async fn get_referrals(&self) -> Result<Vec<Referral>> {
+        self.inner.get_referrals().await
+    }
+
+    async fn save_repair(&self, repair: &Repair) -> Result<()> {
+        self.inner.save_repair(repair).await
+    }
+
+    async fn get_repairs(&self) -> Result<Vec<Repair>> {
+        self.inner.get_repairs().await
+    }
+
+    async fn delete_repair(&self, repair: &Repair) -> Result<()> {
+        self.inner.delete_repair(repair).await
+    }
+
+    async fn available(&self) -> bool {
+        self.inner.available().await
+    }
+}
+
+#[cfg(all(test, feature = "file"))]
+#[tokio::test]
+async fn transient_keys() {
+    let scratch = crate::store::Scratch::new("keys.json");
+    let file = scratch.file();
+    let store = Transient::new(file.clone());
+    let mut peer = Peer::new(7, "alice".to_string());
+    peer.private_key = Some("private".to_string());
+    peer.public_key = Some("public".to_string());
+    store.add(&peer).await.unwrap();
+    let stored = file.find_by_id(7).await.unwrap().unwrap();
+    assert!(stored.private_key.is_none() && stored.public_key.as_deref() == Some("public"));
+    #[cfg(feature = "encryption")]
+    {
+        let master = vec![7; 32];
+        let store = Sealed::new(file.clone(), master.clone());
+        store.update(&peer).await.unwrap();
+        let sealed = file
+            .find_by_id(7)
+            .await
+            .unwrap()
+            .unwrap()
+            .private_key
+            .unwrap();
+        assert!(sealed.starts_with(SEALED) && !sealed.contains("private"));
+        assert!(
+            store
+                .find_by_id(7)
+                .await
+                .unwrap()
+                .unwrap()
+                .private_key
+                .as_deref()
+                == Some("private")
+        );
+        assert!(unseal(&[8; 32], &sealed).is_none());
+        assert!(Sealed::open(file.clone(), vec![8; 32]).await.is_err());
+        assert!(Sealed::open(file.clone(), master.clone()).await.is_ok());
+        assert!(unseal(&master, "plain").as_deref() == Some("plain"));
+    }
+}
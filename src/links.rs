@@ -0,0 +1,98 @@
+//! One-time download links for client configs, served by the http server under `/config/<token>`.
+use crate::error::Result;
+use crate::keys;
+use crate::wireguard::{self, Peer};
+use bson::DateTime;
+use configparser::ini::Ini;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, LazyLock};
+use tokio::sync::Mutex;
+
+/// Configs waiting for their download by token, kept in memory only.
+static LINKS: LazyLock<std::sync::Mutex<HashMap<String, Link>>> =
+    LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub filename: String,
+    pub content: String,
+    pub expires: DateTime,
+}
+
+/// `[Links] URL`, the public https address of the http server, links are off without it.
+pub fn base(config: &Ini) -> Option<String> {
+    let url = config.get("Links", "URL")?;
+    Some(url.trim_end_matches('/').to_string())
+}
+
+/// Minutes a link works for, `[Links] TTL`.
+fn ttl(config: &Ini) -> u64 {
+    config.getuint("Links", "TTL").unwrap_or(None).unwrap_or(15)
+}
+
+/// Keeps the content for one download within `ttl` minutes, returns its token.
+pub fn create(filename: String, content: String, ttl: u64) -> Result<String> {
+    let mut random = [0; 24];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+    let token: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+    let now = DateTime::now();
+    let link = Link {
+        filename,
+        content,
+        expires: DateTime::from_millis(now.timestamp_millis() + ttl as i64 * 60 * 1000),
+    };
+    let mut links = LINKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    links.retain(|_, link| link.expires > now);
+    links.insert(token.clone(), link);
+    Ok(token)
+}
+
+/// Whether the token has a link which still works.
+pub fn exists(token: &str) -> bool {
+    let links = LINKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    links
+        .get(token)
+        .is_some_and(|link| link.expires > DateTime::now())
+}
+
+/// Hands out the link's content and forgets it, so a link works once.
+pub fn take(token: &str) -> Option<Link> {
+    let mut links = LINKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    links
+        .remove(token)
+        .filter(|link| link.expires > DateTime::now())
+}
+
+/// A link to the peer's config, None when `[Links] URL` is unset and configs go to the chat.
+pub async fn share(peer: &Peer, config: Arc<Mutex<Ini>>) -> Result<Option<String>> {
+    let (base, ttl) = {
+        let config = config.lock().await;
+        match base(&config) {
+            None => return Ok(None),
+            Some(base) => (base, ttl(&config)),
+        }
+    };
+    let path = wireguard::gen_conf(peer, config.clone()).await?;
+    let content = std::fs::read_to_string(&path);
+    keys::forget(&path, &*config.lock().await);
+    let token = create(format!("{}.conf", peer.username), content?, ttl)?;
+    Ok(Some(format!("{}/config/{}", base, token)))
+}
+
+#[cfg(test)]
+#[test]
+fn one_time() {
+    let token = create("alice.conf".to_string(), "[Interface]".to_string(), 15).unwrap();
+    assert!(token.len() == 48 && exists(&token));
+    assert!(take(&token).unwrap().content == "[Interface]");
+    assert!(take(&token).is_none() && !exists(&token));
+    let expired = create("bob.conf".to_string(), String::new(), 0).unwrap();
+    assert!(take(&expired).is_none());
+}
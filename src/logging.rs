@@ -0,0 +1,26 @@
+use configparser::ini::Ini;
+use tracing_subscriber::EnvFilter;
+
+/// Sets up tracing from `[Log]`. `Level` takes `RUST_LOG` style directives, e.g.
+/// `info,gimmewire::peers=debug`, and `RUST_LOG` overrides it. `Format = json` prints
+/// one JSON object per event, with the fields of the spans it happened in.
+pub fn init(config: &Ini) {
+    let level = config
+        .get("Log", "Level")
+        .unwrap_or_else(|| "info".to_string());
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&level))
+        .unwrap_or_else(|why| {
+            eprintln!("Cannot parse [Log] Level {}: {}", level, why);
+            EnvFilter::new("info")
+        });
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.get("Log", "Format").as_deref() {
+        Some("json") => subscriber.json().init(),
+        Some("text") | None => subscriber.init(),
+        Some(format) => {
+            subscriber.init();
+            tracing::warn!("Unknown [Log] Format {}, using text", format);
+        }
+    }
+}
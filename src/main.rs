@@ -1,64 +1,296 @@
-use crate::bot::{admin_handle, user_handle, AdminCommands, UserCommands};
-use crate::mongo::Mongo;
-use clap::{arg, command, Parser};
+// Partial builds leave some helpers unused
+#![cfg_attr(
+    not(all(feature = "store", feature = "telegram", feature = "http")),
+    allow(dead_code)
+)]
+#[cfg(feature = "telegram")]
+use crate::bot::{
+    admin_handle, callback_handle, payment_handle, pre_checkout_handle, user_handle, AdminCommands,
+    UserCommands,
+};
+#[cfg(feature = "telegram")]
+use crate::store::Store;
+use clap::Parser;
 use configparser::ini::Ini;
+#[cfg(feature = "store")]
 use std::collections::HashMap;
 use std::sync::Arc;
+#[cfg(feature = "telegram")]
 use teloxide::{prelude::*, utils::command::BotCommands};
 use tokio::sync::Mutex;
+#[cfg(feature = "store")]
+mod alerts;
+#[cfg(feature = "store")]
+mod archive;
+#[cfg(feature = "store")]
+mod audit;
+#[cfg(feature = "store")]
+mod backup;
+#[cfg(feature = "store")]
+mod billing;
+#[cfg(any(feature = "http", feature = "webhook"))]
+mod body;
+#[cfg(feature = "telegram")]
 mod bot;
+#[cfg(feature = "store")]
+mod bulk;
+#[cfg(feature = "store")]
+mod cache;
+#[cfg(feature = "store")]
+mod cli;
+#[cfg(feature = "store")]
+mod doctor;
+mod dryrun;
+#[cfg(feature = "store")]
+mod endpoint;
+mod error;
+#[cfg(feature = "store")]
+mod export;
+mod failures;
+mod features;
+#[cfg(feature = "file")]
+mod file;
+#[cfg(feature = "store")]
+mod firewall;
+#[cfg(feature = "hooks")]
+mod hooks;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "telegram")]
+mod i18n;
+#[cfg(feature = "store")]
+mod keys;
+#[cfg(feature = "http")]
+mod links;
+mod logging;
+#[cfg(feature = "mongo")]
+mod migrations;
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+mod mock;
+#[cfg(feature = "mongo")]
 mod mongo;
+mod notify;
+#[cfg(feature = "store")]
+mod peers;
+#[cfg(feature = "store")]
+mod probe;
+mod queue;
+#[cfg(feature = "store")]
+mod reconcile;
+#[cfg(feature = "store")]
+mod referral;
+mod reload;
+#[cfg(feature = "store")]
+mod repair;
+#[cfg(feature = "telegram")]
+mod roles;
+#[cfg(feature = "store")]
+mod rotation;
+#[cfg(feature = "store")]
+mod scheduler;
+#[cfg(feature = "store")]
+mod server;
+mod settings;
+#[cfg(feature = "store")]
+mod shaping;
+#[cfg(feature = "store")]
+mod shutdown;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+mod sql;
+#[cfg(feature = "store")]
+mod stale;
+#[cfg(feature = "store")]
+mod store;
+#[cfg(feature = "telegram")]
+mod throttle;
+#[cfg(feature = "store")]
+mod trial;
+#[cfg(feature = "store")]
+mod usage;
+#[cfg(all(target_os = "linux", not(feature = "mock")))]
+mod userspace;
+#[cfg(feature = "telegram")]
+mod verify;
+#[cfg(feature = "webhook")]
+mod webhook;
 mod wireguard;
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
-    log::info!("Starting bot...");
     let args = Args::parse();
-    let content = std::fs::read_to_string(&args.config).expect("Cannot read config file");
-    let config: Arc<Mutex<Ini>> = Arc::new(Mutex::new(Ini::new()));
-    config
-        .lock()
+    let config: Arc<Mutex<Ini>> = Arc::new(Mutex::new(
+        reload::read(&args.config).expect("Cannot read config file"),
+    ));
+    logging::init(&*config.lock().await);
+    match settings::Settings::parse(&*config.lock().await) {
+        Err(why) => {
+            eprintln!("{}", why);
+            std::process::exit(1);
+        }
+        Ok(settings) => tracing::debug!(
+            "{} interfaces, {} storage",
+            settings.interfaces.len(),
+            settings.storage.backend()
+        ),
+    }
+    if args.dry_run || dryrun::configured(&*config.lock().await) {
+        dryrun::enable();
+    }
+    features::check(&*config.lock().await);
+    failures::configure(&*config.lock().await);
+    #[cfg(any(feature = "mock", not(target_os = "linux")))]
+    tracing::warn!("Using the in-memory mock wg backend, interfaces are not touched");
+    #[cfg(feature = "store")]
+    let store = store::open(&*config.lock().await)
         .await
-        .read(content)
-        .expect("Cannot parse config");
-    let url = &config
-        .lock()
-        .await
-        .get("Mongo", "URL")
-        .expect("Cannot find db url");
-    let name = config
-        .lock()
-        .await
-        .get("Mongo", "Name")
-        .expect("Cannot find db name");
-    let table = config
-        .lock()
-        .await
-        .get("Mongo", "Table")
-        .expect("Cannot find db table");
-    let mongo = Mongo::new(url, name, table).await;
+        .expect("Cannot open storage");
+    #[cfg(feature = "store")]
+    if let Some(command) = args.command {
+        if let Err(why) = cli::run(command, &store, config, &args.config).await {
+            eprintln!("{}", why);
+            std::process::exit(1);
+        }
+        return;
+    }
+    #[cfg(feature = "mongo")]
+    {
+        let config = config.lock().await;
+        match migrations::run(&config, !migrations::on_start(&config)).await {
+            Err(why) => tracing::error!("Cannot migrate peers, they are read as they are: {}", why),
+            Ok(report) => report.iter().for_each(|line| tracing::info!("{}", line)),
+        }
+    }
+    tracing::info!("Starting bot...");
+    queue::start(&*config.lock().await);
+    #[cfg(unix)]
+    tokio::spawn(reload::watch(args.config.clone(), config.clone()));
+    #[cfg(feature = "telegram")]
+    if let Some(chat_id) = notify::chat(&*config.lock().await) {
+        notify::start(Bot::from_env(), chat_id);
+    }
+    #[cfg(feature = "hooks")]
+    hooks::start(&*config.lock().await);
+    #[cfg(all(target_os = "linux", not(feature = "mock")))]
+    let userspace = match userspace::start(config.clone()).await {
+        Err(why) => {
+            tracing::error!("Cannot start userspace WireGuard: {}", why);
+            std::process::exit(1);
+        }
+        Ok(children) => children,
+    };
+    #[cfg(all(target_os = "linux", not(feature = "mock")))]
+    tokio::spawn(userspace::watch(userspace));
+    #[cfg(feature = "store")]
+    reconcile::apply_all(&store, &wireguard::interfaces(&*config.lock().await)).await;
+    #[cfg(feature = "store")]
+    peers::refresh_rules(&store, config.clone()).await;
+    #[cfg(feature = "store")]
+    let probes: probe::Probes = Arc::new(Mutex::new(HashMap::new()));
+    #[cfg(feature = "store")]
+    tokio::spawn(probe::watch(store.clone(), config.clone(), probes.clone()));
+    #[cfg(feature = "http")]
+    tokio::spawn(http::serve(store.clone(), config.clone(), probes.clone()));
+    #[cfg(feature = "store")]
+    let timeout = shutdown::timeout(&*config.lock().await);
+    #[cfg(feature = "telegram")]
+    run_bot(store, config, probes).await;
+    #[cfg(all(feature = "store", not(feature = "telegram")))]
+    {
+        tokio::spawn(alerts::watch(store.clone(), config.clone()));
+        let ctx = scheduler::Context {
+            store: store.clone(),
+            config: config.clone(),
+        };
+        scheduler::Scheduler::new(ctx, &*config.lock().await).start();
+        tokio::spawn(reconcile::watch(store, config));
+        shutdown::ready();
+        shutdown::signal().await;
+    }
+    #[cfg(feature = "store")]
+    shutdown::drain(timeout).await;
+    #[cfg(not(feature = "store"))]
+    tracing::warn!("gimmewire was built without the `store` feature, nothing to run");
+}
+
+#[cfg(feature = "telegram")]
+async fn run_bot(store: Store, config: Arc<Mutex<Ini>>, probes: probe::Probes) {
     let bot = Bot::from_env();
+    #[cfg(feature = "webhook")]
+    let webhook =
+        webhook::Options::from_config(&*config.lock().await).expect("Cannot set up webhook");
+    let locales: i18n::Locales =
+        Arc::new(i18n::Catalog::load(&*config.lock().await).expect("Cannot load bot messages"));
+    tokio::spawn(reconcile::watch(store.clone(), config.clone()));
+    tokio::spawn(alerts::watch(store.clone(), config.clone()));
+    let ctx = scheduler::Context {
+        store: store.clone(),
+        config: config.clone(),
+        bot: bot.clone(),
+        locales: locales.clone(),
+    };
+    let scheduler = scheduler::Scheduler::new(ctx, &*config.lock().await);
+    scheduler.start();
     let chats: Arc<Mutex<HashMap<UserId, ChatId>>> = Arc::new(Mutex::new(HashMap::new()));
-    bot.set_my_commands(UserCommands::bot_commands())
-        .await
-        .unwrap();
-    let handler = Update::filter_message()
+    let commands = locales
+        .tr(None, None)
+        .commands(UserCommands::bot_commands());
+    if let Err(why) = bot.set_my_commands(commands).await {
+        tracing::error!("Cannot set bot commands: {}", why);
+    }
+    // Telegram shows users the command list of their own language when there is one
+    for lang in locales.languages() {
+        let commands = locales
+            .tr(Some(&lang), None)
+            .commands(UserCommands::bot_commands());
+        if let Err(why) = bot.set_my_commands(commands).language_code(&lang).await {
+            tracing::error!("Cannot set {} bot commands: {}", lang, why);
+        }
+    }
+    let handler = dptree::entry()
         .branch(
-            dptree::entry()
-                .filter_command::<UserCommands>()
-                .endpoint(user_handle),
+            Update::filter_message()
+                .branch(
+                    dptree::filter_map(|message: Message| message.successful_payment().cloned())
+                        .endpoint(payment_handle),
+                )
+                .branch(
+                    dptree::entry()
+                        .filter_command::<UserCommands>()
+                        .endpoint(user_handle),
+                )
+                .branch(
+                    dptree::entry()
+                        .filter_command::<AdminCommands>()
+                        .endpoint(admin_handle),
+                ),
         )
-        .branch(
-            dptree::entry()
-                .filter_command::<AdminCommands>()
-                .endpoint(admin_handle),
-        );
-    Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![mongo, chats, config])
-        .build()
-        .dispatch()
-        .await;
+        .branch(Update::filter_callback_query().endpoint(callback_handle))
+        .branch(Update::filter_pre_checkout_query().endpoint(pre_checkout_handle));
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![
+            store, chats, config, probes, locales, scheduler
+        ])
+        .build();
+    let token = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        // Stops taking updates, dispatching returns once handlers under way are done
+        if let Err(why) = token.shutdown() {
+            tracing::warn!("Cannot stop the bot: {}", why);
+        }
+    });
+    #[cfg(feature = "webhook")]
+    if let Some(options) = webhook {
+        let listener = webhook::listen(bot, options)
+            .await
+            .expect("Cannot set up webhook");
+        shutdown::ready();
+        let errors = LoggingErrorHandler::with_custom_text("Webhook update failed");
+        dispatcher.dispatch_with_listener(listener, errors).await;
+        return;
+    }
+    shutdown::ready();
+    dispatcher.dispatch().await;
 }
 
 #[derive(Parser, Debug)]
@@ -66,4 +298,10 @@ async fn main() {
 struct Args {
     #[arg(short, long)]
     config: String,
+    /// Log changes to interfaces instead of making them and work on a staging copy of the store
+    #[arg(long)]
+    dry_run: bool,
+    #[cfg(feature = "store")]
+    #[command(subcommand)]
+    command: Option<cli::Command>,
 }
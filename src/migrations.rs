@@ -0,0 +1,143 @@
+//! Upgrades of the peer documents in Mongo. Fields peers gained over releases read as their
+//! defaults but queries only see what is stored, so each migration fills in what its version
+//! added and stamps `schema_version`. They run when the bot starts unless `[Mongo] Migrate =
+//! false`, `gimmewire migrate --dry-run` tells what they would change and how to go back.
+use crate::error::Result;
+use crate::mongo::Mongo;
+use crate::settings::Storage;
+use crate::wireguard::{DEFAULT_INTERFACE, SCHEMA_VERSION};
+use configparser::ini::Ini;
+use mongodb::bson::{doc, Document};
+use mongodb::Collection;
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    /// What to do before an older release reads the documents again.
+    pub rollback: &'static str,
+    /// Documents it changes, out of those older than `version`.
+    changes: fn() -> Document,
+    /// `$set` stage of the update, a pipeline so values can come from other fields. Fields which
+    /// are there already are kept, so a migration can run again.
+    set: fn() -> Document,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "peers from before interfaces were configurable are put on wg0",
+        rollback: "nothing, older releases read a missing interface as wg0 too",
+        changes: || doc! { "interface": null },
+        set: || doc! { "interface": { "$ifNull": ["$interface", DEFAULT_INTERFACE] } },
+    },
+    Migration {
+        version: 2,
+        description: "missing forwards and tags become empty lists",
+        rollback: "nothing, older releases read empty lists like missing ones",
+        changes: || doc! { "$or": [{ "forwards": null }, { "tags": null }] },
+        set: || {
+            doc! {
+                "forwards": { "$ifNull": ["$forwards", []] },
+                "tags": { "$ifNull": ["$tags", []] },
+            }
+        },
+    },
+    Migration {
+        version: 3,
+        description: "keys_issued is the peer date where keys were never rotated",
+        rollback: "nothing, older releases read a missing keys_issued as the peer date too",
+        changes: || doc! { "keys_issued": null },
+        set: || doc! { "keys_issued": { "$ifNull": ["$keys_issued", "$date"] } },
+    },
+];
+
+/// Documents written before the version, including those without any.
+fn older(version: u32) -> Document {
+    doc! { "schema_version": { "$not": { "$gte": version } } }
+}
+
+/// Runs the migrations the peers need, or with `dry_run` only counts what they would change.
+/// A line for every migration which is due, for the log.
+pub async fn apply(
+    peers: &Collection<Document>,
+    dry_run: bool,
+) -> mongodb::error::Result<Vec<String>> {
+    let mut report = Vec::new();
+    let newer = peers
+        .count_documents(doc! { "schema_version": { "$gt": SCHEMA_VERSION } }, None)
+        .await?;
+    if newer > 0 {
+        report.push(format!(
+            "{} peers are newer than schema {}, follow the rollback notes of the release which wrote them",
+            newer, SCHEMA_VERSION
+        ));
+    }
+    for migration in MIGRATIONS {
+        let due = peers
+            .count_documents(older(migration.version), None)
+            .await?;
+        if due == 0 {
+            continue;
+        }
+        let changes = doc! { "$and": [older(migration.version), (migration.changes)()] };
+        let changed = peers.count_documents(changes, None).await?;
+        if !dry_run {
+            let mut set = (migration.set)();
+            set.insert("schema_version", migration.version);
+            peers
+                .update_many(older(migration.version), vec![doc! { "$set": set }], None)
+                .await?;
+        }
+        report.push(format!(
+            "{} {}: {}, {} of {} peers change. To roll back: {}",
+            match dry_run {
+                true => "Would migrate to",
+                false => "Migrated to",
+            },
+            migration.version,
+            migration.description,
+            changed,
+            due,
+            migration.rollback
+        ));
+    }
+    Ok(report)
+}
+
+/// Whether the bot migrates peers when it starts, `[Mongo] Migrate`.
+pub fn on_start(config: &Ini) -> bool {
+    config
+        .getbool("Mongo", "Migrate")
+        .unwrap_or(None)
+        .unwrap_or(true)
+}
+
+/// Migrates the peers of `[Storage]` when they are kept in Mongo, the staging copy in a dry run
+/// of the bot.
+pub async fn run(config: &Ini, dry_run: bool) -> Result<Vec<String>> {
+    let (url, name, table) = match Storage::from_config(config)? {
+        Storage::Mongo { url, name, table } => (url, name, table),
+        _ => return Ok(vec![]),
+    };
+    let table = match crate::dryrun::on() {
+        true => format!("{}{}", table, crate::dryrun::STAGING),
+        false => table,
+    };
+    let settings = crate::mongo::Settings::from_config(config);
+    let mongo = Mongo::new(&url, name, table, settings).await?;
+    mongo.migrate(dry_run).await
+}
+
+#[cfg(test)]
+#[test]
+fn migrations() {
+    let versions: Vec<u32> = MIGRATIONS
+        .iter()
+        .map(|migration| migration.version)
+        .collect();
+    assert!(versions == (1..=SCHEMA_VERSION).collect::<Vec<u32>>());
+    assert!(MIGRATIONS
+        .iter()
+        .all(|migration| !(migration.set)().contains_key("schema_version")));
+    assert!(older(2) == doc! { "schema_version": { "$not": { "$gte": 2 } } });
+}
@@ -0,0 +1,174 @@
+//! In-memory stand-in for `/usr/bin/wg`, used on non-Linux hosts and with the `mock` feature.
+//! Keys only look like WireGuard keys, they are not real Curve25519 keys.
+use crate::error::{GimmewireError, Result};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+const SERVER_KEY: &str = "bW9ja21vY2ttb2NrbW9ja21vY2ttb2NrbW9ja21vY2s=";
+
+/// Public keys set by `wg set <interface> private-key`, `SERVER_KEY` until then.
+static SERVER_PUBLIC_KEYS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Private keys set by `wg set <interface> private-key`.
+static SERVER_PRIVATE_KEYS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Peers on the simulated interfaces: interface -> public key -> allowed ips.
+static INTERFACES: Mutex<BTreeMap<String, BTreeMap<String, String>>> = Mutex::new(BTreeMap::new());
+
+/// Fails like wg does for what it doesn't simulate, which counts towards failure alerts.
+pub fn wg(args: &[&str], input: Option<&str>) -> Result<String> {
+    let command = || format!("wg {}", args.join(" "));
+    crate::failures::track("wg", command, simulate(args, input))
+}
+
+fn simulate(args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut interfaces = INTERFACES.lock().unwrap();
+    let mut server_keys = SERVER_PUBLIC_KEYS.lock().unwrap();
+    // Every wg subcommand but genkey and pubkey takes the interface second
+    let name = args.get(1).copied().unwrap_or_default();
+    let server_key = server_keys
+        .get(name)
+        .cloned()
+        .unwrap_or_else(|| SERVER_KEY.to_string());
+    let interface = interfaces.entry(name.to_string()).or_default();
+    match args {
+        ["genkey"] => Ok(format!("{}\n", base64::encode(rand::random::<[u8; 32]>()))),
+        ["pubkey"] => Ok(format!("{}\n", pubkey(input.unwrap_or(""))?)),
+        ["set", _, "private-key", "/dev/stdin"] => {
+            server_keys.insert(name.to_string(), pubkey(input.unwrap_or(""))?);
+            let private_key = input.unwrap_or_default().trim().to_string();
+            SERVER_PRIVATE_KEYS
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), private_key);
+            Ok(String::new())
+        }
+        ["set", _, "peer", key, "allowed-ips", ips] => {
+            interface.insert(key.to_string(), ips.to_string());
+            Ok(String::new())
+        }
+        ["set", _, "peer", key, "remove"] => {
+            interface.remove(*key);
+            Ok(String::new())
+        }
+        ["syncconf", _, "/dev/stdin"] => {
+            interface.clear();
+            let mut key = None;
+            for line in input.unwrap_or_default().lines() {
+                match line.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                    Some(("PublicKey", value)) => key = Some(value.to_string()),
+                    Some(("AllowedIPs", ips)) => {
+                        if let Some(key) = key.take() {
+                            interface.insert(key, ips.to_string());
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Ok(String::new())
+        }
+        ["showconf", _] => {
+            let mut conf = "[Interface]\nListenPort = 51820\n".to_string();
+            for (key, ips) in interface.iter() {
+                conf.push_str(&format!(
+                    "\n[Peer]\nPublicKey = {}\nAllowedIPs = {}\n",
+                    key, ips
+                ));
+            }
+            Ok(conf)
+        }
+        ["show", _, "public-key"] => Ok(format!("{}\n", server_key)),
+        ["show", _, "private-key"] => Ok(format!(
+            "{}\n",
+            SERVER_PRIVATE_KEYS
+                .lock()
+                .unwrap()
+                .get(name)
+                .map_or("(none)", String::as_str)
+        )),
+        ["show", _, "dump"] => {
+            let mut dump = format!("(hidden)\t{}\t51820\toff\n", server_key);
+            for (key, ips) in interface.iter() {
+                dump.push_str(&format!("{}\t(none)\t(none)\t{}\t0\t0\t0\toff\n", key, ips));
+            }
+            Ok(dump)
+        }
+        _ => Err(GimmewireError::WgCommandFailed {
+            command: format!("wg {}", args.join(" ")),
+            message: "unsupported by the mock".to_string(),
+        }),
+    }
+}
+
+/// Stand-in for `nft -f -`, the rules are only logged.
+pub fn nft(host: Option<&str>, script: &str) -> Result<()> {
+    tracing::debug!("nft on {}:\n{}", host.unwrap_or("localhost"), script);
+    Ok(())
+}
+
+/// Stand-in for tc, the commands are only logged.
+pub fn tc(host: Option<&str>, args: &[&str], input: Option<&str>) -> Result<String> {
+    tracing::debug!(
+        "tc {} on {}:\n{}",
+        args.join(" "),
+        host.unwrap_or("localhost"),
+        input.unwrap_or_default()
+    );
+    Ok(String::new())
+}
+
+fn pubkey(private_key: &str) -> Result<String> {
+    let private_key = base64::decode(private_key.trim())
+        .map_err(|why| GimmewireError::KeyGeneration(why.to_string()))?;
+    let public_key: Vec<u8> = private_key.iter().rev().map(|b| b ^ 0x5a).collect();
+    Ok(base64::encode(public_key))
+}
+
+#[cfg(test)]
+#[test]
+fn interface_state() {
+    wg(
+        &[
+            "set",
+            "wg0",
+            "peer",
+            "mockpeer",
+            "allowed-ips",
+            "10.0.0.9/32",
+        ],
+        None,
+    )
+    .unwrap();
+    assert!(wg(&["show", "wg0", "dump"], None)
+        .unwrap()
+        .contains("mockpeer\t(none)\t(none)\t10.0.0.9/32"));
+    wg(&["set", "wg0", "peer", "mockpeer", "remove"], None).unwrap();
+    assert!(!wg(&["show", "wg0", "dump"], None)
+        .unwrap()
+        .contains("mockpeer"));
+    wg(
+        &[
+            "set",
+            "wg1",
+            "peer",
+            "otherpeer",
+            "allowed-ips",
+            "10.1.0.2/32",
+        ],
+        None,
+    )
+    .unwrap();
+    assert!(!wg(&["show", "wg0", "dump"], None)
+        .unwrap()
+        .contains("otherpeer"));
+    let private_key = wg(&["genkey"], None).unwrap();
+    wg(
+        &["set", "wg0", "private-key", "/dev/stdin"],
+        Some(&private_key),
+    )
+    .unwrap();
+    assert!(
+        wg(&["show", "wg0", "public-key"], None).unwrap()
+            == wg(&["pubkey"], Some(&private_key)).unwrap()
+    );
+}
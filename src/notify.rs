@@ -0,0 +1,57 @@
+//! Messages for operators, delivered by the bot to `[Bot] NotifyChat`, the admin chat by default.
+#[cfg(feature = "telegram")]
+use configparser::ini::Ini;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+#[cfg(feature = "telegram")]
+use teloxide::prelude::*;
+use tokio::sync::mpsc::UnboundedSender;
+
+static SENDER: OnceLock<UnboundedSender<String>> = OnceLock::new();
+/// Messages queued and not delivered yet.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Queues a message for operators, it is dropped when no bot delivers them.
+pub fn send(msg: impl Into<String>) {
+    if let Some(sender) = SENDER.get() {
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        if sender.send(msg.into()).is_err() {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Waits up to `timeout` for queued messages to be delivered, before shutting down.
+pub async fn flush(timeout: std::time::Duration) {
+    let started = std::time::Instant::now();
+    while PENDING.load(Ordering::SeqCst) > 0 && started.elapsed() < timeout {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+/// Chat which receives notifications, a channel id works as well.
+#[cfg(feature = "telegram")]
+pub fn chat(config: &Ini) -> Option<ChatId> {
+    config
+        .getint("Bot", "NotifyChat")
+        .unwrap_or(None)
+        .or(config.getint("Bot", "AdminId").unwrap_or(None))
+        .map(ChatId)
+}
+
+/// Starts delivering messages queued by `send` to the chat.
+#[cfg(feature = "telegram")]
+pub fn start(bot: Bot, chat_id: ChatId) {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<String>();
+    if SENDER.set(sender).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        while let Some(msg) = receiver.recv().await {
+            if let Err(why) = bot.send_message(chat_id, msg).await {
+                tracing::error!("Cannot send notification: {}", why);
+            }
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    });
+}
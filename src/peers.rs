@@ -0,0 +1,1064 @@
+use crate::audit;
+use crate::error::{GimmewireError, Result};
+use crate::rotation::Rotation;
+use crate::scheduler::Context;
+use crate::store::{Filter, Status, Store};
+use crate::wireguard::{self, Interface, Peer};
+use crate::{firewall, queue, shaping};
+use bson::{oid::ObjectId, DateTime};
+use configparser::ini::Ini;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Creates and provisions a peer which is not linked to a Telegram user, placed by `[Placement]` if no interface is given.
+#[tracing::instrument(skip_all, fields(peer = %name))]
+pub async fn create(
+    name: String,
+    expires: Option<DateTime>,
+    allowed_ips: Option<String>,
+    interface: Option<String>,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<Peer> {
+    valid_name(&name)?;
+    if store.find_by_username(&name).await?.is_some() {
+        return Err(GimmewireError::PeerExists(name.to_string()));
+    }
+    let mut peer = Peer::new(0, name);
+    peer.id = Some(ObjectId::new());
+    peer.expires = expires;
+    peer.allowed_ips = allowed_ips;
+    peer.interface = match interface {
+        Some(interface) => interface,
+        None => placement(store, config.clone()).await?,
+    };
+    store.add(&peer).await?;
+    // A failed peer would keep its name taken
+    if let Err(why) = provision(&mut peer, store, config).await {
+        if let Err(undo) = store.delete(&peer).await {
+            tracing::error!(
+                "Cannot remove unprovisioned peer {}: {}",
+                peer.username,
+                undo
+            );
+        }
+        return Err(why);
+    }
+    Ok(peer)
+}
+
+/// Creates a peer which is removed after `hours` and only routes `[Temporary] AllowedIPs`.
+pub async fn grant_temporary(
+    name: String,
+    hours: u64,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<Peer> {
+    valid_name(&name)?;
+    if hours == 0 {
+        return Err(GimmewireError::Invalid(
+            "Temporary access needs at least an hour".to_string(),
+        ));
+    }
+    let allowed_ips = config
+        .lock()
+        .await
+        .get("Temporary", "AllowedIPs")
+        .unwrap_or_else(|| "10.0.0.0/16".to_string());
+    let expires = hours
+        .checked_mul(60 * 60 * 1000)
+        .and_then(|millis| i64::try_from(millis).ok())
+        .and_then(|millis| DateTime::now().timestamp_millis().checked_add(millis))
+        .ok_or_else(|| {
+            GimmewireError::Invalid(format!("Temporary access for {} hours is too long", hours))
+        })?;
+    let expires = DateTime::from_millis(expires);
+    create(name, Some(expires), Some(allowed_ips), None, store, config).await
+}
+
+/// Stores peers of the interface missing from the db under placeholder names, returns the new peers.
+pub async fn import(showconf: &str, interface: &str, store: &Store) -> Result<Vec<Peer>> {
+    let mut known: Vec<String> = store
+        .get_peers()
+        .await?
+        .into_iter()
+        .flat_map(|peer| peer.public_key)
+        .collect();
+    known.extend(
+        store
+            .get_archived()
+            .await?
+            .into_iter()
+            .flat_map(|peer| peer.public_key),
+    );
+    let mut imported = Vec::new();
+    for (public_key, ip) in wireguard::parse_showconf(showconf) {
+        if known.contains(&public_key) {
+            continue;
+        }
+        let name: String = public_key
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .take(8)
+            .collect();
+        let mut peer = Peer::new(0, format!("imported-{}", name));
+        peer.id = Some(ObjectId::new());
+        peer.public_key = Some(public_key);
+        peer.ip = ip;
+        peer.interface = interface.to_string();
+        store.add(&peer).await?;
+        imported.push(peer);
+    }
+    Ok(imported)
+}
+
+/// Links an unclaimed peer, e.g. an imported one, to a Telegram user.
+pub async fn claim(name: &str, user_id: u64, username: String, store: &Store) -> Result<Peer> {
+    let mut peer = match store.find_by_username(name).await? {
+        None => return Err(GimmewireError::PeerNotFound(name.to_string())),
+        Some(peer) => peer,
+    };
+    if peer.user_id != 0 {
+        return Err(GimmewireError::Invalid(format!(
+            "Peer {} is already linked",
+            name
+        )));
+    }
+    if store.find_by_id(user_id).await?.is_some() {
+        return Err(GimmewireError::Invalid(format!(
+            "User {} already has a peer",
+            user_id
+        )));
+    }
+    peer.user_id = user_id;
+    peer.username = username;
+    store.update(&peer).await?;
+    Ok(peer)
+}
+
+/// Tags are kept lowercase, without a leading `#`.
+fn tag_name(tag: &str) -> Option<String> {
+    let tag = tag.trim().trim_start_matches('#').to_lowercase();
+    Some(tag).filter(|tag| !tag.is_empty())
+}
+
+/// Adds the tags to the peer, or takes them off with `remove`.
+pub async fn tag(name: &str, tags: &[&str], remove: bool, store: &Store) -> Result<Peer> {
+    let mut peer = match store.find_by_username(name).await? {
+        None => return Err(GimmewireError::PeerNotFound(name.to_string())),
+        Some(peer) => peer,
+    };
+    for tag in tags.iter().filter_map(|tag| tag_name(tag)) {
+        match remove {
+            true => peer.tags.retain(|other| *other != tag),
+            false if !peer.tags.contains(&tag) => peer.tags.push(tag),
+            false => (),
+        }
+    }
+    store.update(&peer).await?;
+    Ok(peer)
+}
+
+/// Names which are safe as config file names and in the shell of other nodes.
+pub fn valid_name(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    match !name.is_empty() && name.len() <= 32 && name.chars().all(valid) {
+        true => Ok(()),
+        false => Err(GimmewireError::Invalid(
+            "Peer names are up to 32 letters, digits, - and _".to_string(),
+        )),
+    }
+}
+
+/// Gives the peer a new name, which its next configs are saved under. Keys, address and
+/// everything else stay, the config saved under the old name is removed.
+pub async fn rename(name: &str, new_name: &str, store: &Store) -> Result<Peer> {
+    valid_name(new_name)?;
+    let mut peer = match store.find_by_username(name).await? {
+        None => return Err(GimmewireError::PeerNotFound(name.to_string())),
+        Some(peer) => peer,
+    };
+    if store.find_by_username(new_name).await?.is_some() {
+        return Err(GimmewireError::PeerExists(new_name.to_string()));
+    }
+    let old = wireguard::conf_path(&peer);
+    peer.username = new_name.to_string();
+    store.update(&peer).await?;
+    if let Ok(path) = old {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(peer)
+}
+
+/// Sets the notes of the peer, None or only whitespace clears them.
+pub async fn note(name: &str, notes: Option<&str>, store: &Store) -> Result<Peer> {
+    let mut peer = match store.find_by_username(name).await? {
+        None => return Err(GimmewireError::PeerNotFound(name.to_string())),
+        Some(peer) => peer,
+    };
+    peer.notes = notes
+        .map(|notes| notes.trim().to_string())
+        .filter(|notes| !notes.is_empty());
+    store.update(&peer).await?;
+    Ok(peer)
+}
+
+/// When the peer's access ends, by `expires` or the paid subscription.
+pub fn access_ends(peer: &Peer) -> Option<DateTime> {
+    peer.expires.or(peer
+        .subscription
+        .as_ref()
+        .map(|subscription| subscription.paid_until))
+}
+
+/// What /find looks for, every term must match.
+#[derive(Debug, Default, PartialEq)]
+pub struct Search {
+    pub filter: Filter,
+    /// Part of the name, any case.
+    pub name: Option<String>,
+    /// Days within which access ends, see `access_ends`.
+    pub expires: Option<i64>,
+}
+
+impl Search {
+    /// Parses terms like `tag=vip name=ali ip=10.0.0.5 expires=7`.
+    pub fn parse(terms: &[&str]) -> Result<Search> {
+        let mut search = Search::default();
+        for term in terms.iter().filter(|term| !term.is_empty()) {
+            let invalid = || {
+                GimmewireError::Invalid(format!(
+                    "Cannot search by {}, expected tag=, name=, ip= or expires=<days>",
+                    term
+                ))
+            };
+            let (key, value) = term.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "tag" => search.filter.tag = Some(tag_name(value).ok_or_else(invalid)?),
+                "name" => search.name = Some(value.to_lowercase()),
+                "ip" => search.filter.ip = Some(value.parse().map_err(|_| invalid())?),
+                "expires" => search.expires = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(search)
+    }
+
+    pub fn matches(&self, peer: &Peer) -> bool {
+        let ends = access_ends(peer);
+        let until = |days: i64| DateTime::now().timestamp_millis() + days * 24 * 60 * 60 * 1000;
+        self.filter.matches(peer)
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| peer.username.to_lowercase().contains(name))
+            && self
+                .expires
+                .is_none_or(|days| ends.is_some_and(|ends| ends.timestamp_millis() <= until(days)))
+    }
+
+    /// Peers which match, the db does what it can of the search.
+    pub async fn run(&self, store: &Store) -> Result<Vec<Peer>> {
+        Ok(store
+            .find_peers(&self.filter, 0, None)
+            .await?
+            .into_iter()
+            .filter(|peer| self.matches(peer))
+            .collect())
+    }
+}
+
+/// Active peers the user may have: the limit an admin set on any of them, otherwise
+/// `[Bot] PeerLimit`.
+pub fn peer_limit(peers: &[Peer], config: &Ini) -> u64 {
+    peers
+        .iter()
+        .find_map(|peer| peer.peer_limit)
+        .map(u64::from)
+        .unwrap_or_else(|| {
+            config
+                .getuint("Bot", "PeerLimit")
+                .unwrap_or(None)
+                .unwrap_or(3)
+        })
+}
+
+/// Stores another device of the owner's user, named `<owner>-<device>` and not provisioned yet.
+/// A device the user already has is returned as it is, so adding it twice takes no address.
+/// Fails when the user already has as many peers as `peer_limit`.
+pub async fn add_device(
+    owner: &Peer,
+    device: &str,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<Peer> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-';
+    if device.is_empty() || device.len() > 16 || !device.chars().all(valid) {
+        return Err(GimmewireError::Invalid(
+            "Device names are up to 16 letters, digits and -".to_string(),
+        ));
+    }
+    let name = format!("{}-{}", owner.username, device);
+    match store.find_by_username(&name).await? {
+        Some(existing) if existing.user_id == owner.user_id => return Ok(existing),
+        Some(_) => return Err(GimmewireError::PeerExists(name)),
+        None => (),
+    }
+    let filter = Filter {
+        user_id: Some(owner.user_id),
+        ..Filter::default()
+    };
+    let devices = store.find_peers(&filter, 0, None).await?;
+    let limit = peer_limit(&devices, &*config.lock().await);
+    if devices.len() as u64 >= limit {
+        return Err(GimmewireError::PeerLimit(limit));
+    }
+    let mut peer = Peer::new(owner.user_id, name);
+    peer.id = Some(ObjectId::new());
+    peer.language = owner.language.clone();
+    peer.interface = owner.interface.clone();
+    peer.peer_limit = owner.peer_limit;
+    store.add(&peer).await?;
+    Ok(peer)
+}
+
+/// The user's peer on each of the interfaces, so they can switch regions by turning on another
+/// config: the one they have there already, otherwise a new device named after the interface
+/// which isn't provisioned yet. New devices count against `peer_limit`.
+pub async fn bundle(
+    owner: &Peer,
+    interfaces: &[String],
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<Vec<Peer>> {
+    let filter = Filter {
+        user_id: Some(owner.user_id),
+        ..Filter::default()
+    };
+    let devices = store.find_peers(&filter, 0, None).await?;
+    let mut bundle = Vec::new();
+    for interface in interfaces {
+        let existing = devices
+            .iter()
+            .filter(|device| device.interface == *interface)
+            .min_by_key(|device| device.username != owner.username);
+        if let Some(device) = existing {
+            bundle.push(device.clone());
+            continue;
+        }
+        let name: String = interface
+            .to_lowercase()
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c,
+                false => '-',
+            })
+            .collect();
+        let mut device = add_device(owner, &name, store, config.clone()).await?;
+        if device.interface != *interface {
+            if device.public_key.is_some() {
+                return Err(GimmewireError::Invalid(format!(
+                    "Device {} is on {} already",
+                    device.username, device.interface
+                )));
+            }
+            device.interface = interface.clone();
+            store.update(&device).await?;
+        }
+        bundle.push(device);
+    }
+    Ok(bundle)
+}
+
+/// Sets how many active peers the user of the named peer may have, None goes back to
+/// `[Bot] PeerLimit`. Every peer of the user keeps the limit.
+pub async fn set_limit(name: &str, limit: Option<u32>, store: &Store) -> Result<()> {
+    let peer = match store.find_by_username(name).await? {
+        None => return Err(GimmewireError::PeerNotFound(name.to_string())),
+        Some(peer) => peer,
+    };
+    let mut peers = match peer.user_id {
+        0 => vec![peer],
+        user_id => {
+            let filter = Filter {
+                user_id: Some(user_id),
+                ..Filter::default()
+            };
+            store.find_peers(&filter, 0, None).await?
+        }
+    };
+    for peer in &mut peers {
+        peer.peer_limit = limit;
+        store.update(peer).await?;
+    }
+    Ok(())
+}
+
+/// Interface for a new peer by `[Placement] Strategy`, see `place`.
+pub async fn placement(store: &Store, config: Arc<Mutex<Ini>>) -> Result<String> {
+    let (strategy, interfaces, main) = {
+        let config = config.lock().await;
+        (
+            config
+                .get("Placement", "Strategy")
+                .unwrap_or_else(|| "main".to_string()),
+            wireguard::interfaces(&config),
+            wireguard::main_interface(&config),
+        )
+    };
+    Ok(place(&strategy, &interfaces, &store.get_peers().await?).unwrap_or(main))
+}
+
+/// `main` keeps everyone on the `[Peer]` interface, `round-robin` takes the interface after the one of
+/// the newest peer and `least-loaded` the one with the smallest share of its pool in use.
+fn place(strategy: &str, interfaces: &[Interface], peers: &[Peer]) -> Option<String> {
+    match strategy {
+        "round-robin" => {
+            let last = peers.iter().max_by_key(|peer| peer.date);
+            let next = match last.and_then(|last| {
+                interfaces
+                    .iter()
+                    .position(|interface| interface.name == last.interface)
+            }) {
+                None => 0,
+                Some(i) => (i + 1) % interfaces.len(),
+            };
+            interfaces.get(next).map(|interface| interface.name.clone())
+        }
+        "least-loaded" => interfaces
+            .iter()
+            .map(|interface| {
+                let used = peers
+                    .iter()
+                    .filter(|peer| peer.interface == interface.name)
+                    .count();
+                (
+                    used as f64 / interface.addresses().count() as f64,
+                    interface,
+                )
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, interface)| interface.name.clone()),
+        "main" => None,
+        _ => {
+            tracing::error!("Unknown placement strategy {}", strategy);
+            None
+        }
+    }
+}
+
+/// Addresses of archived peers are not handed out again for this long.
+const IP_REUSE_DAYS: i64 = 30;
+
+/// Active peers of the interface plus recently archived ones, whose addresses are still reserved.
+pub async fn allocated(store: &Store, interface: &str) -> Result<Vec<Peer>> {
+    let since = DateTime::now().timestamp_millis() - IP_REUSE_DAYS * 24 * 60 * 60 * 1000;
+    let mut filter = Filter {
+        interface: Some(interface.to_string()),
+        ..Filter::default()
+    };
+    let mut peers = store.find_peers(&filter, 0, None).await?;
+    filter.status = Some(Status::Archived);
+    peers.extend(
+        store
+            .find_peers(&filter, 0, None)
+            .await?
+            .into_iter()
+            .filter(|peer| peer.archived.map(|date| date.timestamp_millis() > since) == Some(true)),
+    );
+    Ok(peers)
+}
+
+/// Issues fresh keys and an address for the peer, applies it to its interface and stores it.
+#[tracing::instrument(skip_all, fields(peer = %peer.username, interface = %peer.interface))]
+pub async fn provision(peer: &mut Peer, store: &Store, config: Arc<Mutex<Ini>>) -> Result<()> {
+    queue::exclusive(async {
+        unsuspended(peer)?;
+        let interface = wireguard::find_interface(&*config.lock().await, &peer.interface)?;
+        if peer.public_key.is_some() {
+            wireguard::remove_peer(peer, &interface).await?;
+        }
+        let allocated = allocated(store, &interface.name).await?;
+        wireguard::add_peer(peer, &allocated, &interface).await?;
+        peer.keys_issued = Some(DateTime::now());
+        if let Err(why) = store.update(peer).await {
+            let _ = wireguard::remove_peer(peer, &interface).await; // Something like dummy rollback
+            return Err(why);
+        }
+        refresh_rules(store, config).await;
+        Ok(())
+    })
+    .await
+}
+
+/// Takes a peer whose config never reached its user off its interface and forgets its keys, so
+/// asking again issues a new config instead of resending one the interface doesn't know.
+pub async fn withdraw(peer: &mut Peer, store: &Store, config: Arc<Mutex<Ini>>) -> Result<()> {
+    queue::exclusive(async {
+        let interface = wireguard::find_interface(&*config.lock().await, &peer.interface)?;
+        if peer.public_key.is_some() {
+            wireguard::remove_peer(peer, &interface).await?;
+        }
+        peer.public_key = None;
+        peer.private_key = None;
+        store.update(peer).await?;
+        refresh_rules(store, config).await;
+        Ok(())
+    })
+    .await
+}
+
+/// Gives an existing peer fresh keys on the same address, e.g. when the client key leaked.
+#[tracing::instrument(skip_all, fields(peer = %peer.username, interface = %peer.interface))]
+pub async fn rotate(
+    peer: &mut Peer,
+    reason: &str,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<()> {
+    queue::exclusive(async {
+        if peer.public_key.is_none() || peer.ip.is_none() {
+            return Err(GimmewireError::Invalid(format!(
+                "Peer {} has no config yet",
+                peer.username
+            )));
+        }
+        unsuspended(peer)?;
+        let interface = wireguard::find_interface(&*config.lock().await, &peer.interface)?;
+        let old = peer.clone();
+        wireguard::rotate_keys(peer, &interface).await?;
+        peer.keys_issued = Some(DateTime::now());
+        if let Err(why) = store.update(peer).await {
+            // Put the old key back, the db still has it
+            let _ = wireguard::apply_peer(&old, &interface).await;
+            let _ = wireguard::remove_peer(peer, &interface).await;
+            *peer = old;
+            return Err(why);
+        }
+        let _ = store
+            .log_rotation(&Rotation {
+                peer_id: peer.id,
+                username: peer.username.clone(),
+                old_key: old.public_key,
+                new_key: peer.public_key.clone(),
+                date: DateTime::now(),
+                reason: reason.to_string(),
+            })
+            .await;
+        Ok(())
+    })
+    .await
+}
+
+/// Gives the peer a fixed address of its interface pool in place of the allocated one, e.g. for
+/// firewall rules which name it. Its client config has to be imported again.
+#[tracing::instrument(skip_all, fields(peer = %peer.username, interface = %peer.interface))]
+pub async fn assign_ip(
+    peer: &mut Peer,
+    ip: Ipv4Addr,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<()> {
+    queue::exclusive(async {
+        let interface = wireguard::find_interface(&*config.lock().await, &peer.interface)?;
+        if !interface.addresses().any(|address| address == ip) {
+            return Err(GimmewireError::Invalid(format!(
+                "{} is not a peer address of the {} pool",
+                ip, interface.name
+            )));
+        }
+        if let Some(holder) = allocated(store, &interface.name)
+            .await?
+            .into_iter()
+            .find(|other| other.ip == Some(ip) && other.username != peer.username)
+        {
+            return Err(GimmewireError::Invalid(format!(
+                "{} is taken by {}",
+                ip, holder.username
+            )));
+        }
+        let previous = (peer.ip, peer.fixed_ip);
+        peer.ip = Some(ip);
+        peer.fixed_ip = true;
+        // Suspended peers and those without a config yet get the address when they are applied
+        let applied = peer.public_key.is_some() && peer.suspended.is_none();
+        if applied {
+            if let Err(why) = wireguard::apply_peer(peer, &interface).await {
+                (peer.ip, peer.fixed_ip) = previous;
+                return Err(why);
+            }
+        }
+        if let Err(why) = store.update(peer).await {
+            (peer.ip, peer.fixed_ip) = previous;
+            if applied {
+                let _ = wireguard::apply_peer(peer, &interface).await;
+            }
+            return Err(why);
+        }
+        refresh_rules(store, config).await;
+        Ok(())
+    })
+    .await
+}
+
+/// Moves the peer to another interface with a new address and keys, the old config stops working.
+#[tracing::instrument(skip_all, fields(peer = %peer.username, interface = %peer.interface))]
+pub async fn switch(
+    peer: &mut Peer,
+    interface: &str,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<()> {
+    queue::exclusive(async {
+        let (old, new) = {
+            let config = config.lock().await;
+            (
+                wireguard::find_interface(&config, &peer.interface).ok(),
+                wireguard::find_interface(&config, interface)?,
+            )
+        };
+        if peer.interface == new.name {
+            return Err(GimmewireError::Invalid(format!(
+                "Peer {} is already on {}",
+                peer.username, new.name
+            )));
+        }
+        let previous = peer.clone();
+        if let (Some(old), true) = (&old, peer.public_key.is_some()) {
+            wireguard::remove_peer(peer, old).await?;
+        }
+        peer.public_key = None;
+        peer.private_key = None;
+        peer.ip = None;
+        peer.fixed_ip = false;
+        peer.interface = new.name;
+        if let Err(why) = provision(peer, store, config).await {
+            if let (Some(old), true) = (&old, previous.public_key.is_some()) {
+                let _ = wireguard::apply_peer(&previous, old).await;
+            }
+            *peer = previous;
+            return Err(why);
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Takes an unpaid peer or one with a finished trial off its interface, keeping its keys and address.
+#[tracing::instrument(skip_all, fields(peer = %peer.username, interface = %peer.interface))]
+pub async fn suspend(peer: &mut Peer, store: &Store, config: Arc<Mutex<Ini>>) -> Result<()> {
+    queue::exclusive(async {
+        let interface = wireguard::find_interface(&*config.lock().await, &peer.interface)?;
+        if peer.public_key.is_some() {
+            wireguard::remove_peer(peer, &interface).await?;
+        }
+        peer.suspended = Some(DateTime::now());
+        if let Err(why) = store.update(peer).await {
+            peer.suspended = None;
+            if peer.public_key.is_some() {
+                let _ = wireguard::apply_peer(peer, &interface).await;
+            }
+            return Err(why);
+        }
+        refresh_rules(store, config).await;
+        Ok(())
+    })
+    .await
+}
+
+/// Puts a suspended peer back on its interface with its old config.
+#[tracing::instrument(skip_all, fields(peer = %peer.username, interface = %peer.interface))]
+pub async fn resume(peer: &mut Peer, store: &Store, config: Arc<Mutex<Ini>>) -> Result<()> {
+    queue::exclusive(async {
+        let interface = wireguard::find_interface(&*config.lock().await, &peer.interface)?;
+        let suspended = peer.suspended.take();
+        if peer.public_key.is_some() {
+            if let Err(why) = wireguard::apply_peer(peer, &interface).await {
+                peer.suspended = suspended;
+                return Err(why);
+            }
+        }
+        if let Err(why) = store.update(peer).await {
+            peer.suspended = suspended;
+            let _ = wireguard::remove_peer(peer, &interface).await;
+            return Err(why);
+        }
+        refresh_rules(store, config).await;
+        Ok(())
+    })
+    .await
+}
+
+/// Brings the firewall rules and rate limits of the nodes in line with the db, after peers were
+/// added, moved or removed. Failures are reported, the peer changes stay.
+pub async fn refresh_rules(store: &Store, config: Arc<Mutex<Ini>>) {
+    if let Err(why) = firewall::sync(store, config.clone()).await {
+        tracing::error!("Cannot update the firewall rules: {}", why);
+    }
+    if let Err(why) = shaping::sync(store, config).await {
+        tracing::error!("Cannot update the rate limits: {}", why);
+    }
+}
+
+/// The peer as stored now, None when it was revoked or given new keys since `peer` was read.
+/// Jobs read it again in their turn, see `queue::exclusive`, so their writes don't undo changes
+/// made since they listed the peers.
+pub async fn current(peer: &Peer, store: &Store) -> Result<Option<Peer>> {
+    Ok(store
+        .find_by_username(&peer.username)
+        .await?
+        .filter(|stored| stored.id == peer.id && stored.public_key == peer.public_key))
+}
+
+fn unsuspended(peer: &Peer) -> Result<()> {
+    match peer.suspended {
+        Some(_) => Err(GimmewireError::Invalid(format!(
+            "Peer {} is suspended",
+            peer.username
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Removes the peer from its interface and archives it in the db.
+#[tracing::instrument(skip_all, fields(peer = %peer.username, interface = %peer.interface))]
+pub async fn revoke(
+    peer: &mut Peer,
+    reason: &str,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<()> {
+    queue::exclusive(async {
+        if peer.public_key.is_some() {
+            // Peers of interfaces dropped from the config are only archived
+            match wireguard::find_interface(&*config.lock().await, &peer.interface) {
+                Err(why) => tracing::warn!("{}", why),
+                Ok(interface) => {
+                    let _ = wireguard::remove_peer(peer, &interface).await;
+                }
+            }
+        }
+        peer.archived = Some(DateTime::now());
+        peer.archive_reason = Some(reason.to_string());
+        // Forwarded ports go back to the node
+        peer.forwards.clear();
+        store.update(peer).await?;
+        refresh_rules(store, config).await;
+        Ok(())
+    })
+    .await
+}
+
+/// Brings the latest archived peer with this name back, on a new address if its old one is taken.
+pub async fn unarchive(name: &str, store: &Store, config: Arc<Mutex<Ini>>) -> Result<Peer> {
+    queue::exclusive(async {
+        let mut peer = match store
+            .get_archived()
+            .await?
+            .into_iter()
+            .filter(|peer| peer.username == name)
+            .max_by_key(|peer| peer.archived)
+        {
+            None => {
+                return Err(GimmewireError::PeerNotFound(format!(
+                    "{} in the archive",
+                    name
+                )))
+            }
+            Some(peer) => peer,
+        };
+        if store.find_by_username(name).await?.is_some() {
+            return Err(GimmewireError::PeerExists(name.to_string()));
+        }
+        if peer.user_id != 0 && store.find_by_id(peer.user_id).await?.is_some() {
+            return Err(GimmewireError::Invalid(format!(
+                "User {} already has a peer",
+                peer.user_id
+            )));
+        }
+        let taken = match peer.ip {
+            None => false,
+            Some(ip) => {
+                let filter = Filter {
+                    ip: Some(ip),
+                    interface: Some(peer.interface.clone()),
+                    ..Filter::default()
+                };
+                !store.find_peers(&filter, 0, Some(1)).await?.is_empty()
+            }
+        };
+        let interface = wireguard::find_interface(&*config.lock().await, &peer.interface)?;
+        if peer.ip.is_none() || taken {
+            let allocated = allocated(store, &interface.name).await?;
+            peer.ip = Some(wireguard::get_ip(&allocated, &interface)?);
+        }
+        peer.archived = None;
+        peer.archive_reason = None;
+        if peer.public_key.is_some() {
+            wireguard::apply_peer(&peer, &interface).await?;
+        }
+        store.update(&peer).await?;
+        refresh_rules(store, config).await;
+        Ok(peer)
+    })
+    .await
+}
+
+/// Sets an interface option of the peer's client config, `default` goes back to the config's.
+/// The peer is not saved.
+pub fn tune(peer: &mut Peer, option: &str, value: &str) -> Result<()> {
+    let value = Some(value).filter(|value| *value != "default");
+    match option {
+        "mtu" => {
+            peer.mtu = match value.map(str::parse::<u16>) {
+                None => None,
+                Some(Ok(mtu)) if mtu >= 576 => Some(mtu),
+                Some(_) => {
+                    return Err(GimmewireError::Invalid(
+                        "MTU must be a number from 576 to 65535".to_string(),
+                    ))
+                }
+            }
+        }
+        "keepalive" => {
+            peer.keepalive = match value.map(str::parse::<u16>) {
+                None => None,
+                Some(Ok(keepalive)) => Some(keepalive),
+                Some(Err(_)) => {
+                    return Err(GimmewireError::Invalid(
+                        "KeepAlive must be seconds, 0 turns it off".to_string(),
+                    ))
+                }
+            }
+        }
+        "download" | "upload" => {
+            let rate = match value.map(str::parse::<u32>) {
+                None => None,
+                Some(Ok(rate)) => Some(rate),
+                Some(Err(_)) => {
+                    return Err(GimmewireError::Invalid(
+                        "Rates are Mbit/s, 0 is unlimited".to_string(),
+                    ))
+                }
+            };
+            match option {
+                "download" => peer.download = rate,
+                _ => peer.upload = rate,
+            }
+        }
+        "dns" => {
+            peer.dns = match value.map(dns_servers) {
+                None => None,
+                Some(Some(servers)) => Some(servers),
+                Some(None) => {
+                    return Err(GimmewireError::Invalid(
+                        "DNS must be a list of addresses like 1.1.1.1, 9.9.9.9".to_string(),
+                    ))
+                }
+            }
+        }
+        "table" => {
+            peer.table = match value {
+                Some(table) if table.contains(char::is_whitespace) || table.is_empty() => {
+                    return Err(GimmewireError::Invalid(
+                        "Table must be off, auto or a table number".to_string(),
+                    ))
+                }
+                table => table.map(str::to_string),
+            }
+        }
+        _ => {
+            return Err(GimmewireError::Invalid(format!(
+                "Unknown option {}, there are dns, download, keepalive, mtu, table and upload",
+                option
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// DNS servers separated by commas or spaces as the config has them, None if any isn't an address.
+fn dns_servers(value: &str) -> Option<String> {
+    let servers: Vec<&str> = value
+        .split([',', ' '])
+        .filter(|server| !server.is_empty())
+        .collect();
+    if servers.is_empty()
+        || servers
+            .iter()
+            .any(|server| server.parse::<IpAddr>().is_err())
+    {
+        return None;
+    }
+    Some(servers.join(", "))
+}
+
+/// Revokes expired peers and removes their saved configs, the `expiry` job.
+pub async fn expire(ctx: &Context) -> Result<()> {
+    let (store, config) = (&ctx.store, &ctx.config);
+    let now = DateTime::now();
+    let expired = |peer: &Peer| peer.expires.is_some_and(|expires| expires <= now);
+    for listed in store.get_peers().await? {
+        if !expired(&listed) {
+            continue;
+        }
+        let revoked = queue::exclusive(async {
+            match current(&listed, store).await? {
+                Some(mut peer) if expired(&peer) => {
+                    let revoked = revoke(&mut peer, "expired", store, config.clone()).await;
+                    audit::record(store, "expiry", "remove", &peer.username, &revoked).await;
+                    revoked.map(|_| Some(peer))
+                }
+                // Renewed, extended or revoked since it was listed
+                _ => Ok(None),
+            }
+        })
+        .await;
+        match revoked {
+            Err(why) => {
+                tracing::error!("Cannot revoke expired peer {}: {}", listed.username, why)
+            }
+            Ok(None) => (),
+            Ok(Some(peer)) => {
+                if let Ok(path) = wireguard::conf_path(&peer) {
+                    let _ = std::fs::remove_file(path);
+                }
+                tracing::info!("Peer {} expired", peer.username);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn placement_strategies() {
+    let interface = |name: &str, network| Interface {
+        name: name.to_string(),
+        section: format!("interface {}", name),
+        device: name.to_string(),
+        host: None,
+        network,
+        prefix: 24,
+        amnezia: false,
+        reserved: vec![],
+        save: None,
+    };
+    let interfaces = vec![
+        interface("wg0", Ipv4Addr::new(10, 0, 0, 0)),
+        interface("wg1", Ipv4Addr::new(10, 1, 0, 0)),
+    ];
+    let mut old = Peer::new(1, "alice".to_string());
+    old.date = DateTime::from_millis(0);
+    let mut new = Peer::new(2, "bob".to_string());
+    new.interface = "wg1".to_string();
+    let peers = vec![old, new];
+    assert!(place("round-robin", &interfaces, &peers).as_deref() == Some("wg0"));
+    assert!(place("round-robin", &interfaces, &peers[..1]).as_deref() == Some("wg1"));
+    assert!(place("least-loaded", &interfaces, &peers[..1]).as_deref() == Some("wg1"));
+    assert!(place("main", &interfaces, &peers).is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn interface_options() {
+    let mut peer = Peer::new(1, "alice".to_string());
+    tune(&mut peer, "mtu", "1280").unwrap();
+    tune(&mut peer, "table", "off").unwrap();
+    assert!(peer.mtu == Some(1280) && peer.table.as_deref() == Some("off"));
+    assert!(tune(&mut peer, "mtu", "100").is_err() && tune(&mut peer, "speed", "1").is_err());
+    tune(&mut peer, "mtu", "default").unwrap();
+    assert!(peer.mtu.is_none() && peer.table.is_some());
+    tune(&mut peer, "dns", "1.1.1.1,9.9.9.9 2606:4700::1111").unwrap();
+    assert!(peer.dns.as_deref() == Some("1.1.1.1, 9.9.9.9, 2606:4700::1111"));
+    assert!(tune(&mut peer, "dns", "dns.google").is_err());
+    tune(&mut peer, "keepalive", "0").unwrap();
+    assert!(peer.keepalive == Some(0) && tune(&mut peer, "keepalive", "-1").is_err());
+    tune(&mut peer, "upload", "5").unwrap();
+    assert!(peer.upload == Some(5) && peer.download.is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn search_terms() {
+    let mut peer = Peer::new(1, "Alice".to_string());
+    peer.tags.push("vip".to_string());
+    peer.expires = Some(DateTime::from_millis(
+        DateTime::now().timestamp_millis() + 3 * 24 * 60 * 60 * 1000,
+    ));
+    let search = |terms: &str| Search::parse(&terms.split(' ').collect::<Vec<_>>()).unwrap();
+    assert!(search("tag=#VIP name=lic expires=7").matches(&peer));
+    assert!(!search("expires=1").matches(&peer) && !search("tag=work").matches(&peer));
+    assert!(!search("ip=10.0.0.2").matches(&peer));
+    assert!(Search::parse(&["owner=bob"]).is_err() && Search::parse(&["ip=me"]).is_err());
+}
+
+#[cfg(all(test, feature = "file"))]
+#[tokio::test]
+async fn device_limits() {
+    let scratch = crate::store::Scratch::new("devices.json");
+    let store = scratch.file();
+    let mut config = Ini::new();
+    config.set("Bot", "PeerLimit", Some("2".to_string()));
+    let config = Arc::new(Mutex::new(config));
+    let owner = Peer::new(1, "alice".to_string());
+    store.add(&owner).await.unwrap();
+    let phone = add_device(&owner, "phone", &store, config.clone())
+        .await
+        .unwrap();
+    assert!(phone.username == "alice-phone" && phone.user_id == 1);
+    let full = add_device(&owner, "laptop", &store, config.clone()).await;
+    assert!(matches!(full, Err(GimmewireError::PeerLimit(2))));
+    let again = add_device(&owner, "phone", &store, config.clone())
+        .await
+        .unwrap();
+    assert!(again.id == phone.id);
+    assert!(add_device(&owner, "my laptop", &store, config.clone())
+        .await
+        .is_err());
+    let outside = grant_temporary("../x".to_string(), 1, &store, config.clone()).await;
+    assert!(matches!(outside, Err(GimmewireError::Invalid(_))));
+    let forever = grant_temporary("guest".to_string(), u64::MAX, &store, config.clone()).await;
+    assert!(matches!(forever, Err(GimmewireError::Invalid(_))));
+    let nowhere = Some("nowhere".to_string());
+    assert!(create(
+        "guest".to_string(),
+        None,
+        None,
+        nowhere,
+        &store,
+        config.clone()
+    )
+    .await
+    .is_err());
+    assert!(store.find_by_username("guest").await.unwrap().is_none());
+    set_limit("alice", Some(3), &store).await.unwrap();
+    let owner = store.find_by_username("alice").await.unwrap().unwrap();
+    add_device(&owner, "laptop", &store, config.clone())
+        .await
+        .unwrap();
+    let interfaces = ["wg0".to_string(), "wg_eu".to_string()];
+    assert!(matches!(
+        bundle(&owner, &interfaces, &store, config.clone()).await,
+        Err(GimmewireError::PeerLimit(3))
+    ));
+    set_limit("alice", Some(4), &store).await.unwrap();
+    let owner = store.find_by_username("alice").await.unwrap().unwrap();
+    let regions = bundle(&owner, &interfaces, &store, config).await.unwrap();
+    assert!(regions[0].username == "alice" && regions[1].username == "alice-wg-eu");
+    assert!(regions[1].interface == "wg_eu" && regions[1].public_key.is_none());
+    assert!(matches!(
+        rename("alice-laptop", "alice-phone", &store).await,
+        Err(GimmewireError::PeerExists(_))
+    ));
+    assert!(rename("alice-laptop", "work laptop", &store).await.is_err());
+    let laptop = rename("alice-laptop", "alice-work", &store).await.unwrap();
+    let work = note("alice-work", Some(" office "), &store).await.unwrap();
+    assert!(work.id == laptop.id && work.notes.as_deref() == Some("office"));
+    assert!(store
+        .find_by_username("alice-laptop")
+        .await
+        .unwrap()
+        .is_none());
+}
@@ -0,0 +1,166 @@
+use crate::store::Store;
+use crate::wireguard;
+use bson::DateTime;
+use configparser::ini::Ini;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::process::Command;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// How many samples are kept per peer.
+const HISTORY: usize = 12;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub date: DateTime,
+    pub latency_ms: f64,
+    pub jitter_ms: f64,
+    pub loss: f64,
+}
+
+/// Latest samples by peer public key.
+pub type Probes = Arc<Mutex<HashMap<String, Vec<Sample>>>>;
+
+/// Pings the tunnel address of every online peer, if `[Probe] Interval` is set.
+pub async fn watch(store: Store, config: Arc<Mutex<Ini>>, probes: Probes) {
+    let (interval, count, interfaces) = {
+        let config = config.lock().await;
+        (
+            config
+                .getuint("Probe", "Interval")
+                .unwrap_or(None)
+                .unwrap_or(0),
+            config
+                .getuint("Probe", "Count")
+                .unwrap_or(None)
+                .unwrap_or(5),
+            wireguard::interfaces(&config),
+        )
+    };
+    if interval == 0 {
+        return;
+    }
+    let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval));
+    loop {
+        ticker.tick().await;
+        let stats = match wireguard::show_all(&interfaces).await {
+            Err(why) => {
+                tracing::error!("Cannot read interface state: {}", why);
+                continue;
+            }
+            Ok(stats) => stats,
+        };
+        let online: Vec<&str> = stats
+            .iter()
+            .filter(|stat| stat.online())
+            .map(|stat| stat.public_key.as_str())
+            .collect();
+        let peers = match store.get_peers().await {
+            Err(why) => {
+                tracing::warn!("Cannot probe peers: {}", why);
+                continue;
+            }
+            Ok(peers) => peers,
+        };
+        for peer in peers {
+            let (key, ip) = match (peer.public_key, peer.ip) {
+                (Some(key), Some(ip)) if online.contains(&key.as_str()) => (key, ip),
+                _ => continue,
+            };
+            let sample = match ping(ip, count).await {
+                None => continue,
+                Some(sample) => sample,
+            };
+            let mut probes = probes.lock().await;
+            let samples = probes.entry(key).or_default();
+            samples.push(sample);
+            if samples.len() > HISTORY {
+                samples.remove(0);
+            }
+        }
+    }
+}
+
+async fn ping(ip: Ipv4Addr, count: u64) -> Option<Sample> {
+    let output = tokio::task::spawn_blocking(move || {
+        Command::new("ping")
+            .args(["-q", "-n", "-c", &count.to_string(), &ip.to_string()])
+            .output()
+    })
+    .await;
+    match output {
+        Ok(Ok(output)) => parse_ping(&String::from_utf8_lossy(&output.stdout)),
+        Ok(Err(why)) => {
+            tracing::error!("Cannot run ping: {}", why);
+            None
+        }
+        Err(why) => {
+            tracing::error!("Cannot run ping: {}", why);
+            None
+        }
+    }
+}
+
+/// Reads loss and rtt summary lines of iputils or busybox ping.
+fn parse_ping(output: &str) -> Option<Sample> {
+    let loss: f64 = output
+        .split(',')
+        .find(|part| part.contains("packet loss"))?
+        .trim()
+        .split('%')
+        .next()?
+        .parse()
+        .ok()?;
+    let rtt: Vec<f64> = match output.lines().find(|line| line.contains("min/avg/max")) {
+        // Nothing came back, so there is no rtt line
+        None => vec![0.0, 0.0, 0.0, 0.0],
+        Some(line) => line
+            .split('=')
+            .nth(1)?
+            .trim()
+            .trim_end_matches("ms")
+            .trim()
+            .split('/')
+            .filter_map(|value| value.parse().ok())
+            .collect(),
+    };
+    Some(Sample {
+        date: DateTime::now(),
+        latency_ms: *rtt.get(1)?,
+        jitter_ms: match rtt.get(3) {
+            Some(mdev) => *mdev,
+            None => rtt.get(2)? - rtt.first()?,
+        },
+        loss: loss / 100.0,
+    })
+}
+
+/// Averages samples into a one line summary.
+pub fn summary(samples: &[Sample]) -> Option<String> {
+    let answered: Vec<&Sample> = samples.iter().filter(|sample| sample.loss < 1.0).collect();
+    let last = samples.last()?;
+    if answered.is_empty() {
+        return Some(format!("no reply, {:.0}% loss", last.loss * 100.0));
+    }
+    let n = answered.len() as f64;
+    Some(format!(
+        "{:.1} ms ± {:.1} ms, {:.0}% loss",
+        answered.iter().map(|sample| sample.latency_ms).sum::<f64>() / n,
+        answered.iter().map(|sample| sample.jitter_ms).sum::<f64>() / n,
+        samples.iter().map(|sample| sample.loss).sum::<f64>() / samples.len() as f64 * 100.0
+    ))
+}
+
+#[cfg(test)]
+#[test]
+fn ping_parsing() {
+    let iputils = "--- 10.0.0.2 ping statistics ---
+5 packets transmitted, 4 received, 20% packet loss, time 4005ms
+rtt min/avg/max/mdev = 10.045/20.058/30.075/5.011 ms
+";
+    let sample = parse_ping(iputils).unwrap();
+    assert!(sample.latency_ms == 20.058 && sample.jitter_ms == 5.011 && sample.loss == 0.2);
+    let lost = "3 packets transmitted, 0 packets received, 100% packet loss\n";
+    assert!(parse_ping(lost).unwrap().loss == 1.0);
+}
@@ -0,0 +1,179 @@
+//! Changes to the interfaces one after another. Bot handlers, jobs and the dashboard run at the
+//! same time, so two of them could pick the same free address or take a peer off between the
+//! `wg set` and the db write of another. A single worker takes wg commands which change
+//! interfaces and the turns of peer operations, see `exclusive`, in the order they were queued.
+//! Failed commands are tried `[Queue] Retries` more times, waiting a second, then two... keys
+//! whose change still failed are left to `repair`.
+use crate::error::Result;
+use configparser::ini::Ini;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+static SENDER: OnceLock<UnboundedSender<Job>> = OnceLock::new();
+static RETRIES: AtomicU64 = AtomicU64::new(2);
+/// Interface, public key and error of key changes which failed after their retries, for `repair`.
+static FAILED: LazyLock<Mutex<Vec<(String, String, String)>>> =
+    LazyLock::new(|| Mutex::new(Vec::new()));
+
+tokio::task_local! {
+    /// Set while an operation has its turn, what it runs doesn't queue behind itself.
+    static TURN: ();
+}
+
+type Command = Arc<dyn Fn() -> Result<String> + Send + Sync>;
+
+enum Job {
+    /// A command changing an interface, answered with its output.
+    Run {
+        command: Command,
+        reply: oneshot::Sender<Result<String>>,
+    },
+    /// An operation which waits for its turn, the queue is held until it drops the sender it
+    /// gets.
+    Turn(oneshot::Sender<oneshot::Sender<()>>),
+}
+
+/// Reads `[Queue] Retries` and starts the worker, commands run where they are called until then,
+/// like in the cli.
+pub fn start(config: &Ini) {
+    configure(config);
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<Job>();
+    if SENDER.set(sender).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        while let Some(job) = receiver.recv().await {
+            match job {
+                Job::Run { command, reply } => {
+                    let _ = reply.send(retrying(command).await);
+                }
+                Job::Turn(granted) => {
+                    let (done, finished) = oneshot::channel();
+                    // The operation is gone when nobody waits for the turn anymore
+                    if granted.send(done).is_ok() {
+                        let _ = finished.await;
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn configure(config: &Ini) {
+    let retries = config
+        .getuint("Queue", "Retries")
+        .unwrap_or(None)
+        .unwrap_or(2);
+    RETRIES.store(retries, Ordering::SeqCst);
+}
+
+/// Runs the command after those queued before it, and the operations which have their turn.
+pub async fn run<F>(command: F) -> Result<String>
+where
+    F: Fn() -> Result<String> + Send + Sync + 'static,
+{
+    let sender = match SENDER.get() {
+        Some(sender) if TURN.try_with(|_| ()).is_err() => sender,
+        _ => return retrying(Arc::new(command)).await,
+    };
+    let (reply, answer) = oneshot::channel();
+    let job = Job::Run {
+        command: Arc::new(command),
+        reply,
+    };
+    if let Err(tokio::sync::mpsc::error::SendError(Job::Run { command, .. })) = sender.send(job) {
+        return retrying(command).await;
+    }
+    match answer.await {
+        Ok(result) => result,
+        // The worker stopped while the command ran, it may have done its change
+        Err(_) => Err(crate::error::GimmewireError::Invalid(
+            "The interface queue stopped".to_string(),
+        )),
+    }
+}
+
+/// Runs the operation alone: commands and operations queued after it wait until it is done. It
+/// can read the peers, change interfaces and write the db without others doing so in between.
+pub async fn exclusive<F: Future>(operation: F) -> F::Output {
+    let sender = match SENDER.get() {
+        Some(sender) if TURN.try_with(|_| ()).is_err() => sender,
+        _ => return operation.await,
+    };
+    let (turn, granted) = oneshot::channel();
+    if sender.send(Job::Turn(turn)).is_err() {
+        return operation.await;
+    }
+    let done = granted.await.ok();
+    let output = TURN.scope((), operation).await;
+    drop(done);
+    output
+}
+
+/// Remembers that a change of the key failed, the interface may not have what the db says.
+pub fn failed(interface: &str, public_key: &str, why: &str) {
+    FAILED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push((
+            interface.to_string(),
+            public_key.to_string(),
+            why.to_string(),
+        ));
+}
+
+/// The failed changes remembered since the last call.
+pub fn take_failed() -> Vec<(String, String, String)> {
+    std::mem::take(
+        &mut *FAILED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    )
+}
+
+/// Commands wait for processes, so they run on the blocking threads and the runtime goes on.
+async fn retrying(command: Command) -> Result<String> {
+    let retries = RETRIES.load(Ordering::SeqCst);
+    let mut attempt = 0;
+    loop {
+        let once = command.clone();
+        let result = tokio::task::spawn_blocking(move || once())
+            .await
+            .unwrap_or_else(|why| {
+                Err(crate::error::GimmewireError::Invalid(format!(
+                    "The interface command stopped: {}",
+                    why
+                )))
+            });
+        match result {
+            Err(why) if attempt < retries => {
+                let wait = Duration::from_secs(1 << attempt.min(6));
+                tracing::warn!("Retrying in {:?}: {}", wait, why);
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn retries() {
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+    let mut config = Ini::new();
+    config.read("[Queue]\nRetries = 1".to_string()).unwrap();
+    configure(&config);
+    let failing = || {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        Err(crate::error::GimmewireError::Invalid("busy".to_string()))
+    };
+    assert!(run(failing).await.is_err() && CALLS.load(Ordering::SeqCst) == 2);
+    let done = exclusive(async { run(|| Ok("done".to_string())).await }).await;
+    assert!(done.unwrap() == "done");
+    configure(&Ini::new());
+}
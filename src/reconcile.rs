@@ -0,0 +1,159 @@
+use crate::doctor::{self, Finding};
+use crate::notify;
+use crate::peers;
+use crate::queue;
+use crate::shutdown;
+use crate::store::Store;
+use crate::wireguard::{self, Interface};
+use configparser::ini::Ini;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Puts every peer known to the db on its interface, since the kernel forgets them on restart.
+/// Suspended peers stay off. Each interface gets its peers in one batch, peer by peer if that fails.
+pub async fn apply_all(store: &Store, interfaces: &[Interface]) {
+    let peers = match store.get_peers().await {
+        Err(why) => {
+            tracing::error!("Cannot read the peers to apply: {}", why);
+            return;
+        }
+        Ok(peers) => peers,
+    };
+    let mut batched = Vec::new();
+    for interface in interfaces {
+        match wireguard::sync_peers(interface, &peers).await {
+            Err(why) => tracing::warn!("Cannot sync {} in one batch: {}", interface.name, why),
+            Ok(_) => batched.push(interface.name.clone()),
+        }
+    }
+    let (mut applied, mut failed) = (0, 0);
+    for peer in peers {
+        if peer.public_key.is_none() || peer.ip.is_none() || peer.suspended.is_some() {
+            continue;
+        }
+        if batched.contains(&peer.interface) {
+            applied += 1;
+            continue;
+        }
+        let applied_peer = match wireguard::interface_of(interfaces, &peer) {
+            Err(why) => Err(why),
+            Ok(interface) => wireguard::apply_peer(&peer, interface).await,
+        };
+        match applied_peer {
+            Err(why) => {
+                tracing::error!("Cannot apply peer {}: {}", peer.username, why);
+                failed += 1;
+            }
+            Ok(_) => applied += 1,
+        }
+    }
+    tracing::info!("Applied {} peers, {} failed", applied, failed);
+}
+
+/// Periodically compares the interfaces with the db, reports new drift to the admin and optionally
+/// puts back missing peers and their allowed ips. Duplicate addresses are only reported, which of
+/// the peers keeps it is the admin's call.
+pub async fn watch(store: Store, config: Arc<Mutex<Ini>>) {
+    let (interval, repair, interfaces) = {
+        let config = config.lock().await;
+        (
+            config
+                .getuint("Reconcile", "Interval")
+                .unwrap_or(None)
+                .unwrap_or(300),
+            config
+                .getbool("Reconcile", "Repair")
+                .unwrap_or(None)
+                .unwrap_or(false),
+            wireguard::interfaces(&config),
+        )
+    };
+    if interval == 0 {
+        return;
+    }
+    let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval));
+    let mut reported: Vec<Finding> = Vec::new();
+    loop {
+        ticker.tick().await;
+        let _busy = match shutdown::begin() {
+            None => return,
+            Some(busy) => busy,
+        };
+        // Puts back rules flushed by someone else
+        peers::refresh_rules(&store, config.clone()).await;
+        let server_keys = doctor::server_keys(&interfaces, &*config.lock().await).await;
+        // Nobody changes a peer between reading it and repairing it
+        let checked = queue::exclusive(async {
+            let stats = match wireguard::show_all(&interfaces).await {
+                Err(why) => {
+                    tracing::error!("Cannot read interface state: {}", why);
+                    return None;
+                }
+                Ok(stats) => stats,
+            };
+            // Every key would look unknown without the db
+            let mut peers = match store.get_peers().await {
+                Err(why) => {
+                    tracing::error!("Cannot compare the interfaces with the db: {}", why);
+                    return None;
+                }
+                Ok(peers) => peers,
+            };
+            let findings = doctor::diagnose(&peers, &stats, &server_keys, &interfaces);
+            let mut repaired = String::new();
+            for finding in findings.iter().filter(|f| repair && repairable(f)) {
+                match doctor::repair(finding, &mut peers, &interfaces, &store).await {
+                    Err(why) => repaired.push_str(&format!("Cannot repair: {}\n", why)),
+                    Ok(true) => repaired.push_str(&format!("Repaired: {}\n", finding)),
+                    Ok(false) => (),
+                }
+            }
+            Some((findings, repaired))
+        })
+        .await;
+        let (findings, repaired) = match checked {
+            None => continue,
+            Some(checked) => checked,
+        };
+        let mut report = String::new();
+        for finding in findings.iter().filter(|f| !reported.contains(f)) {
+            report.push_str(&format!("- {}\n", finding));
+        }
+        report.push_str(&repaired);
+        reported = findings;
+        if report.is_empty() {
+            continue;
+        }
+        let report = format!("Drift between the interfaces and the db:\n{}", report);
+        tracing::warn!("{}", report);
+        notify::send(report);
+    }
+}
+
+/// Drift the watcher fixes on its own, the db is right about these.
+fn repairable(finding: &Finding) -> bool {
+    matches!(
+        finding,
+        Finding::MissingPeer { .. } | Finding::AllowedIpsDrift { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_duplicates_to_the_admin() {
+        assert!(!repairable(&Finding::DuplicateIp {
+            ip: "10.0.0.2".parse().unwrap(),
+            peers: vec!["a".to_string(), "b".to_string()],
+        }));
+        assert!(repairable(&Finding::MissingPeer {
+            username: "a".to_string(),
+        }));
+        assert!(repairable(&Finding::AllowedIpsDrift {
+            username: "a".to_string(),
+            actual: "10.0.0.3/32".to_string(),
+        }));
+    }
+}
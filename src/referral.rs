@@ -0,0 +1,215 @@
+use crate::error::{GimmewireError, Result};
+#[cfg(feature = "telegram")]
+use crate::i18n;
+use crate::scheduler::Context;
+use crate::store::Store;
+use crate::wireguard::Peer;
+use crate::{audit, peers};
+use bson::DateTime;
+use configparser::ini::Ini;
+use serde::{Deserialize, Serialize};
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+const GB: u64 = 1024 * 1024 * 1024;
+
+/// A user who came through another user's link, one per referred user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Referral {
+    pub referrer: u64,
+    pub referred: u64,
+    pub date: DateTime,
+    /// When the referrer got the reward for the referred user's first config.
+    pub credited: Option<DateTime>,
+}
+
+/// What a referrer gets, `[Referral] Days` and `[Referral] Quota` in GB.
+#[derive(Debug, PartialEq)]
+pub struct Reward {
+    pub days: Option<u64>,
+    pub quota: Option<u64>,
+}
+
+/// None when neither reward is set and referral links are off.
+pub fn reward(config: &Ini) -> Option<Reward> {
+    let days = config.getuint("Referral", "Days").unwrap_or(None);
+    let quota = config.getuint("Referral", "Quota").unwrap_or(None);
+    match (days, quota) {
+        (None | Some(0), None | Some(0)) => None,
+        _ => Some(Reward {
+            days: days.filter(|days| *days > 0),
+            quota: quota.filter(|quota| *quota > 0).map(|quota| quota * GB),
+        }),
+    }
+}
+
+/// The /start payload of a user's referral link.
+pub fn payload(user_id: u64) -> String {
+    format!("ref{}", user_id)
+}
+
+/// The referrer of a /start payload.
+pub fn referrer(payload: &str) -> Option<u64> {
+    payload.trim().strip_prefix("ref")?.parse().ok()
+}
+
+/// Gives the reward to the peer: more days and traffic on a trial, more days on a subscription.
+/// False when the peer has neither and there is nothing to add to.
+pub fn apply(peer: &mut Peer, reward: &Reward, now: DateTime) -> bool {
+    let days = reward.days.unwrap_or(0) as i64 * DAY;
+    let after = |date: Option<DateTime>| {
+        let from = date.filter(|date| *date > now).unwrap_or(now);
+        DateTime::from_millis(from.timestamp_millis() + days)
+    };
+    if let Some(trial) = &mut peer.trial {
+        if days > 0 && trial.until.is_some() {
+            trial.until = Some(after(trial.until));
+        }
+        if let (Some(quota), Some(extra)) = (&mut trial.quota, reward.quota) {
+            *quota += extra;
+        }
+        trial.ended = None;
+        true
+    } else if let Some(subscription) = &mut peer.subscription {
+        if days == 0 {
+            return false;
+        }
+        subscription.paid_until = after(Some(subscription.paid_until));
+        subscription.reminded = false;
+        true
+    } else {
+        false
+    }
+}
+
+/// Remembers who referred a new user, they must not have had a peer or a referrer before.
+#[tracing::instrument(skip_all, fields(referrer = referrer, referred = referred))]
+pub async fn attribute(referrer: u64, referred: u64, store: &Store) -> Result<()> {
+    if referrer == referred {
+        return Err(GimmewireError::Invalid(
+            "Users cannot refer themselves".to_string(),
+        ));
+    }
+    if store.find_by_id(referrer).await?.is_none() {
+        return Err(GimmewireError::Invalid(format!(
+            "Referrer {} has no peer",
+            referrer
+        )));
+    }
+    let known = store.find_by_id(referred).await?.is_some()
+        || store
+            .get_archived()
+            .await?
+            .iter()
+            .any(|peer| peer.user_id == referred);
+    if known {
+        return Err(GimmewireError::Invalid(format!(
+            "User {} is not new",
+            referred
+        )));
+    }
+    let referred_before = store
+        .get_referrals()
+        .await?
+        .iter()
+        .any(|referral| referral.referred == referred);
+    if referred_before {
+        return Err(GimmewireError::Invalid(format!(
+            "User {} was already referred",
+            referred
+        )));
+    }
+    store
+        .save_referral(&Referral {
+            referrer,
+            referred,
+            date: DateTime::now(),
+            credited: None,
+        })
+        .await
+}
+
+/// Credits referrers for referred users who have got their first config, the `referral` job.
+pub async fn run(ctx: &Context) -> Result<()> {
+    let store = &ctx.store;
+    let reward = match reward(&*ctx.config.lock().await) {
+        None => return Ok(()),
+        Some(reward) => reward,
+    };
+    for mut referral in store.get_referrals().await? {
+        if referral.credited.is_some() {
+            continue;
+        }
+        let referred = match store.find_by_id(referral.referred).await? {
+            Some(peer) if peer.public_key.is_some() => peer,
+            _ => continue,
+        };
+        referral.credited = Some(DateTime::now());
+        if let Err(why) = store.save_referral(&referral).await {
+            tracing::error!("Cannot save referral of {}: {}", referred.username, why);
+            continue;
+        }
+        // Referrers who are gone by now get nothing, but the referral is still used up
+        let mut referrer = match store.find_by_id(referral.referrer).await? {
+            None => continue,
+            Some(peer) => peer,
+        };
+        if !apply(&mut referrer, &reward, DateTime::now()) {
+            tracing::info!(
+                "{} has nothing to credit the referral to",
+                referrer.username
+            );
+            continue;
+        }
+        let credited = match referrer.suspended {
+            Some(_) => peers::resume(&mut referrer, store, ctx.config.clone()).await,
+            None => store.update(&referrer).await,
+        };
+        audit::record(
+            store,
+            "referral",
+            &format!("reward for {}", referred.username),
+            &referrer.username,
+            &credited,
+        )
+        .await;
+        if let Err(why) = credited {
+            tracing::error!("Cannot credit {}: {}", referrer.username, why);
+            continue;
+        }
+        #[cfg(feature = "telegram")]
+        i18n::tell(
+            &ctx.bot,
+            &ctx.locales,
+            &referrer,
+            "referral-credited",
+            &[("name", &referred.username)],
+        )
+        .await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn referrals() {
+    let mut config = Ini::new();
+    assert!(reward(&config).is_none());
+    config
+        .read("[Referral]\nDays = 7\nQuota = 5".to_string())
+        .unwrap();
+    let reward = reward(&config).unwrap();
+    assert!(referrer(&payload(42)) == Some(42) && referrer("42").is_none());
+    let now = DateTime::from_millis(10 * DAY);
+    let mut peer = Peer::new(1, "alice".to_string());
+    assert!(!apply(&mut peer, &reward, now));
+    peer.trial = Some(crate::wireguard::Trial {
+        started: None,
+        until: Some(DateTime::from_millis(DAY)),
+        quota: Some(GB),
+        ended: Some(DateTime::from_millis(DAY)),
+    });
+    assert!(apply(&mut peer, &reward, now));
+    let trial = peer.trial.as_ref().unwrap();
+    assert!(trial.until == Some(DateTime::from_millis(17 * DAY)));
+    assert!(trial.quota == Some(6 * GB) && trial.ended.is_none());
+}
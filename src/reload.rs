@@ -0,0 +1,246 @@
+use crate::error::{GimmewireError, Result};
+use crate::notify;
+use crate::settings::Settings;
+use crate::wireguard;
+use configparser::ini::Ini;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Mutex;
+
+/// Sections which are only read at startup, changing them needs a restart.
+const STARTUP: &[&str] = &[
+    "storage",
+    "mongo",
+    "keys",
+    "http",
+    "webhook",
+    "hooks",
+    "log",
+    "reconcile",
+];
+
+/// Environment variables like `GIMMEWIRE_PEER__ENDPOINT` override `[Peer] Endpoint`.
+const PREFIX: &str = "GIMMEWIRE_";
+
+/// The config file read first, where changes made at runtime are persisted.
+static PATH: OnceLock<String> = OnceLock::new();
+
+/// Reads and parses the config file, with overrides from the environment on top.
+pub fn read(path: &str) -> Result<Ini> {
+    let mut config = parse(path)?;
+    PATH.get_or_init(|| path.to_string());
+    overrides(&mut config, std::env::vars());
+    Ok(config)
+}
+
+fn parse(path: &str) -> Result<Ini> {
+    let content = std::fs::read_to_string(path)?;
+    let mut config = Ini::new();
+    config.read(content).map_err(GimmewireError::Config)?;
+    Ok(config)
+}
+
+/// Applies `GIMMEWIRE_<SECTION>__<KEY>` variables, a single `_` in the section stands for a
+/// space, so `GIMMEWIRE_INTERFACE_NODE1__ENDPOINT` sets `[Interface node1] Endpoint`.
+pub fn overrides(config: &mut Ini, vars: impl IntoIterator<Item = (String, String)>) {
+    for (name, value) in vars {
+        let (section, key) = match name
+            .strip_prefix(PREFIX)
+            .and_then(|name| name.split_once("__"))
+        {
+            Some((section, key)) if !section.is_empty() && !key.is_empty() => (section, key),
+            _ => continue,
+        };
+        let section = section.replace('_', " ");
+        tracing::debug!("[{}] {} is set by {}", section, key, name);
+        config.set(&section, key, Some(value));
+    }
+}
+
+/// Sets a key in the config file itself, so overrides from the environment don't end up in it.
+/// Only its line changes, comments and the rest of the file stay as the admin wrote them.
+pub fn persist(path: &str, section: &str, key: &str, value: &str) -> Result<()> {
+    let content = std::fs::read_to_string(path)?;
+    std::fs::write(path, set_line(&content, section, key, value))?;
+    Ok(())
+}
+
+/// The content with `key` of `section` set to `value`: its lines are replaced, or one is added
+/// after the last setting of the section, or the section is added at the end.
+fn set_line(content: &str, section: &str, key: &str, value: &str) -> String {
+    let mut lines: Vec<String> = content.split_inclusive('\n').map(str::to_string).collect();
+    let (mut current, mut found, mut last) = (None::<String>, false, None);
+    for (i, line) in lines.iter_mut().enumerate() {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            current = Some(name.trim().to_string());
+            continue;
+        }
+        if !current
+            .as_deref()
+            .is_some_and(|name| name.eq_ignore_ascii_case(section))
+        {
+            continue;
+        }
+        if trimmed.is_empty() || trimmed.starts_with(';') || trimmed.starts_with('#') {
+            continue;
+        }
+        last = Some(i);
+        let name = match trimmed.split_once(['=', ':']) {
+            Some((name, _)) if name.trim().eq_ignore_ascii_case(key) => name.trim(),
+            _ => continue,
+        };
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let ending = &line[line.trim_end_matches(['\r', '\n']).len()..];
+        *line = format!("{}{} = {}{}", indent, name, value, ending);
+        found = true;
+    }
+    if found {
+        return lines.concat();
+    }
+    let setting = format!("{} = {}\n", key, value);
+    match last {
+        Some(i) => {
+            if !lines[i].ends_with('\n') {
+                lines[i].push('\n');
+            }
+            lines.insert(i + 1, setting);
+        }
+        None => {
+            let header = lines.iter().position(|line| {
+                line.trim()
+                    .strip_prefix('[')
+                    .and_then(|s| s.strip_suffix(']'))
+                    .is_some_and(|name| name.trim().eq_ignore_ascii_case(section))
+            });
+            match header {
+                Some(i) => lines.insert(i + 1, setting),
+                None => {
+                    if lines.last().is_some_and(|line| !line.ends_with('\n')) {
+                        lines.push("\n".to_string());
+                    }
+                    lines.push(format!("\n[{}]\n{}", section, setting));
+                }
+            }
+        }
+    }
+    lines.concat()
+}
+
+/// The config file, None before it is read.
+pub fn path() -> Option<&'static str> {
+    PATH.get().map(String::as_str)
+}
+
+/// Checks the whole config, so a typo doesn't break configs of new peers.
+pub fn validate(config: &Ini) -> Result<()> {
+    Settings::parse(config).map(|_| ())
+}
+
+/// Replaces the shared config with the file on SIGHUP. A file which doesn't parse or validate,
+/// or which drops an interface peers may be on, is refused and the old config is kept.
+#[cfg(unix)]
+pub async fn watch(path: String, config: Arc<Mutex<Ini>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Err(why) => {
+            tracing::error!("Cannot listen for SIGHUP: {}", why);
+            return;
+        }
+        Ok(hangups) => hangups,
+    };
+    while hangups.recv().await.is_some() {
+        let mut current = config.lock().await;
+        match reload(&path, &current) {
+            Err(why) => {
+                tracing::error!("Config is not reloaded: {}", why);
+                notify::send(format!("⚠️ Config is not reloaded: {}", why));
+            }
+            Ok(fresh) => {
+                let restart: Vec<&str> = STARTUP
+                    .iter()
+                    .filter(|section| {
+                        current.get_map_ref().get(**section) != fresh.get_map_ref().get(**section)
+                    })
+                    .copied()
+                    .collect();
+                *current = fresh;
+                tracing::info!("Config is reloaded from {}", path);
+                if !restart.is_empty() {
+                    tracing::warn!("Changes to [{}] need a restart", restart.join("], ["));
+                }
+            }
+        }
+    }
+}
+
+fn reload(path: &str, current: &Ini) -> Result<Ini> {
+    let fresh = read(path)?;
+    validate(&fresh)?;
+    let names: Vec<String> = wireguard::interfaces(&fresh)
+        .into_iter()
+        .map(|interface| interface.name)
+        .collect();
+    for interface in wireguard::interfaces(current) {
+        if !names.contains(&interface.name) {
+            return Err(GimmewireError::Config(format!(
+                "Interface {} cannot be removed while running",
+                interface.name
+            )));
+        }
+    }
+    Ok(fresh)
+}
+
+#[cfg(test)]
+#[test]
+fn persisted_lines() {
+    let content = "; Server\n[Peer]\nPool = 10.0.0.0/16\r\n  Key=old ; rotated\n\n; Others\n[Interface node1]\nPool = 10.1.0.0/16\n";
+    assert!(
+        set_line(content, "peer", "Key", "new")
+            == "; Server\n[Peer]\nPool = 10.0.0.0/16\r\n  Key = new\n\n; Others\n[Interface node1]\nPool = 10.1.0.0/16\n"
+    );
+    assert!(set_line(content, "interface node1", "Key", "new")
+        .ends_with("Pool = 10.1.0.0/16\nKey = new\n"));
+    assert!(
+        set_line("[Peer]\nPool = x", "Bot", "AdminId", "1")
+            == "[Peer]\nPool = x\n\n[Bot]\nAdminId = 1\n"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn validation() {
+    assert!(validate(&read("gimmewire.conf").unwrap()).is_ok());
+    let mut config = Ini::new();
+    config
+        .read(
+            "[Storage]\nBackend = file\n[Peer]\nPool = 10.0.0.0/16\nEndpoint = vpn.example.com:51820\nDNS = 1.1.1.1, 8.8.8.8\n[Bot]\nAdminId = 1\n[Interface node1]\nPool = 10.1.0.0/16\nEndpoint = 128.0.0.2:51820"
+                .to_string(),
+        )
+        .unwrap();
+    assert!(validate(&config).is_ok());
+    config.set("Peer", "KeepAlive", Some("soon".to_string()));
+    assert!(validate(&config).is_err());
+    config.set("Peer", "KeepAlive", Some("25".to_string()));
+    config.set("Interface node1", "Endpoint", Some("128.0.0.2".to_string()));
+    assert!(validate(&config).is_err());
+    config.set(
+        "Interface node1",
+        "Endpoint",
+        Some("128.0.0.2:51820".to_string()),
+    );
+    config.set("Bot", "AdminId", Some("admin".to_string()));
+    assert!(validate(&config).is_err());
+    let vars = [
+        ("GIMMEWIRE_BOT__ADMINID", "2"),
+        ("GIMMEWIRE_INTERFACE_NODE1__ENDPOINT", "128.0.0.3:51820"),
+        ("GIMMEWIRE_NOSECTION", "x"),
+        ("HOME", "/root"),
+    ];
+    overrides(
+        &mut config,
+        vars.map(|(name, value)| (name.to_string(), value.to_string())),
+    );
+    assert!(validate(&config).is_ok());
+    assert!(config.get("Interface node1", "Endpoint").as_deref() == Some("128.0.0.3:51820"));
+}
@@ -0,0 +1,174 @@
+//! Keys whose interface change failed, like a `wg set` while the interface was down or the wg
+//! binary missing. The db and the interface may not agree about them anymore, so the `repair`
+//! job keeps them in the store and puts each key on its interface or takes it off, whatever the
+//! db says now, once the interface can be read again. Failed attempts wait longer each time, a
+//! minute, two, four... about an hour at most.
+use crate::error::{GimmewireError, Result};
+use crate::scheduler::Context;
+use crate::store::Store;
+use crate::wireguard::{self, Peer};
+use crate::{notify, queue};
+use bson::DateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const MINUTE: i64 = 60 * 1000;
+
+/// A key of an interface which has to be brought in line with the db.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Repair {
+    pub interface: String,
+    pub public_key: String,
+    /// Error of the change which failed, then of the last attempt.
+    pub error: String,
+    pub attempts: u32,
+    pub since: DateTime,
+    /// When it is attempted again.
+    pub next: DateTime,
+}
+
+/// Stores a failed change, one which is stored already keeps its attempts.
+pub async fn record(store: &Store, interface: &str, public_key: &str, why: &str) -> Result<()> {
+    let existing = store
+        .get_repairs()
+        .await?
+        .into_iter()
+        .find(|repair| repair.interface == interface && repair.public_key == public_key);
+    let repair = match existing {
+        Some(repair) => Repair {
+            error: why.to_string(),
+            ..repair
+        },
+        None => Repair {
+            interface: interface.to_string(),
+            public_key: public_key.to_string(),
+            error: why.to_string(),
+            attempts: 0,
+            since: DateTime::now(),
+            next: DateTime::now(),
+        },
+    };
+    store.save_repair(&repair).await
+}
+
+/// The peer which should have the key on the interface, None when it should be gone.
+pub fn wanted<'a>(peers: &'a [Peer], interface: &str, public_key: &str) -> Option<&'a Peer> {
+    peers.iter().find(|peer| {
+        peer.interface == interface
+            && peer.public_key.as_deref() == Some(public_key)
+            && peer.ip.is_some()
+            && peer.suspended.is_none()
+            && peer.archived.is_none()
+    })
+}
+
+/// How long to wait after this many failed attempts.
+fn backoff(attempts: u32) -> i64 {
+    MINUTE << attempts.min(6)
+}
+
+/// Stores the changes which failed since the last run and attempts the repairs which are due,
+/// the `repair` job.
+pub async fn run(ctx: &Context) -> Result<()> {
+    for (interface, public_key, why) in queue::take_failed() {
+        record(&ctx.store, &interface, &public_key, &why).await?;
+    }
+    let now = DateTime::now();
+    let due: Vec<Repair> = ctx
+        .store
+        .get_repairs()
+        .await?
+        .into_iter()
+        .filter(|repair| repair.next <= now)
+        .collect();
+    if due.is_empty() {
+        return Ok(());
+    }
+    let interfaces = wireguard::interfaces(&*ctx.config.lock().await);
+    let mut healthy: HashMap<String, bool> = HashMap::new();
+    for mut repair in due {
+        let interface = match interfaces.iter().find(|i| i.name == repair.interface) {
+            // Dropped from the config, nothing to repair there
+            None => {
+                ctx.store.delete_repair(&repair).await?;
+                continue;
+            }
+            Some(interface) => interface,
+        };
+        if !healthy.contains_key(&interface.name) {
+            let up = wireguard::show(interface).await.is_ok();
+            healthy.insert(interface.name.clone(), up);
+        }
+        if !healthy[&interface.name] {
+            continue;
+        }
+        // Without the db every key would look unwanted, the job waits for the next tick then
+        let repaired = queue::exclusive(async {
+            let peers = ctx.store.get_peers().await?;
+            Ok::<_, GimmewireError>(
+                match wanted(&peers, &repair.interface, &repair.public_key) {
+                    Some(peer) => wireguard::apply_peer(peer, interface)
+                        .await
+                        .map(|_| format!("{} is back on {}", peer.username, interface.name)),
+                    None => wireguard::remove_key(interface, &repair.public_key)
+                        .await
+                        .map(|_| format!("Stale key is off {}", interface.name)),
+                },
+            )
+        })
+        .await?;
+        match repaired {
+            Ok(done) => {
+                tracing::info!("{}", done);
+                if repair.attempts > 0 {
+                    notify::send(format!(
+                        "🔧 {} after {} attempts",
+                        done,
+                        repair.attempts + 1
+                    ));
+                }
+                ctx.store.delete_repair(&repair).await?;
+            }
+            Err(why) => {
+                tracing::warn!(
+                    "Cannot repair {} on {}: {}",
+                    repair.public_key,
+                    repair.interface,
+                    why
+                );
+                repair.error = why.to_string();
+                repair.next =
+                    DateTime::from_millis(now.timestamp_millis() + backoff(repair.attempts));
+                repair.attempts += 1;
+                ctx.store.save_repair(&repair).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "file"))]
+#[tokio::test]
+async fn repairs() {
+    let scratch = crate::store::Scratch::new("repairs.json");
+    let store = scratch.file();
+    record(&store, "wg0", "key", "Unable to access interface")
+        .await
+        .unwrap();
+    let mut repair = store.get_repairs().await.unwrap().pop().unwrap();
+    repair.attempts = 3;
+    store.save_repair(&repair).await.unwrap();
+    record(&store, "wg0", "key", "No such device")
+        .await
+        .unwrap();
+    let repairs = store.get_repairs().await.unwrap();
+    assert!(repairs.len() == 1 && repairs[0].attempts == 3 && repairs[0].error == "No such device");
+    store.delete_repair(&repairs[0]).await.unwrap();
+    assert!(store.get_repairs().await.unwrap().is_empty());
+    let mut peer = Peer::new(1, "alice".to_string());
+    peer.public_key = Some("key".to_string());
+    peer.ip = Some("10.0.0.2".parse().unwrap());
+    let peers = vec![peer];
+    assert!(wanted(&peers, "wg0", "key").is_some() && wanted(&peers, "wg1", "key").is_none());
+    assert!(backoff(0) == MINUTE && backoff(10) == 64 * MINUTE);
+}
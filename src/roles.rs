@@ -0,0 +1,94 @@
+//! Who may run admin commands: `[Roles] Owner`, `Admin` and `Support` list Telegram user ids,
+//! `[Bot] AdminId` is always an owner. Owners run everything, what the other roles may run is
+//! `[Permissions] Admin` and `[Permissions] Support`, command names without the slash. Audit
+//! events name the role and the user, like `support 12345`.
+use configparser::ini::Ini;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Owner,
+    Admin,
+    Support,
+}
+
+/// Admin commands admins can't run unless `[Permissions] Admin` says otherwise.
+const OWNER_ONLY: &[&str] = &["backup", "bulk", "endpoint", "limit"];
+
+/// What support can run unless `[Permissions] Support` says otherwise: looking things up, new
+/// users and fixing their configs.
+const SUPPORT: &[&str] = &[
+    "approve",
+    "reject",
+    "claim",
+    "archived",
+    "audit",
+    "history",
+    "find",
+    "jobs",
+    "note",
+    "rotatepeer",
+];
+
+impl Role {
+    /// The role of the user, None for users who are no admins at all.
+    pub fn of(config: &Ini, user_id: u64) -> Option<Role> {
+        if config.getint("Bot", "AdminId").unwrap_or(None) == Some(user_id as i64) {
+            return Some(Role::Owner);
+        }
+        [Role::Owner, Role::Admin, Role::Support]
+            .into_iter()
+            .find(|role| {
+                config
+                    .get("Roles", role.key())
+                    .is_some_and(|ids| ids.split([' ', ',']).any(|id| id.parse() == Ok(user_id)))
+            })
+    }
+
+    /// Whether the role may run the command, `approve` for /approve.
+    pub fn allows(&self, config: &Ini, command: &str) -> bool {
+        if *self == Role::Owner {
+            return true;
+        }
+        match config.get("Permissions", self.key()) {
+            Some(commands) => commands
+                .split_whitespace()
+                .any(|allowed| allowed == command),
+            None if *self == Role::Admin => !OWNER_ONLY.contains(&command),
+            None => SUPPORT.contains(&command),
+        }
+    }
+
+    /// The audit actor for an action of the user in this role.
+    pub fn actor(&self, user_id: u64) -> String {
+        format!("{} {}", self, user_id)
+    }
+
+    fn key(&self) -> &'static str {
+        match self {
+            Role::Owner => "Owner",
+            Role::Admin => "Admin",
+            Role::Support => "Support",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.key().to_lowercase())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn roles() {
+    let mut config = Ini::new();
+    config
+        .read("[Bot]\nAdminId = 1\n[Roles]\nAdmin = 2, 3\nSupport = 4\n[Permissions]\nSupport = audit find".to_string())
+        .unwrap();
+    assert!(Role::of(&config, 1) == Some(Role::Owner) && Role::of(&config, 3) == Some(Role::Admin));
+    assert!(Role::of(&config, 4) == Some(Role::Support) && Role::of(&config, 5).is_none());
+    assert!(Role::Owner.allows(&config, "backup") && !Role::Admin.allows(&config, "backup"));
+    assert!(Role::Admin.allows(&config, "broadcast") && !Role::Support.allows(&config, "approve"));
+    assert!(Role::Support.allows(&config, "find") && Role::Support.actor(4) == "support 4");
+}
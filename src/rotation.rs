@@ -0,0 +1,104 @@
+use crate::error::Result;
+use crate::scheduler::Context;
+use crate::wireguard::Peer;
+use crate::{audit, peers};
+#[cfg(feature = "telegram")]
+use crate::{keys, wireguard};
+use bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "telegram")]
+use teloxide::{prelude::*, types::InputFile};
+
+/// A record of replaced peer keys.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rotation {
+    pub peer_id: Option<ObjectId>,
+    pub username: String,
+    pub old_key: Option<String>,
+    pub new_key: Option<String>,
+    pub date: DateTime,
+    pub reason: String,
+}
+
+/// Whether the peer's keys are older than `days`.
+pub fn due(peer: &Peer, days: u64, now: DateTime) -> bool {
+    if peer.public_key.is_none() || peer.ip.is_none() || peer.suspended.is_some() {
+        return false;
+    }
+    let issued = peer.keys_issued.unwrap_or(peer.date);
+    now.timestamp_millis() - issued.timestamp_millis() >= days as i64 * 24 * 60 * 60 * 1000
+}
+
+/// Rotates keys older than `[Rotation] Days` and sends linked users their new config, the
+/// `rotation` job.
+pub async fn run(ctx: &Context) -> Result<()> {
+    let (store, config) = (&ctx.store, &ctx.config);
+    let days = config
+        .lock()
+        .await
+        .getuint("Rotation", "Days")
+        .unwrap_or(None)
+        .unwrap_or(0);
+    if days == 0 {
+        return Ok(());
+    }
+    let now = DateTime::now();
+    for mut peer in store.get_peers().await? {
+        if !due(&peer, days, now) {
+            continue;
+        }
+        let rotated = peers::rotate(&mut peer, "scheduled", store, config.clone()).await;
+        audit::record(store, "schedule", "rotate", &peer.username, &rotated).await;
+        if let Err(why) = rotated {
+            tracing::error!("Cannot rotate keys of {}: {}", peer.username, why);
+            continue;
+        }
+        tracing::info!("Rotated keys of {}", peer.username);
+        #[cfg(feature = "telegram")]
+        {
+            // Private chat ids match user ids, unlinked peers are reported to the admin
+            let chat_id = match peer.user_id {
+                0 => config.lock().await.getint("Bot", "AdminId").unwrap_or(None),
+                user_id => Some(user_id as i64),
+            };
+            let chat_id = match chat_id {
+                None => continue,
+                Some(chat_id) => ChatId(chat_id),
+            };
+            let path = match wireguard::gen_conf(&peer, config.clone()).await {
+                Err(why) => {
+                    tracing::error!("Cannot generate config for {}: {}", peer.username, why);
+                    continue;
+                }
+                Ok(path) => path,
+            };
+            let caption = ctx.locales.tr(peer.language.as_deref(), None).format(
+                "keys-rotated",
+                &[("name", &peer.username), ("days", &days.to_string())],
+            );
+            let sent = ctx
+                .bot
+                .send_document(chat_id, InputFile::file(&path))
+                .caption(caption)
+                .await;
+            keys::forget(&path, &*config.lock().await);
+            if let Err(why) = sent {
+                tracing::error!("{}", why);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn rotation_due() {
+    let mut peer = Peer::new(1, "alice".to_string());
+    let now = DateTime::from_millis(peer.date.timestamp_millis() + 91 * 24 * 60 * 60 * 1000);
+    assert!(!due(&peer, 90, now));
+    peer.public_key = Some("A".to_string());
+    peer.ip = Some(std::net::Ipv4Addr::new(10, 0, 0, 2));
+    assert!(due(&peer, 90, now));
+    peer.keys_issued = Some(DateTime::from_millis(now.timestamp_millis() - 1000));
+    assert!(!due(&peer, 90, now));
+}
@@ -0,0 +1,241 @@
+//! Periodic jobs by name: each runs every `[Schedule] <Name>` (e.g. `1h`, `off` turns it off),
+//! a little later by up to `[Schedule] Jitter` percent so nodes sharing a db don't run in step.
+//! Failures are logged and sent to the admin, /run triggers a job right away. Watchers with
+//! state between ticks, like alerts, probes and reconcile, keep their own loops.
+use crate::error::{GimmewireError, Result};
+#[cfg(feature = "telegram")]
+use crate::i18n::Locales;
+use crate::store::Store;
+use crate::{
+    alerts, billing, endpoint, notify, peers, referral, repair, rotation, shutdown, stale, trial,
+    usage,
+};
+use bson::DateTime;
+use configparser::ini::Ini;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(feature = "telegram")]
+use teloxide::Bot;
+use tokio::sync::Mutex;
+
+/// What jobs work with.
+#[derive(Clone)]
+pub struct Context {
+    pub store: Store,
+    pub config: Arc<Mutex<Ini>>,
+    #[cfg(feature = "telegram")]
+    pub bot: Bot,
+    #[cfg(feature = "telegram")]
+    pub locales: Locales,
+}
+
+type Run = fn(Context) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// The jobs with their default intervals in seconds.
+fn jobs() -> Vec<(&'static str, u64, Run)> {
+    vec![
+        ("expiry", 60, |ctx| {
+            Box::pin(async move { peers::expire(&ctx).await })
+        }),
+        ("billing", 60 * 60, |ctx| {
+            Box::pin(async move { billing::run(&ctx).await })
+        }),
+        ("trial", 60 * 60, |ctx| {
+            Box::pin(async move { trial::run(&ctx).await })
+        }),
+        ("referral", 10 * 60, |ctx| {
+            Box::pin(async move { referral::run(&ctx).await })
+        }),
+        ("rotation", 60 * 60, |ctx| {
+            Box::pin(async move { rotation::run(&ctx).await })
+        }),
+        ("stale", 60 * 60, |ctx| {
+            Box::pin(async move { stale::run(&ctx).await })
+        }),
+        ("endpoint", 10 * 60, |ctx| {
+            Box::pin(async move { endpoint::run(&ctx).await })
+        }),
+        ("usage", 10 * 60, |ctx| {
+            Box::pin(async move { usage::run(&ctx).await })
+        }),
+        ("repair", 60, |ctx| {
+            Box::pin(async move { repair::run(&ctx).await })
+        }),
+    ]
+}
+
+pub struct Job {
+    pub name: &'static str,
+    /// None when the job is off and only runs with /run.
+    pub every: Option<std::time::Duration>,
+    run: Run,
+    /// Held while the job runs, a trigger waits for a scheduled run and the other way round.
+    running: Mutex<()>,
+    /// When the job last finished and how.
+    last: std::sync::Mutex<Option<(DateTime, std::result::Result<(), String>)>>,
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+    ctx: Context,
+    jobs: Arc<Vec<Job>>,
+    jitter: u64,
+}
+
+impl Scheduler {
+    pub fn new(ctx: Context, config: &Ini) -> Self {
+        let jobs = jobs()
+            .into_iter()
+            .map(|(name, default, run)| Job {
+                name,
+                every: interval(config, name, default),
+                run,
+                running: Mutex::new(()),
+                last: std::sync::Mutex::new(None),
+            })
+            .collect();
+        let jitter = config
+            .getuint("Schedule", "Jitter")
+            .unwrap_or(None)
+            .unwrap_or(10)
+            .min(100);
+        Scheduler {
+            ctx,
+            jobs: Arc::new(jobs),
+            jitter,
+        }
+    }
+
+    /// Runs every job which is on right away and then at its interval.
+    pub fn start(&self) {
+        for job in self.jobs.iter() {
+            let every = match job.every {
+                None => continue,
+                Some(every) => every,
+            };
+            let (scheduler, name) = (self.clone(), job.name);
+            tokio::spawn(async move {
+                loop {
+                    // Failures are already reported
+                    let _ = scheduler.trigger(name).await;
+                    tokio::time::sleep(every + jitter(every, scheduler.jitter)).await;
+                }
+            });
+        }
+    }
+
+    /// Runs the job now, after a run of it which is under way.
+    pub async fn trigger(&self, name: &str) -> Result<()> {
+        let job = match self.jobs.iter().find(|job| job.name == name) {
+            None => {
+                return Err(GimmewireError::Invalid(format!(
+                    "There is no job {}, try {}",
+                    name,
+                    self.names().join(", ")
+                )))
+            }
+            Some(job) => job,
+        };
+        let _running = job.running.lock().await;
+        let _busy = match shutdown::begin() {
+            None => {
+                return Err(GimmewireError::Invalid(
+                    "gimmewire is shutting down".to_string(),
+                ))
+            }
+            Some(busy) => busy,
+        };
+        let result = (job.run)(self.ctx.clone()).await;
+        if let Err(why) = &result {
+            tracing::error!("Job {} failed: {}", name, why);
+            notify::send(format!("⚠️ Job {} failed: {}", name, why));
+        }
+        *job.last
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some((
+            DateTime::now(),
+            result.as_ref().map(|_| ()).map_err(|why| why.to_string()),
+        ));
+        result
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.jobs.iter().map(|job| job.name).collect()
+    }
+
+    /// A line per job: its interval and last run.
+    pub fn status(&self) -> String {
+        self.jobs
+            .iter()
+            .map(|job| {
+                let every = match job.every {
+                    None => "off".to_string(),
+                    Some(every) => format!("every {}s", every.as_secs()),
+                };
+                let last = job
+                    .last
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .clone();
+                let last = match last {
+                    None => "not run yet".to_string(),
+                    Some((date, result)) => format!(
+                        "{} {}",
+                        date.try_to_rfc3339_string().unwrap_or_default(),
+                        match result {
+                            Ok(_) => "ok".to_string(),
+                            Err(why) => format!("failed: {}", why),
+                        }
+                    ),
+                };
+                format!("{}: {}, {}\n", job.name, every, last)
+            })
+            .collect()
+    }
+}
+
+/// `[Schedule] <Name>` of the job, its default when unset, None when `off` or 0.
+pub fn interval(config: &Ini, name: &str, default: u64) -> Option<std::time::Duration> {
+    let every = match config.get("Schedule", name) {
+        None => std::time::Duration::from_secs(default),
+        Some(every) if every == "off" || every == "0" => return None,
+        Some(every) => match alerts::parse_duration(&every) {
+            Some(every) => every,
+            None => {
+                tracing::error!("[Schedule] {} = {} is not like 90s, 10m or 2h", name, every);
+                std::time::Duration::from_secs(default)
+            }
+        },
+    };
+    Some(every).filter(|every| !every.is_zero())
+}
+
+/// A random delay of up to `percent` of the interval.
+fn jitter(every: std::time::Duration, percent: u64) -> std::time::Duration {
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let most = every.as_millis() as u64 * percent / 100;
+    std::time::Duration::from_millis(random % (most + 1))
+}
+
+#[cfg(test)]
+#[test]
+fn job_intervals() {
+    let mut config = Ini::new();
+    config
+        .read("[Schedule]\nRotation = 2h\nStale = off\nTrial = soon".to_string())
+        .unwrap();
+    let hours = |hours: u64| Some(std::time::Duration::from_secs(hours * 60 * 60));
+    assert!(interval(&config, "rotation", 60) == hours(2));
+    assert!(interval(&config, "stale", 60).is_none());
+    assert!(
+        interval(&config, "trial", 3600) == hours(1)
+            && interval(&config, "billing", 3600) == hours(1)
+    );
+    let every = std::time::Duration::from_secs(100);
+    assert!(jitter(every, 10) <= std::time::Duration::from_secs(10));
+    assert!(jitter(every, 0).is_zero());
+}
@@ -0,0 +1,248 @@
+use crate::error::Result;
+use crate::store::{Filter, Store};
+#[cfg(all(target_os = "linux", not(feature = "mock")))]
+use crate::userspace;
+use crate::wireguard::{Interface, Peer, AMNEZIA};
+use crate::{keys, reload, wireguard};
+use configparser::ini::Ini;
+use std::sync::Arc;
+#[cfg(feature = "telegram")]
+use teloxide::{prelude::*, types::InputFile};
+use tokio::sync::Mutex;
+
+/// Replaces the interface key, stores it as its `Key` setting and re-issues the client configs of its peers.
+pub async fn rotate_key(
+    interface: &str,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+    config_path: &str,
+) -> Result<()> {
+    let (interface, key_file) = {
+        let config = config.lock().await;
+        let interface = wireguard::find_interface(&config, interface)?;
+        // Interfaces on other nodes don't run on this host's boringtun
+        #[cfg(all(target_os = "linux", not(feature = "mock")))]
+        let key_file = userspace::key_file(&config).filter(|_| interface.host.is_none());
+        #[cfg(any(not(target_os = "linux"), feature = "mock"))]
+        let key_file: Option<String> = None;
+        (interface, key_file)
+    };
+    std::fs::copy(config_path, format!("{}.bak", config_path))?;
+    let public_key = wireguard::rotate_server_key(&interface, key_file.as_deref()).await?;
+    {
+        let mut config = config.lock().await;
+        config.set(&interface.section, "Key", Some(public_key.clone()));
+        reload::persist(config_path, &interface.section, "Key", &public_key)?;
+    }
+    println!(
+        "{} public key is now {}, the old config is saved to {}.bak",
+        interface.name, public_key, config_path
+    );
+    let filter = Filter {
+        interface: Some(interface.name.clone()),
+        ..Filter::default()
+    };
+    let peers: Vec<_> = store
+        .find_peers(&filter, 0, None)
+        .await?
+        .into_iter()
+        .filter(|peer| peer.private_key.is_some() && peer.ip.is_some())
+        .collect();
+    // Stay well below the Telegram limit of 30 messages per second
+    #[cfg(feature = "telegram")]
+    let delay = config
+        .lock()
+        .await
+        .getuint("Rotation", "PushDelay")
+        .unwrap_or(None)
+        .unwrap_or(1000);
+    #[cfg(feature = "telegram")]
+    let bot = Bot::from_env();
+    let mut manual = Vec::new();
+    for (i, peer) in peers.iter().enumerate() {
+        let path = match wireguard::gen_conf(peer, config.clone()).await {
+            Err(why) => {
+                println!(
+                    "[{}/{}] Cannot generate config for {}: {}",
+                    i + 1,
+                    peers.len(),
+                    peer.username,
+                    why
+                );
+                manual.push(peer.username.clone());
+                continue;
+            }
+            Ok(path) => path,
+        };
+        #[cfg(feature = "telegram")]
+        if peer.user_id != 0 {
+            // Private chat ids match user ids
+            match bot
+                .send_document(ChatId(peer.user_id as i64), InputFile::file(&path))
+                .caption("The server key has changed, import this config instead of the old one")
+                .await
+            {
+                Err(why) => {
+                    println!(
+                        "[{}/{}] Cannot send config to {}: {}",
+                        i + 1,
+                        peers.len(),
+                        peer.username,
+                        why
+                    );
+                    manual.push(peer.username.clone());
+                }
+                Ok(_) => println!(
+                    "[{}/{}] Sent config to {}",
+                    i + 1,
+                    peers.len(),
+                    peer.username
+                ),
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+            continue;
+        }
+        println!(
+            "[{}/{}] Saved config of {} to {}",
+            i + 1,
+            peers.len(),
+            peer.username,
+            path
+        );
+        manual.push(peer.username.clone());
+    }
+    if !manual.is_empty() {
+        println!("Deliver these configs by hand: {}", manual.join(", "));
+    }
+    if !keys::stored(&*config.lock().await) {
+        println!("Private keys are not kept, users get a config with the new key when they rotate");
+    }
+    println!("Reload gimmewire with SIGHUP so the bot picks up the new key");
+    Ok(())
+}
+
+/// The wg-quick config of the interface as the db has it, an `[Interface]` with the server
+/// address, port and key and a `[Peer]` for every active peer, e.g. to rebuild it after a
+/// reinstall. Without a private key it is left for the operator to fill in.
+pub fn render(
+    interface: &Interface,
+    config: &Ini,
+    peers: &[Peer],
+    private_key: Option<&str>,
+    listen_port: Option<u16>,
+) -> String {
+    let port = listen_port
+        .or_else(|| {
+            let endpoint = interface.get(config, "Endpoint")?;
+            endpoint.rsplit_once(':')?.1.parse().ok()
+        })
+        .unwrap_or(51820);
+    let mut conf = format!(
+        "# {}, written by gimmewire from its db\n[Interface]\nAddress = {}\nListenPort = {}\n",
+        interface.name,
+        interface.server_address(),
+        port
+    );
+    match private_key {
+        Some(key) => conf.push_str(&format!("PrivateKey = {}\n", key)),
+        None => conf.push_str(&format!(
+            "# PrivateKey = the key whose public key is {}\n",
+            interface.get(config, "Key").unwrap_or_default()
+        )),
+    }
+    if interface.amnezia {
+        for key in AMNEZIA {
+            if let Some(value) = interface.get(config, key) {
+                conf.push_str(&format!("{} = {}\n", key, value));
+            }
+        }
+    }
+    for peer in peers.iter().filter(|peer| {
+        peer.interface == interface.name && peer.suspended.is_none() && peer.archived.is_none()
+    }) {
+        if let (Some(public_key), Some(ip)) = (&peer.public_key, peer.ip) {
+            conf.push_str(&format!(
+                "\n[Peer]\n# {}\nPublicKey = {}\nAllowedIPs = {}/32\n",
+                peer.username, public_key, ip
+            ));
+        }
+    }
+    conf
+}
+
+/// Renders the interface config, the private key and port come from `private_key`, a file,
+/// or the running interface. Prints it or saves it to `output`, readable by its owner only.
+pub async fn export(
+    interface: &str,
+    private_key: Option<String>,
+    output: Option<String>,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<()> {
+    let interface = wireguard::find_interface(&*config.lock().await, interface)?;
+    let running = wireguard::showconf(&interface).await.ok();
+    let setting = |key: &str| {
+        running.as_deref()?.lines().find_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            let value = value.trim();
+            (name.trim().eq_ignore_ascii_case(key) && !value.is_empty()).then(|| value.to_string())
+        })
+    };
+    let private_key = match private_key {
+        Some(path) => Some(std::fs::read_to_string(&path)?.trim().to_string()),
+        None => setting("PrivateKey"),
+    };
+    if private_key.is_none() {
+        eprintln!(
+            "Cannot read the private key of {}, fill it in or pass --private-key",
+            interface.name
+        );
+    }
+    let port = setting("ListenPort").and_then(|port| port.parse().ok());
+    let filter = Filter {
+        interface: Some(interface.name.clone()),
+        ..Filter::default()
+    };
+    let peers = store.find_peers(&filter, 0, None).await?;
+    let conf = render(
+        &interface,
+        &*config.lock().await,
+        &peers,
+        private_key.as_deref(),
+        port,
+    );
+    match output {
+        None => print!("{}", conf),
+        Some(path) => {
+            wireguard::write_private(&path, &conf)?;
+            println!(
+                "Saved {} with {} peers to {}",
+                interface.name,
+                peers.len(),
+                path
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn server_conf() {
+    let mut config = Ini::new();
+    config
+        .read("[Peer]\nPool = 10.8.0.0/16\nEndpoint = vpn.example.com:51999\nKey = PUB".to_string())
+        .unwrap();
+    let interface = &wireguard::interfaces(&config)[0];
+    let mut alice = Peer::new(1, "alice".to_string());
+    alice.public_key = Some("keyA".to_string());
+    alice.ip = Some(std::net::Ipv4Addr::new(10, 8, 0, 2));
+    let mut bob = alice.clone();
+    bob.username = "bob".to_string();
+    bob.suspended = Some(bson::DateTime::now());
+    let conf = render(interface, &config, &[alice, bob], None, None);
+    assert!(conf.contains("Address = 10.8.0.1/16\nListenPort = 51999\n# PrivateKey = the key whose public key is PUB\n"));
+    assert!(conf.ends_with("\n[Peer]\n# alice\nPublicKey = keyA\nAllowedIPs = 10.8.0.2/32\n"));
+    assert!(render(interface, &config, &[], Some("PRIV"), Some(51820))
+        .contains("ListenPort = 51820\nPrivateKey = PRIV\n"));
+}
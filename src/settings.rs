@@ -0,0 +1,374 @@
+//! The config read into types once, at startup and on reload, so a mistake is reported right
+//! away with every other one instead of surfacing when a client config is generated.
+use crate::error::{GimmewireError, Result};
+use crate::wireguard::{self, Interface};
+use configparser::ini::Ini;
+use std::net::{IpAddr, SocketAddr};
+
+/// Client config settings of an interface, DNS, KeepAlive, MTU and Table fall back to `[Peer]`.
+#[derive(Debug, Clone)]
+pub struct Network {
+    pub endpoint: String,
+    /// Public key of the server, clients cannot connect without it.
+    pub key: Option<String>,
+    /// Prefix of client addresses, the pool's by default.
+    pub subnet: u8,
+    pub dns: String,
+    pub keepalive: u16,
+    pub mtu: Option<u16>,
+    pub table: Option<String>,
+    /// AmneziaWG fields which are set, when the interface is an AmneziaWG one.
+    pub obfuscation: Vec<(String, String)>,
+}
+
+impl Network {
+    /// The settings of the interface, every problem with them in one error.
+    pub fn of(config: &Ini, interface: &Interface) -> Result<Network> {
+        let mut problems = Vec::new();
+        let network = Network::read(config, interface, &mut problems);
+        match problems.is_empty() {
+            true => Ok(network),
+            false => Err(invalid(problems)),
+        }
+    }
+
+    fn read(config: &Ini, interface: &Interface, problems: &mut Vec<String>) -> Network {
+        let section = &interface.section;
+        let mut problem = |what: &str| problems.push(format!("[{}] {}", section, what));
+        let endpoint = interface.get(config, "Endpoint").unwrap_or_default();
+        let valid = endpoint
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            problem("Endpoint must look like host:port");
+        }
+        let key = interface.get(config, "Key");
+        if key.as_deref().is_some_and(|key| !public_key(key)) {
+            problem("Key must be a public key like `wg show wg0 public-key` prints");
+        }
+        let subnet = match interface.get(config, "Subnet").map(|subnet| subnet.parse()) {
+            None => interface.prefix,
+            Some(Ok(subnet)) if subnet <= 32 => subnet,
+            Some(_) => {
+                problem("Subnet must be a prefix length");
+                interface.prefix
+            }
+        };
+        let dns = interface
+            .get(config, "DNS")
+            .unwrap_or_else(|| "8.8.8.8".to_string());
+        if dns
+            .split(',')
+            .any(|ip| ip.trim().parse::<IpAddr>().is_err())
+        {
+            problem("DNS must be a list of addresses");
+        }
+        let keepalive = match interface.get(config, "KeepAlive").map(|k| k.parse()) {
+            None => 25,
+            Some(Ok(keepalive)) => keepalive,
+            Some(Err(_)) => {
+                problem("KeepAlive must be seconds");
+                25
+            }
+        };
+        let mtu = match interface.get(config, "MTU").map(|mtu| mtu.parse()) {
+            None => None,
+            Some(Ok(mtu)) if mtu >= 576 => Some(mtu),
+            Some(_) => {
+                problem("MTU must be a number from 576 to 65535");
+                None
+            }
+        };
+        let mut obfuscation = Vec::new();
+        if interface.amnezia {
+            let mut values = Vec::new();
+            for field in wireguard::AMNEZIA {
+                match interface
+                    .get(config, field)
+                    .map(|value| value.parse::<u32>())
+                {
+                    None => values.push(None),
+                    Some(Ok(value)) => {
+                        values.push(Some(value));
+                        obfuscation.push((field.to_string(), value.to_string()));
+                    }
+                    Some(Err(_)) => {
+                        problem(&format!("{} must be a number", field));
+                        values.push(None);
+                    }
+                }
+            }
+            if let Some(why) = amnezia_problem(&values) {
+                problem(why);
+            }
+        }
+        Network {
+            endpoint,
+            key,
+            subnet,
+            dns,
+            keepalive,
+            mtu,
+            table: interface.get(config, "Table"),
+            obfuscation,
+        }
+    }
+}
+
+/// Where peers are kept, `[Storage] Backend`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Storage {
+    Mongo {
+        url: String,
+        name: String,
+        table: String,
+    },
+    Sql {
+        url: String,
+    },
+    File {
+        path: String,
+    },
+}
+
+impl Storage {
+    /// `[Storage] Backend` and the settings of that backend, every problem with them in one error.
+    pub fn from_config(config: &Ini) -> Result<Storage> {
+        let mut problems = Vec::new();
+        match Storage::read(config, &mut problems) {
+            Some(storage) if problems.is_empty() => Ok(storage),
+            _ => Err(invalid(problems)),
+        }
+    }
+
+    pub fn backend(&self) -> &'static str {
+        match self {
+            Storage::Mongo { .. } => "mongo",
+            Storage::Sql { .. } => "sql",
+            Storage::File { .. } => "file",
+        }
+    }
+
+    fn read(config: &Ini, problems: &mut Vec<String>) -> Option<Storage> {
+        let backend = config
+            .get("Storage", "Backend")
+            .unwrap_or_else(|| "mongo".to_string());
+        let mut setting = |section: &str, key: &str| match config.get(section, key) {
+            Some(value) => value,
+            None => {
+                problems.push(format!("[{}] {} must be set", section, key));
+                String::new()
+            }
+        };
+        let storage = match backend.as_str() {
+            "mongo" => {
+                let (url, name, table) = (
+                    setting("Mongo", "URL"),
+                    setting("Mongo", "Name"),
+                    setting("Mongo", "Table"),
+                );
+                if !url.is_empty()
+                    && !url.starts_with("mongodb://")
+                    && !url.starts_with("mongodb+srv://")
+                {
+                    problems.push("[Mongo] URL must start with mongodb://".to_string());
+                }
+                #[cfg(feature = "mongo")]
+                if let Some(mode) = config.get("Mongo", "ReadPreference") {
+                    if crate::mongo::read_preference(&mode).is_none() {
+                        problems.push(format!(
+                            "[Mongo] ReadPreference {} is not primary, primaryPreferred, secondary, secondaryPreferred or nearest",
+                            mode
+                        ));
+                    }
+                }
+                Storage::Mongo { url, name, table }
+            }
+            "sqlite" | "postgres" => Storage::Sql {
+                url: setting("Storage", "URL"),
+            },
+            "file" => Storage::File {
+                path: config
+                    .get("Storage", "Path")
+                    .unwrap_or_else(|| "gimmewire.json".to_string()),
+            },
+            _ => {
+                problems.push(format!(
+                    "[Storage] Backend {} is not mongo, sqlite, postgres or file",
+                    backend
+                ));
+                return None;
+            }
+        };
+        Some(storage)
+    }
+}
+
+/// What is checked at startup, chat ids and the dashboard address only need to be valid.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub interfaces: Vec<(Interface, Network)>,
+    pub storage: Storage,
+}
+
+impl Settings {
+    /// Reads the config, failing with a list of everything which is wrong in it.
+    pub fn parse(config: &Ini) -> Result<Settings> {
+        let mut problems = Vec::new();
+        let interfaces: Vec<(Interface, Network)> = wireguard::interfaces(config)
+            .into_iter()
+            .map(|interface| {
+                let network = Network::read(config, &interface, &mut problems);
+                (interface, network)
+            })
+            .collect();
+        if !interfaces
+            .iter()
+            .any(|(interface, _)| interface.section == "Peer")
+        {
+            problems.push("[Peer] Pool must look like 10.0.0.0/16".to_string());
+        }
+        for (interface, _) in &interfaces {
+            // The file would be written on this host, not on the node
+            if interface.host.is_some()
+                && interface
+                    .save
+                    .as_deref()
+                    .is_some_and(|save| save != "wg-quick")
+            {
+                problems.push(format!(
+                    "[{}] Save can only be wg-quick for an interface with a Host",
+                    interface.section
+                ));
+            }
+        }
+        for section in config.sections() {
+            let parsed = interfaces
+                .iter()
+                .any(|(interface, _)| interface.section == section);
+            if section.starts_with("interface ") && !parsed {
+                problems.push(format!("[{}] needs a Pool like 10.1.0.0/16", section));
+            }
+            if let Some(Err(why)) = config
+                .get(&section, "Reserved")
+                .map(|reserved| wireguard::reserved_ranges(&reserved))
+            {
+                problems.push(format!("[{}] Reserved: {}", section, why));
+            }
+        }
+        let storage = Storage::read(config, &mut problems);
+        for key in ["AdminId", "NotifyChat"] {
+            if config.getint("Bot", key).is_err() {
+                problems.push(format!("[Bot] {} must be a chat id", key));
+            }
+        }
+        let listen = config.get("Http", "Listen");
+        if listen.is_some_and(|listen| listen.parse::<SocketAddr>().is_err()) {
+            problems.push("[Http] Listen must look like 127.0.0.1:8080".to_string());
+        }
+        let webhook = config.get("Webhook", "URL");
+        if webhook.is_some_and(|url| !url.starts_with("https://")) {
+            problems.push("[Webhook] URL must start with https://".to_string());
+        }
+        let listen = config.get("Webhook", "Listen");
+        if listen.is_some_and(|listen| listen.parse::<SocketAddr>().is_err()) {
+            problems.push("[Webhook] Listen must look like 0.0.0.0:8443".to_string());
+        }
+        #[cfg(feature = "hooks")]
+        problems.extend(crate::hooks::problems(config));
+        match storage {
+            Some(storage) if problems.is_empty() => Ok(Settings {
+                interfaces,
+                storage,
+            }),
+            _ => Err(invalid(problems)),
+        }
+    }
+}
+
+/// What is wrong with AmneziaWG values in the order of `wireguard::AMNEZIA`, by the limits of
+/// the AmneziaWG docs.
+fn amnezia_problem(values: &[Option<u32>]) -> Option<&'static str> {
+    let (jc, jmin, jmax, s1, s2) = (values[0], values[1], values[2], values[3], values[4]);
+    if jc.is_some_and(|jc| !(1..=128).contains(&jc)) {
+        return Some("Jc must be from 1 to 128");
+    }
+    if jmin.zip(jmax).is_some_and(|(jmin, jmax)| jmin > jmax) || jmax.is_some_and(|j| j > 1280) {
+        return Some("Jmin must not be above Jmax, nor Jmax above 1280");
+    }
+    if s1.is_some_and(|s1| s1 > 1132) || s2.is_some_and(|s2| s2 > 1188) {
+        return Some("S1 must not be above 1132, nor S2 above 1188");
+    }
+    if s1.zip(s2).is_some_and(|(s1, s2)| s1 + 56 == s2) {
+        return Some("S1 + 56 must not be S2, packets would look alike");
+    }
+    let headers: Vec<u32> = values[5..].iter().flatten().copied().collect();
+    let distinct = headers
+        .iter()
+        .enumerate()
+        .all(|(i, h)| *h > 4 && !headers[..i].contains(h));
+    if !distinct {
+        return Some("H1 to H4 must differ from each other and be above 4");
+    }
+    None
+}
+
+/// A WireGuard key is 32 bytes in base64.
+fn public_key(key: &str) -> bool {
+    key.len() == 44
+        && key.ends_with('=')
+        && key[..43]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+}
+
+fn invalid(problems: Vec<String>) -> GimmewireError {
+    GimmewireError::Config(format!(
+        "The config has {} problem{}:\n- {}",
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" },
+        problems.join("\n- ")
+    ))
+}
+
+#[cfg(test)]
+#[test]
+fn settings_problems() {
+    let mut config = Ini::new();
+    config
+        .read(
+            "[Peer]\nEndpoint = vpn.example.com\nKey = short\nMTU = 100\n[Mongo]\nURL = localhost\n[Bot]\nAdminId = admin\n[Interface node1]\nEndpoint = 128.0.0.2:51820"
+                .to_string(),
+        )
+        .unwrap();
+    let problems = Settings::parse(&config).unwrap_err().to_string();
+    for problem in [
+        "[Peer] Endpoint",
+        "[Peer] Key",
+        "[Peer] MTU",
+        "[Mongo] URL must",
+        "[Mongo] Name",
+        "[Bot] AdminId",
+        "[interface node1] needs a Pool",
+    ] {
+        assert!(
+            problems.contains(problem),
+            "{} is not in {}",
+            problem,
+            problems
+        );
+    }
+    config.set("Peer", "Amnezia", Some("true".to_string()));
+    config.set("Peer", "Jc", Some("4".to_string()));
+    config.set("Peer", "H1", Some("7".to_string()));
+    config.set("Peer", "H2", Some("7".to_string()));
+    let problems = Settings::parse(&config).unwrap_err().to_string();
+    assert!(problems.contains("H1 to H4") && !problems.contains("Jc"));
+    config.set("Peer", "Host", Some("node1".to_string()));
+    config.set("Peer", "Save", Some("/etc/wireguard/wg0.conf".to_string()));
+    let problems = Settings::parse(&config).unwrap_err().to_string();
+    assert!(problems.contains("[Peer] Save can only be wg-quick"));
+    let settings = Settings::parse(&crate::reload::read("gimmewire.conf").unwrap()).unwrap();
+    let network = &settings.interfaces[0].1;
+    assert!(network.subnet == 16 && network.keepalive == 25 && network.key.is_some());
+}
@@ -0,0 +1,200 @@
+//! Rate limits of peers with tc: an HTB class per peer shapes what the interface sends to it,
+//! an ingress police filter what it receives from it. Rebuilt from the db like `firewall`.
+use crate::error::Result;
+use crate::store::Store;
+use crate::wireguard::{self, Interface, Peer};
+use configparser::ini::Ini;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Whether limits may be on the interfaces, a previous run could have left them.
+static INSTALLED: AtomicBool = AtomicBool::new(true);
+
+/// Handle of the root qdisc gimmewire puts on devices, those of others are never deleted.
+const HANDLE: &str = "7767:";
+
+/// Mbit/s a peer may download and upload, None is unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Limits {
+    pub download: Option<u32>,
+    pub upload: Option<u32>,
+}
+
+impl Limits {
+    pub fn is_limited(&self) -> bool {
+        self.download.is_some() || self.upload.is_some()
+    }
+}
+
+/// The peer's own limits set with /tune, otherwise `Download` and `Upload` of its plan. 0 is
+/// unlimited.
+pub fn limits(peer: &Peer, config: &Ini) -> Limits {
+    let plan = peer
+        .subscription
+        .as_ref()
+        .map(|subscription| format!("plan {}", subscription.plan));
+    let rate = |own: Option<u32>, key: &str| {
+        own.or_else(|| {
+            let plan = plan.as_ref()?;
+            config.getuint(plan, key).unwrap_or(None)?.try_into().ok()
+        })
+        .filter(|rate| *rate > 0)
+    };
+    Limits {
+        download: rate(peer.download, "Download"),
+        upload: rate(peer.upload, "Upload"),
+    }
+}
+
+/// A `tc -batch` script putting the limits on the device, which has no qdiscs yet.
+pub fn script(device: &str, peers: &[(&Peer, Limits)]) -> String {
+    let mut script = format!(
+        "qdisc add dev {dev} root handle {handle} htb default 1\nclass add dev {dev} parent {handle} classid {handle}1 htb rate 100gbit\nqdisc add dev {dev} handle ffff: ingress\n",
+        dev = device,
+        handle = HANDLE
+    );
+    for (i, (peer, limits)) in peers.iter().enumerate() {
+        let ip = match peer.ip {
+            None => continue,
+            Some(ip) => ip,
+        };
+        if let Some(download) = limits.download {
+            let class = format!("{}{:x}", HANDLE, i + 2);
+            script.push_str(&format!(
+                "class add dev {dev} parent {handle} classid {class} htb rate {rate}mbit ceil {rate}mbit\nfilter add dev {dev} parent {handle} protocol ip prio 1 u32 match ip dst {ip}/32 flowid {class}\n",
+                dev = device,
+                handle = HANDLE,
+                class = class,
+                rate = download,
+                ip = ip
+            ));
+        }
+        if let Some(upload) = limits.upload {
+            script.push_str(&format!(
+                "filter add dev {dev} parent ffff: protocol ip prio 1 u32 match ip src {ip}/32 police rate {rate}mbit burst {burst}k drop flowid :1\n",
+                dev = device,
+                ip = ip,
+                rate = upload,
+                burst = (upload * 16).max(32)
+            ));
+        }
+    }
+    script
+}
+
+/// Puts the limits of every peer on its interface. Only the qdiscs gimmewire added are replaced,
+/// devices without limited peers nor those qdiscs are left alone.
+pub async fn sync(store: &Store, config: Arc<Mutex<Ini>>) -> Result<()> {
+    let (peers, interfaces) = {
+        let peers = store.get_peers().await?;
+        let config = config.lock().await;
+        let peers: Vec<(Peer, Limits)> = peers
+            .into_iter()
+            .filter(|peer| peer.ip.is_some() && peer.suspended.is_none())
+            .map(|peer| {
+                let limits = limits(&peer, &config);
+                (peer, limits)
+            })
+            .filter(|(_, limits)| limits.is_limited())
+            .collect();
+        (peers, wireguard::interfaces(&config))
+    };
+    if peers.is_empty() && !INSTALLED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    for interface in &interfaces {
+        let limited: Vec<(&Peer, Limits)> = peers
+            .iter()
+            .filter(|(peer, _)| peer.interface == interface.name)
+            .map(|(peer, limits)| (peer, *limits))
+            .collect();
+        let qdiscs = tc_on(
+            interface,
+            &["qdisc", "show", "dev", &interface.device],
+            None,
+        )?;
+        if ours(&qdiscs) {
+            // The ingress qdisc is added with the root one
+            for parent in ["root", "ingress"] {
+                tc_on(
+                    interface,
+                    &["qdisc", "del", "dev", &interface.device, parent],
+                    None,
+                )?;
+            }
+        }
+        if !limited.is_empty() {
+            tc_on(
+                interface,
+                &["-batch", "-"],
+                Some(&script(&interface.device, &limited)),
+            )?;
+        }
+    }
+    INSTALLED.store(!peers.is_empty(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether `tc qdisc show` lists the root qdisc gimmewire adds.
+fn ours(qdiscs: &str) -> bool {
+    qdiscs.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.starts_with(&["qdisc", "htb", HANDLE]) && fields.contains(&"root")
+    })
+}
+
+/// Runs tc on the node of the interface, over ssh if it has a Host. Changes are only logged in a
+/// dry run.
+fn tc_on(interface: &Interface, args: &[&str], input: Option<&str>) -> Result<String> {
+    let reads = args.get(1) == Some(&"show");
+    if !reads && crate::dryrun::skip(&format!("tc {}", args.join(" ")), input) {
+        return Ok(String::new());
+    }
+    tc(interface, args, input)
+}
+
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn tc(interface: &Interface, args: &[&str], input: Option<&str>) -> Result<String> {
+    match &interface.host {
+        Some(host) => {
+            let mut remote = vec!["-o", "BatchMode=yes", host.as_str(), "tc"];
+            remote.extend_from_slice(args);
+            wireguard::run("/usr/bin/ssh", &remote, input)
+        }
+        None => wireguard::run("/usr/sbin/tc", args, input),
+    }
+}
+
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+fn tc(interface: &Interface, args: &[&str], input: Option<&str>) -> Result<String> {
+    crate::mock::tc(interface.host.as_deref(), args, input)
+}
+
+#[cfg(test)]
+#[test]
+fn rate_limits() {
+    let mut config = Ini::new();
+    config
+        .read("[Plan basic]\nDownload = 20\nUpload = 5".to_string())
+        .unwrap();
+    let mut peer = Peer::new(1, "alice".to_string());
+    peer.ip = Some(std::net::Ipv4Addr::new(10, 0, 0, 2));
+    assert!(!limits(&peer, &config).is_limited());
+    peer.subscription = Some(crate::wireguard::Subscription {
+        plan: "basic".to_string(),
+        paid_until: bson::DateTime::now(),
+        reminded: false,
+    });
+    peer.upload = Some(0);
+    let limited = limits(&peer, &config);
+    assert!(limited.download == Some(20) && limited.upload.is_none());
+    let script = script("wg0", &[(&peer, limited)]);
+    assert!(script.contains("classid 7767:2 htb rate 20mbit ceil 20mbit\n"));
+    assert!(script.contains("u32 match ip dst 10.0.0.2/32 flowid 7767:2\n"));
+    assert!(!script.contains("match ip src"));
+    assert!(ours("qdisc htb 7767: dev wg0 root refcnt 2 r2q 10 default 0x1\nqdisc ingress ffff: dev wg0 parent ffff:fff1 ----------------\n"));
+    assert!(!ours(
+        "qdisc htb 1: dev wg0 root refcnt 2 r2q 10 default 0x1\n"
+    ));
+}
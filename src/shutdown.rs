@@ -0,0 +1,176 @@
+//! Stopping on SIGTERM or Ctrl-C without half-applied peers: bot updates, jobs and dashboard
+//! requests stop being taken and those under way get `[Shutdown] Timeout` seconds to finish.
+//! Under systemd with `Type=notify` the service reports when it is ready and stopping and pings
+//! the watchdog, see gimmewire.service.
+use configparser::ini::Ini;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// Operations under way, see `begin`.
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+static STOP: LazyLock<Notify> = LazyLock::new(Notify::new);
+static IDLE: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// An operation under way, shutdown waits until it is dropped.
+pub struct Busy(());
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        if BUSY.fetch_sub(1, Ordering::SeqCst) == 1 {
+            IDLE.notify_waiters();
+        }
+    }
+}
+
+/// Marks an operation as under way, None once shutting down so it is not started.
+pub fn begin() -> Option<Busy> {
+    if STOPPING.load(Ordering::SeqCst) {
+        return None;
+    }
+    BUSY.fetch_add(1, Ordering::SeqCst);
+    Some(Busy(()))
+}
+
+/// Waits for SIGTERM or Ctrl-C, after which nothing new is begun.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Err(why) => {
+                tracing::error!("Cannot listen for SIGTERM: {}", why);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => (),
+                    _ = tokio::signal::ctrl_c() => (),
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    tracing::info!("Shutting down...");
+    STOPPING.store(true, Ordering::SeqCst);
+    STOP.notify_waiters();
+    sd_notify("STOPPING=1");
+}
+
+/// Resolves once shutting down.
+pub async fn stopped() {
+    let stop = STOP.notified();
+    if STOPPING.load(Ordering::SeqCst) {
+        return;
+    }
+    stop.await;
+}
+
+/// `[Shutdown] Timeout`, seconds operations under way get to finish.
+pub fn timeout(config: &Ini) -> Duration {
+    let seconds = config
+        .getuint("Shutdown", "Timeout")
+        .unwrap_or(None)
+        .unwrap_or(30);
+    Duration::from_secs(seconds)
+}
+
+/// Waits for operations under way and then for queued notifications and hook events, at most
+/// `timeout` in all.
+pub async fn drain(timeout: Duration) {
+    let started = Instant::now();
+    let idle = async {
+        loop {
+            let idle = IDLE.notified();
+            if BUSY.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            idle.await;
+        }
+    };
+    if tokio::time::timeout(timeout, idle).await.is_err() {
+        tracing::warn!(
+            "Stopping with {} operations still under way",
+            BUSY.load(Ordering::SeqCst)
+        );
+    }
+    crate::notify::flush(timeout.saturating_sub(started.elapsed())).await;
+    #[cfg(feature = "hooks")]
+    crate::hooks::flush(timeout.saturating_sub(started.elapsed())).await;
+    tracing::info!("Stopped");
+}
+
+/// Tells systemd the service is up and starts pinging its watchdog.
+pub fn ready() {
+    sd_notify("READY=1");
+    let every = watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    );
+    if let Some(every) = every {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(every).await;
+                sd_notify("WATCHDOG=1");
+            }
+        });
+    }
+}
+
+/// Half of `WatchdogSec`, None without a watchdog or when it is meant for another process.
+fn watchdog(usec: Option<&str>, pid: Option<&str>, own: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(own)) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|every| !every.is_zero())
+}
+
+/// Sends a state like `READY=1` to `NOTIFY_SOCKET`, when systemd set one.
+#[cfg(unix)]
+fn sd_notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Err(_) => return,
+        Ok(path) => path,
+    };
+    let socket = match UnixDatagram::unbound() {
+        Err(why) => {
+            tracing::warn!("Cannot notify systemd: {}", why);
+            return;
+        }
+        Ok(socket) => socket,
+    };
+    let sent = match path.strip_prefix('@') {
+        // An abstract socket
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    };
+    if let Err(why) = sent {
+        tracing::warn!("Cannot notify systemd: {}", why);
+    }
+}
+
+#[cfg(not(unix))]
+fn sd_notify(_state: &str) {}
+
+#[cfg(test)]
+#[test]
+fn watchdog_interval() {
+    assert!(watchdog(Some("60000000"), None, 7) == Some(Duration::from_secs(30)));
+    assert!(watchdog(Some("60000000"), Some("7"), 7).is_some());
+    assert!(watchdog(Some("60000000"), Some("8"), 7).is_none());
+    assert!(watchdog(None, None, 7).is_none() && watchdog(Some("0"), None, 7).is_none());
+    let busy = begin().unwrap();
+    assert!(BUSY.load(Ordering::SeqCst) >= 1);
+    drop(busy);
+}
@@ -0,0 +1,315 @@
+use crate::audit::Event;
+use crate::error::{GimmewireError, Result};
+use crate::referral::Referral;
+use crate::repair::Repair;
+use crate::rotation::Rotation;
+use crate::store::PeerStore;
+use crate::wireguard::Peer;
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use serde::de::DeserializeOwned;
+use sqlx::any::{AnyArguments, AnyPoolOptions};
+use sqlx::query::Query;
+use sqlx::{Any, AnyPool, Row};
+
+/// SQLite or Postgres store, picked by the url scheme. Peers are kept as JSON next to the
+/// columns they are looked up by.
+pub struct Sql {
+    pool: AnyPool,
+}
+
+impl Sql {
+    pub async fn new(url: &str) -> Result<Self> {
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .connect(url)
+            .await
+            .map_err(GimmewireError::from)?;
+        for schema in [
+            "CREATE TABLE IF NOT EXISTS peers (id TEXT PRIMARY KEY, user_id BIGINT NOT NULL, username TEXT NOT NULL, archived BIGINT, data TEXT NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS rotations (username TEXT NOT NULL, date BIGINT NOT NULL, data TEXT NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS audit (date BIGINT NOT NULL, data TEXT NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS referrals (referred BIGINT PRIMARY KEY, data TEXT NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS repairs (interface TEXT NOT NULL, public_key TEXT NOT NULL, data TEXT NOT NULL, PRIMARY KEY (interface, public_key))",
+        ] {
+            sqlx::query(schema)
+                .execute(&pool)
+                .await
+                .map_err(GimmewireError::from)?;
+        }
+        Ok(Sql { pool })
+    }
+
+    async fn fetch<'q, T: DeserializeOwned>(
+        &self,
+        query: Query<'q, Any, AnyArguments<'q>>,
+    ) -> Result<Vec<T>> {
+        let rows = query.fetch_all(&self.pool).await.map_err(|why| {
+            tracing::error!("{}", why);
+            GimmewireError::from(why)
+        })?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let data: String = row.try_get("data").ok()?;
+                match serde_json::from_str(&data) {
+                    Err(why) => {
+                        tracing::error!("Cannot read a row from db {}", why);
+                        None
+                    }
+                    Ok(row) => Some(row),
+                }
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl PeerStore for Sql {
+    async fn add(&self, peer: &Peer) -> Result<()> {
+        // Like Mongo, the store assigns ids to peers which don't have one
+        let mut peer = peer.clone();
+        let id = *peer.id.get_or_insert_with(ObjectId::new);
+        let data = serde_json::to_string(&peer).map_err(GimmewireError::from)?;
+        match sqlx::query(
+            "INSERT INTO peers (id, user_id, username, archived, data) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(id.to_hex())
+        .bind(peer.user_id as i64)
+        .bind(peer.username.clone())
+        .bind(peer.archived.map(|date| date.timestamp_millis()))
+        .bind(data)
+        .execute(&self.pool)
+        .await
+        {
+            Err(why) => {
+                tracing::error!("Cannot add peer to db {}", why);
+                Err(GimmewireError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn update(&self, peer: &Peer) -> Result<()> {
+        let updated = async {
+            let mut peer = peer.clone();
+            let mut transaction = self.pool.begin().await?;
+            // Like delete, a peer without an id is the active peer of its user
+            if peer.id.is_none() {
+                let stored =
+                    sqlx::query("SELECT id FROM peers WHERE user_id = $1 AND archived IS NULL")
+                        .bind(peer.user_id as i64)
+                        .fetch_optional(&mut *transaction)
+                        .await?;
+                peer.id = stored
+                    .and_then(|row| row.try_get::<String, _>("id").ok())
+                    .and_then(|id| ObjectId::parse_str(id).ok());
+            }
+            let id = *peer.id.get_or_insert_with(ObjectId::new);
+            let data =
+                serde_json::to_string(&peer).map_err(|why| sqlx::Error::Encode(why.into()))?;
+            sqlx::query(
+                "INSERT INTO peers (id, user_id, username, archived, data) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (id) DO UPDATE SET user_id = excluded.user_id, username = excluded.username, archived = excluded.archived, data = excluded.data",
+            )
+            .bind(id.to_hex())
+            .bind(peer.user_id as i64)
+            .bind(peer.username.clone())
+            .bind(peer.archived.map(|date| date.timestamp_millis()))
+            .bind(data)
+            .execute(&mut *transaction)
+            .await?;
+            transaction.commit().await
+        };
+        match updated.await {
+            Err(why) => {
+                tracing::error!("Cannot update peer in db {}", why);
+                Err(GimmewireError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<Option<Peer>> {
+        Ok(self
+            .fetch(
+                sqlx::query("SELECT data FROM peers WHERE user_id = $1 AND archived IS NULL")
+                    .bind(id as i64),
+            )
+            .await?
+            .pop())
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<Peer>> {
+        Ok(self
+            .fetch(
+                sqlx::query("SELECT data FROM peers WHERE username = $1 AND archived IS NULL")
+                    .bind(username.to_string()),
+            )
+            .await?
+            .pop())
+    }
+
+    async fn delete(&self, peer: &Peer) -> Result<()> {
+        let query = match peer.id {
+            Some(id) => sqlx::query("DELETE FROM peers WHERE id = $1").bind(id.to_hex()),
+            None => sqlx::query("DELETE FROM peers WHERE user_id = $1 AND archived IS NULL")
+                .bind(peer.user_id as i64),
+        };
+        match query.execute(&self.pool).await {
+            Err(why) => {
+                tracing::error!("Cannot delete peer from db {}", why);
+                Err(GimmewireError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn get_peers(&self) -> Result<Vec<Peer>> {
+        self.fetch(sqlx::query("SELECT data FROM peers WHERE archived IS NULL"))
+            .await
+    }
+
+    async fn get_archived(&self) -> Result<Vec<Peer>> {
+        self.fetch(sqlx::query(
+            "SELECT data FROM peers WHERE archived IS NOT NULL",
+        ))
+        .await
+    }
+
+    async fn log_rotation(&self, rotation: &Rotation) -> Result<()> {
+        let data = serde_json::to_string(rotation).map_err(GimmewireError::from)?;
+        match sqlx::query("INSERT INTO rotations (username, date, data) VALUES ($1, $2, $3)")
+            .bind(rotation.username.clone())
+            .bind(rotation.date.timestamp_millis())
+            .bind(data)
+            .execute(&self.pool)
+            .await
+        {
+            Err(why) => {
+                tracing::error!("Cannot log key rotation {}", why);
+                Err(GimmewireError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn log_event(&self, event: &Event) -> Result<()> {
+        let data = serde_json::to_string(event).map_err(GimmewireError::from)?;
+        match sqlx::query("INSERT INTO audit (date, data) VALUES ($1, $2)")
+            .bind(event.date.timestamp_millis())
+            .bind(data)
+            .execute(&self.pool)
+            .await
+        {
+            Err(why) => {
+                tracing::error!("Cannot log audit event {}", why);
+                Err(GimmewireError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn get_events(&self, skip: u64, limit: u64) -> Result<Vec<Event>> {
+        self.fetch(
+            sqlx::query("SELECT data FROM audit ORDER BY date DESC LIMIT $1 OFFSET $2")
+                .bind(limit as i64)
+                .bind(skip as i64),
+        )
+        .await
+    }
+
+    async fn save_referral(&self, referral: &Referral) -> Result<()> {
+        let data = serde_json::to_string(referral).map_err(GimmewireError::from)?;
+        match sqlx::query(
+            "INSERT INTO referrals (referred, data) VALUES ($1, $2) ON CONFLICT (referred) DO UPDATE SET data = excluded.data",
+        )
+        .bind(referral.referred as i64)
+        .bind(data)
+        .execute(&self.pool)
+        .await
+        {
+            Err(why) => {
+                tracing::error!("Cannot save referral {}", why);
+                Err(GimmewireError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn get_referrals(&self) -> Result<Vec<Referral>> {
+        self.fetch(sqlx::query("SELECT data FROM referrals")).await
+    }
+
+    async fn save_repair(&self, repair: &Repair) -> Result<()> {
+        let data = serde_json::to_string(repair).map_err(GimmewireError::from)?;
+        match sqlx::query(
+            "INSERT INTO repairs (interface, public_key, data) VALUES ($1, $2, $3) ON CONFLICT (interface, public_key) DO UPDATE SET data = excluded.data",
+        )
+        .bind(&repair.interface)
+        .bind(&repair.public_key)
+        .bind(data)
+        .execute(&self.pool)
+        .await
+        {
+            Err(why) => {
+                tracing::error!("Cannot save repair {}", why);
+                Err(GimmewireError::from(why))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn get_repairs(&self) -> Result<Vec<Repair>> {
+        self.fetch(sqlx::query("SELECT data FROM repairs")).await
+    }
+
+    async fn delete_repair(&self, repair: &Repair) -> Result<()> {
+        sqlx::query("DELETE FROM repairs WHERE interface = $1 AND public_key = $2")
+            .bind(&repair.interface)
+            .bind(&repair.public_key)
+            .execute(&self.pool)
+            .await
+            .map_err(GimmewireError::from)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+#[tokio::test]
+async fn sqlite_store() {
+    // Every pooled connection would get its own in-memory db, so use a file
+    let scratch = crate::store::Scratch::new("sqlite.db");
+    let store = Sql::new(&format!("sqlite://{}?mode=rwc", scratch.path()))
+        .await
+        .unwrap();
+    let mut peer = Peer::new(7, "alice".to_string());
+    store.add(&peer).await.unwrap();
+    let stored = store.find_by_id(7).await.unwrap().unwrap();
+    assert!(stored.id.is_some() && stored.username == "alice");
+    peer = stored;
+    peer.archived = Some(bson::DateTime::now());
+    store.update(&peer).await.unwrap();
+    // Replaced where it was, not added again
+    let mut unsaved = Peer::new(8, "bob".to_string());
+    store.add(&unsaved).await.unwrap();
+    unsaved.username = "carol".to_string();
+    store.update(&unsaved).await.unwrap();
+    assert!(store.find_by_id(8).await.unwrap().unwrap().username == "carol");
+    assert!(store.get_peers().await.unwrap().len() == 1);
+    assert!(store.find_by_username("alice").await.unwrap().is_none());
+    assert!(store.get_archived().await.unwrap().len() == 1);
+    let mut repair = Repair {
+        interface: "wg0".to_string(),
+        public_key: "key".to_string(),
+        error: "No such device".to_string(),
+        attempts: 0,
+        since: bson::DateTime::now(),
+        next: bson::DateTime::now(),
+    };
+    store.save_repair(&repair).await.unwrap();
+    repair.attempts = 1;
+    store.save_repair(&repair).await.unwrap();
+    assert!(store.get_repairs().await.unwrap() == vec![repair.clone()]);
+    store.delete_repair(&repair).await.unwrap();
+    assert!(store.get_repairs().await.unwrap().is_empty());
+}
@@ -0,0 +1,159 @@
+//! Peers which stopped connecting: latest handshakes are kept in the db, peers silent for
+//! `[Stale] WarnDays` are told and those silent for `[Stale] RemoveDays` are removed.
+use crate::error::Result;
+#[cfg(feature = "telegram")]
+use crate::i18n;
+use crate::scheduler::Context;
+use crate::wireguard::{self, Peer};
+use crate::{audit, notify, peers, queue};
+use bson::DateTime;
+use std::collections::HashMap;
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Warn,
+    Remove,
+}
+
+/// What to do about a peer without a handshake since `last_handshake`, or since its keys were
+/// issued if it never connected. 0 days turns a step off, a peer is warned once.
+pub fn due(peer: &Peer, warn_days: i64, remove_days: i64, now: DateTime) -> Option<Action> {
+    if peer.public_key.is_none() || peer.suspended.is_some() {
+        return None;
+    }
+    let seen = peer
+        .last_handshake
+        .or(peer.keys_issued)
+        .unwrap_or(peer.date);
+    let silent = now.timestamp_millis() - seen.timestamp_millis();
+    if remove_days > 0 && silent >= remove_days * DAY {
+        return Some(Action::Remove);
+    }
+    if warn_days > 0 && silent >= warn_days * DAY && peer.stale_warned.is_none() {
+        return Some(Action::Warn);
+    }
+    None
+}
+
+/// Records handshakes and handles stale peers, the `stale` job. Off unless `[Stale] WarnDays`
+/// or `RemoveDays` is set.
+pub async fn run(ctx: &Context) -> Result<()> {
+    let (store, config) = (&ctx.store, &ctx.config);
+    let (warn_days, remove_days, interfaces) = {
+        let config = config.lock().await;
+        let days = |key| {
+            config
+                .getint("Stale", key)
+                .unwrap_or(None)
+                .unwrap_or(0)
+                .max(0)
+        };
+        (
+            days("WarnDays"),
+            days("RemoveDays"),
+            wireguard::interfaces(&config),
+        )
+    };
+    if warn_days == 0 && remove_days == 0 {
+        return Ok(());
+    }
+    // Without the interfaces every peer would look silent
+    let handshakes: HashMap<String, DateTime> = wireguard::show_all(&interfaces)
+        .await?
+        .into_iter()
+        .filter_map(|stat| Some((stat.public_key, stat.latest_handshake?)))
+        .collect();
+    let now = DateTime::now();
+    for listed in store.get_peers().await? {
+        let latest = listed
+            .public_key
+            .as_ref()
+            .and_then(|key| handshakes.get(key))
+            .copied();
+        let checked = queue::exclusive(check(&listed, latest, warn_days, remove_days, now, ctx));
+        let (peer, action) = match checked.await {
+            Err(why) => {
+                tracing::error!("Cannot check {} for staleness: {}", listed.username, why);
+                continue;
+            }
+            Ok(None) => continue,
+            Ok(Some(checked)) => checked,
+        };
+        match action {
+            Action::Warn => {
+                #[cfg(feature = "telegram")]
+                {
+                    let days = warn_days.to_string();
+                    let args = [("name", peer.username.as_str()), ("days", days.as_str())];
+                    i18n::tell(&ctx.bot, &ctx.locales, &peer, "stale-warning", &args).await;
+                }
+            }
+            Action::Remove => {
+                let reason = format!("no handshake for {} days", remove_days);
+                notify::send(format!("🧹 {} removed, {}", peer.username, reason));
+                #[cfg(feature = "telegram")]
+                {
+                    let args = [("name", peer.username.as_str())];
+                    i18n::tell(&ctx.bot, &ctx.locales, &peer, "stale-removed", &args).await;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Records the latest handshake of the peer as stored now and warns or removes it when due,
+/// returns what was done to it. Runs in the peer's turn, users are told afterwards.
+async fn check(
+    listed: &Peer,
+    latest: Option<DateTime>,
+    warn_days: i64,
+    remove_days: i64,
+    now: DateTime,
+    ctx: &Context,
+) -> Result<Option<(Peer, Action)>> {
+    let (store, config) = (&ctx.store, &ctx.config);
+    let mut peer = match peers::current(listed, store).await? {
+        None => return Ok(None),
+        Some(peer) => peer,
+    };
+    if latest.is_some_and(|latest| Some(latest) > peer.last_handshake) {
+        peer.last_handshake = latest;
+        peer.stale_warned = None;
+        store.update(&peer).await?;
+    }
+    let action = match due(&peer, warn_days, remove_days, now) {
+        None => return Ok(None),
+        Some(action) => action,
+    };
+    match action {
+        Action::Warn => {
+            peer.stale_warned = Some(now);
+            store.update(&peer).await?;
+        }
+        Action::Remove => {
+            let reason = format!("no handshake for {} days", remove_days);
+            let revoked = peers::revoke(&mut peer, &reason, store, config.clone()).await;
+            audit::record(store, "stale", "remove", &peer.username, &revoked).await;
+            revoked?;
+        }
+    }
+    Ok(Some((peer, action)))
+}
+
+#[cfg(test)]
+#[test]
+fn stale_peers() {
+    let mut peer = Peer::new(1, "alice".to_string());
+    let now = DateTime::from_millis(peer.date.timestamp_millis() + 20 * DAY);
+    assert!(due(&peer, 14, 30, now).is_none());
+    peer.public_key = Some("A".to_string());
+    assert!(due(&peer, 14, 30, now) == Some(Action::Warn));
+    assert!(due(&peer, 14, 15, now) == Some(Action::Remove) && due(&peer, 0, 0, now).is_none());
+    peer.stale_warned = Some(now);
+    assert!(due(&peer, 14, 30, now).is_none());
+    peer.last_handshake = Some(DateTime::from_millis(now.timestamp_millis() - DAY));
+    assert!(due(&peer, 14, 15, now).is_none());
+}
@@ -0,0 +1,253 @@
+use crate::audit::Event;
+use crate::error::{GimmewireError, Result};
+use crate::referral::Referral;
+use crate::repair::Repair;
+use crate::rotation::Rotation;
+use crate::settings::Storage;
+use crate::wireguard::Peer;
+use async_trait::async_trait;
+use configparser::ini::Ini;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+/// Where a peer is in its life.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Active,
+    Suspended,
+    Archived,
+}
+
+/// Which peers `find_peers` returns, unset fields match any peer. Without a status only peers
+/// which are not archived match, like `get_peers`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    pub user_id: Option<u64>,
+    pub ip: Option<Ipv4Addr>,
+    pub public_key: Option<String>,
+    pub interface: Option<String>,
+    pub tag: Option<String>,
+    pub status: Option<Status>,
+}
+
+impl Filter {
+    pub fn matches(&self, peer: &Peer) -> bool {
+        let status = match (peer.archived, peer.suspended) {
+            (Some(_), _) => Status::Archived,
+            (None, Some(_)) => Status::Suspended,
+            (None, None) => Status::Active,
+        };
+        let status = match self.status {
+            None => status != Status::Archived,
+            Some(wanted) => status == wanted,
+        };
+        status
+            && self.user_id.is_none_or(|user_id| peer.user_id == user_id)
+            && self.ip.is_none_or(|ip| peer.ip == Some(ip))
+            && self
+                .public_key
+                .as_ref()
+                .is_none_or(|key| peer.public_key.as_ref() == Some(key))
+            && self
+                .interface
+                .as_ref()
+                .is_none_or(|interface| &peer.interface == interface)
+            && self.tag.as_ref().is_none_or(|tag| peer.tags.contains(tag))
+    }
+}
+
+/// Where peers are kept. Lookups only see active peers, archived ones come from `get_archived`.
+/// Reads fail when the backend can't be reached, so nobody takes an outage for an empty db.
+#[async_trait]
+pub trait PeerStore: Send + Sync {
+    async fn add(&self, peer: &Peer) -> Result<()>;
+    async fn update(&self, peer: &Peer) -> Result<()>;
+    async fn find_by_id(&self, id: u64) -> Result<Option<Peer>>;
+    async fn find_by_username(&self, username: &str) -> Result<Option<Peer>>;
+    /// Deletes the peer with the same `id`, or the user's active peer if the id is unknown yet.
+    async fn delete(&self, peer: &Peer) -> Result<()>;
+    async fn get_peers(&self) -> Result<Vec<Peer>>;
+    async fn get_archived(&self) -> Result<Vec<Peer>>;
+    /// Peers matching the filter, `limit` of them after skipping `skip`, in a stable order so
+    /// pages can be walked. Backends which can should query instead of loading every peer.
+    async fn find_peers(
+        &self,
+        filter: &Filter,
+        skip: u64,
+        limit: Option<u64>,
+    ) -> Result<Vec<Peer>> {
+        let peers = match filter.status {
+            Some(Status::Archived) => self.get_archived().await?,
+            _ => self.get_peers().await?,
+        };
+        Ok(peers
+            .into_iter()
+            .filter(|peer| filter.matches(peer))
+            .skip(skip as usize)
+            .take(limit.map_or(usize::MAX, |limit| limit as usize))
+            .collect())
+    }
+    async fn log_rotation(&self, rotation: &Rotation) -> Result<()>;
+    async fn log_event(&self, event: &Event) -> Result<()>;
+    /// Audit events newest first.
+    async fn get_events(&self, skip: u64, limit: u64) -> Result<Vec<Event>>;
+    /// Adds the referral or replaces the one of the same referred user.
+    async fn save_referral(&self, referral: &Referral) -> Result<()>;
+    async fn get_referrals(&self) -> Result<Vec<Referral>>;
+    /// Adds the repair or replaces the one of the same interface and key.
+    async fn save_repair(&self, repair: &Repair) -> Result<()>;
+    async fn get_repairs(&self) -> Result<Vec<Repair>>;
+    async fn delete_repair(&self, repair: &Repair) -> Result<()>;
+    /// False while the backend cannot be reached, so callers can ask users to come back later.
+    async fn available(&self) -> bool {
+        true
+    }
+}
+
+pub type Store = Arc<dyn PeerStore>;
+
+/// Opens the store selected by `[Storage] Backend`, which seals private keys with `[Keys] MasterKey`
+/// and forgets them unless `[Keys] Store`. Peers are cached for `[Storage] Cache` seconds.
+pub async fn open(config: &Ini) -> Result<Store> {
+    let store = backend(config).await?;
+    let ttl = crate::cache::ttl(config);
+    let store: Store = match ttl.is_zero() {
+        true => store,
+        false => Arc::new(crate::cache::Cached::new(store, ttl)),
+    };
+    #[cfg(feature = "encryption")]
+    let store: Store = match crate::keys::master_key(config)? {
+        None => store,
+        Some(master) => Arc::new(crate::keys::Sealed::open(store, master).await?),
+    };
+    #[cfg(not(feature = "encryption"))]
+    crate::keys::master_key(config)?;
+    match crate::keys::stored(config) {
+        true => Ok(store),
+        false => Ok(Arc::new(crate::keys::Transient::new(store))),
+    }
+}
+
+/// The store selected by `[Storage] Backend`, `mongo` by default.
+async fn backend(config: &Ini) -> Result<Store> {
+    match Storage::from_config(config)? {
+        #[cfg(feature = "mongo")]
+        Storage::Mongo { url, name, table } => {
+            let staging = format!("{}{}", table, crate::dryrun::STAGING);
+            let mongo = crate::mongo::Mongo::new(
+                &url,
+                name,
+                match crate::dryrun::on() {
+                    true => staging,
+                    false => table.clone(),
+                },
+                crate::mongo::Settings::from_config(config),
+            )
+            .await?;
+            if crate::dryrun::on() {
+                mongo.seed(&table).await?;
+            }
+            Ok(Arc::new(mongo))
+        }
+        #[cfg(any(feature = "sqlite", feature = "postgres"))]
+        Storage::Sql { url } => {
+            if crate::dryrun::on() {
+                return Err(GimmewireError::Config(
+                    "Dry runs have no staging copy in sql, use the mongo or file backend"
+                        .to_string(),
+                ));
+            }
+            Ok(Arc::new(crate::sql::Sql::new(&url).await?))
+        }
+        #[cfg(feature = "file")]
+        Storage::File { path } => {
+            if !crate::dryrun::on() {
+                return Ok(Arc::new(crate::file::File::open(&path)?));
+            }
+            let staging = format!("{}{}", path, crate::dryrun::STAGING);
+            if !std::path::Path::new(&staging).exists() && std::path::Path::new(&path).exists() {
+                std::fs::copy(&path, &staging)?;
+            }
+            Ok(Arc::new(crate::file::File::open(&staging)?))
+        }
+        #[allow(unreachable_patterns)]
+        storage => Err(GimmewireError::Config(format!(
+            "Storage backend {} was not built in",
+            storage.backend()
+        ))),
+    }
+}
+
+/// A file in the temp dir for the store of a test, removed with the guard even when an assert
+/// fails, so tests of one run never see each other's peers.
+#[cfg(test)]
+pub struct Scratch(std::path::PathBuf);
+
+#[cfg(test)]
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let name = format!("gimmewire-{}-{}", std::process::id(), name);
+        Scratch(std::env::temp_dir().join(name))
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    /// A JSON file store in it.
+    #[cfg(feature = "file")]
+    pub fn file(&self) -> Store {
+        Arc::new(crate::file::File::open(self.path()).unwrap())
+    }
+}
+
+#[cfg(test)]
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        let _ = std::fs::remove_file(format!("{}.tmp", self.path()));
+        let _ = std::fs::remove_file(format!("{}.lock", self.path()));
+    }
+}
+
+#[cfg(all(test, feature = "file"))]
+#[tokio::test]
+async fn filtered_peers() {
+    let scratch = Scratch::new("filter.json");
+    let store = scratch.file();
+    for (user_id, name) in [(1, "alice"), (2, "bob"), (3, "carol")] {
+        let mut peer = Peer::new(user_id, name.to_string());
+        peer.ip = Some(Ipv4Addr::new(10, 0, 0, user_id as u8 + 1));
+        if name == "bob" {
+            peer.suspended = Some(bson::DateTime::now());
+        }
+        store.add(&peer).await.unwrap();
+    }
+    let names =
+        |peers: Vec<Peer>| -> Vec<String> { peers.into_iter().map(|p| p.username).collect() };
+    assert!(
+        names(
+            store
+                .find_peers(&Filter::default(), 1, Some(1))
+                .await
+                .unwrap()
+        ) == ["bob"]
+    );
+    let filter = Filter {
+        status: Some(Status::Active),
+        ..Filter::default()
+    };
+    assert!(names(store.find_peers(&filter, 0, None).await.unwrap()) == ["alice", "carol"]);
+    let filter = Filter {
+        ip: Some(Ipv4Addr::new(10, 0, 0, 3)),
+        interface: Some("wg0".to_string()),
+        ..Filter::default()
+    };
+    assert!(names(store.find_peers(&filter, 0, None).await.unwrap()) == ["bob"]);
+    let filter = Filter {
+        user_id: Some(2),
+        status: Some(Status::Archived),
+        ..Filter::default()
+    };
+    assert!(store.find_peers(&filter, 0, None).await.unwrap().is_empty());
+}
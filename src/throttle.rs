@@ -0,0 +1,31 @@
+//! How often users may do something costly or abusable, counted in memory per user and action.
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// When each user did each action within the last window.
+type Uses = HashMap<(u64, &'static str), Vec<Instant>>;
+
+static USES: LazyLock<Mutex<Uses>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Counts a use of the action by the user, false when they already had `max` within the window.
+pub fn allow(user_id: u64, action: &'static str, max: u64, window: Duration) -> bool {
+    let mut uses = USES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let now = Instant::now();
+    let times = uses.entry((user_id, action)).or_default();
+    times.retain(|time| now.duration_since(*time) < window);
+    if times.len() as u64 >= max {
+        return false;
+    }
+    times.push(now);
+    true
+}
+
+#[cfg(test)]
+#[test]
+fn allowed_uses() {
+    let hour = Duration::from_secs(60 * 60);
+    assert!(allow(1, "test", 2, hour) && allow(1, "test", 2, hour));
+    assert!(!allow(1, "test", 2, hour) && allow(2, "test", 2, hour));
+    assert!(allow(1, "test", 2, Duration::ZERO) && !allow(3, "test", 0, hour));
+}
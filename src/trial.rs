@@ -0,0 +1,285 @@
+use crate::error::{GimmewireError, Result};
+#[cfg(feature = "telegram")]
+use crate::i18n;
+use crate::scheduler::Context;
+use crate::store::Store;
+use crate::wireguard::{self, Peer, Trial};
+use crate::{audit, peers, queue, usage};
+use bson::DateTime;
+use configparser::ini::Ini;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+const GB: u64 = 1024 * 1024 * 1024;
+
+/// What a trial gives, `[Trial] Days` and `[Trial] Quota` in GB.
+#[derive(Debug, PartialEq)]
+pub struct Settings {
+    pub days: Option<u64>,
+    pub quota: Option<u64>,
+}
+
+/// None when neither limit is set and /start grants nothing.
+pub fn settings(config: &Ini) -> Option<Settings> {
+    let days = config.getuint("Trial", "Days").unwrap_or(None);
+    let quota = config.getuint("Trial", "Quota").unwrap_or(None);
+    match (days, quota) {
+        (None | Some(0), None | Some(0)) => None,
+        _ => Some(Settings {
+            days: days.filter(|days| *days > 0),
+            quota: quota.filter(|quota| *quota > 0).map(|quota| quota * GB),
+        }),
+    }
+}
+
+/// Whether the peer is on a trial which hasn't run out.
+pub fn running(peer: &Peer, now: DateTime) -> bool {
+    match &peer.trial {
+        Some(trial) => trial.ended.is_none() && trial.until.is_none_or(|until| until > now),
+        None => false,
+    }
+}
+
+/// When the trial of the peer started, trials from before this was stored began with the peer.
+pub fn began(peer: &Peer) -> DateTime {
+    peer.trial
+        .as_ref()
+        .and_then(|trial| trial.started)
+        .unwrap_or(peer.date)
+}
+
+/// Whether a running trial is out of days or of traffic, `used` in bytes since it began.
+pub fn over(trial: &Trial, used: u64, now: DateTime) -> bool {
+    trial.ended.is_none()
+        && (trial.until.is_some_and(|until| until <= now)
+            || trial.quota.is_some_and(|quota| used >= quota))
+}
+
+/// Creates a peer on a trial for a user who has never had one.
+#[tracing::instrument(skip_all, fields(user_id = user_id, peer = %username))]
+pub async fn start(
+    user_id: u64,
+    username: String,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<Peer> {
+    let settings = match settings(&*config.lock().await) {
+        None => return Err(GimmewireError::Invalid("There are no trials".to_string())),
+        Some(settings) => settings,
+    };
+    if store.find_by_id(user_id).await?.is_some() {
+        return Err(GimmewireError::PeerExists(username));
+    }
+    // Deleting the peer doesn't give another trial
+    let had_trial = store
+        .get_archived()
+        .await?
+        .iter()
+        .any(|peer| peer.user_id == user_id && peer.trial.is_some());
+    if had_trial {
+        return Err(GimmewireError::Invalid(format!(
+            "{} has already had a trial",
+            username
+        )));
+    }
+    let now = DateTime::now().timestamp_millis();
+    let mut peer = Peer::new(user_id, username);
+    peer.interface = peers::placement(store, config).await?;
+    peer.trial = Some(Trial {
+        started: Some(DateTime::from_millis(now)),
+        until: settings
+            .days
+            .map(|days| DateTime::from_millis(now + days as i64 * DAY)),
+        quota: settings.quota,
+        ended: None,
+    });
+    store.add(&peer).await?;
+    Ok(peer)
+}
+
+/// Gives a trial `days` more and brings its peer back if the trial had run out.
+#[tracing::instrument(skip_all, fields(peer = %peer.username))]
+pub async fn extend(
+    peer: &mut Peer,
+    days: u64,
+    store: &Store,
+    config: Arc<Mutex<Ini>>,
+) -> Result<()> {
+    let now = DateTime::now();
+    let trial = match &mut peer.trial {
+        None => {
+            return Err(GimmewireError::Invalid(format!(
+                "{} has no trial",
+                peer.username
+            )))
+        }
+        Some(trial) => trial,
+    };
+    let from = match trial.until {
+        Some(until) if until > now => until,
+        _ => now,
+    };
+    trial.until = Some(DateTime::from_millis(
+        from.timestamp_millis() + days as i64 * DAY,
+    ));
+    trial.ended = None;
+    match peer.suspended {
+        Some(_) => peers::resume(peer, store, config).await,
+        None => store.update(peer).await,
+    }
+}
+
+/// Ends trials out of days or traffic and suspends their peers unless they were paid for, the
+/// `trial` job. Traffic is what the `usage` job added up since the trial began, and what the
+/// interfaces counted since it last ran.
+pub async fn run(ctx: &Context) -> Result<()> {
+    let (store, config) = (&ctx.store, &ctx.config);
+    let interfaces = wireguard::interfaces(&*config.lock().await);
+    let peers: Vec<Peer> = store
+        .get_peers()
+        .await?
+        .into_iter()
+        .filter(|peer| peer.trial.as_ref().is_some_and(|t| t.ended.is_none()))
+        .collect();
+    if peers.is_empty() {
+        return Ok(());
+    }
+    // Only what wasn't added to the days yet, the trial still ends by date without it
+    let counters: HashMap<String, (u64, u64)> = match wireguard::show_all(&interfaces).await {
+        Err(why) => {
+            tracing::error!("Cannot read trial traffic: {}", why);
+            HashMap::new()
+        }
+        Ok(stats) => stats
+            .into_iter()
+            .map(|stat| (stat.public_key, (stat.rx, stat.tx)))
+            .collect(),
+    };
+    let now = DateTime::now();
+    for listed in peers {
+        let live = listed
+            .public_key
+            .as_ref()
+            .and_then(|key| counters.get(key))
+            .copied();
+        let used = usage::since(&listed, began(&listed), live);
+        if !listed
+            .trial
+            .as_ref()
+            .is_some_and(|trial| over(trial, used, now))
+        {
+            continue;
+        }
+        // Read again in its turn, so ending the trial doesn't undo an extension made since
+        let ended = queue::exclusive(end(&listed, live, now, ctx)).await;
+        let peer = match ended {
+            Err(why) => {
+                tracing::error!("Cannot end trial of {}: {}", listed.username, why);
+                continue;
+            }
+            Ok(Some(peer)) if peer.suspended.is_some() => peer,
+            // Paid for, the peer keeps working
+            Ok(_) => continue,
+        };
+        tracing::info!("Trial of {} ended", peer.username);
+        #[cfg(feature = "telegram")]
+        {
+            let billed = !crate::billing::plans(&*config.lock().await).is_empty();
+            let key = match billed {
+                true => "trial-ended-subscribe",
+                false => "trial-ended",
+            };
+            i18n::tell(&ctx.bot, &ctx.locales, &peer, key, &[]).await;
+        }
+    }
+    Ok(())
+}
+
+/// Ends the trial of the peer as stored now if it is over, and suspends the peer unless it was
+/// paid for. Returns the peer whose trial ended.
+async fn end(
+    listed: &Peer,
+    live: Option<(u64, u64)>,
+    now: DateTime,
+    ctx: &Context,
+) -> Result<Option<Peer>> {
+    let (store, config) = (&ctx.store, &ctx.config);
+    let mut peer = match peers::current(listed, store).await? {
+        None => return Ok(None),
+        Some(peer) => peer,
+    };
+    let used = usage::since(&peer, began(&peer), live);
+    match &mut peer.trial {
+        Some(trial) if trial.ended.is_none() && over(trial, used, now) => trial.ended = Some(now),
+        _ => return Ok(None),
+    }
+    #[cfg(feature = "hooks")]
+    if peer
+        .trial
+        .as_ref()
+        .and_then(|trial| trial.quota)
+        .is_some_and(|quota| used >= quota)
+    {
+        let payload =
+            crate::hooks::Payload::new("quota-exceeded", &peer.username, "trial", "trial");
+        crate::hooks::send(payload);
+    }
+    let paid = peer
+        .subscription
+        .as_ref()
+        .is_some_and(|subscription| subscription.paid_until > now);
+    if paid {
+        store.update(&peer).await?;
+        return Ok(Some(peer));
+    }
+    let suspended = peers::suspend(&mut peer, store, config.clone()).await;
+    audit::record(store, "trial", "suspend", &peer.username, &suspended).await;
+    suspended?;
+    Ok(Some(peer))
+}
+
+#[cfg(test)]
+#[test]
+fn trials() {
+    let mut config = Ini::new();
+    assert!(settings(&config).is_none());
+    config
+        .read("[Trial]\nDays = 7\nQuota = 10".to_string())
+        .unwrap();
+    let settings = settings(&config).unwrap();
+    assert!(settings.days == Some(7) && settings.quota == Some(10 * GB));
+    let trial = Trial {
+        started: Some(DateTime::from_millis(0)),
+        until: Some(DateTime::from_millis(7 * DAY)),
+        quota: settings.quota,
+        ended: None,
+    };
+    let mut peer = Peer::new(1, "alice".to_string());
+    peer.trial = Some(trial.clone());
+    assert!(running(&peer, DateTime::from_millis(0)));
+    assert!(!over(&trial, GB, DateTime::from_millis(DAY)));
+    assert!(over(&trial, 10 * GB, DateTime::from_millis(DAY)));
+    assert!(over(&trial, 0, DateTime::from_millis(7 * DAY)));
+    assert!(!running(&peer, DateTime::from_millis(7 * DAY)));
+    // Days before the trial and counters already added up are not counted again
+    peer.usage = Some(crate::wireguard::Usage {
+        rx: 100,
+        tx: 100,
+        days: vec![
+            crate::wireguard::Day {
+                date: DateTime::from_millis(-DAY),
+                rx: GB,
+                tx: GB,
+            },
+            crate::wireguard::Day {
+                date: DateTime::from_millis(0),
+                rx: 3 * GB,
+                tx: 0,
+            },
+        ],
+    });
+    assert!(usage::since(&peer, began(&peer), None) == 3 * GB);
+    assert!(usage::since(&peer, began(&peer), Some((150, 100))) == 3 * GB + 50);
+}
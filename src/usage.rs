@@ -0,0 +1,252 @@
+//! Traffic of peers by day: the `usage` job adds what the wg counters grew by to the day, and
+//! /usage shows users the month and the last 30 days of their devices, as a bar chart with
+//! `/usage chart`.
+use crate::error::Result;
+use crate::scheduler::Context;
+use crate::wireguard::{self, Day, Peer, Usage};
+use crate::{peers, queue};
+use bson::DateTime;
+use std::collections::HashMap;
+
+/// Days of traffic kept per peer.
+pub const DAYS: i64 = 30;
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Adds what the counters grew by since they were last seen to the day of `now`. Counters start
+/// over when the peer is applied again, then all of the new count is traffic.
+pub fn record(usage: &mut Usage, rx: u64, tx: u64, now: DateTime) {
+    let (sent, received) = (grown(rx, usage.rx), grown(tx, usage.tx));
+    usage.rx = rx;
+    usage.tx = tx;
+    let today = DateTime::from_millis(now.timestamp_millis() / DAY * DAY);
+    match usage.days.last_mut() {
+        Some(day) if day.date == today => {
+            day.rx += sent;
+            day.tx += received;
+        }
+        _ => usage.days.push(Day {
+            date: today,
+            rx: sent,
+            tx: received,
+        }),
+    }
+    let oldest = today.timestamp_millis() - (DAYS - 1) * DAY;
+    usage
+        .days
+        .retain(|day| day.date.timestamp_millis() >= oldest);
+}
+
+fn grown(count: u64, before: u64) -> u64 {
+    count.checked_sub(before).unwrap_or(count)
+}
+
+/// Bytes the peer sent and received from the day of `start` on: the days the `usage` job added
+/// up, and what the counters `live` grew by since it last saw them.
+pub fn since(peer: &Peer, start: DateTime, live: Option<(u64, u64)>) -> u64 {
+    let first = DateTime::from_millis(start.timestamp_millis() / DAY * DAY);
+    let (days, seen) = match &peer.usage {
+        None => (&[][..], (0, 0)),
+        Some(usage) => (&usage.days[..], (usage.rx, usage.tx)),
+    };
+    let counted: u64 = days
+        .iter()
+        .filter(|day| day.date >= first)
+        .map(|day| day.rx + day.tx)
+        .sum();
+    let pending = live.map_or(0, |(rx, tx)| grown(rx, seen.0) + grown(tx, seen.1));
+    counted + pending
+}
+
+/// Counts traffic of every peer on the interfaces, the `usage` job. Peers are counted from the
+/// first time the job sees them.
+pub async fn run(ctx: &Context) -> Result<()> {
+    let interfaces = wireguard::interfaces(&*ctx.config.lock().await);
+    let counters: HashMap<String, (u64, u64)> = wireguard::show_all(&interfaces)
+        .await?
+        .into_iter()
+        .map(|stat| (stat.public_key, (stat.rx, stat.tx)))
+        .collect();
+    let now = DateTime::now();
+    for listed in ctx.store.get_peers().await? {
+        let (rx, tx) = match listed.public_key.as_ref().and_then(|key| counters.get(key)) {
+            None => continue,
+            Some(counters) => *counters,
+        };
+        // Read again in its turn, so the write doesn't undo changes made since the list was read
+        let recorded = queue::exclusive(async {
+            let mut peer = match peers::current(&listed, &ctx.store).await? {
+                None => return Ok(()),
+                Some(peer) => peer,
+            };
+            match &mut peer.usage {
+                Some(usage) if usage.rx == rx && usage.tx == tx => return Ok(()),
+                Some(usage) => record(usage, rx, tx, now),
+                None => {
+                    peer.usage = Some(Usage {
+                        rx,
+                        tx,
+                        days: vec![],
+                    })
+                }
+            }
+            ctx.store.update(&peer).await
+        })
+        .await;
+        if let Err(why) = recorded {
+            tracing::error!("Cannot record traffic of {}: {}", listed.username, why);
+        }
+    }
+    Ok(())
+}
+
+/// Received and sent bytes of the peers on each of the last 30 days, the oldest first.
+pub fn daily(peers: &[Peer], now: DateTime) -> Vec<(u64, u64)> {
+    let today = now.timestamp_millis() / DAY;
+    let mut days = vec![(0, 0); DAYS as usize];
+    for day in peers
+        .iter()
+        .filter_map(|peer| peer.usage.as_ref())
+        .flat_map(|usage| &usage.days)
+    {
+        let ago = today - day.date.timestamp_millis() / DAY;
+        if (0..DAYS).contains(&ago) {
+            let slot = &mut days[(DAYS - 1 - ago) as usize];
+            slot.0 += day.tx;
+            slot.1 += day.rx;
+        }
+    }
+    days
+}
+
+/// Midnight of the first day of the month of `now`, UTC.
+pub fn month_start(now: DateTime) -> DateTime {
+    let days = now.timestamp_millis().div_euclid(DAY);
+    // Day of the month from days since 1970, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    DateTime::from_millis((days - day + 1) * DAY)
+}
+
+/// Received and sent bytes of the peers since the month of `now` began.
+pub fn month(peers: &[Peer], now: DateTime) -> (u64, u64) {
+    let start = month_start(now);
+    peers
+        .iter()
+        .filter_map(|peer| peer.usage.as_ref())
+        .flat_map(|usage| &usage.days)
+        .filter(|day| day.date >= start)
+        .fold((0, 0), |(down, up), day| (down + day.tx, up + day.rx))
+}
+
+/// Bytes a running trial of the peer has left, from the days the `usage` job added up since it
+/// began.
+pub fn quota_left(peer: &Peer, now: DateTime) -> Option<u64> {
+    let quota = peer.trial.as_ref()?.quota?;
+    if !crate::trial::running(peer, now) {
+        return None;
+    }
+    let used = since(peer, crate::trial::began(peer), None);
+    Some(quota.saturating_sub(used))
+}
+
+pub fn bytes(n: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+/// Draws the days as a PNG bar chart into `path`: downloads blue and uploads orange, a line at
+/// every quarter of the busiest day.
+#[cfg(feature = "telegram")]
+pub fn chart(days: &[(u64, u64)], path: &str) -> Result<()> {
+    use image::{Rgb, RgbImage};
+    const WIDTH: u32 = 20;
+    const HEIGHT: u32 = 300;
+    let mut image = RgbImage::from_pixel(WIDTH * days.len() as u32, HEIGHT, Rgb([255, 255, 255]));
+    let busiest = days
+        .iter()
+        .map(|(down, up)| (*down).max(*up))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    for quarter in 1..4 {
+        let y = HEIGHT - HEIGHT * quarter / 4;
+        for x in 0..image.width() {
+            image.put_pixel(x, y, Rgb([220, 220, 220]));
+        }
+    }
+    let mut bar = |x: u32, value: u64, color: Rgb<u8>| {
+        let height = (value as f64 / busiest as f64 * (HEIGHT - 1) as f64) as u32;
+        for x in x..x + 8 {
+            for y in HEIGHT - height..HEIGHT {
+                image.put_pixel(x, y, color);
+            }
+        }
+    };
+    for (i, (down, up)) in days.iter().enumerate() {
+        let x = i as u32 * WIDTH + 2;
+        bar(x, *down, Rgb([52, 101, 164]));
+        bar(x + 8, *up, Rgb([245, 121, 0]));
+    }
+    image
+        .save(path)
+        .map_err(|why| crate::error::GimmewireError::Io(std::io::Error::other(why)))
+}
+
+#[cfg(test)]
+#[test]
+fn usage_days() {
+    let now = DateTime::from_millis(100 * DAY + 1000);
+    let mut usage = Usage {
+        rx: 500,
+        tx: 1000,
+        days: vec![],
+    };
+    record(&mut usage, 700, 1500, now);
+    record(&mut usage, 800, 1800, now);
+    // The peer was applied again and its counters started over
+    record(&mut usage, 50, 100, DateTime::from_millis(101 * DAY));
+    assert!(usage.days.len() == 2 && usage.days[0].rx == 300 && usage.days[0].tx == 800);
+    assert!(usage.days[1].rx == 50 && usage.days[1].tx == 100);
+    record(&mut usage, 60, 100, DateTime::from_millis(140 * DAY));
+    assert!(usage.days.len() == 1);
+    let mut peer = Peer::new(1, "alice".to_string());
+    peer.usage = Some(usage);
+    let days = daily(
+        std::slice::from_ref(&peer),
+        DateTime::from_millis(141 * DAY),
+    );
+    assert!(days.len() == 30 && days[28] == (0, 10) && days[29] == (0, 0));
+    assert!(bytes(1536) == "1.5 KiB");
+    // 2024-03-15 and 2024-03-01
+    assert!(
+        month_start(DateTime::from_millis(19797 * DAY + 1000))
+            == DateTime::from_millis(19783 * DAY)
+    );
+    assert!(month_start(DateTime::from_millis(19783 * DAY)) == DateTime::from_millis(19783 * DAY));
+    assert!(
+        month(
+            std::slice::from_ref(&peer),
+            DateTime::from_millis(141 * DAY)
+        ) == (0, 10)
+    );
+    peer.trial = Some(crate::wireguard::Trial {
+        started: Some(DateTime::from_millis(141 * DAY)),
+        until: None,
+        quota: Some(100),
+        ended: None,
+    });
+    // The 10 bytes of day 140 were before the trial
+    assert!(quota_left(&peer, DateTime::now()) == Some(100));
+    peer.trial.as_mut().unwrap().started = Some(DateTime::from_millis(140 * DAY + 1000));
+    assert!(quota_left(&peer, DateTime::now()) == Some(90));
+}
@@ -0,0 +1,183 @@
+//! Userspace WireGuard with `[Userspace] Enabled`, for containers and hosts without the kernel
+//! module: gimmewire starts boringtun-cli for each interface on this host, which creates the TUN
+//! device, and gives it the server key, listen port and the first address of the pool. Peers are
+//! then managed through wg as usual, boringtun speaks the same control protocol. This is only a
+//! launcher, WireGuard isn't built into gimmewire: boringtun-cli, wg and ip must be installed.
+use crate::error::{GimmewireError, Result};
+use crate::notify;
+use crate::wireguard::{self, Interface};
+use configparser::ini::Ini;
+use std::sync::Arc;
+use tokio::process::Child;
+use tokio::sync::Mutex;
+
+pub fn enabled(config: &Ini) -> bool {
+    config
+        .getbool("Userspace", "Enabled")
+        .unwrap_or(None)
+        .unwrap_or(false)
+}
+
+/// The file boringtun reads the server key of interfaces on this host from, None without
+/// userspace WireGuard.
+pub fn key_file(config: &Ini) -> Option<String> {
+    match enabled(config) {
+        false => None,
+        true => Some(
+            config
+                .get("Userspace", "PrivateKey")
+                .unwrap_or_else(|| "/etc/gimmewire/server.key".to_string()),
+        ),
+    }
+}
+
+/// Starts the data plane of every interface on this host, the processes stop when dropped.
+/// The server key is read from `[Userspace] PrivateKey`, a file created when missing, and its
+/// public key becomes `Key` of interfaces which have none.
+pub async fn start(config: Arc<Mutex<Ini>>) -> Result<Vec<Child>> {
+    let mut config = config.lock().await;
+    if !enabled(&config) || crate::dryrun::skip("boringtun-cli", None) {
+        return Ok(vec![]);
+    }
+    let binary = config
+        .get("Userspace", "Binary")
+        .unwrap_or_else(|| "boringtun-cli".to_string());
+    let port = config
+        .getuint("Userspace", "ListenPort")
+        .unwrap_or(None)
+        .unwrap_or(51820);
+    let key_file = key_file(&config).unwrap_or_default();
+    let public_key = server_key(&key_file)?;
+    let mut children = Vec::new();
+    for (i, interface) in wireguard::interfaces(&config).into_iter().enumerate() {
+        if interface.host.is_some() {
+            continue;
+        }
+        let child = tokio::process::Command::new(&binary)
+            .args(["--foreground", &interface.device])
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|why| GimmewireError::Config(format!("Cannot start {}: {}", binary, why)))?;
+        children.push(child);
+        wait_for_socket(&interface.device).await?;
+        // Every interface needs a port of its own
+        let port = (port + i as u64).to_string();
+        setup(&interface, &port, &key_file)?;
+        if interface.get(&config, "Key").is_none() {
+            config.set(&interface.section, "Key", Some(public_key.clone()));
+        }
+        tracing::info!(
+            "Userspace {} is up on port {}, {}",
+            interface.device,
+            port,
+            interface.server_address()
+        );
+    }
+    Ok(children)
+}
+
+/// Reports a userspace data plane which stopped, peers cannot connect until a restart.
+pub async fn watch(mut children: Vec<Child>) {
+    if children.is_empty() {
+        return;
+    }
+    let exited = first_exit(&mut children).await;
+    tracing::error!("The userspace WireGuard process stopped: {}", exited);
+    notify::send(format!(
+        "⚠️ The userspace WireGuard process stopped: {}, restart gimmewire",
+        exited
+    ));
+}
+
+/// Waits for the first of the children to exit.
+async fn first_exit(children: &mut [Child]) -> String {
+    loop {
+        for child in children.iter_mut() {
+            match child.try_wait() {
+                Ok(None) => (),
+                Ok(Some(status)) => return status.to_string(),
+                Err(why) => return why.to_string(),
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    }
+}
+
+/// The server public key, the private key is generated into `path` when it has none yet.
+fn server_key(path: &str) -> Result<String> {
+    let private_key = match std::fs::read_to_string(path) {
+        Ok(key) => key.trim().to_string(),
+        Err(why) if why.kind() == std::io::ErrorKind::NotFound => {
+            let (private_key, _) = wireguard::generate()?;
+            write_secret(path, &private_key)?;
+            tracing::info!("Generated a server key in {}", path);
+            private_key
+        }
+        Err(why) => return Err(GimmewireError::from(why)),
+    };
+    wireguard::public_key_of(&private_key)
+}
+
+#[cfg(unix)]
+fn write_secret(path: &str, secret: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(secret.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &str, secret: &str) -> Result<()> {
+    Ok(std::fs::write(path, secret)?)
+}
+
+/// Waits up to 5 seconds for the control socket boringtun creates once the device is up.
+async fn wait_for_socket(device: &str) -> Result<()> {
+    let socket = format!("/var/run/wireguard/{}.sock", device);
+    for _ in 0..50 {
+        if std::path::Path::new(&socket).exists() {
+            return Ok(());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    Err(GimmewireError::Config(format!(
+        "{} did not appear, is boringtun-cli allowed to create TUN devices?",
+        socket
+    )))
+}
+
+fn setup(interface: &Interface, port: &str, key_file: &str) -> Result<()> {
+    let device = interface.device.as_str();
+    wireguard::run(
+        "/usr/bin/wg",
+        &["set", device, "listen-port", port, "private-key", key_file],
+        None,
+    )?;
+    let address = interface.server_address();
+    wireguard::run(
+        "/sbin/ip",
+        &["address", "add", &address, "dev", device],
+        None,
+    )?;
+    wireguard::run("/sbin/ip", &["link", "set", device, "up"], None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn userspace_addresses() {
+    let mut config = Ini::new();
+    config
+        .read("[Peer]\nPool = 10.8.3.0/16\n[Userspace]\nEnabled = true".to_string())
+        .unwrap();
+    let interface = &wireguard::interfaces(&config)[0];
+    assert!(enabled(&config) && interface.server_address() == "10.8.0.1/16");
+    assert!(!interface
+        .addresses()
+        .any(|ip| ip == std::net::Ipv4Addr::new(10, 8, 0, 1)));
+}
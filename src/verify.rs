@@ -0,0 +1,111 @@
+//! Checks new users pass before /register or a /start trial, with `[Verify]`, so bot farms can't
+//! drain the address pool: an arithmetic question answered with a button, a channel they must
+//! have joined and `MaxUserId`. Telegram doesn't tell how old an account is but hands out ids in
+//! order, so users with a larger id than a recent one are refused as too new.
+use configparser::ini::Ini;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a question can be answered.
+const ANSWER_WITHIN: Duration = Duration::from_secs(5 * 60);
+
+/// Answers of questions asked, by user id.
+static ASKED: LazyLock<Mutex<HashMap<u64, (u32, Instant)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Users who answered, until the bot restarts.
+static SOLVED: LazyLock<Mutex<HashSet<u64>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// What `[Verify]` requires.
+#[derive(Debug, PartialEq)]
+pub struct Settings {
+    pub captcha: bool,
+    /// Chat whose member the user must be, like `@gimmewire_news`.
+    pub channel: Option<String>,
+    pub max_user_id: Option<u64>,
+}
+
+/// None when new users aren't checked at all.
+pub fn settings(config: &Ini) -> Option<Settings> {
+    let settings = Settings {
+        captcha: config
+            .getbool("Verify", "Captcha")
+            .unwrap_or(None)
+            .unwrap_or(false),
+        channel: config.get("Verify", "Channel"),
+        max_user_id: config
+            .getuint("Verify", "MaxUserId")
+            .unwrap_or(None)
+            .filter(|id| *id > 0),
+    };
+    match settings {
+        Settings {
+            captcha: false,
+            channel: None,
+            max_user_id: None,
+        } => None,
+        settings => Some(settings),
+    }
+}
+
+/// Asks the user a new question, the text and the choices for its buttons.
+pub fn ask(user_id: u64) -> (String, Vec<u32>) {
+    let mut rng = rand::thread_rng();
+    let (a, b) = (rng.gen_range(1..10), rng.gen_range(1..10));
+    let answer = a + b;
+    let mut choices = vec![answer];
+    while choices.len() < 4 {
+        let wrong = rng.gen_range(2..19);
+        if !choices.contains(&wrong) {
+            choices.push(wrong);
+        }
+    }
+    choices.sort();
+    lock(&ASKED).insert(user_id, (answer, Instant::now()));
+    (format!("{} + {}", a, b), choices)
+}
+
+/// Whether the choice answers the last question asked, each question takes one answer. None
+/// when there is no question or it is too late for it.
+pub fn answer(user_id: u64, choice: &str) -> Option<bool> {
+    let (answer, asked) = lock(&ASKED).remove(&user_id)?;
+    if asked.elapsed() > ANSWER_WITHIN {
+        return None;
+    }
+    let right = choice.parse() == Ok(answer);
+    if right {
+        lock(&SOLVED).insert(user_id);
+    }
+    Some(right)
+}
+
+pub fn solved(user_id: u64) -> bool {
+    lock(&SOLVED).contains(&user_id)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+#[test]
+fn verification() {
+    let mut config = Ini::new();
+    assert!(settings(&config).is_none());
+    config
+        .read("[Verify]\nCaptcha = true\nMaxUserId = 7000000000".to_string())
+        .unwrap();
+    let settings = settings(&config).unwrap();
+    assert!(settings.captcha && settings.channel.is_none() && settings.max_user_id.is_some());
+    let (question, choices) = ask(1);
+    let (a, b) = question.split_once(" + ").unwrap();
+    let right = a.parse::<u32>().unwrap() + b.parse::<u32>().unwrap();
+    assert!(choices.len() == 4 && choices.contains(&right));
+    assert!(answer(1, "0") == Some(false) && answer(1, &right.to_string()).is_none());
+    ask(1);
+    let right = lock(&ASKED)[&1].0;
+    assert!(answer(1, &right.to_string()) == Some(true) && solved(1) && !solved(2));
+}
@@ -0,0 +1,275 @@
+//! Telegram webhooks instead of long polling, with `[Webhook] URL`: Telegram posts updates to
+//! `[Webhook] Listen`, directly over TLS with `Certificate` and `PrivateKey` or through a
+//! reverse proxy which terminates it. Updates go to the same dispatcher and handlers as polled
+//! ones, the webhook is removed on shutdown so updates wait at Telegram until the next start.
+//! Every request must carry the secret, a random one is made at each start without `Secret`.
+use crate::error::{GimmewireError, Result};
+use configparser::ini::Ini;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use rand::distributions::{Alphanumeric, DistString};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use teloxide::dispatching::update_listeners::{StatefulListener, UpdateListener};
+use teloxide::prelude::*;
+use teloxide::stop::{mk_stop_token, StopFlag, StopToken};
+use teloxide::types::InputFile;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+type Updates = mpsc::UnboundedReceiver<std::result::Result<Update, Infallible>>;
+
+/// Largest update taken, those from Telegram are a few kilobytes.
+const MAX_BODY: usize = 1024 * 1024;
+
+/// `[Webhook]`, read when the bot starts.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Public address Telegram posts to, its path is the one served.
+    pub url: url::Url,
+    pub listen: SocketAddr,
+    /// Sent by Telegram in `X-Telegram-Bot-Api-Secret-Token`, other requests are refused.
+    pub secret: String,
+    /// PEM certificate and key to terminate TLS with, the certificate is uploaded to Telegram
+    /// so it may be self-signed.
+    pub tls: Option<(String, String)>,
+}
+
+impl Options {
+    /// None when polling, i.e. without `[Webhook] URL`.
+    pub fn from_config(config: &Ini) -> Result<Option<Options>> {
+        let url = match config.get("Webhook", "URL") {
+            None => return Ok(None),
+            Some(url) => url,
+        };
+        let invalid = |what: &str| GimmewireError::Config(format!("[Webhook] {}", what));
+        let url = url::Url::parse(&url)
+            .ok()
+            .filter(|url| url.scheme() == "https")
+            .ok_or_else(|| invalid("URL must look like https://bot.example.com/telegram"))?;
+        let listen = config
+            .get("Webhook", "Listen")
+            .unwrap_or_else(|| "0.0.0.0:8443".to_string())
+            .parse()
+            .map_err(|_| invalid("Listen must look like 0.0.0.0:8443"))?;
+        let secret = config
+            .get("Webhook", "Secret")
+            .unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 32));
+        if !valid_secret(&secret) {
+            return Err(invalid("Secret must be 1 to 256 letters, digits, _ and -"));
+        }
+        let tls = match (
+            config.get("Webhook", "Certificate"),
+            config.get("Webhook", "PrivateKey"),
+        ) {
+            (Some(certificate), Some(key)) => Some((certificate, key)),
+            (None, None) => None,
+            _ => return Err(invalid("Certificate and PrivateKey are set together")),
+        };
+        Ok(Some(Options {
+            url,
+            listen,
+            secret,
+            tls,
+        }))
+    }
+}
+
+/// Telegram only sends these characters in the secret header.
+fn valid_secret(secret: &str) -> bool {
+    (1..=256).contains(&secret.len())
+        && secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Registers the webhook with Telegram and starts serving it, the listener is handed to the
+/// dispatcher in place of polling.
+pub async fn listen(bot: Bot, options: Options) -> Result<impl UpdateListener<Err = Infallible>> {
+    let tls = match &options.tls {
+        None => None,
+        Some((certificate, key)) => Some(acceptor(certificate, key)?),
+    };
+    let mut request = bot
+        .set_webhook(options.url.clone())
+        .secret_token(options.secret.clone());
+    if let Some((certificate, _)) = &options.tls {
+        request = request.certificate(InputFile::file(certificate));
+    }
+    request.await.map_err(|why| {
+        GimmewireError::Config(format!("Cannot set the webhook {}: {}", options.url, why))
+    })?;
+    let listener = tokio::net::TcpListener::bind(options.listen).await?;
+    tracing::info!(
+        "Taking bot updates at {} on {}",
+        options.url,
+        options.listen
+    );
+    let (sender, updates) = mpsc::unbounded_channel();
+    let (stop_token, stop_flag) = mk_stop_token();
+    let receiver = Arc::new(Receiver {
+        path: options.url.path().to_string(),
+        secret: options.secret,
+        sender,
+        stopped: stop_flag.clone(),
+    });
+    tokio::spawn(async move {
+        serve(listener, tls, receiver, stop_flag).await;
+        if let Err(why) = bot.delete_webhook().await {
+            tracing::warn!("Cannot remove the webhook: {}", why);
+        }
+    });
+    Ok(StatefulListener::new(
+        (updates, stop_token),
+        stream,
+        |state: &mut (Updates, StopToken)| state.1.clone(),
+    ))
+}
+
+fn stream(
+    state: &mut (Updates, StopToken),
+) -> impl futures::Stream<Item = std::result::Result<Update, Infallible>> + Send + '_ {
+    futures::stream::poll_fn(move |cx| state.0.poll_recv(cx))
+}
+
+fn acceptor(certificate: &str, key_file: &str) -> Result<TlsAcceptor> {
+    let invalid = |what: String| GimmewireError::Config(format!("[Webhook] {}", what));
+    let mut file = std::io::BufReader::new(std::fs::File::open(certificate)?);
+    let certificates = rustls_pemfile::certs(&mut file)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let mut file = std::io::BufReader::new(std::fs::File::open(key_file)?);
+    let key = match rustls_pemfile::pkcs8_private_keys(&mut file)?.pop() {
+        Some(key) => key,
+        None => {
+            let mut file = std::io::BufReader::new(std::fs::File::open(key_file)?);
+            rustls_pemfile::rsa_private_keys(&mut file)?
+                .pop()
+                .ok_or_else(|| invalid(format!("{} has no private key", key_file)))?
+        }
+    };
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, rustls::PrivateKey(key))
+        .map_err(|why| invalid(format!("Certificate cannot be used: {}", why)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts connections until the dispatcher stops the listener.
+async fn serve(
+    listener: tokio::net::TcpListener,
+    tls: Option<TlsAcceptor>,
+    receiver: Arc<Receiver>,
+    stopped: StopFlag,
+) {
+    tokio::pin!(stopped);
+    loop {
+        let stream = tokio::select! {
+            _ = &mut stopped => return,
+            accepted = listener.accept() => match accepted {
+                Err(why) => {
+                    tracing::warn!("Cannot accept a webhook connection: {}", why);
+                    continue;
+                }
+                Ok((stream, _)) => stream,
+            },
+        };
+        let (tls, receiver) = (tls.clone(), receiver.clone());
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let receiver = receiver.clone();
+                async move { Ok::<_, Infallible>(receiver.handle(req).await) }
+            });
+            let served = match tls {
+                None => Http::new().serve_connection(stream, service).await,
+                Some(tls) => match tls.accept(stream).await {
+                    Err(why) => {
+                        tracing::debug!("Webhook TLS handshake failed: {}", why);
+                        return;
+                    }
+                    Ok(stream) => Http::new().serve_connection(stream, service).await,
+                },
+            };
+            if let Err(why) = served {
+                tracing::debug!("Webhook connection failed: {}", why);
+            }
+        });
+    }
+}
+
+struct Receiver {
+    path: String,
+    secret: String,
+    sender: mpsc::UnboundedSender<std::result::Result<Update, Infallible>>,
+    stopped: StopFlag,
+}
+
+impl Receiver {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.method() != Method::POST || req.uri().path() != self.path {
+            return status(StatusCode::NOT_FOUND);
+        }
+        let secret = req
+            .headers()
+            .get("x-telegram-bot-api-secret-token")
+            .map(|secret| secret.as_bytes());
+        // Requests without the header are refused
+        if !crate::body::same_secret(secret, &self.secret) {
+            return status(StatusCode::UNAUTHORIZED);
+        }
+        if self.stopped.is_stopped() {
+            return status(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let body = match crate::body::read(req.into_body(), MAX_BODY).await {
+            Err(code) => return status(code),
+            Ok(body) => body,
+        };
+        match serde_json::from_slice::<Update>(&body) {
+            // Telegram would resend it forever, so it is dropped
+            Err(why) => tracing::error!("Cannot parse a webhook update: {}", why),
+            Ok(update) => {
+                if self.sender.send(Ok(update)).is_err() {
+                    return status(StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+        }
+        status(StatusCode::OK)
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+#[test]
+fn webhook_options() {
+    let mut config = Ini::new();
+    assert!(Options::from_config(&config).unwrap().is_none());
+    config.set(
+        "Webhook",
+        "URL",
+        Some("http://bot.example.com/tg".to_string()),
+    );
+    assert!(Options::from_config(&config).is_err());
+    config.set(
+        "Webhook",
+        "URL",
+        Some("https://bot.example.com/tg".to_string()),
+    );
+    let generated = Options::from_config(&config).unwrap().unwrap().secret;
+    assert!(generated.len() == 32 && valid_secret(&generated));
+    config.set("Webhook", "Secret", Some("s3cret_-".to_string()));
+    let options = Options::from_config(&config).unwrap().unwrap();
+    assert!(options.url.path() == "/tg" && options.listen.port() == 8443 && options.tls.is_none());
+    config.set("Webhook", "Certificate", Some("bot.crt".to_string()));
+    assert!(Options::from_config(&config).is_err());
+}
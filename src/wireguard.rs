@@ -1,213 +1,1172 @@
-use crate::mongo::Mongo;
+use crate::error::{GimmewireError, Result};
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+use crate::mock::wg;
+use crate::settings::Network;
+use bson::{oid::ObjectId, DateTime};
 use configparser::ini::Ini;
-use mongodb::bson::{doc, DateTime};
 use serde::{Deserialize, Serialize};
-use simple_error::{SimpleError, SimpleResult};
 use std::collections::HashSet;
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
 use std::io::Write;
 use std::net::Ipv4Addr;
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
 use std::process::{Command, Stdio};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-#[derive(Serialize, Deserialize, Debug)]
+
+/// Peers stored before interfaces were configurable live here.
+pub const DEFAULT_INTERFACE: &str = "wg0";
+
+/// Layout of stored peers, bumped with a migration whenever stored documents need a new field
+/// filled in, see `migrations`.
+pub const SCHEMA_VERSION: u32 = 3;
+
+fn default_interface() -> String {
+    DEFAULT_INTERFACE.to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Peer {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
     pub user_id: u64,
     pub username: String,
     pub public_key: Option<String>,
     pub private_key: Option<String>,
     pub ip: Option<Ipv4Addr>,
+    /// Whether `ip` was assigned by an admin, it is kept when the peer is provisioned again.
+    #[serde(default)]
+    pub fixed_ip: bool,
     pub date: DateTime,
+    pub expires: Option<DateTime>,
+    /// Routes sent through the tunnel by the client, everything if unset.
+    pub allowed_ips: Option<String>,
+    /// When the peer was revoked, archived peers are kept for history.
+    pub archived: Option<DateTime>,
+    pub archive_reason: Option<String>,
+    /// When the current keys were issued, `date` if unset.
+    pub keys_issued: Option<DateTime>,
+    /// Bot language chosen by the user, their Telegram one if unset.
+    pub language: Option<String>,
+    /// What the user has paid for, peers without one are not billed.
+    pub subscription: Option<Subscription>,
+    /// Free access from the first /start, separate from `expires` so admins can extend it.
+    pub trial: Option<Trial>,
+    /// When billing or the end of a trial took the peer off its interface, it keeps its keys and address.
+    pub suspended: Option<DateTime>,
+    /// Whether the client config blocks traffic outside the tunnel, `[KillSwitch] Enabled` if unset.
+    pub kill_switch: Option<bool>,
+    /// Interface options of the client config, `MTU` and `Table` of the interface section if unset.
+    pub mtu: Option<u16>,
+    pub table: Option<String>,
+    /// DNS servers chosen with /dns, the interface DNS if unset.
+    pub dns: Option<String>,
+    /// PersistentKeepalive seconds of the client config, 0 turns it off, `KeepAlive` if unset.
+    pub keepalive: Option<u16>,
+    /// Public ports of its node forwarded to the peer, see `firewall`.
+    #[serde(default)]
+    pub forwards: Vec<Forward>,
+    /// Mbit/s limits set by the admin, 0 is unlimited, those of the plan if unset. See `shaping`.
+    pub download: Option<u32>,
+    pub upload: Option<u32>,
+    /// Free-form labels set by admins with /tag, e.g. work or vip.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Free-form text about the peer set by admins with /note, like who it belongs to.
+    pub notes: Option<String>,
+    /// Active peers the user may have, set by admins with /limit, `[Bot] PeerLimit` if unset.
+    pub peer_limit: Option<u32>,
+    /// Latest handshake recorded by `stale`, and when the user was told the peer is unused.
+    pub last_handshake: Option<DateTime>,
+    pub stale_warned: Option<DateTime>,
+    /// Traffic by day counted by the `usage` job, see `usage`.
+    pub usage: Option<Usage>,
+    #[serde(default = "default_interface")]
+    pub interface: String,
+    /// `SCHEMA_VERSION` the peer was written with, 0 for documents from before there was one.
+    #[serde(default)]
+    pub schema_version: u32,
+}
+
+impl Peer {
+    pub fn new(user_id: u64, username: String) -> Self {
+        Peer {
+            id: None,
+            user_id,
+            username,
+            public_key: None,
+            private_key: None,
+            ip: None,
+            fixed_ip: false,
+            date: DateTime::now(),
+            expires: None,
+            allowed_ips: None,
+            archived: None,
+            archive_reason: None,
+            keys_issued: None,
+            language: None,
+            subscription: None,
+            trial: None,
+            suspended: None,
+            kill_switch: None,
+            mtu: None,
+            table: None,
+            dns: None,
+            keepalive: None,
+            forwards: vec![],
+            download: None,
+            upload: None,
+            tags: vec![],
+            notes: None,
+            peer_limit: None,
+            last_handshake: None,
+            stale_warned: None,
+            usage: None,
+            interface: default_interface(),
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+}
+
+/// A paid period of a peer, see `billing`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub plan: String,
+    pub paid_until: DateTime,
+    /// Whether the user was told the subscription lapsed.
+    #[serde(default)]
+    pub reminded: bool,
+}
+
+/// Limits of a trial, see `trial`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Trial {
+    /// When /start gave it, the date of the peer if unset.
+    #[serde(default)]
+    pub started: Option<DateTime>,
+    pub until: Option<DateTime>,
+    /// Bytes the peer may send and receive from `started` on, as counted by its interface.
+    pub quota: Option<u64>,
+    /// When the trial ran out.
+    pub ended: Option<DateTime>,
+}
+
+/// Traffic of a peer, the wg counters last seen and bytes by day, the last 30 days.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Usage {
+    pub rx: u64,
+    pub tx: u64,
+    pub days: Vec<Day>,
+}
+
+/// Bytes a peer sent (`rx` of the interface) and received (`tx`) on a day, from midnight UTC.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Day {
+    pub date: DateTime,
+    pub rx: u64,
+    pub tx: u64,
+}
+
+/// A public port of the node forwarded to a port of the peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Forward {
+    /// tcp or udp.
+    pub protocol: String,
+    pub port: u16,
+    pub target: u16,
+}
+
+/// A WireGuard interface, either the one described by `[Peer]` or an `[Interface <name>]` section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    /// Config section holding Key, Endpoint, Subnet and Pool of the interface.
+    pub section: String,
+    /// Name of the interface on its node, `name` unless `Device` is set.
+    pub device: String,
+    /// Where wg is run over ssh, e.g. `root@node1`, the local host if unset.
+    pub host: Option<String>,
+    pub network: Ipv4Addr,
+    pub prefix: u8,
+    /// An AmneziaWG interface, driven with awg and with obfuscation fields in client configs.
+    pub amnezia: bool,
+    /// First and last addresses of the `Reserved` ranges, never handed out by `get_ip`.
+    pub reserved: Vec<(Ipv4Addr, Ipv4Addr)>,
+    /// `Save` of its section: `wg-quick` runs `wg-quick save` after every change, a path on this
+    /// host gets the running config written to it. Either way peers survive a reboot of the node
+    /// without the bot putting them back.
+    pub save: Option<String>,
+}
+
+/// AmneziaWG obfuscation fields, the H ones and S ones must be the same as the server's.
+pub const AMNEZIA: [&str; 9] = ["Jc", "Jmin", "Jmax", "S1", "S2", "H1", "H2", "H3", "H4"];
+
+impl Interface {
+    /// A setting of this interface, DNS, KeepAlive, MTU, Table and the AmneziaWG ones fall back
+    /// to `[Peer]`.
+    pub fn get(&self, config: &Ini, key: &str) -> Option<String> {
+        config.get(&self.section, key).or_else(|| match key {
+            "DNS" | "KeepAlive" | "MTU" | "Table" | "Amnezia" => config.get("Peer", key),
+            _ if AMNEZIA.contains(&key) => config.get("Peer", key),
+            _ => None,
+        })
+    }
+
+    /// Addresses which can be handed out to peers, .0, .1 and .255 of every /24 are skipped.
+    pub fn addresses(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let base = u32::from(self.network) & (u32::MAX << (32 - self.prefix as u32));
+        (0..1u64 << (32 - self.prefix as u32))
+            .map(move |i| Ipv4Addr::from(base + i as u32))
+            .filter(|ip| !matches!(ip.octets()[3], 0 | 1 | 255))
+    }
+
+    /// The server address on the interface, `.1` of the pool which is never handed out.
+    pub fn server_address(&self) -> String {
+        let base = u32::from(self.network) & (u32::MAX << (32 - self.prefix as u32));
+        format!("{}/{}", Ipv4Addr::from(base + 1), self.prefix)
+    }
+
+    /// Whether the address is in one of the `Reserved` ranges.
+    pub fn reserves(&self, ip: Ipv4Addr) -> bool {
+        self.reserved
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&ip))
+    }
+}
+
+/// Ranges of a `Reserved` setting, addresses, `10.0.0.2-10.0.0.20` ranges and CIDRs separated by
+/// commas or spaces.
+pub fn reserved_ranges(value: &str) -> std::result::Result<Vec<(Ipv4Addr, Ipv4Addr)>, String> {
+    value
+        .split([',', ' '])
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let invalid = || format!("{} is not an address, a range or a CIDR", range);
+            let parse = |ip: &str| ip.trim().parse::<Ipv4Addr>().map_err(|_| invalid());
+            if let Some((first, last)) = range.split_once('-') {
+                let (first, last) = (parse(first)?, parse(last)?);
+                return match first <= last {
+                    true => Ok((first, last)),
+                    false => Err(invalid()),
+                };
+            }
+            if let Some((network, prefix)) = range.split_once('/') {
+                let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+                if prefix > 32 {
+                    return Err(invalid());
+                }
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                let first = u32::from(parse(network)?) & mask;
+                return Ok((Ipv4Addr::from(first), Ipv4Addr::from(first | !mask)));
+            }
+            let ip = parse(range)?;
+            Ok((ip, ip))
+        })
+        .collect()
 }
 
-pub async fn add_peer(peer: &mut Peer, mongo: &Mongo) -> SimpleResult<()> {
-    let (private_key, public_key) = gen_keys();
+/// Interfaces from the config, the `[Peer]` one first. Interface names are lowercase.
+pub fn interfaces(config: &Ini) -> Vec<Interface> {
+    let pool = |section: &str, default: Option<&str>| {
+        let pool = config
+            .get(section, "Pool")
+            .or_else(|| default.map(str::to_string))?;
+        let (network, prefix) = pool.split_once('/')?;
+        match (network.trim().parse(), prefix.trim().parse()) {
+            (Ok(network), Ok(prefix)) if (8..=30).contains(&prefix) => Some((network, prefix)),
+            _ => None,
+        }
+    };
+    let mut interfaces = Vec::new();
+    match pool("Peer", Some("10.0.0.0/16")) {
+        None => tracing::error!("[Peer] Pool must look like 10.0.0.0/16"),
+        Some((network, prefix)) => interfaces.push(Interface {
+            name: main_interface(config),
+            section: "Peer".to_string(),
+            device: config
+                .get("Peer", "Device")
+                .unwrap_or_else(|| main_interface(config)),
+            host: config.get("Peer", "Host"),
+            network,
+            prefix,
+            amnezia: amnezia(config, "Peer"),
+            reserved: reserved(config, "Peer"),
+            save: config.get("Peer", "Save"),
+        }),
+    }
+    let mut sections: Vec<&String> = config.get_map_ref().keys().collect();
+    sections.sort();
+    for section in sections {
+        let name = match section.strip_prefix("interface ") {
+            None => continue,
+            Some(name) => name.trim(),
+        };
+        match pool(section, None) {
+            None => tracing::error!("[{}] needs a Pool like 10.1.0.0/16", section),
+            Some((network, prefix)) => interfaces.push(Interface {
+                name: name.to_string(),
+                section: section.to_string(),
+                device: config
+                    .get(section, "Device")
+                    .unwrap_or_else(|| name.to_string()),
+                host: config.get(section, "Host"),
+                network,
+                prefix,
+                amnezia: amnezia(config, section),
+                reserved: reserved(config, section),
+                save: config.get(section, "Save"),
+            }),
+        }
+    }
+    interfaces
+}
+
+/// `Reserved` of the section, invalid ranges are left out.
+fn reserved(config: &Ini, section: &str) -> Vec<(Ipv4Addr, Ipv4Addr)> {
+    let value = config.get(section, "Reserved").unwrap_or_default();
+    reserved_ranges(&value).unwrap_or_else(|why| {
+        tracing::error!("[{}] Reserved: {}", section, why);
+        vec![]
+    })
+}
+
+/// `Amnezia` of the section or of `[Peer]`.
+fn amnezia(config: &Ini, section: &str) -> bool {
+    let amnezia = |section| config.getbool(section, "Amnezia").unwrap_or(None);
+    amnezia(section).or(amnezia("Peer")).unwrap_or(false)
+}
+
+/// Interface configured by `[Peer]`, where new peers go by default.
+pub fn main_interface(config: &Ini) -> String {
+    config
+        .get("Peer", "Interface")
+        .unwrap_or_else(default_interface)
+}
+
+/// The configured interface the peer belongs to.
+pub fn interface_of<'a>(interfaces: &'a [Interface], peer: &Peer) -> Result<&'a Interface> {
+    interfaces
+        .iter()
+        .find(|interface| interface.name == peer.interface)
+        .ok_or_else(|| GimmewireError::UnknownInterface(peer.interface.clone()))
+}
+
+pub fn find_interface(config: &Ini, name: &str) -> Result<Interface> {
+    interfaces(config)
+        .into_iter()
+        .find(|interface| interface.name == name)
+        .ok_or_else(|| GimmewireError::UnknownInterface(name.to_string()))
+}
+
+/// Runtime state of a peer as reported by `wg show <interface> dump`.
+#[derive(Debug)]
+pub struct PeerStats {
+    pub interface: String,
+    pub public_key: String,
+    pub endpoint: Option<String>,
+    pub allowed_ips: String,
+    pub latest_handshake: Option<DateTime>,
+    pub rx: u64,
+    pub tx: u64,
+}
+
+impl PeerStats {
+    /// WireGuard re-handshakes every two minutes while a peer is connected.
+    pub fn online(&self) -> bool {
+        self.latest_handshake
+            .map(|date| DateTime::now().timestamp_millis() - date.timestamp_millis() < 180_000)
+            == Some(true)
+    }
+}
+
+pub async fn add_peer(peer: &mut Peer, peers: &[Peer], interface: &Interface) -> Result<()> {
+    let ip = match (peer.fixed_ip, peer.ip) {
+        (true, Some(ip)) => ip,
+        _ => get_ip(peers, interface)?,
+    };
+    let (private_key, public_key) = gen_keys().await?;
     peer.private_key = Some(private_key);
     peer.public_key = Some(public_key);
-    peer.ip = Some(get_ip(&mut mongo.get_peers().await));
-    let mut wg = match Command::new("/usr/bin/wg")
-        .args([
+    peer.ip = Some(ip);
+    peer.interface = interface.name.clone();
+    apply_peer(peer, interface).await
+}
+
+/// Puts the peer's existing key and address on its interface.
+pub async fn apply_peer(peer: &Peer, interface: &Interface) -> Result<()> {
+    let (public_key, ip) = match (&peer.public_key, peer.ip) {
+        (Some(public_key), Some(ip)) => (public_key, ip),
+        _ => {
+            return Err(GimmewireError::Invalid(format!(
+                "Peer {} has no key or address yet",
+                peer.username
+            )))
+        }
+    };
+    change(
+        interface,
+        &[
             "set",
-            "wg0",
+            &interface.device,
             "peer",
-            format!("{}", peer.public_key.clone().unwrap()).as_str(),
+            public_key,
             "allowed-ips",
-            format!("{}/32", peer.ip.unwrap()).as_str(),
-        ])
-        .spawn()
-    {
-        Err(why) => return Err(SimpleError::from(why)),
-        Ok(wg) => wg,
-    };
-    match wg.wait() {
-        Err(why) => Err(SimpleError::from(why)),
-        Ok(_) => Ok(()),
+            &format!("{}/32", ip),
+        ],
+        None,
+    )
+    .await
+    .inspect_err(|why| crate::queue::failed(&interface.name, public_key, &why.to_string()))
+    .map(|_| ())
+}
+
+/// Replaces the peer's keys keeping its address, the new key is added before the old one is
+/// removed. When the old one can't be removed, the peer is left with its old keys.
+pub async fn rotate_keys(peer: &mut Peer, interface: &Interface) -> Result<()> {
+    let old = (peer.private_key.take(), peer.public_key.take());
+    let (private_key, public_key) = gen_keys().await?;
+    peer.private_key = Some(private_key);
+    peer.public_key = Some(public_key);
+    if let Err(why) = apply_peer(peer, interface).await {
+        (peer.private_key, peer.public_key) = old;
+        return Err(why);
     }
+    if let Some(old_key) = old.1.clone() {
+        if let Err(why) = remove_key(interface, &old_key).await {
+            // Both keys would be valid, so the old one gets its address back and stays alone
+            let _ = remove_peer(peer, interface).await;
+            (peer.private_key, peer.public_key) = old;
+            let _ = apply_peer(peer, interface).await;
+            return Err(why);
+        }
+    }
+    Ok(())
 }
 
-pub async fn remove_peer(peer: &Peer) -> SimpleResult<()> {
-    let mut wg = match Command::new("/usr/bin/wg")
-        .args([
-            "set",
-            "wg0",
-            "peer",
-            format!("{}", peer.public_key.clone().unwrap()).as_str(),
-            "remove",
-        ])
-        .spawn()
-    {
-        Err(why) => return Err(SimpleError::from(why)),
-        Ok(wg) => wg,
-    };
-    match wg.wait() {
-        Err(why) => Err(SimpleError::from(why)),
-        Ok(_) => Ok(()),
+/// Takes the peer's key off its interface, peers without keys were never applied.
+pub async fn remove_peer(peer: &Peer, interface: &Interface) -> Result<()> {
+    match &peer.public_key {
+        None => Ok(()),
+        Some(public_key) => remove_key(interface, public_key).await,
     }
 }
 
-pub async fn gen_conf(peer: &Peer, conf: Arc<Mutex<Ini>>) -> SimpleResult<String> {
-    let mut config = Ini::new_cs();
-    config.set(
-        "Interface",
-        "PrivateKey",
-        Some(peer.private_key.clone().unwrap()),
-    );
-    config.set(
-        "Interface",
-        "Address",
-        Some(format!(
-            "{}/{}",
-            peer.ip.unwrap().to_string(),
-            conf.lock()
-                .await
-                .get("Peer", "Subnet")
-                .unwrap_or(16.to_string())
-        )),
-    );
-    config.set(
-        "Interface",
-        "DNS",
-        Some(
-            conf.lock()
-                .await
-                .get("Peer", "DNS")
-                .unwrap_or("8.8.8.8".to_string()),
-        ),
-    );
-    config.set("Peer", "PublicKey", conf.lock().await.get("Peer", "Key"));
-    config.set(
-        "Peer",
-        "Endpoint",
-        conf.lock().await.get("Peer", "Endpoint"),
-    );
-    config.set("Peer", "AllowedIPs", Some("0.0.0.0/0".to_string()));
-    config.set(
-        "Peer",
-        "PersistentKeepalive",
-        Some(
-            conf.lock()
-                .await
-                .get("Peer", "KeepAlive")
-                .unwrap_or(25.to_string()),
-        ),
-    );
-    let config_path = format!(
-        "{}/{}.conf",
-        dirs::home_dir().unwrap().to_string_lossy(),
-        peer.username
-    );
-    match config.write(&config_path) {
-        Err(why) => {
-            log::error!("Cannot save a client config: {}", why);
-            Err(SimpleError::from(why))
+pub async fn remove_key(interface: &Interface, public_key: &str) -> Result<()> {
+    change(
+        interface,
+        &["set", &interface.device, "peer", public_key, "remove"],
+        None,
+    )
+    .await
+    .inspect_err(|why| crate::queue::failed(&interface.name, public_key, &why.to_string()))
+    .map(|_| ())
+}
+
+pub async fn show(interface: &Interface) -> Result<Vec<PeerStats>> {
+    Ok(parse_dump(
+        &interface.name,
+        &read(interface, &["show", &interface.device, "dump"]).await?,
+    ))
+}
+
+/// Runs a wg read, which may go over ssh, without holding up the async workers.
+async fn read(interface: &Interface, args: &[&str]) -> Result<String> {
+    let interface = interface.clone();
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    blocking(move || {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        wg_on(&interface, &args, None)
+    })
+    .await
+}
+
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|why| Err(GimmewireError::Invalid(format!("wg stopped: {}", why))))
+}
+
+/// Peers of all the interfaces.
+pub async fn show_all(interfaces: &[Interface]) -> Result<Vec<PeerStats>> {
+    let mut stats = Vec::new();
+    for interface in interfaces {
+        stats.extend(show(interface).await?);
+    }
+    Ok(stats)
+}
+
+pub async fn showconf(interface: &Interface) -> Result<String> {
+    read(interface, &["showconf", &interface.device]).await
+}
+
+/// Splits `wg showconf` output into its [Interface] section and the [Peer] sections by key.
+fn split_showconf(conf: &str) -> (String, Vec<(String, String)>) {
+    let mut interface = String::new();
+    let mut peers: Vec<(String, String)> = Vec::new();
+    let mut in_peer = false;
+    for line in conf.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_peer = trimmed.eq_ignore_ascii_case("[Peer]");
+            if in_peer {
+                peers.push((String::new(), String::new()));
+            }
+        }
+        if !in_peer {
+            interface.push_str(line);
+            interface.push('\n');
+            continue;
+        }
+        let peer = peers.last_mut().expect("a [Peer] line comes first");
+        if let Some((key, value)) = trimmed.split_once('=') {
+            if key.trim().eq_ignore_ascii_case("PublicKey") {
+                peer.0 = value.trim().to_string();
+            }
+        }
+        if !trimmed.is_empty() {
+            peer.1.push_str(line);
+            peer.1.push('\n');
         }
-        Ok(_) => Ok(config_path),
     }
+    (interface.trim_end().to_string(), peers)
 }
 
-fn get_ip(peers: &mut Vec<Peer>) -> Ipv4Addr {
-    let mut ip_set = HashSet::new();
-    for i in 0..255 {
-        for j in 2..255 {
-            ip_set.insert(Ipv4Addr::new(10, 0, i, j));
+/// The interface config with exactly the active peers of the db on it, peers the db doesn't know
+/// are kept as they are so `doctor` can still report them.
+pub fn render_peers(showconf: &str, interface: &Interface, peers: &[Peer]) -> String {
+    let (mut conf, existing) = split_showconf(showconf);
+    conf.push('\n');
+    for (key, section) in existing {
+        if !peers
+            .iter()
+            .any(|peer| peer.public_key.as_deref() == Some(key.as_str()))
+        {
+            conf.push('\n');
+            conf.push_str(&section);
+        }
+    }
+    for peer in peers.iter().filter(|peer| {
+        peer.interface == interface.name && peer.suspended.is_none() && peer.archived.is_none()
+    }) {
+        if let (Some(public_key), Some(ip)) = (&peer.public_key, peer.ip) {
+            conf.push_str(&format!(
+                "\n[Peer]\nPublicKey = {}\nAllowedIPs = {}/32\n",
+                public_key, ip
+            ));
         }
     }
-    let peers_ip_set: HashSet<Ipv4Addr> = peers.into_iter().flat_map(|peer| peer.ip).collect();
-    ip_set.difference(&peers_ip_set).next().unwrap().to_owned()
+    conf
 }
 
-fn gen_keys() -> (String, String) {
-    let genkey_process = match Command::new("/usr/bin/wg")
-        .arg("genkey")
-        .stdout(Stdio::piped())
-        .spawn()
+/// Puts the active peers of the db on the interface in one `wg syncconf`, instead of a `wg set`
+/// per peer. Suspended peers are taken off.
+pub async fn sync_peers(interface: &Interface, peers: &[Peer]) -> Result<()> {
+    // Nothing may change the interface between reading and replacing its peers
+    crate::queue::exclusive(async {
+        let conf = render_peers(&showconf(interface).await?, interface, peers);
+        change(
+            interface,
+            &["syncconf", &interface.device, "/dev/stdin"],
+            Some(&conf),
+        )
+        .await
+        .map(|_| ())
+    })
+    .await
+}
+
+/// Extracts (public key, first IPv4 /32 of AllowedIPs) of every [Peer] in `wg showconf` output.
+pub fn parse_showconf(conf: &str) -> Vec<(String, Option<Ipv4Addr>)> {
+    let mut peers: Vec<(String, Option<Ipv4Addr>)> = Vec::new();
+    let mut in_peer = false;
+    for line in conf.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_peer = line.eq_ignore_ascii_case("[Peer]");
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if in_peer => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        if key.eq_ignore_ascii_case("PublicKey") {
+            peers.push((value.to_string(), None));
+        } else if key.eq_ignore_ascii_case("AllowedIPs") {
+            if let Some(peer) = peers.last_mut() {
+                peer.1 = value
+                    .split(',')
+                    .filter_map(|ip| ip.trim().strip_suffix("/32"))
+                    .find_map(|ip| ip.parse().ok());
+            }
+        }
+    }
+    peers
+}
+
+/// Gives the interface a new private key and stores it where it is read on restart: `key_file`
+/// for userspace WireGuard, else the config `Save` writes. The old key is put back when storing
+/// fails, so clients aren't sent configs the interface forgets. Returns the new public key.
+pub async fn rotate_server_key(interface: &Interface, key_file: Option<&str>) -> Result<String> {
+    if key_file.is_none() && interface.save.is_none() {
+        return Err(GimmewireError::Config(format!(
+            "{} has no Save, its new key would be lost on restart",
+            interface.name
+        )));
+    }
+    let (private_key, public_key) = gen_keys().await?;
+    let interface = interface.clone();
+    let key_file = key_file.map(str::to_string);
+    crate::queue::run(move || {
+        let device = interface.device.as_str();
+        let old = wg_on(&interface, &["show", device, "private-key"], None)?;
+        wg_on(
+            &interface,
+            &["set", device, "private-key", "/dev/stdin"],
+            Some(&private_key),
+        )?;
+        let stored = match &key_file {
+            None => save(&interface),
+            Some(path) => match crate::dryrun::skip(&format!("writing {}", path), None) {
+                true => Ok(()),
+                false => write_private(path, &private_key).map_err(GimmewireError::from),
+            },
+        };
+        if let Err(why) = stored {
+            let old = old.trim();
+            if old != "(none)" {
+                wg_on(
+                    &interface,
+                    &["set", device, "private-key", "/dev/stdin"],
+                    Some(old),
+                )?;
+            }
+            return Err(GimmewireError::Config(format!(
+                "Cannot store the new key of {}, it keeps the old one: {}",
+                interface.name, why
+            )));
+        }
+        Ok(String::new())
+    })
+    .await?;
+    Ok(public_key)
+}
+pub async fn public_key(interface: &Interface) -> Result<String> {
+    Ok(read(interface, &["show", &interface.device, "public-key"])
+        .await?
+        .trim()
+        .to_string())
+}
+
+/// Runs a wg command which changes the interface after the changes queued before it, see `queue`.
+async fn change(interface: &Interface, args: &[&str], input: Option<&str>) -> Result<String> {
+    let interface = interface.clone();
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let input = input.map(str::to_string);
+    crate::queue::run(move || {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let output = wg_on(&interface, &args, input.as_deref())?;
+        // The change is done, the bot still puts the peers back when it starts
+        if let Err(why) = save(&interface) {
+            tracing::warn!("Cannot save {}: {}", interface.name, why);
+        }
+        Ok(output)
+    })
+    .await
+}
+
+/// Settings only wg-quick knows, `wg showconf` leaves them out.
+const WG_QUICK: [&str; 9] = [
+    "Address",
+    "DNS",
+    "MTU",
+    "Table",
+    "PreUp",
+    "PostUp",
+    "PreDown",
+    "PostDown",
+    "SaveConfig",
+];
+
+/// Saves the interface as `Save` says after a change.
+fn save(interface: &Interface) -> Result<()> {
+    match interface.save.as_deref() {
+        None => Ok(()),
+        Some("wg-quick") => {
+            let program = match interface.amnezia {
+                true => "awg-quick",
+                false => "wg-quick",
+            };
+            match crate::dryrun::skip(&format!("{} save {}", program, interface.device), None) {
+                true => Ok(()),
+                false => wg_quick(interface, program).map(|_| ()),
+            }
+        }
+        Some(path) => {
+            wg_on(interface, &["showconf", &interface.device], None).and_then(|running| {
+                let existing = std::fs::read_to_string(path).ok();
+                let conf = saved_conf(&running, existing.as_deref(), interface);
+                match crate::dryrun::skip(&format!("writing {}", path), None) {
+                    true => Ok(()),
+                    false => write_private(path, &conf).map_err(GimmewireError::from),
+                }
+            })
+        }
+    }
+}
+
+/// The running config as wg-quick reads it, like `wg-quick save` writes it: the wg-quick settings
+/// of the config it replaces, or just the server address without one, and what wg has.
+pub fn saved_conf(showconf: &str, existing: Option<&str>, interface: &Interface) -> String {
+    let (running, peers) = split_showconf(showconf);
+    let mut conf = "[Interface]\n".to_string();
+    match existing {
+        None => conf.push_str(&format!("Address = {}\n", interface.server_address())),
+        Some(existing) => {
+            for line in split_showconf(existing).0.lines().filter(|line| {
+                line.split_once('=').is_some_and(|(key, _)| {
+                    WG_QUICK
+                        .iter()
+                        .any(|setting| setting.eq_ignore_ascii_case(key.trim()))
+                })
+            }) {
+                conf.push_str(line);
+                conf.push('\n');
+            }
+        }
+    }
+    for line in running
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim().starts_with('['))
     {
-        Err(why) => panic!("Could not run wg genkey: {}", why),
-        Ok(genkey_process) => genkey_process,
-    };
+        conf.push_str(line);
+        conf.push('\n');
+    }
+    for (_, section) in peers {
+        conf.push('\n');
+        conf.push_str(&section);
+    }
+    conf
+}
 
-    let genkey_output = match genkey_process.wait_with_output() {
-        Err(why) => panic!("Could not run wg genkey: {}", why),
-        Ok(genkey_output) => genkey_output,
-    };
+/// Runs `wg-quick save` on the node of the interface.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn wg_quick(interface: &Interface, program: &str) -> Result<String> {
+    match &interface.host {
+        Some(host) => run(
+            "/usr/bin/ssh",
+            &[
+                "-o",
+                "BatchMode=yes",
+                host,
+                program,
+                "save",
+                &interface.device,
+            ],
+            None,
+        ),
+        None => run(
+            &format!("/usr/bin/{}", program),
+            &["save", &interface.device],
+            None,
+        ),
+    }
+}
+
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+fn wg_quick(_interface: &Interface, _program: &str) -> Result<String> {
+    Ok(String::new())
+}
 
-    if !genkey_output.status.success() {
-        panic!(
-            "wg genkey finished with code {}",
-            String::from_utf8(genkey_output.stderr).unwrap()
-        );
+/// Writes the file readable by its owner only, it holds private keys. The content goes to a
+/// temporary file next to it first, so a crash leaves the old file rather than half of the new.
+pub fn write_private(path: &str, content: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let temporary = format!("{}.tmp", path);
+    // One left by a crash may be readable by others
+    let _ = std::fs::remove_file(&temporary);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temporary)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Runs wg, or awg for AmneziaWG, on the node of the interface, over ssh if it has a Host.
+/// Changes are only logged in a dry run, their stdin holds keys and is not.
+fn wg_on(interface: &Interface, args: &[&str], input: Option<&str>) -> Result<String> {
+    let program = match interface.amnezia {
+        true => "awg",
+        false => "wg",
+    };
+    let reads = matches!(args.first(), Some(&"show" | &"showconf"));
+    if !reads && crate::dryrun::skip(&format!("{} {}", program, args.join(" ")), None) {
+        return Ok(String::new());
+    }
+    match &interface.host {
+        #[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+        Some(host) => {
+            // Keys and addresses need no quoting in the remote shell
+            let mut remote = vec!["-o", "BatchMode=yes", host.as_str(), program];
+            remote.extend_from_slice(args);
+            run("/usr/bin/ssh", &remote, input)
+        }
+        #[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+        None if interface.amnezia => run("/usr/bin/awg", args, input),
+        _ => wg(args, input),
     }
+}
+
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn wg(args: &[&str], input: Option<&str>) -> Result<String> {
+    run("/usr/bin/wg", args, input)
+}
 
-    let private_key =
-        String::from_utf8(genkey_output.stdout).expect("Cannot convert wg genkey to string");
+/// Runs the program with the given arguments, writing `input` to its stdin, and returns its stdout.
+/// Failures in a row of the program are reported to the admin, see `failures`.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+pub fn run(program: &str, args: &[&str], input: Option<&str>) -> Result<String> {
+    let kind = program.rsplit('/').next().unwrap_or(program);
+    let command = || format!("{} {}", program, args.join(" "));
+    crate::failures::track(kind, command, execute(program, args, input))
+}
 
-    let mut pubkey_process = match Command::new("/usr/bin/wg")
-        .arg("pubkey")
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn execute(program: &str, args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut process = Command::new(program)
+        .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn()
-    {
-        Err(why) => panic!("Could not run wg pubkey: {}", why),
-        Ok(pubkey_process) => pubkey_process,
-    };
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(input) = input {
+        if let Some(mut stdin) = process.stdin.take() {
+            stdin.write_all(input.as_bytes())?;
+        }
+    }
+    let output = process.wait_with_output()?;
+    if !output.status.success() {
+        return Err(GimmewireError::WgCommandFailed {
+            command: format!("{} {}", program, args[0]),
+            message: format!(
+                "{}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
 
-    match pubkey_process
-        .stdin
-        .take()
-        .unwrap()
-        .write_all(&private_key.as_bytes())
-    {
-        Err(why) => panic!("Couldn't write to wg pubkey stdin: {}", why),
-        Ok(_) => (),
+fn parse_dump(interface: &str, dump: &str) -> Vec<PeerStats> {
+    // First line describes the interface itself
+    dump.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 8 {
+                return None;
+            }
+            let handshake: i64 = fields[4].parse().unwrap_or(0);
+            Some(PeerStats {
+                interface: interface.to_string(),
+                public_key: fields[0].to_string(),
+                endpoint: Some(fields[2].to_string()).filter(|e| e != "(none)"),
+                allowed_ips: fields[3].to_string(),
+                latest_handshake: Some(DateTime::from_millis(handshake * 1000))
+                    .filter(|_| handshake > 0),
+                rx: fields[5].parse().unwrap_or(0),
+                tx: fields[6].parse().unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+/// What the client of a peer connects with, everything its config is made of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConf {
+    pub private_key: String,
+    /// Address with the prefix of the interface network, like 10.0.0.2/16.
+    pub address: String,
+    pub dns: String,
+    pub mtu: Option<String>,
+    /// wg-quick routing table, the other formats have no such option.
+    pub table: Option<String>,
+    pub public_key: Option<String>,
+    pub endpoint: Option<String>,
+    pub allowed_ips: String,
+    pub keepalive: String,
+    /// wg-quick hooks like PostUp, the kill switch rules.
+    pub scripts: Vec<(String, String)>,
+    /// AmneziaWG fields like Jc, only the AmneziaWG apps understand them.
+    pub obfuscation: Vec<(String, String)>,
+}
+
+impl ClientConf {
+    /// The wg-quick config.
+    pub fn render(&self) -> String {
+        let mut config = Ini::new_cs();
+        config.set("Interface", "PrivateKey", Some(self.private_key.clone()));
+        config.set("Interface", "Address", Some(self.address.clone()));
+        config.set("Interface", "DNS", Some(self.dns.clone()));
+        if let Some(mtu) = &self.mtu {
+            config.set("Interface", "MTU", Some(mtu.clone()));
+        }
+        if let Some(table) = &self.table {
+            config.set("Interface", "Table", Some(table.clone()));
+        }
+        for (hook, script) in &self.scripts {
+            config.set("Interface", hook, Some(script.clone()));
+        }
+        for (field, value) in &self.obfuscation {
+            config.set("Interface", field, Some(value.clone()));
+        }
+        config.set("Peer", "PublicKey", self.public_key.clone());
+        config.set("Peer", "Endpoint", self.endpoint.clone());
+        config.set("Peer", "AllowedIPs", Some(self.allowed_ips.clone()));
+        config.set("Peer", "PersistentKeepalive", Some(self.keepalive.clone()));
+        config.writes()
+    }
+}
+
+/// wg-quick hooks a kill switch can have.
+const HOOKS: [&str; 4] = ["PreUp", "PostUp", "PreDown", "PostDown"];
+
+/// Rejects everything which is not sent through the tunnel or to the endpoint, from the
+/// wg-quick man page.
+const KILL_SWITCH_UP: &str = "iptables -I OUTPUT ! -o %i -m mark ! --mark $(wg show %i fwmark) -m addrtype ! --dst-type LOCAL -j REJECT && ip6tables -I OUTPUT ! -o %i -m mark ! --mark $(wg show %i fwmark) -m addrtype ! --dst-type LOCAL -j REJECT";
+const KILL_SWITCH_DOWN: &str = "iptables -D OUTPUT ! -o %i -m mark ! --mark $(wg show %i fwmark) -m addrtype ! --dst-type LOCAL -j REJECT && ip6tables -D OUTPUT ! -o %i -m mark ! --mark $(wg show %i fwmark) -m addrtype ! --dst-type LOCAL -j REJECT";
+
+/// Whether the peer's config gets a kill switch, its own choice or `[KillSwitch] Enabled`.
+pub fn kill_switch(peer: &Peer, conf: &Ini) -> bool {
+    peer.kill_switch.unwrap_or_else(|| {
+        conf.getbool("KillSwitch", "Enabled")
+            .unwrap_or(None)
+            .unwrap_or(false)
+    })
+}
+
+/// The kill switch hooks, `[KillSwitch]` PreUp, PostUp, PreDown and PostDown replace the
+/// iptables rules when any of them is set.
+fn kill_switch_scripts(conf: &Ini) -> Vec<(String, String)> {
+    let custom: Vec<(String, String)> = HOOKS
+        .iter()
+        .filter_map(|hook| Some((hook.to_string(), conf.get("KillSwitch", hook)?)))
+        .collect();
+    if !custom.is_empty() {
+        return custom;
     }
+    vec![
+        ("PostUp".to_string(), KILL_SWITCH_UP.to_string()),
+        ("PreDown".to_string(), KILL_SWITCH_DOWN.to_string()),
+    ]
+}
 
-    let pubkey_output = match pubkey_process.wait_with_output() {
-        Err(why) => panic!("Could not run wg genkey: {}", why),
-        Ok(pubkey_output) => pubkey_output,
+pub async fn client_conf(peer: &Peer, conf: Arc<Mutex<Ini>>) -> Result<ClientConf> {
+    let conf = conf.lock().await;
+    let interface = find_interface(&conf, &peer.interface)?;
+    let (private_key, ip) = match (&peer.private_key, peer.ip) {
+        (Some(private_key), Some(ip)) => (private_key.clone(), ip),
+        _ => {
+            return Err(GimmewireError::Invalid(format!(
+                "Peer {} has no key or address yet",
+                peer.username
+            )))
+        }
     };
+    let network = Network::of(&conf, &interface)?;
+    Ok(ClientConf {
+        private_key,
+        address: format!("{}/{}", ip, network.subnet),
+        dns: peer.dns.clone().unwrap_or(network.dns),
+        mtu: peer.mtu.or(network.mtu).map(|mtu| mtu.to_string()),
+        table: peer.table.clone().or(network.table),
+        public_key: network.key,
+        endpoint: Some(network.endpoint),
+        allowed_ips: peer
+            .allowed_ips
+            .clone()
+            .unwrap_or_else(|| "0.0.0.0/0".to_string()),
+        keepalive: peer.keepalive.unwrap_or(network.keepalive).to_string(),
+        scripts: match kill_switch(peer, &conf) {
+            true => kill_switch_scripts(&conf),
+            false => vec![],
+        },
+        obfuscation: network.obfuscation,
+    })
+}
 
-    if !pubkey_output.status.success() {
-        panic!(
-            "wg pubkey finished with code {}",
-            String::from_utf8(pubkey_output.stderr).unwrap()
-        );
+/// Writes the client config of the peer to `conf_path`, returns the path.
+pub async fn gen_conf(peer: &Peer, conf: Arc<Mutex<Ini>>) -> Result<String> {
+    let client = client_conf(peer, conf).await?;
+    let config_path = conf_path(peer)?;
+    match std::fs::write(&config_path, client.render()) {
+        Err(why) => {
+            tracing::error!("Cannot save a client config: {}", why);
+            Err(GimmewireError::from(why))
+        }
+        Ok(_) => Ok(config_path),
     }
-    let public_key =
-        String::from_utf8(pubkey_output.stdout).expect("Cannot convert wg pubkey to string");
+}
 
-    (
-        private_key.trim().to_string(),
-        public_key.trim().to_string(),
-    )
+/// Where gen_conf saves the client config of the peer.
+pub fn conf_path(peer: &Peer) -> Result<String> {
+    Ok(format!("{}/{}.conf", home()?, peer.username))
+}
+
+/// Home directory of the user running gimmewire, where configs and backups are written.
+pub fn home() -> Result<String> {
+    dirs::home_dir()
+        .map(|home| home.to_string_lossy().to_string())
+        .ok_or_else(|| GimmewireError::Config("Cannot find the home directory".to_string()))
+}
+
+/// First free address of the interface pool which isn't reserved, ignoring peers of other
+/// interfaces.
+pub fn get_ip(peers: &[Peer], interface: &Interface) -> Result<Ipv4Addr> {
+    let taken: HashSet<Ipv4Addr> = peers
+        .iter()
+        .filter(|peer| peer.interface == interface.name)
+        .flat_map(|peer| peer.ip)
+        .collect();
+    interface
+        .addresses()
+        .find(|ip| !taken.contains(ip) && !interface.reserves(*ip))
+        .ok_or_else(|| GimmewireError::PoolExhausted(interface.name.clone()))
+}
+
+/// The public key of a private key, with `wg pubkey`.
+pub fn public_key_of(private_key: &str) -> Result<String> {
+    let public_key = wg(&["pubkey"], Some(private_key))
+        .map_err(|why| GimmewireError::KeyGeneration(format!("wg pubkey: {}", why)))?;
+    Ok(public_key.trim().to_string())
+}
+
+/// A new key pair, made off the async workers.
+pub async fn gen_keys() -> Result<(String, String)> {
+    blocking(generate).await
+}
+
+pub fn generate() -> Result<(String, String)> {
+    let private_key = wg(&["genkey"], None)
+        .map_err(|why| GimmewireError::KeyGeneration(format!("wg genkey: {}", why)))?;
+    let private_key = private_key.trim().to_string();
+    let public_key = public_key_of(&private_key)?;
+    Ok((private_key, public_key))
 }
 
 #[cfg(test)]
 #[test]
 fn generate_keys() {
-    let (private, public) = gen_keys();
+    let (private, public) = generate().unwrap();
     println!("{}", private.len());
     assert!(private.len() == 44 && public.len() == 44);
 }
 
+#[cfg(test)]
+#[test]
+fn kill_switch_hooks() {
+    let mut conf = Ini::new();
+    let mut peer = Peer::new(1, "alice".to_string());
+    assert!(!kill_switch(&peer, &conf));
+    conf.set("KillSwitch", "Enabled", Some("true".to_string()));
+    assert!(kill_switch(&peer, &conf));
+    peer.kill_switch = Some(false);
+    assert!(!kill_switch(&peer, &conf));
+    assert!(kill_switch_scripts(&conf)[0].1 == KILL_SWITCH_UP);
+    conf.set("KillSwitch", "PreUp", Some("nft add rule".to_string()));
+    assert!(kill_switch_scripts(&conf) == vec![("PreUp".to_string(), "nft add rule".to_string())]);
+}
+
+#[cfg(test)]
+#[test]
+fn dump_parsing() {
+    let dump = "priv\tpub\t51820\toff
+peerA\t(none)\t1.2.3.4:5000\t10.0.0.2/32\t1670000000\t100\t200\t25
+peerB\t(none)\t(none)\t10.0.0.3/32\t0\t0\t0\toff
+";
+    let stats = parse_dump("wg0", dump);
+    assert!(stats.len() == 2);
+    assert!(stats[0].endpoint.as_deref() == Some("1.2.3.4:5000") && stats[0].tx == 200);
+    assert!(stats[1].endpoint.is_none() && stats[1].latest_handshake.is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn showconf_parsing() {
+    let conf = "[Interface]
+ListenPort = 51820
+PrivateKey = secret
+
+[Peer]
+PublicKey = keyA
+AllowedIPs = 10.0.0.2/32, fd00::2/128
+
+[Peer]
+PublicKey = keyB
+Endpoint = 1.2.3.4:5000
+AllowedIPs = 192.168.0.0/24
+";
+    assert!(
+        parse_showconf(conf)
+            == vec![
+                ("keyA".to_string(), Some(Ipv4Addr::new(10, 0, 0, 2))),
+                ("keyB".to_string(), None)
+            ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn peers_rendering() {
+    let showconf = "[Interface]
+ListenPort = 51820
+PrivateKey = secret
+
+[Peer]
+PublicKey = stranger
+AllowedIPs = 192.168.0.0/24
+
+[Peer]
+PublicKey = keyB
+AllowedIPs = 10.0.0.3/32
+";
+    let interface = Interface {
+        name: "wg0".to_string(),
+        section: "peer".to_string(),
+        device: "wg0".to_string(),
+        host: None,
+        network: Ipv4Addr::new(10, 0, 0, 0),
+        prefix: 16,
+        amnezia: false,
+        reserved: vec![],
+        save: None,
+    };
+    let peer = |name: &str, key: &str, ip| {
+        let mut peer = Peer::new(0, name.to_string());
+        peer.public_key = Some(key.to_string());
+        peer.ip = Some(Ipv4Addr::new(10, 0, 0, ip));
+        peer
+    };
+    let mut suspended = peer("bob", "keyB", 3);
+    suspended.suspended = Some(DateTime::now());
+    let conf = render_peers(showconf, &interface, &[peer("alice", "keyA", 2), suspended]);
+    assert!(conf.starts_with(
+        "[Interface]\nListenPort = 51820\nPrivateKey = secret\n\n[Peer]\nPublicKey = stranger\n"
+    ));
+    assert!(conf.ends_with("\n[Peer]\nPublicKey = keyA\nAllowedIPs = 10.0.0.2/32\n"));
+    assert!(!conf.contains("keyB"));
+    let existing = "[Interface]\nAddress = 10.0.0.1/16\nPostUp = iptables -A FORWARD -i %i -j ACCEPT\nPrivateKey = old\n";
+    let saved = saved_conf(showconf, Some(existing), &interface);
+    assert!(saved.starts_with("[Interface]\nAddress = 10.0.0.1/16\nPostUp = iptables -A FORWARD -i %i -j ACCEPT\nListenPort = 51820\nPrivateKey = secret\n\n[Peer]\nPublicKey = stranger\n"));
+    assert!(
+        saved_conf(showconf, None, &interface).starts_with("[Interface]\nAddress = 10.0.0.1/16\n")
+    );
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn read_conf() {
@@ -220,4 +1179,80 @@ async fn read_conf() {
         .expect("Cannot parse config");
     let name = config.lock().await.get("Mongo", "Name").unwrap();
     assert!(name == "gimmewire");
+    // A peer without keys gets an error instead of taking the bot down
+    let peer = Peer::new(1, "alice".to_string());
+    assert!(gen_conf(&peer, config).await.is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn interface_pools() {
+    let mut config = Ini::new();
+    config
+        .read(
+            "[Peer]\nKey = A\n\n[Interface WG1]\nPool = 10.1.0.0/24\n\n[Interface wg2]\n"
+                .to_string(),
+        )
+        .unwrap();
+    let interfaces = interfaces(&config);
+    assert!(interfaces.len() == 2 && interfaces[1].name == "wg1");
+    assert!(
+        interfaces[1].get(&config, "Key").is_none() && interfaces[0].get(&config, "Key").is_some()
+    );
+    let mut peer = Peer::new(1, "alice".to_string());
+    peer.interface = "wg1".to_string();
+    peer.ip = Some(Ipv4Addr::new(10, 1, 0, 2));
+    assert!(interfaces[1].addresses().count() == 253);
+    assert!(get_ip(&[peer.clone()], &interfaces[1]).unwrap() == Ipv4Addr::new(10, 1, 0, 3));
+    assert!(get_ip(&[peer], &interfaces[0]).unwrap() == Ipv4Addr::new(10, 0, 0, 2));
+}
+
+#[cfg(test)]
+#[test]
+fn reserved_addresses() {
+    let ip = |last| Ipv4Addr::new(10, 0, 0, last);
+    let reserved = reserved_ranges("10.0.0.2-10.0.0.3, 10.0.0.4 10.0.1.7/24").unwrap();
+    assert!(
+        reserved
+            == vec![
+                (ip(2), ip(3)),
+                (ip(4), ip(4)),
+                (Ipv4Addr::new(10, 0, 1, 0), Ipv4Addr::new(10, 0, 1, 255))
+            ]
+    );
+    assert!(
+        reserved_ranges("10.0.0.9-10.0.0.3").is_err() && reserved_ranges("10.0.0.0/33").is_err()
+    );
+    let interface = Interface {
+        name: "wg0".to_string(),
+        section: "peer".to_string(),
+        device: "wg0".to_string(),
+        host: None,
+        network: ip(0),
+        prefix: 16,
+        amnezia: false,
+        reserved,
+        save: None,
+    };
+    let mut alice = Peer::new(1, "alice".to_string());
+    alice.ip = Some(ip(5));
+    assert!(get_ip(&[alice], &interface).unwrap() == ip(6) && interface.reserves(ip(3)));
+}
+
+#[cfg(all(test, any(feature = "mock", not(target_os = "linux"))))]
+#[tokio::test]
+async fn server_key_rotation() {
+    let mut config = Ini::new();
+    config
+        .read("[Peer]\nPool = 10.9.0.0/24\nDevice = rotated0".to_string())
+        .unwrap();
+    let interface = &interfaces(&config)[0];
+    assert!(rotate_server_key(interface, None).await.is_err());
+    let key_file = std::env::temp_dir().join(format!("gimmewire-key-{}", std::process::id()));
+    let key_file = key_file.to_str().unwrap();
+    let public_key = rotate_server_key(interface, Some(key_file)).await.unwrap();
+    let stored = std::fs::read_to_string(key_file).unwrap();
+    std::fs::remove_file(key_file).unwrap();
+    assert_eq!(public_key_of(&stored).unwrap(), public_key);
+    assert_eq!(self::public_key(interface).await.unwrap(), public_key);
 }